@@ -0,0 +1,17 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_control_proto();
+}
+
+/// Generates the gRPC control service, using the vendored protoc so no system install is needed
+#[cfg(feature = "grpc")]
+fn compile_control_proto() {
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is not available");
+    std::env::set_var("PROTOC", protoc);
+
+    // clients are generated by the consumers of the service, in their own language
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/control.proto"], &["proto"])
+        .expect("cannot compile proto/control.proto");
+}
@@ -0,0 +1,140 @@
+//! Sessions of the peers, recorded for security review
+//!
+//! With [crate::config::Config::audit_log_path] set, every [crate::events::Event::PeerSession] is appended to the file as
+//! a line of JSON, with the time it happened, so connections, refused hosts and misbehaving peers can be reviewed later,
+//! like `{"at":1700000000,"peer":"laptop","address":"192.168.1.10","activity":"refused","reason":"..."}`
+//!
+//! Both the sessions opened by the peers and the ones this node opens are recorded  
+//! The lines are written by a dedicated thread, so emitting an event never waits for the disk
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::mpsc,
+    thread::JoinHandle,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::events::PeerActivity;
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Seconds since the unix epoch
+    at: u64,
+    peer: &'a str,
+    address: &'a str,
+    #[serde(flatten)]
+    activity: &'a PeerActivity,
+}
+
+/// Appends the peer sessions to a file
+pub(crate) struct AuditLog {
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, keeping the sessions already recorded
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, receiver) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || write_lines(file, receiver))?;
+
+        Ok(Self {
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+
+    /// Appends `activity` of the peer at `address`, the line is handed to the writer thread
+    pub fn record(&self, peer: &str, address: &str, activity: &PeerActivity) -> crate::Result<()> {
+        let entry = Entry {
+            at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            peer,
+            address,
+            activity,
+        };
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        if let Some(lines) = &self.lines {
+            lines.send(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for AuditLog {
+    /// Waits for the lines already recorded to be written
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+/// Writes every line received until the [AuditLog] is dropped, each line is flushed right away
+fn write_lines(mut file: File, lines: mpsc::Receiver<Vec<u8>>) {
+    for line in lines {
+        if let Err(err) = file.write_all(&line).and_then(|_| file.flush()) {
+            log::error!("cannot write to audit log: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventBus};
+
+    #[test]
+    fn peer_sessions_are_appended() -> crate::Result<()> {
+        std::fs::create_dir_all("./tmp/audit_log")?;
+        let path = Path::new("./tmp/audit_log/audit.log");
+        std::fs::write(path, "")?;
+
+        let session = |activity| Event::PeerSession {
+            peer: "laptop".into(),
+            address: "192.168.1.10".into(),
+            activity,
+        };
+        let events = EventBus::new().with_audit_log(AuditLog::open(path)?);
+        events.emit(session(PeerActivity::Connected));
+        events.emit(Event::AliasPaused { alias: "a".into() });
+        events.emit(session(PeerActivity::Refused {
+            reason: "waiting for authorization".into(),
+        }));
+        drop(events);
+
+        let events = EventBus::new().with_audit_log(AuditLog::open(path)?);
+        events.emit(session(PeerActivity::Disconnected { reason: None }));
+        // the lines are written in the background, dropping the log waits for them
+        drop(events);
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["peer"], "laptop");
+        assert_eq!(lines[0]["address"], "192.168.1.10");
+        assert_eq!(lines[0]["activity"], "connected");
+        assert_eq!(lines[1]["activity"], "refused");
+        assert_eq!(lines[1]["reason"], "waiting for authorization");
+        assert_eq!(lines[2]["activity"], "disconnected");
+        assert!(lines[2]["at"].as_u64().unwrap() > 0);
+
+        std::fs::remove_dir_all("./tmp/audit_log")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! Content addressed storage for file blocks
+//!
+//! Files are split in blocks of [BLOCK_SIZE] bytes, unless their alias sets [crate::config::AliasHashing::block_size],
+//! each block is stored once, identified by the SHA-256 of its content.
+//! Blocks are reference counted, blocks without references are removed by [BlockStore::collect_garbage]
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Size of the blocks files are split into by default
+pub(crate) const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Only one [BlockStore] can be opened at a time, so the reference counts are never overwritten by a concurrent operation
+static STORE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// SHA-256 hash of a block content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct BlockHash([u8; 32]);
+
+impl BlockHash {
+    pub fn from_content(content: &[u8]) -> Self {
+        BlockHash(Sha256::digest(content).into())
+    }
+}
+
+impl Display for BlockHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct BlockStore {
+    root_path: PathBuf,
+    references: HashMap<BlockHash, u64>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl BlockStore {
+    /// Opens the block store located at `root_path`, creating it if necessary
+    ///
+    /// The store is locked until the returned [BlockStore] is dropped, changes to the reference counts are only persisted by [BlockStore::save]
+    pub async fn open(root_path: &Path) -> crate::Result<Self> {
+        let lock = STORE_LOCK.lock().await;
+        let blocks_path = root_path.join("blocks");
+        if !blocks_path.exists() {
+            log::debug!("creating block store at {:?}", root_path);
+            tokio::fs::create_dir_all(&blocks_path).await?;
+        }
+
+        let references_path = root_path.join("references");
+        let references = if references_path.exists() {
+            bincode::deserialize(&tokio::fs::read(&references_path).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(BlockStore {
+            root_path: root_path.to_owned(),
+            references,
+            _lock: lock,
+        })
+    }
+
+    fn block_path(&self, hash: &BlockHash) -> PathBuf {
+        let hash = hash.to_string();
+        self.root_path.join("blocks").join(&hash[..2]).join(hash)
+    }
+
+    /// Stores `content`, returning its hash
+    /// If the block is already stored, only the reference count is increased
+    pub async fn put(&mut self, content: &[u8]) -> crate::Result<BlockHash> {
+        let hash = BlockHash::from_content(content);
+        let block_path = self.block_path(&hash);
+
+        if !block_path.exists() {
+            if let Some(parent) = block_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            let temp_path = block_path.with_extension("tmp");
+            tokio::fs::write(&temp_path, content).await?;
+            tokio::fs::rename(&temp_path, &block_path).await?;
+        }
+
+        *self.references.entry(hash).or_insert(0) += 1;
+
+        Ok(hash)
+    }
+
+    /// Returns the content of the block identified by `hash`
+    ///
+    /// The content is checked against the hash, a damaged block is reported as [crate::IronCarrierError::IOReadingError]
+    pub async fn get(&self, hash: &BlockHash) -> crate::Result<Vec<u8>> {
+        let content = tokio::fs::read(self.block_path(hash)).await?;
+        if BlockHash::from_content(&content) != *hash {
+            log::error!("block {} is damaged", hash);
+            return Err(crate::IronCarrierError::IOReadingError.into());
+        }
+
+        Ok(content)
+    }
+
+    /// Writes the content of `blocks`, in order, to `file`
+    pub async fn write_blocks(
+        &self,
+        blocks: &[BlockHash],
+        file: &mut (impl AsyncWrite + Unpin),
+    ) -> crate::Result<()> {
+        for hash in blocks {
+            file.write_all(&self.get(hash).await?).await?;
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    /// Drops one reference to the block identified by `hash`
+    /// The block content is only removed by [BlockStore::collect_garbage]
+    pub fn release(&mut self, hash: &BlockHash) {
+        if let Some(count) = self.references.get_mut(hash) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Splits the file at `path` in blocks of `block_size` bytes and stores all of them
+    ///
+    /// Returns the list of blocks, in order, necessary to restore the file
+    pub async fn store_file(
+        &mut self,
+        path: &Path,
+        block_size: usize,
+    ) -> crate::Result<Vec<BlockHash>> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut blocks = Vec::new();
+        let mut buf = vec![0u8; block_size];
+
+        loop {
+            let mut read = 0;
+            while read < block_size {
+                match file.read(&mut buf[read..]).await? {
+                    0 => break,
+                    size => read += size,
+                }
+            }
+
+            if read == 0 {
+                break;
+            }
+
+            blocks.push(self.put(&buf[..read]).await?);
+        }
+
+        Ok(blocks)
+    }
+
+    /// Removes all blocks without references, returning how many blocks were removed
+    pub async fn collect_garbage(&mut self) -> crate::Result<usize> {
+        let unreferenced: Vec<BlockHash> = self
+            .references
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(hash, _)| *hash)
+            .collect();
+
+        for hash in unreferenced.iter() {
+            let block_path = self.block_path(hash);
+            if block_path.exists() {
+                tokio::fs::remove_file(block_path).await?;
+            }
+            self.references.remove(hash);
+        }
+
+        log::debug!("removed {} unreferenced blocks", unreferenced.len());
+        Ok(unreferenced.len())
+    }
+
+    /// Persists the reference counts
+    pub async fn save(&self) -> crate::Result<()> {
+        let references_path = self.root_path.join("references");
+        let temp_path = references_path.with_extension("tmp");
+
+        tokio::fs::write(&temp_path, bincode::serialize(&self.references)?).await?;
+        tokio::fs::rename(&temp_path, &references_path).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_store_and_collect_blocks() -> crate::Result<()> {
+        let root_path = Path::new("./tmp/block_store/store_blocks");
+        {
+            let mut store = BlockStore::open(root_path).await?;
+
+            let hash = store.put(b"some content").await?;
+            assert_eq!(hash, store.put(b"some content").await?);
+            assert_eq!(
+                tokio::fs::read(store.block_path(&hash)).await?,
+                b"some content"
+            );
+
+            store.release(&hash);
+            assert_eq!(store.collect_garbage().await?, 0);
+
+            store.release(&hash);
+            assert_eq!(store.collect_garbage().await?, 1);
+            assert!(!store.references.contains_key(&hash));
+            assert!(!store.block_path(&hash).exists());
+        }
+
+        tokio::fs::remove_dir_all(root_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_store_files() -> crate::Result<()> {
+        let root_path = Path::new("./tmp/block_store/store_files");
+        tokio::fs::create_dir_all(root_path).await?;
+
+        let content: Vec<u8> = (0..BLOCK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(root_path.join("file"), &content).await?;
+
+        {
+            let mut store = BlockStore::open(&root_path.join("store")).await?;
+            let blocks = store
+                .store_file(&root_path.join("file"), BLOCK_SIZE)
+                .await?;
+            assert_eq!(blocks.len(), 3);
+            store.save().await?;
+        }
+
+        {
+            let mut store = BlockStore::open(&root_path.join("store")).await?;
+            let blocks = store
+                .store_file(&root_path.join("file"), BLOCK_SIZE)
+                .await?;
+            assert_eq!(store.references[&blocks[0]], 2);
+            assert_eq!(
+                store.get(&blocks[1]).await?,
+                &content[BLOCK_SIZE..BLOCK_SIZE * 2]
+            );
+            assert_eq!(
+                tokio::fs::read(store.block_path(&blocks[2])).await?,
+                &content[BLOCK_SIZE * 2..]
+            );
+        }
+
+        tokio::fs::remove_dir_all(root_path).await?;
+
+        Ok(())
+    }
+}
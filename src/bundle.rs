@@ -0,0 +1,291 @@
+//! Offline transfer of pending changes, through an external drive
+//!
+//! A bundle holds the files changed since the last synchronization with a peer, along with the deletions.
+//! Imported files keep their modification time, so the next synchronization over the network only sends what the bundle didn't carry
+
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    config::Config, deletion_tracker::DeletionTracker, fs, fs::FileInfo,
+    peer_sync_state::PeerSyncState, skipped_files::SkippedFiles, version_store::VersionStores,
+    IronCarrierError,
+};
+
+/// Name of the bundle index, the file contents are stored in a folder for each alias
+const INDEX_FILE_NAME: &str = "bundle.ironcarrier";
+
+/// Content of a bundle for an alias
+#[derive(Serialize, Deserialize)]
+struct BundleAlias {
+    /// Hash of the exporting node file list, when the bundle was created
+    hash: u64,
+    /// Files and deletions carried by the bundle
+    changes: Vec<FileInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleIndex {
+    aliases: HashMap<String, BundleAlias>,
+}
+
+/// Returns the files of `local_files` that are not in `agreed_files` with the same modification time and size  
+/// When there is no agreed list, every file and deletion is pending
+fn pending_changes(local_files: &[FileInfo], agreed_files: Option<&[FileInfo]>) -> Vec<FileInfo> {
+    let agreed: HashMap<&Path, &FileInfo> = agreed_files
+        .unwrap_or_default()
+        .iter()
+        .map(|file| (file.path.as_path(), file))
+        .collect();
+
+    local_files
+        .iter()
+        .filter(|local| match agreed.get(local.path.as_path()) {
+            None => agreed_files.is_none() || local.deleted_at.is_none(),
+            Some(agreed) if local.deleted_at.is_some() => agreed.deleted_at.is_none(),
+            Some(agreed) => agreed.modified_at != local.modified_at || agreed.size != local.size,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Writes the changes pending for `peer_address` to a bundle at `bundle_path`
+///
+/// The bundle content becomes the file list agreed with the peer, if the peer imports it, the next synchronization won't need to exchange file lists
+pub async fn export_bundle(
+    config: &Config,
+    peer_address: &str,
+    bundle_path: &Path,
+) -> crate::Result<()> {
+    let mut index = BundleIndex {
+        aliases: HashMap::new(),
+    };
+    let mut skipped = SkippedFiles::new();
+
+    for (alias, root_path) in &config.paths {
+        let (hash, files) = fs::get_files_with_hash(root_path, alias, config).await?;
+        let sync_state = PeerSyncState::new(root_path);
+        let agreed_files = sync_state.get(peer_address).await.map(|(_, files)| files);
+
+        let skipped_before = skipped.len();
+        let mut changes = Vec::new();
+        for file in pending_changes(&files, agreed_files.as_deref()) {
+            if file.deleted_at.is_none() {
+                let destination = bundle_path.join(alias).join(&file.path);
+                if let Some(parent) = destination.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                if let Err(err) = fs::copy_file(&root_path.join(&file.path), &destination).await {
+                    skipped.add(&file.path, err);
+                    continue;
+                }
+            }
+
+            changes.push(file);
+        }
+
+        log::info!(
+            "{} changes of alias {} added to the bundle",
+            changes.len(),
+            alias
+        );
+
+        if skipped.len() == skipped_before {
+            sync_state.set(peer_address, hash, files).await?;
+        }
+
+        index
+            .aliases
+            .insert(alias.to_owned(), BundleAlias { hash, changes });
+    }
+
+    tokio::fs::create_dir_all(bundle_path).await?;
+    tokio::fs::write(
+        bundle_path.join(INDEX_FILE_NAME),
+        bincode::serialize(&index)?,
+    )
+    .await?;
+
+    skipped.log_summary(&format!("exporting bundle for peer {}", peer_address));
+    Ok(())
+}
+
+/// Returns true if the local file is newer than the `change` carried by the bundle
+fn is_local_newer(root_path: &Path, change: &FileInfo) -> bool {
+    let local = match root_path.join(&change.path).metadata() {
+        Ok(metadata) => FileInfo::new(change.alias.clone(), change.path.clone(), metadata),
+        Err(_) => return false,
+    };
+
+    let change_time = change.deleted_at.or(change.modified_at);
+    local.modified_at > change_time
+}
+
+async fn import_change(
+    config: &Config,
+    bundle_path: &Path,
+    root_path: &Path,
+    change: &FileInfo,
+    versions: &VersionStores,
+) -> crate::Result<()> {
+    let deletion_tracker = DeletionTracker::new(root_path);
+
+    if change.deleted_at.is_some() {
+        fs::delete_file(change, config, versions).await?;
+        return deletion_tracker.add_entry(&change.path).await;
+    }
+
+    let source = bundle_path.join(&change.alias).join(&change.path);
+    let mut source = tokio::fs::File::open(source).await?;
+    {
+        let mut temp_file = fs::get_temp_file(change, config).await?;
+        tokio::io::copy(&mut source, &mut temp_file).await?;
+    }
+
+    fs::flush_temp_file(change, config, versions).await?;
+    deletion_tracker.remove_entry(&change.path).await
+}
+
+/// Applies the bundle at `bundle_path`, exported by `peer_address`
+///
+/// Local files newer than the ones in the bundle are kept. When an alias ends up with the same file list as the exporting peer,
+/// the list is stored as agreed with the peer, so the next synchronization doesn't need to exchange file lists
+pub async fn import_bundle(
+    config: &Config,
+    peer_address: &str,
+    bundle_path: &Path,
+) -> crate::Result<()> {
+    let index_path = bundle_path.join(INDEX_FILE_NAME);
+    if !index_path.exists() {
+        log::error!("{:?} is not a bundle", bundle_path);
+        return Err(IronCarrierError::IOReadingError.into());
+    }
+
+    let index: BundleIndex = bincode::deserialize(&tokio::fs::read(index_path).await?)?;
+    let mut skipped = SkippedFiles::new();
+    let versions = VersionStores::new();
+
+    for (alias, bundle_alias) in index.aliases {
+        let root_path = match config.paths.get(&alias) {
+            Some(root_path) => root_path,
+            None => {
+                log::warn!("alias {} is not configured, ignoring it", alias);
+                continue;
+            }
+        };
+
+        let mut imported = 0;
+        for change in bundle_alias.changes.iter() {
+            if is_local_newer(root_path, change) {
+                continue;
+            }
+
+            match import_change(config, bundle_path, root_path, change, &versions).await {
+                Ok(_) => imported += 1,
+                Err(err) => skipped.add(&change.path, err),
+            }
+        }
+
+        log::info!("{} changes of alias {} imported", imported, alias);
+
+        let (hash, files) = fs::get_files_with_hash(root_path, &alias, config).await?;
+        if hash == bundle_alias.hash {
+            PeerSyncState::new(root_path)
+                .set(peer_address, hash, files)
+                .await?;
+        }
+    }
+
+    skipped.log_summary(&format!("importing bundle from peer {}", peer_address));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FileKind;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn can_export_and_import_bundles() -> crate::Result<()> {
+        let config_a = Config::parse_content(
+            "
+            [paths]
+            a = \"./tmp/bundle/peer_a\""
+                .to_string(),
+        )?;
+        let config_b = Config::parse_content(
+            "
+            [paths]
+            a = \"./tmp/bundle/peer_b\""
+                .to_string(),
+        )?;
+
+        tokio::fs::create_dir_all("./tmp/bundle/peer_a/folder").await?;
+        tokio::fs::write("./tmp/bundle/peer_a/folder/file", "content").await?;
+
+        let bundle_path = Path::new("./tmp/bundle/drive");
+        export_bundle(&config_a, "peer_b", bundle_path).await?;
+        assert!(PeerSyncState::new(Path::new("./tmp/bundle/peer_a"))
+            .get("peer_b")
+            .await
+            .is_some());
+
+        import_bundle(&config_b, "peer_a", bundle_path).await?;
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/bundle/peer_b/folder/file").await?,
+            "content"
+        );
+
+        let (hash_a, _) =
+            fs::get_files_with_hash(Path::new("./tmp/bundle/peer_a"), "a", &config_a).await?;
+        let (hash_b, _) =
+            fs::get_files_with_hash(Path::new("./tmp/bundle/peer_b"), "a", &config_b).await?;
+        assert_eq!(hash_a, hash_b);
+        assert!(PeerSyncState::new(Path::new("./tmp/bundle/peer_b"))
+            .get("peer_a")
+            .await
+            .is_some());
+
+        tokio::fs::remove_dir_all("./tmp/bundle").await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn pending_changes_are_based_on_agreed_files() {
+        let file = |path: &str, modified_at: u64| FileInfo {
+            alias: "a".into(),
+            path: path.into(),
+            modified_at: Some(modified_at),
+            created_at: None,
+            deleted_at: None,
+            size: Some(1),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        let local = vec![
+            file("changed", 2),
+            FileInfo::new_deleted("a".into(), "deleted".into(), None),
+            file("new", 1),
+            file("same", 1),
+        ];
+        let agreed = vec![file("changed", 1), file("deleted", 1), file("same", 1)];
+
+        let paths: Vec<PathBuf> = pending_changes(&local, Some(&agreed))
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("changed"),
+                PathBuf::from("deleted"),
+                PathBuf::from("new")
+            ]
+        );
+        assert_eq!(pending_changes(&local, None).len(), 4);
+    }
+}
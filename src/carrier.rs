@@ -0,0 +1,362 @@
+//! Synchronization engine for applications embedding iron-carrier
+//!
+//! ```no_run
+//! # async fn run() -> iron_carrier::Result<()> {
+//! let mut carrier = iron_carrier::IronCarrier::builder()
+//!     .config_file("./config.toml")
+//!     .build()?;
+//!
+//! let mut events = carrier.subscribe();
+//! carrier.start().await?;
+//! carrier.sync_alias("my_docs").await?;
+//!
+//! while let Ok(event) = events.recv().await {
+//!     println!("{:?}", event);
+//! }
+//!
+//! carrier.stop().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    sync::{broadcast, mpsc, mpsc::Sender},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config,
+    events::{Event, EventBus, SyncObserver},
+    network::{
+        authorization::PeerAuthorizations,
+        transport::{TcpTransport, Transport},
+    },
+    outbox::{self, PendingChange},
+    quarantine::{self, QuarantinedFile},
+    storage::Storage,
+    sync::{
+        in_flight::AfterCancel, introduced_peers::IntroducedPeers, pause_switch::PauseSwitch,
+        sync_state::SyncStates, AliasSyncState, InFlightTransfer, SyncEvent, Synchronizer,
+    },
+    IronCarrierError,
+};
+
+/// Builds an [IronCarrier] from a [Config], or from a configuration file
+#[derive(Default)]
+pub struct IronCarrierBuilder {
+    config: Option<Config>,
+    config_file: Option<PathBuf>,
+    transport: Option<Arc<dyn Transport>>,
+    storages: Vec<(String, Arc<dyn Storage>)>,
+    observers: Vec<Arc<dyn SyncObserver>>,
+}
+
+impl IronCarrierBuilder {
+    /// Uses `config` as the configuration, it takes precedence over [IronCarrierBuilder::config_file]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Reads the configuration from the file at `path` when the engine is built
+    pub fn config_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Connects to the peers with `transport`, instead of the default [TcpTransport]
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Keeps the files of `alias` in `storage`, instead of the local file system, see [crate::storage]
+    pub fn storage<S: Storage + 'static>(mut self, alias: &str, storage: S) -> Self {
+        self.storages.push((alias.to_owned(), Arc::new(storage)));
+        self
+    }
+
+    /// Calls the hooks of `observer` during the synchronization, see [SyncObserver]
+    pub fn observer<O: SyncObserver + 'static>(mut self, observer: O) -> Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Builds the engine, the configuration file is read and validated here
+    pub fn build(self) -> crate::Result<IronCarrier> {
+        let mut config = match (self.config, self.config_file) {
+            (Some(config), _) => config,
+            (None, Some(path)) => Config::new(&path.to_string_lossy())?,
+            (None, None) => return Err(IronCarrierError::ConfigFileNotFound.into()),
+        };
+
+        for (alias, storage) in self.storages {
+            config.set_storage(&alias, storage)?;
+        }
+
+        let transport = self
+            .transport
+            .unwrap_or_else(|| Arc::new(TcpTransport::new(&config)));
+        let synchronizer = Synchronizer::with_transport(config, transport);
+        for observer in self.observers {
+            synchronizer.event_bus().add_observer(observer);
+        }
+
+        Ok(IronCarrier {
+            config: synchronizer.config(),
+            events: synchronizer.event_bus(),
+            introduced_peers: synchronizer.introduced_peers(),
+            authorizations: synchronizer.authorizations(),
+            sync_states: synchronizer.sync_states(),
+            pause_switch: synchronizer.pause_switch(),
+            cancel: synchronizer.cancellation_token(),
+            synchronizer: Some(synchronizer),
+            running: None,
+        })
+    }
+}
+
+/// Synchronization engine, it runs the server, the file watcher and the synchronization with the peers in the background
+///
+/// The engine runs in the current tokio runtime, from [IronCarrier::start] until [IronCarrier::stop] or until it is dropped
+/// A stopped engine can't be started again, a new one must be built
+pub struct IronCarrier {
+    config: Arc<Config>,
+    events: Arc<EventBus>,
+    introduced_peers: Arc<IntroducedPeers>,
+    authorizations: Arc<PeerAuthorizations>,
+    sync_states: Arc<SyncStates>,
+    pause_switch: Arc<PauseSwitch>,
+    cancel: CancellationToken,
+    synchronizer: Option<Synchronizer>,
+    running: Option<(Sender<SyncEvent>, JoinHandle<()>)>,
+}
+
+impl IronCarrier {
+    /// Returns a builder for the engine
+    pub fn builder() -> IronCarrierBuilder {
+        IronCarrierBuilder::default()
+    }
+
+    /// Returns the configuration used by the engine
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Starts listening to the peers and synchronizing with them, returns once the server is listening
+    pub async fn start(&mut self) -> crate::Result<()> {
+        let mut synchronizer = self
+            .synchronizer
+            .take()
+            .ok_or(IronCarrierError::AlreadyStarted)?;
+
+        let (sync_events_sender, sync_events_receiver) = mpsc::channel(50);
+        synchronizer
+            .start_services(sync_events_sender.clone())
+            .await?;
+
+        let task = tokio::spawn(async move {
+            synchronizer.sync_events(sync_events_receiver).await;
+        });
+        self.running = Some((sync_events_sender, task));
+
+        Ok(())
+    }
+
+    /// Stops the engine, the server stops listening and no new synchronization is started
+    /// Scans, transfers and peer sessions in progress are interrupted, partially received files are kept in their temp files
+    pub async fn stop(&mut self) {
+        self.cancel.cancel();
+        if let Some((_, task)) = self.running.take() {
+            task.abort();
+            task.await.ok();
+        }
+    }
+
+    /// Synchronizes `alias` with all configured peers, in the background
+    pub async fn sync_alias(&self, alias: &str) -> crate::Result<()> {
+        let alias = &self.config.alias_id(alias);
+        if !self.config.paths.contains_key(alias) {
+            return Err(IronCarrierError::AliasNotAvailable(alias.to_owned()).into());
+        }
+
+        match &self.running {
+            Some((sync_events, _)) => {
+                sync_events
+                    .send(SyncEvent::SyncAlias(alias.to_owned()))
+                    .await?;
+                Ok(())
+            }
+            None => Err(IronCarrierError::NotStarted.into()),
+        }
+    }
+
+    /// Pauses the synchronization of `alias`, its changes are not sent and the peers stop seeing it, see
+    /// [Config::paused_aliases]  
+    /// Returns false if it was already paused
+    pub fn pause_alias(&self, alias: &str) -> crate::Result<bool> {
+        let alias = &self.config.alias_id(alias);
+        if !self.config.paths.contains_key(alias) {
+            return Err(IronCarrierError::AliasNotAvailable(alias.to_owned()).into());
+        }
+
+        Ok(self.pause_switch.pause_alias(alias))
+    }
+
+    /// Resumes the synchronization of `alias`, it is synchronized with every peer right away when the engine is running  
+    /// Returns false if it wasn't paused
+    pub async fn resume_alias(&self, alias: &str) -> crate::Result<bool> {
+        let alias = &self.config.alias_id(alias);
+        if !self.config.paths.contains_key(alias) {
+            return Err(IronCarrierError::AliasNotAvailable(alias.to_owned()).into());
+        }
+
+        if !self.pause_switch.resume_alias(alias) {
+            return Ok(false);
+        }
+
+        // changes made while paused were not sent, a synchronization with every peer catches up with them
+        if let Some((sync_events, _)) = &self.running {
+            sync_events
+                .send(SyncEvent::SyncAlias(alias.to_owned()))
+                .await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Synchronizes all aliases with `peer_address`, in the background
+    #[cfg(any(test, feature = "simulation"))]
+    pub(crate) async fn sync_peer(&self, peer_address: &str) -> crate::Result<()> {
+        match &self.running {
+            Some((sync_events, _)) => {
+                sync_events
+                    .send(SyncEvent::EnqueueSyncToPeer(peer_address.to_owned(), false))
+                    .await?;
+                Ok(())
+            }
+            None => Err(IronCarrierError::NotStarted.into()),
+        }
+    }
+
+    /// Returns the synchronization state of each alias with each peer, sorted by alias and peer  
+    /// Only the aliases synchronized since the engine started are returned
+    pub fn sync_status(&self) -> Vec<AliasSyncState> {
+        self.sync_states.states()
+    }
+
+    /// Returns the files being sent and received with the peers, files sent in batches are not listed
+    pub fn transfers(&self) -> Vec<InFlightTransfer> {
+        self.sync_states.in_flight().list()
+    }
+
+    /// Cancels the transfer `id`, interrupting the synchronization with its peer, see [IronCarrier::transfers]  
+    /// The file goes after the other transfers in the next synchronization with the peer, or, when `skip` is set, it is
+    /// left out of the synchronizations with the peer until the engine is built again  
+    /// Returns false if there is no such transfer, like when it is already done
+    pub fn cancel_transfer(&self, id: u64, skip: bool) -> bool {
+        let after = if skip {
+            AfterCancel::Skip
+        } else {
+            AfterCancel::Requeue
+        };
+        self.sync_states.in_flight().cancel(id, after)
+    }
+
+    /// Returns the peers learned from the introducers that are waiting for [IronCarrier::approve_peer]
+    pub fn pending_peers(&self) -> Vec<String> {
+        self.introduced_peers.pending()
+    }
+
+    /// Approves a peer learned from the introducers, it is synchronized like the configured peers from now on  
+    /// Approvals are kept in memory only, add the peer to [Config::peers] to keep it
+    pub async fn approve_peer(&self, address: &str) -> crate::Result<()> {
+        if !self.introduced_peers.approve(address) {
+            return Err(IronCarrierError::UnknownPeer(address.to_owned()).into());
+        }
+
+        if let Some((sync_events, _)) = &self.running {
+            sync_events
+                .send(SyncEvent::EnqueueSyncToPeer(address.to_owned(), false))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the hosts that tried to connect without being configured peers, waiting for [IronCarrier::authorize_peer]  
+    /// Only used with [Config::authorized_peers_path]
+    pub fn unauthorized_peers(&self) -> Vec<String> {
+        self.authorizations.pending()
+    }
+
+    /// Approves or rejects the connections from the host at `address`, the decision is written to
+    /// [Config::authorized_peers_path]  
+    /// An approved host connects again on its own, it is not added to [Config::peers]
+    pub async fn authorize_peer(&self, address: &str, approved: bool) -> crate::Result<()> {
+        self.authorizations.decide(address, approved).await
+    }
+
+    /// Returns the changes the file watcher couldn't send to offline peers, sorted by peer, alias and path  
+    /// They are sent once the peer is reachable again, or dropped once the alias is fully synchronized with it
+    pub async fn outbox(&self) -> Vec<PendingChange> {
+        outbox::pending_changes(&self.config).await
+    }
+
+    /// Returns the files left out of the synchronizations because they kept failing, sorted by alias and path  
+    /// Each file is synchronized again after [Config::quarantine_cooldown], see [Config::quarantine_after]
+    pub async fn quarantined_files(&self) -> Vec<QuarantinedFile> {
+        quarantine::quarantined_files(&self.config).await
+    }
+
+    /// Returns the token cancelled when the engine stops, cancelling it interrupts the synchronizations in progress
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Returns a receiver for the [Event]s emitted by the engine
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+}
+
+impl Drop for IronCarrier {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        if let Some((_, task)) = self.running.take() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_start_and_stop() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "port = 8120
+            enable_file_watcher = false
+            [paths]
+            a = \"./tmp/carrier\""
+                .to_string(),
+        )?;
+
+        let mut carrier = IronCarrier::builder().config(config).build()?;
+        assert!(carrier.sync_alias("a").await.is_err());
+
+        carrier.start().await?;
+        assert!(carrier.sync_alias("a").await.is_ok());
+        assert!(carrier.sync_alias("b").await.is_err());
+        assert!(carrier.start().await.is_err());
+
+        carrier.stop().await;
+        assert!(carrier.sync_alias("a").await.is_err());
+
+        std::fs::remove_dir_all("./tmp/carrier")?;
+        Ok(())
+    }
+}
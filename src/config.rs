@@ -1,9 +1,21 @@
 //! Handles configuration
 
 use serde::Deserialize;
-use std::{collections::HashMap, fs::read_to_string, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::IronCarrierError;
+use crate::{
+    pattern::Pattern,
+    scan_index::ScanHints,
+    secrets::{deserialize_optional_secret, deserialize_secret},
+    storage::{LocalStorage, Storage},
+    IronCarrierError,
+};
 
 fn default_port() -> u32 {
     8090
@@ -14,56 +26,1582 @@ fn default_enable_watcher() -> bool {
 fn default_watcher_debounce() -> u64 {
     10
 }
+fn default_incremental_scan() -> bool {
+    true
+}
+fn default_enable_fsync() -> bool {
+    true
+}
+fn default_disk_full_retry() -> u64 {
+    60
+}
+fn default_locked_file_retries() -> u32 {
+    4
+}
+fn default_versions_to_keep() -> usize {
+    5
+}
+fn default_mirror_interval() -> u64 {
+    300
+}
+fn default_scan_workers() -> usize {
+    4
+}
+fn default_transfer_chunk_size() -> usize {
+    64 * 1024
+}
+fn default_max_concurrent_peers() -> usize {
+    4
+}
+fn default_max_concurrent_file_hooks() -> usize {
+    2
+}
+fn default_max_parallel_transfers() -> usize {
+    4
+}
+fn default_enable_bootstrap() -> bool {
+    true
+}
+fn default_multi_source_min_size() -> u64 {
+    64 * 1024 * 1024
+}
+fn default_deduplicate_transfers() -> bool {
+    true
+}
+fn default_quarantine_after() -> u32 {
+    5
+}
+fn default_quarantine_cooldown() -> u64 {
+    3600
+}
+fn default_zero_copy_send() -> bool {
+    true
+}
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+const MAX_PORT: u32 = 65535;
+/// Smallest [AliasHashing::block_size]
+const MIN_BLOCK_SIZE: usize = 4 * 1024;
+/// Largest [AliasHashing::block_size], each block is read in memory
+const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+/// Represents the configuration for the current machine
+#[derive(Clone, Deserialize)]
+pub struct Config {
+    /// Version of the configuration format, see [crate::config_migration]  
+    /// Files of older versions are upgraded when they are read, files without it are from before the format had versions
+    #[serde(default)]
+    pub version: u32,
+
+    /// Contains the folder that will be watched for synchronization  
+    /// **Key** is the path alias  
+    /// **Value** is the path itself  
+    /// Each alias must have its own folder, aliases with the same path are rejected
+    pub paths: HashMap<String, PathBuf>,
+
+    /// Identity of the aliases shared with the peers, each alias is its own identity by default  
+    /// **Key** is the alias, as written in [Config::paths]  
+    /// **Value** is the id the peers and the state files know the alias by  
+    /// To rename an alias, keep its old name as the id, so the peers don't see every file deleted and added again
+    #[serde(default)]
+    pub alias_ids: HashMap<String, String>,
+
+    /// Other folders merged into the aliases, defaults to none  
+    /// **Key** is the alias, it must be present in [Config::paths]  
+    /// **Value** is the folders, see [AliasRoot]  
+    /// The peers see a single alias. Files received from the peers go to the first root whose route matches them, or to
+    /// the folder in [Config::paths], which also keeps the state of the alias. A path present in more than one root is
+    /// taken from the first of them
+    #[serde(default)]
+    pub extra_roots: HashMap<String, Vec<AliasRoot>>,
+    /// contains the address for the other peers  
+    /// in the format IPV4:PORT (**192.168.1.1:9090**)  
+    /// SFTP servers can be declared as `sftp://user@host[:port]/path`, they are moved to [Config::sftp_peers] when the configuration is parsed  
+    /// A peer can only be listed once, by its address or by its name
+    pub peers: Option<Vec<String>>,
+
+    /// Names of the peers, shown in the logs, the events and the errors instead of their addresses, defaults to none  
+    /// **Key** is the name  
+    /// **Value** is the address of the peer  
+    /// The names can be used instead of the addresses in every option that refers to a peer, so when the address of a peer
+    /// changes, only its name has to be updated
+    #[serde(default)]
+    pub peer_names: HashMap<String, String>,
+
+    /// Peers that share their own peers list, defaults to none  
+    /// Each address must be present in [Config::peers], the peers learned from them are synchronized like the configured
+    /// ones, only for the aliases both sides have, once approved, see [Config::auto_add_introduced_peers]
+    #[serde(default)]
+    pub introducers: HashSet<String>,
+
+    /// Synchronizes with the peers learned from the [Config::introducers] without waiting for approval, defaults to false  
+    /// Otherwise, the peers are only synchronized after [crate::IronCarrier::approve_peer]
+    #[serde(default)]
+    pub auto_add_introduced_peers: bool,
+
+    /// File with the decisions about the hosts that connect without being in [Config::peers], defaults to none  
+    /// When set, connections from unknown hosts are refused until they are approved with [crate::IronCarrier::authorize_peer],
+    /// the approved and rejected hosts are written to the file. Without it, every host can connect  
+    /// The decisions are kept apart from the configuration file, so its comments and formatting are never rewritten
+    pub authorized_peers_path: Option<PathBuf>,
+
+    /// File where the sessions of the peers are recorded for security review, defaults to none  
+    /// Connections, authorization results, disconnections and protocol violations are appended as lines of JSON, see
+    /// [crate::events::PeerActivity]
+    pub audit_log_path: Option<PathBuf>,
+
+    /// Named groups of peers, used by [Config::topology], defaults to none  
+    /// **Key** is the group name  
+    /// **Value** is the addresses of the peers in the group, as written in [Config::peers]
+    #[serde(default)]
+    pub peer_groups: HashMap<String, Vec<String>>,
+
+    /// Peers each alias is synchronized with, every alias is synchronized with every peer by default  
+    /// **Key** is the alias, it must be present in [Config::paths]  
+    /// **Value** is the topology, see [AliasTopology]
+    #[serde(default)]
+    pub topology: HashMap<String, AliasTopology>,
+
+    /// Servers that receive a one way copy of every alias through SFTP
+    #[serde(skip)]
+    pub sftp_peers: Vec<SftpPeer>,
+
+    /// Port to listen to connections, defaults to 8090
+    #[serde(default = "default_port")]
+    pub port: u32,
+
+    /// Messages logged by the command line, one of `off`, `error`, `warn`, `info`, `debug` or `trace`, defaults to `error`  
+    /// The `-v` flags take precedence over it
+    pub log_level: Option<String>,
+
+    /// Enable file watchers for real time syncs, defaults to true
+    #[serde(default = "default_enable_watcher")]
+    pub enable_file_watcher: bool,
+
+    /// Seconds to debounce file events, defaults to 10 seconds
+    #[serde(default = "default_watcher_debounce")]
+    pub delay_watcher_events: u64,
+
+    /// Read only the folders changed since the last scan while the file watcher runs, defaults to true  
+    /// The files found by the last scan are kept in the alias root, the first scan after the watcher starts reads the whole alias  
+    /// Archives, aliases in [Config::preserve_hard_links] or [Config::follow_symlinks], and every alias when junctions are
+    /// followed, are always read entirely
+    #[serde(default = "default_incremental_scan")]
+    pub incremental_scan: bool,
+
+    /// Flush received files and their folders to disk before they replace the local file, defaults to true  
+    /// Disabling it is faster, but a power loss may leave empty or partial files behind
+    #[serde(default = "default_enable_fsync")]
+    pub enable_fsync: bool,
+
+    /// Seconds to wait before retrying to write a received file when the disk is full, defaults to 60 seconds  
+    /// The file is given up after a few attempts, files that don't fit the disk are refused before they are sent
+    #[serde(default = "default_disk_full_retry")]
+    pub disk_full_retry_seconds: u64,
+
+    /// Times to retry replacing a local file locked by another process, defaults to 4  
+    /// The first retry waits 250 milliseconds, and each retry waits twice as long as the previous one  
+    /// Only Windows locks files in use, like documents opened by Office or database files
+    #[serde(default = "default_locked_file_retries")]
+    pub locked_file_retries: u32,
+
+    /// Keeps received files that are still locked after [Config::locked_file_retries] in their temp file, defaults to false  
+    /// They replace the local file the next time the engine starts, otherwise they are discarded and received again in the next synchronization
+    #[serde(default)]
+    pub replace_locked_files_on_start: bool,
+
+    /// Synchronizations in a row a file can fail before it is quarantined, defaults to 5, 0 disables the quarantine  
+    /// Quarantined files are left out of the synchronizations for [Config::quarantine_cooldown], see [crate::quarantine]
+    #[serde(default = "default_quarantine_after")]
+    pub quarantine_after: u32,
+
+    /// Seconds a quarantined file is left out of the synchronizations, defaults to 3600  
+    /// Failures further apart than this are not counted in a row
+    #[serde(default = "default_quarantine_cooldown")]
+    pub quarantine_cooldown: u64,
+
+    /// Path for the block store, disabled by default  
+    /// When provided, the previous content of files changed or deleted by the synchronization is kept in the store
+    pub block_store_path: Option<PathBuf>,
+
+    /// Turns this peer into an append only backup, defaults to false, requires [Config::block_store_path]  
+    /// Files deleted by the peers are kept on disk and only listed as deleted, every previous version is kept in the
+    /// block store and local changes are not sent to the peers
+    #[serde(default)]
+    pub archive_mode: bool,
+
+    /// Number of previous versions kept for each file in the block store, defaults to 5
+    #[serde(default = "default_versions_to_keep")]
+    pub versions_to_keep: usize,
+
+    /// Remote mirrors for the aliases, disabled by default  
+    /// **Key** is the alias, it must be present in [Config::paths]  
+    /// **Value** is the mirror configuration
+    #[serde(default)]
+    pub mirrors: HashMap<String, MirrorConfig>,
+
+    /// Commands executed before and after an alias is synchronized, defaults to none  
+    /// **Key** is the alias, it must be present in [Config::paths]  
+    /// **Value** is the commands, see [SyncHooks]
+    #[serde(default)]
+    pub hooks: HashMap<String, SyncHooks>,
+
+    /// Commands executed for each file received from a peer, defaults to none  
+    /// Every hook with a matching pattern is executed, see [FileHook]
+    #[serde(default)]
+    pub file_hooks: Vec<FileHook>,
+
+    /// Number of [Config::file_hooks] commands running at the same time, defaults to 2  
+    /// Other commands wait for their turn, so receiving many files at once doesn't overload the host
+    #[serde(default = "default_max_concurrent_file_hooks")]
+    pub max_concurrent_file_hooks: usize,
+
+    /// Aliases that are scanned without crossing into other file systems, defaults to none  
+    /// Folders mounted inside these aliases, like network shares or bind mounts, are not synchronized
+    #[serde(default)]
+    pub one_file_system: HashSet<String>,
+
+    /// Aliases that keep the creation time of the received files, defaults to none  
+    /// Only Windows and macOS can set the creation time, other platforms keep the time the file was received
+    #[serde(default)]
+    pub preserve_creation_time: HashSet<String>,
+
+    /// Aliases that keep the read only, hidden and system attributes of the files, defaults to none  
+    /// The attributes are read in Windows and sent with the file, unix systems only apply the read only attribute, by
+    /// removing the write permissions. Changing only the attributes doesn't change the file, they are sent with its content
+    #[serde(default)]
+    pub preserve_file_attributes: HashSet<String>,
+
+    /// Aliases that keep the permissions and POSIX ACLs of the files, defaults to none  
+    /// The permission bits are read in unix systems and the access ACL in Linux, both are sent with the file content.
+    /// Owners in the ACL are kept by id, so the peers must share the same users and groups, like in an LDAP domain
+    #[serde(default)]
+    pub preserve_permissions: HashSet<String>,
+
+    /// Aliases where files hard linked together are recreated as hard links in the peers, defaults to none  
+    /// Only unix systems detect hard links, the option must be enabled for the alias in every peer
+    #[serde(default)]
+    pub preserve_hard_links: HashSet<String>,
+
+    /// Aliases where symbolic links to folders are followed, defaults to none  
+    /// Links pointing to one of their parent folders are left out, so they can't loop forever
+    #[serde(default)]
+    pub follow_symlinks: HashSet<String>,
+
+    /// Aliases where hidden files and folders are not synchronized, defaults to none  
+    /// Names starting with a dot are hidden, in Windows the files with the hidden attribute are hidden too  
+    /// Hidden files sent by the peers are refused, so the option can be enabled in a single peer
+    #[serde(default)]
+    pub ignore_hidden: HashSet<String>,
+
+    /// Aliases where text files changed in both peers are merged, defaults to none  
+    /// Changes to different lines are combined, conflict markers are written when both peers changed the same lines  
+    /// Requires [Config::block_store_path], where the last content both peers agreed on is kept
+    #[serde(default)]
+    pub merge_text_files: HashSet<String>,
+
+    /// External commands used to merge the files in [Config::merge_text_files], instead of the line based merge, defaults to none  
+    /// The first driver with a matching pattern is used, see [MergeDriver]
+    #[serde(default)]
+    pub merge_drivers: Vec<MergeDriver>,
+
+    /// Seconds between pushes to the mirrors, defaults to 300 seconds
+    #[serde(default = "default_mirror_interval")]
+    pub mirror_interval_seconds: u64,
+
+    /// Seconds between full synchronizations of the aliases with the peers, disabled by default  
+    /// Without it, the aliases are only scanned at start up, the file watcher sends the changes made after that
+    pub scan_interval_seconds: Option<u64>,
+
+    /// When each alias is scanned, overrides [Config::scan_interval_seconds]
+    #[serde(default)]
+    pub scan_schedule: HashMap<String, ScanSchedule>,
+
+    /// Limits of the files in each alias, there are no limits by default
+    #[serde(default)]
+    pub limits: HashMap<String, AliasLimits>,
+
+    /// Content hashing of each alias, SHA-256 and blocks of 128 KiB by default
+    #[serde(default)]
+    pub hashing: HashMap<String, AliasHashing>,
+
+    /// Deletions synchronized in each alias, deletions are synchronized both ways by default  
+    /// **Key** is the alias, it must be present in [Config::paths]  
+    /// **Value** is `false`, or which deletions are synchronized, see [DeletePropagation]
+    #[serde(default)]
+    pub propagate_deletes: HashMap<String, DeletePropagation>,
+
+    /// Size of the chunks used to send and receive files, in bytes, defaults to 64 KiB  
+    /// Larger chunks are faster in local networks, smaller ones keep slow links responsive
+    #[serde(default = "default_transfer_chunk_size")]
+    pub transfer_chunk_size: usize,
+
+    /// Adjusts the size of the chunks sent according to the throughput, defaults to false  
+    /// The size starts at [Config::transfer_chunk_size] and grows while chunks are sent quickly
+    #[serde(default)]
+    pub adaptive_chunk_size: bool,
+
+    /// Approximate memory, in MiB, each file list may use during a synchronization, unlimited by default  
+    /// Larger lists are sorted and kept in temp files, so gigantic trees can be compared with little memory, at the cost of disk access
+    pub memory_budget_mb: Option<usize>,
+
+    /// Number of folders read, and files hashed, at the same time when scanning an alias, defaults to 4  
+    /// Spinning disks are usually faster with 1, since parallel reads make them seek
+    #[serde(default = "default_scan_workers")]
+    pub scan_workers: usize,
+
+    /// Limits the disk usage of the scans, and of the files hashed in the background, defaults to no limit  
+    /// Keeps the first scan of a huge alias from making the rest of the system unresponsive
+    #[serde(default)]
+    pub scan_throttle: ScanThrottle,
+
+    /// What to do with sockets, FIFOs and devices, defaults to [SpecialFilePolicy::Skip]  
+    /// Their content is never read, reading a FIFO or a device could block the scan forever
+    #[serde(default)]
+    pub special_files: SpecialFilePolicy,
+
+    /// What to do with NTFS junctions and other folders redirected to another place, defaults to [ReparsePointPolicy::Skip]  
+    /// Only used in Windows, symbolic links follow [Config::follow_symlinks]
+    #[serde(default)]
+    pub reparse_points: ReparsePointPolicy,
+
+    /// What to do with received files that only differ by case from a local file, defaults to [CaseCollisionPolicy::Skip]  
+    /// Only case insensitive file systems, like the Windows and macOS defaults, have collisions
+    #[serde(default)]
+    pub case_collision_policy: CaseCollisionPolicy,
+
+    /// Order of the files transferred in each alias, defaults to [TransferOrder::Path]
+    #[serde(default)]
+    pub transfer_order: TransferOrder,
+
+    /// Patterns of files transferred before the others, like `*.md`, defaults to none  
+    /// Files matching the first pattern go first, then the ones matching the second pattern and so on,
+    /// files with the same priority follow [Config::transfer_order]
+    #[serde(default)]
+    pub transfer_priorities: Vec<String>,
+
+    /// Files of each alias applied together, defaults to none  
+    /// **Key** is the alias  
+    /// **Value** has a pattern for each group, like `app/data/**`, same syntax as [Config::transfer_priorities]  
+    /// The files of a group are transferred last, in a single stream, and only replace the local files once all of them
+    /// were received, so local readers never see part of the group updated
+    #[serde(default)]
+    pub atomic_groups: HashMap<String, Vec<String>>,
+
+    /// Files synchronized in each alias, every file by default  
+    /// **Key** is the alias  
+    /// **Value** has the patterns of the files synchronized, like `*.raw`, same syntax as [Config::transfer_priorities]  
+    /// Only the files matching one of the patterns are scanned and sent, files the peers send that don't match any of them
+    /// are refused
+    #[serde(default)]
+    pub include_only: HashMap<String, Vec<String>>,
+
+    /// Number of peers synchronized at the same time, defaults to 4  
+    /// Changes written to the same alias are still applied one session at a time
+    #[serde(default = "default_max_concurrent_peers")]
+    pub max_concurrent_peers: usize,
+
+    /// Files at least this size, in bytes, are downloaded in parts from every peer with the same content, defaults to 64 MiB  
+    /// Smaller files are downloaded from a single peer
+    #[serde(default = "default_multi_source_min_size")]
+    pub multi_source_min_size: u64,
+
+    /// Copy the content of a requested file from a local file with the same content, in any alias, defaults to true  
+    /// Only files of at least 1 MiB are compared, the local file is found by its size and content hash
+    #[serde(default = "default_deduplicate_transfers")]
+    pub deduplicate_transfers: bool,
+
+    /// Seed an alias that is empty in one of the sides, with no agreed state, in a single packed stream, defaults to true  
+    /// The files are sent without a round trip for each one, the normal synchronization takes over once the stream is over
+    #[serde(default = "default_enable_bootstrap")]
+    pub enable_bootstrap: bool,
+
+    /// Aliases compared and transferred while they are scanned, when there is no agreed state with the peer, defaults to none  
+    /// Folders are read in path order as the comparison goes, so the first files are sent before a huge alias is fully scanned  
+    /// Files are sent in path order instead of [Config::transfer_order], deletions are applied at the end, and it takes
+    /// the place of [Config::enable_bootstrap]
+    #[serde(default)]
+    pub streaming_scan: HashSet<String>,
+
+    /// Aliases where only the metadata of the files received from the peers is synchronized, defaults to none  
+    /// The files are kept as placeholders, an empty `<name>.placeholder.ironcarrier` file, until they are fetched with
+    /// [crate::on_demand::fetch]. Fetched files are kept up to date, placeholders are not sent to the peers
+    #[serde(default)]
+    pub on_demand: HashSet<String>,
+
+    /// Aliases declared but not synchronized, defaults to none  
+    /// Paused aliases are not scanned, their changes are not sent and the peers don't see them, so large shares can be
+    /// configured before they are ready. They are resumed with [crate::IronCarrier::resume_alias] while running, and
+    /// synchronized with every peer right away
+    #[serde(default)]
+    pub paused_aliases: HashSet<String>,
+
+    /// Folders of each alias synchronized by this node, the whole alias by default  
+    /// **Key** is the alias, it must be present in [Config::paths]  
+    /// **Value** is the folders, relative to the alias root, like `2024`  
+    /// The folders are sent to the peers when they connect, files outside them are not listed, sent, requested or deleted
+    /// by either side
+    #[serde(default)]
+    pub subscriptions: HashMap<String, Vec<PathBuf>>,
+
+    /// Transfers each peer runs with this node at the same time, defaults to 4  
+    /// The limit is advertised to the peers when they connect, along with the largest chunk this node wants to receive,
+    /// [Config::transfer_chunk_size] unless [Config::adaptive_chunk_size] is on, keep both low on small devices
+    #[serde(default = "default_max_parallel_transfers")]
+    pub max_parallel_transfers: usize,
+
+    /// Free space, in bytes, below which this node tells the peers it is low on disk space, disabled by default  
+    /// The peers stop sending new files and updates while the space of any alias is below it, files are still sent to the peers
+    #[serde(default)]
+    pub min_free_space: u64,
+
+    /// Bytes per second sent to each peer in the local network, unlimited by default
+    #[serde(default)]
+    pub lan_bandwidth_limit: u64,
+
+    /// Bytes per second sent to each peer outside the local network, unlimited by default  
+    /// Peers are classified every time they connect, so a laptop moving between networks gets the limit of where it is
+    #[serde(default)]
+    pub wan_bandwidth_limit: u64,
+
+    /// Bandwidth limits for parts of the day, defaults to none  
+    /// During a window, its limit replaces [Config::lan_bandwidth_limit] and [Config::wan_bandwidth_limit] for every peer,
+    /// the first window containing the current time is used  
+    /// The schedule is evaluated while the files are sent, so long transfers follow it without a restart
+    #[serde(default)]
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+
+    /// Network of the peers, overriding the classification by address, defaults to none  
+    /// **Key** is the host of the peer, without the port  
+    /// **Value** is `lan` or `wan`, see [NetworkClass]
+    #[serde(default)]
+    pub peer_networks: HashMap<String, NetworkClass>,
+
+    /// Send the content of the files from the disk to the TCP sockets without copying it through iron-carrier, defaults to true  
+    /// Only available on Linux, the content is still read to compute its checksum
+    #[serde(default = "default_zero_copy_send")]
+    pub zero_copy_send: bool,
+
+    /// Verify files of at least this size, in bytes, by hashing samples of their content instead of all of it, disabled by default  
+    /// Only used with peers that set it too, the largest size of both peers is used. Cuts the CPU used by large transfers,
+    /// but corruption outside of the samples is not detected, keep it for trusted networks
+    #[serde(default)]
+    pub sampled_checksum_min_size: Option<u64>,
+
+    /// Options of the TCP sockets used with every peer, the operating system defaults are kept for the options not set
+    #[serde(default)]
+    pub socket: SocketOptions,
+
+    /// Options of the TCP sockets used with specific peers, they take precedence over [Config::socket]  
+    /// **Key** is the peer address, as written in [Config::peers], connections accepted from the peer match it by host
+    #[serde(default)]
+    pub peer_sockets: HashMap<String, SocketOptions>,
+
+    /// Address for the gRPC control service, in the format IP:PORT (**127.0.0.1:8190**), disabled by default  
+    /// The service is only available when built with the `grpc` feature
+    pub grpc_address: Option<String>,
+
+    /// Storage backends of the aliases that are not in the local file system, see [Config::set_storage]
+    #[serde(skip)]
+    storages: HashMap<String, Arc<dyn Storage>>,
+
+    /// Folders changed since the last scan of each alias, recorded by the file watcher
+    #[serde(skip)]
+    scan_hints: Arc<ScanHints>,
+}
+
+/// Order of the files transferred in each alias
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferOrder {
+    /// Alphabetical order of the file paths
+    #[default]
+    Path,
+    /// Smaller files first
+    SmallestFirst,
+    /// Most recently modified files first
+    NewestFirst,
+}
+
+/// Bandwidth limit during part of the day, see [Config::bandwidth_schedule]
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandwidthWindow {
+    /// Local time the window starts, in the format HH:MM
+    pub from: String,
+    /// Local time the window ends, in the format HH:MM, a window ending before it starts goes past midnight
+    pub to: String,
+    /// Bytes per second sent to each peer during the window, unlimited by default
+    #[serde(default)]
+    pub limit: u64,
+}
+
+impl BandwidthWindow {
+    /// Returns true if the window contains `minute`, counted from midnight
+    pub(crate) fn contains(&self, minute: u32) -> bool {
+        match (parse_time_of_day(&self.from), parse_time_of_day(&self.to)) {
+            (Some(from), Some(to)) if from <= to => from <= minute && minute < to,
+            (Some(from), Some(to)) => minute >= from || minute < to,
+            _ => false,
+        }
+    }
+
+    /// Returns the bytes per second sent during the window, [None] if unlimited
+    pub(crate) fn limit(&self) -> Option<u64> {
+        if self.limit == 0 {
+            None
+        } else {
+            Some(self.limit)
+        }
+    }
+}
+
+/// Returns true if `path`, relative to the root of `alias`, is inside the `subscriptions` of the alias, see
+/// [Config::subscriptions]
+pub(crate) fn is_subscribed(
+    subscriptions: &HashMap<String, Vec<PathBuf>>,
+    alias: &str,
+    path: &Path,
+) -> bool {
+    match subscriptions.get(alias) {
+        Some(folders) => folders.iter().any(|folder| path.starts_with(folder)),
+        None => true,
+    }
+}
+
+/// Returns the minutes from midnight of `time`, in the format HH:MM
+fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+/// Replaces the option in `config` named by `config_override`, in the form `key=value`, see [Config::with_overrides]
+fn apply_override(config: &mut toml::Value, config_override: &str) -> crate::Result<()> {
+    let (key, raw_value) = config_override.split_once('=').ok_or_else(|| {
+        IronCarrierError::ConfigFileIsInvalid(format!(
+            "override must be in the form key=value: {}",
+            config_override
+        ))
+    })?;
+    let value = toml::from_str::<toml::value::Table>(&format!("value = {}", raw_value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw_value.to_owned()));
+
+    let mut keys: Vec<&str> = key.trim().split('.').collect();
+    let last_key = keys.pop().unwrap_or_default();
+    let mut table = config;
+    for table_key in keys {
+        table = table
+            .as_table_mut()
+            .ok_or_else(|| {
+                IronCarrierError::ConfigFileIsInvalid(format!("cannot override {}", key))
+            })?
+            .entry(table_key.to_owned())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+    }
+
+    log::debug!("overriding {} with {}", key, value);
+    table
+        .as_table_mut()
+        .ok_or_else(|| IronCarrierError::ConfigFileIsInvalid(format!("cannot override {}", key)))?
+        .insert(last_key.to_owned(), value);
+    Ok(())
+}
+
+/// Network a peer is in, peers with private or link local addresses are in the local network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkClass {
+    /// Local network, files are sent with [Config::lan_bandwidth_limit]
+    Lan,
+    /// Outside the local network, files are sent with [Config::wan_bandwidth_limit]
+    Wan,
+}
+
+/// How the peers of an alias are connected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopologyMode {
+    /// The alias is synchronized with every peer
+    #[default]
+    FullMesh,
+    /// The alias is only synchronized with the hub, the hub synchronizes it with every peer
+    HubAndSpoke,
+}
+
+/// Peers an alias is synchronized with
+///
+/// Only the synchronizations started by this peer follow the topology, so every peer must be configured with the same one
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AliasTopology {
+    /// How the peers are connected, defaults to full_mesh
+    #[serde(default)]
+    pub mode: TopologyMode,
+    /// Address of the hub, required by [TopologyMode::HubAndSpoke]  
+    /// The hub is the peer that doesn't have this address in its [Config::peers]
+    pub hub: Option<String>,
+    /// Name of the [Config::peer_groups] the alias is limited to, all peers by default
+    pub group: Option<String>,
+}
+
+/// What to do with sockets, FIFOs and devices found in an alias
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialFilePolicy {
+    /// Special files are not synchronized, they are reported at the end of the scan
+    #[default]
+    Skip,
+    /// FIFOs are synchronized without content, the peers create them with the same name, other special files are skipped
+    PreserveFifos,
+}
+
+/// What to do with the NTFS junctions, and other redirected folders, found in an alias
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReparsePointPolicy {
+    /// The folders are not synchronized, like redirected system folders
+    #[default]
+    Skip,
+    /// The folders are read like regular folders, redirections to one of their parents are left out
+    Follow,
+    /// The scan fails, the alias is not synchronized until the folder is removed or the policy changes
+    Error,
+}
+
+/// What to do with a received file whose name only differs by case from a local file, in a case insensitive file system
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseCollisionPolicy {
+    /// The file is not received, and it is reported in the synchronization summary
+    #[default]
+    Skip,
+    /// The file is received with ` (case conflict)` added to its name
+    Rename,
+}
+
+/// Deletions synchronized in an alias, see [Config::propagate_deletes]
+///
+/// Files deleted on one side and kept on the other are left as they are, they are not received again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(from = "DeletePropagationValue")]
+pub enum DeletePropagation {
+    /// Local deletions remove the files on the peers, and deletions on the peers remove the local files
+    #[default]
+    Both,
+    /// Local deletions remove the files on the peers, deletions on the peers are not applied
+    Outgoing,
+    /// Deletions on the peers remove the local files, local deletions are not sent
+    Incoming,
+    /// Deletions are never synchronized, the same as `false`
+    None,
+}
+
+impl DeletePropagation {
+    /// Returns true if local deletions remove the files on the peers
+    pub fn outgoing(self) -> bool {
+        matches!(self, DeletePropagation::Both | DeletePropagation::Outgoing)
+    }
+
+    /// Returns true if deletions on the peers remove the local files
+    pub fn incoming(self) -> bool {
+        matches!(self, DeletePropagation::Both | DeletePropagation::Incoming)
+    }
+}
+
+/// [DeletePropagation] as written in the config file, `true`, `false` or the name of a direction
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeletePropagationValue {
+    Enabled(bool),
+    Direction(DeletePropagationDirection),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeletePropagationDirection {
+    Both,
+    Outgoing,
+    Incoming,
+    None,
+}
+
+impl From<DeletePropagationValue> for DeletePropagation {
+    fn from(value: DeletePropagationValue) -> Self {
+        match value {
+            DeletePropagationValue::Enabled(true)
+            | DeletePropagationValue::Direction(DeletePropagationDirection::Both) => {
+                DeletePropagation::Both
+            }
+            DeletePropagationValue::Enabled(false)
+            | DeletePropagationValue::Direction(DeletePropagationDirection::None) => {
+                DeletePropagation::None
+            }
+            DeletePropagationValue::Direction(DeletePropagationDirection::Outgoing) => {
+                DeletePropagation::Outgoing
+            }
+            DeletePropagationValue::Direction(DeletePropagationDirection::Incoming) => {
+                DeletePropagation::Incoming
+            }
+        }
+    }
+}
+
+/// SFTP server declared in the peers list
+///
+/// Every alias is mirrored to a folder with the alias name inside [SftpPeer::path]  
+/// Authentication is delegated to the `sftp` command, so it must be able to log in without a password, using keys or an agent
+#[derive(Debug, Clone, PartialEq)]
+pub struct SftpPeer {
+    /// Login destination, in the format **user@host**
+    pub destination: String,
+    /// SSH port, when not provided the `sftp` command default is used
+    pub port: Option<u16>,
+    /// Folder on the server holding the aliases
+    pub path: String,
+}
+
+impl SftpPeer {
+    /// Parses `sftp://user@host[:port]/path`, returns [None] if `address` is not an sftp address
+    pub fn parse(address: &str) -> Option<Self> {
+        let address = address.strip_prefix("sftp://")?;
+        let (authority, path) = match address.find('/') {
+            Some(index) => (&address[..index], &address[index..]),
+            None => (address, "."),
+        };
+
+        let (destination, port) = match authority.rfind(':') {
+            Some(index) => (
+                &authority[..index],
+                Some(authority[index + 1..].parse().ok()?),
+            ),
+            None => (authority, None),
+        };
+
+        if destination.is_empty() {
+            return None;
+        }
+
+        Some(SftpPeer {
+            destination: destination.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// When an alias is scanned and synchronized with the peers
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ScanSchedule {
+    /// Seconds between full synchronizations of the alias
+    pub interval_seconds: Option<u64>,
+    /// The alias is only synchronized when requested, with [crate::IronCarrier::sync_alias] or the control service  
+    /// It isn't scanned at start up, changes found by the file watcher are still sent
+    #[serde(default)]
+    pub manual: bool,
+}
+
+/// Limits of the files in an alias
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AliasLimits {
+    /// Files larger than this, in bytes, are not synchronized  
+    /// They are reported as skipped by the scan, and refused when a peer sends them
+    pub max_file_size: Option<u64>,
+    /// A warning is logged when the scan finds more files than this in the alias
+    pub max_files: Option<u64>,
+    /// Maximum size of the alias on disk, in bytes, only for aliases in the local file system  
+    /// Files received once the alias reaches it are refused, the peers are told the alias is over its quota
+    pub max_total_size: Option<u64>,
+    /// Levels of folders synchronized, 1 only synchronizes the files in the alias root  
+    /// Deeper files are left out by the scan and refused when a peer sends them, manifests record the depth
+    pub max_depth: Option<usize>,
+}
+
+/// Content hashing of an alias, see [Config::hashing]
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct AliasHashing {
+    /// Algorithm of the content hashes, used by manifests, repairs and to verify files downloaded in parts  
+    /// Repairs compare the hashes of every peer, so the peers should use the same algorithm for the alias
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+    /// Size of the blocks the previous versions and snapshots of the alias are split into, in bytes, defaults to 128 KiB  
+    /// Only the blocks that changed are stored again, smaller blocks keep less data for files changed in small parts
+    pub block_size: Option<usize>,
+}
+
+/// Algorithm of the content hashes of an alias
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum HashAlgorithm {
+    /// SHA-256, the fastest one in processors with SHA instructions
+    #[default]
+    #[serde(rename = "sha256")]
+    Sha256,
+    /// SHA-512 truncated to 256 bits, faster than SHA-256 in 64 bit processors without SHA instructions
+    #[serde(rename = "sha512_256")]
+    Sha512Trunc256,
+}
+
+impl HashAlgorithm {
+    /// Returns the prefix of the hashes of this algorithm, SHA-256 hashes have none, like the ones of older versions
+    pub(crate) fn prefix(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "",
+            HashAlgorithm::Sha512Trunc256 => "sha512_256:",
+        }
+    }
+
+    /// Returns the algorithm that computed `hash`
+    pub(crate) fn of_hash(hash: &str) -> Self {
+        if hash.starts_with(HashAlgorithm::Sha512Trunc256.prefix()) {
+            HashAlgorithm::Sha512Trunc256
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+}
+
+/// Options of the TCP sockets used with the peers, see [Config::socket]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct SocketOptions {
+    /// Sends small writes right away, instead of waiting to fill a packet
+    pub nodelay: Option<bool>,
+    /// Size of the kernel send buffer, in bytes, high latency links need at least their bandwidth times their latency
+    pub send_buffer_size: Option<usize>,
+    /// Size of the kernel receive buffer, in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// Seconds without traffic before keepalive probes are sent, 0 disables keepalive
+    pub keepalive_seconds: Option<u64>,
+}
+
+impl SocketOptions {
+    /// Returns these options, with the ones not set taken from `defaults`
+    pub fn or(&self, defaults: &SocketOptions) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.nodelay.or(defaults.nodelay),
+            send_buffer_size: self.send_buffer_size.or(defaults.send_buffer_size),
+            recv_buffer_size: self.recv_buffer_size.or(defaults.recv_buffer_size),
+            keepalive_seconds: self.keepalive_seconds.or(defaults.keepalive_seconds),
+        }
+    }
+}
+
+/// Limits of the disk usage of the scans, see [Config::scan_throttle]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct ScanThrottle {
+    /// Share of the time spent reading the disk, in percent, no limit by default  
+    /// Each read is followed by a pause in proportion to the time it took, so slower disks get longer pauses
+    pub io_percent: Option<u8>,
+    /// Reads with the idle IO priority, so the disk is only used when no other process needs it, only on Linux
+    #[serde(default)]
+    pub idle_priority: bool,
+}
+
+/// Longest pause after a single read, so a read stalled by the disk doesn't stop the scan for long
+const MAX_SCAN_PAUSE: Duration = Duration::from_secs(1);
+
+impl ScanThrottle {
+    /// Returns the time to wait after `busy` time reading the disk, [None] if there is no limit
+    pub(crate) fn pause_after(&self, busy: Duration) -> Option<Duration> {
+        let io_percent = u32::from(self.io_percent?);
+        if io_percent >= 100 {
+            return None;
+        }
+
+        Some((busy * (100 - io_percent) / io_percent).min(MAX_SCAN_PAUSE))
+    }
+}
+
+/// Commands executed when an alias is synchronized with a peer
+///
+/// The commands run in the alias folder, for the synchronizations started by this peer. The changes made are written to
+/// the command stdin as JSON, the alias, peer and number of changes are available in the `IRON_CARRIER_*` environment variables
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SyncHooks {
+    /// Executed before the alias is scanned, like dumping a database into the alias folder  
+    /// The alias is not synchronized with the peer when the command fails
+    pub pre_sync: Option<String>,
+    /// Executed after the alias is synchronized, like restarting a service that reads the received files
+    pub post_sync: Option<String>,
+}
+
+/// Folder merged into an alias, see [Config::extra_roots]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AliasRoot {
+    /// Path of the folder
+    pub path: PathBuf,
+    /// Patterns of the new files received from the peers kept in this folder, like `2019/**`, defaults to none  
+    /// Same syntax as [Config::transfer_priorities], files that already exist stay in the folder they are
+    #[serde(default)]
+    pub route: Vec<String>,
+}
+
+/// Command executed when a file matching a pattern is created or updated by a peer
+///
+/// The command runs in the alias folder, the `IRON_CARRIER_ALIAS`, `IRON_CARRIER_FILE` (relative to the alias) and
+/// `IRON_CARRIER_FILE_PATH` (absolute) environment variables point to the received file
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileHook {
+    /// Alias the hook applies to, all aliases by default
+    pub alias: Option<String>,
+    /// Pattern matched against the received file, same syntax as [Config::transfer_priorities]
+    pub pattern: String,
+    /// Command executed for each matching file
+    pub command: String,
+}
+
+/// External command that merges files changed in both peers, like a JSON aware merger
+///
+/// The `IRON_CARRIER_BASE`, `IRON_CARRIER_LOCAL` and `IRON_CARRIER_REMOTE` environment variables have the paths of the
+/// last agreed, local and received contents, the command must write the merged content to the `IRON_CARRIER_LOCAL` file  
+/// When the command fails, both files are kept, the local file is renamed with ` (conflict)` added to its name
+#[derive(Debug, Clone, Deserialize)]
+pub struct MergeDriver {
+    /// Pattern matched against the file, like `*.json`, same syntax as [Config::transfer_priorities]
+    pub pattern: String,
+    /// Command executed to merge the file
+    pub command: String,
+}
+
+/// Remote storage an alias is mirrored to
+///
+/// Mirroring is one way, local changes are pushed to the mirror, but changes made directly in the mirror are never pulled
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MirrorConfig {
+    /// S3 compatible bucket
+    S3(S3Config),
+    /// WebDAV collection
+    WebDav(WebDavConfig),
+}
+
+/// Configuration for a S3 compatible bucket (AWS, MinIO, ...)
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    /// Service endpoint, including the scheme (**https://s3.us-east-1.amazonaws.com**)  
+    /// Buckets are addressed in path style, `endpoint/bucket/key`
+    pub endpoint: String,
+    /// Bucket region, defaults to us-east-1
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Bucket name
+    pub bucket: String,
+    /// Prefix added to every key, files are stored in the bucket root by default
+    #[serde(default)]
+    pub prefix: String,
+    /// Access key id, the key itself or where to read it, see [crate::secrets]
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub access_key: String,
+    /// Secret access key, the key itself or where to read it, see [crate::secrets]
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub secret_key: String,
+}
+
+/// Configuration for a WebDAV collection (Nextcloud, ownCloud, ...)
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebDavConfig {
+    /// Url of the collection that receives the files, including the scheme  
+    /// (**https://cloud.example.com/remote.php/dav/files/user/backup**)
+    pub url: String,
+    /// User name, requests are sent without authentication when not provided
+    pub username: Option<String>,
+    /// Password, or app password, for [WebDavConfig::username], the password itself or where to read it, see [crate::secrets]
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub password: Option<String>,
+}
+
+impl Config {
+    /// creates a new [Config] reading the contents from the given path
+    ///
+    /// [Ok]`(`[Config]`)` if successful  
+    /// [IronCarrierError::ConfigFileNotFound] if the provided path doesn't exists   
+    /// [IronCarrierError::ConfigFileIsInvalid] if the provided configuration is not valid   
+    pub fn new(config_path: &str) -> crate::Result<Self> {
+        log::debug!("reading config file {}", config_path);
+
+        Config::parse_content(read_to_string(config_path)?)
+    }
+
+    /// Creates a new [Config] like [Config::new], replacing its options with `overrides`
+    ///
+    /// Each override is in the form `key=value`, keys inside tables are separated by dots (**limits.my_docs.max_depth=3**)  
+    /// Values are written like in the file, text that isn't a valid value is read as a string (**log_level=debug**)
+    pub fn with_overrides(config_path: &str, overrides: &[String]) -> crate::Result<Self> {
+        log::debug!("reading config file {}", config_path);
+
+        Config::parse_with_overrides(read_to_string(config_path)?, overrides)
+    }
+
+    /// Parses the given content into [Config]
+    pub(crate) fn parse_content(content: String) -> crate::Result<Self> {
+        Config::parse_with_overrides(content, &[])
+    }
+
+    /// Parses the given content into [Config], upgrading it from older versions of the format, then applies `overrides`
+    fn parse_with_overrides(content: String, overrides: &[String]) -> crate::Result<Self> {
+        let mut value: toml::Value = toml::from_str(&content)?;
+        if crate::config_migration::upgrade(&mut value)? {
+            log::info!(
+                "config upgraded in memory to version {}, run with --upgrade-config to rewrite it",
+                crate::config_migration::CONFIG_VERSION
+            );
+        }
+
+        for config_override in overrides {
+            apply_override(&mut value, config_override)?;
+        }
+
+        value.try_into::<Config>()?.validate()
+    }
+
+    /// Returns the [Config::log_level] as a filter for the logger
+    pub fn log_level_filter(&self) -> Option<log::LevelFilter> {
+        self.log_level.as_ref().and_then(|level| level.parse().ok())
+    }
+
+    /// Returns the name of the peer at `address`, see [Config::peer_names], or the address itself when it has no name  
+    /// Connections accepted from a peer come from another port, so they are matched by host when no address matches
+    pub fn peer_name(&self, address: &str) -> String {
+        let host = crate::network::locality::host_of(address);
+        self.peer_names
+            .iter()
+            .find(|(_, peer)| peer.as_str() == address)
+            .or_else(|| {
+                self.peer_names
+                    .iter()
+                    .find(|(_, peer)| crate::network::locality::host_of(peer) == host)
+            })
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| address.to_owned())
+    }
+
+    /// Returns the address of `peer`, which is a name in [Config::peer_names] or an address
+    pub fn peer_address(&self, peer: &str) -> String {
+        self.peer_names
+            .get(peer)
+            .cloned()
+            .unwrap_or_else(|| peer.to_owned())
+    }
+
+    /// Returns the id `alias` is known by, see [Config::alias_ids]
+    pub fn alias_id(&self, alias: &str) -> String {
+        self.alias_ids
+            .get(alias)
+            .cloned()
+            .unwrap_or_else(|| alias.to_owned())
+    }
+
+    /// Keeps the files of `alias` in `storage`, instead of the local file system  
+    /// [IronCarrierError::AliasNotAvailable] if `alias` is not in [Config::paths]
+    pub fn set_storage(&mut self, alias: &str, storage: Arc<dyn Storage>) -> crate::Result<()> {
+        let alias = &self.alias_id(alias);
+        if !self.paths.contains_key(alias) {
+            return Err(IronCarrierError::AliasNotAvailable(alias.to_owned()).into());
+        }
+
+        self.storages.insert(alias.to_owned(), storage);
+        Ok(())
+    }
+
+    /// Returns the [Storage] holding the files of `alias`
+    pub(crate) fn storage(&self, alias: &str) -> &dyn Storage {
+        match self.storages.get(alias) {
+            Some(storage) => storage.as_ref(),
+            None => &LocalStorage,
+        }
+    }
+
+    /// Returns the bytes per second sent to a peer in `network`, [None] if unlimited
+    pub(crate) fn bandwidth_limit(&self, network: NetworkClass) -> Option<u64> {
+        let limit = match network {
+            NetworkClass::Lan => self.lan_bandwidth_limit,
+            NetworkClass::Wan => self.wan_bandwidth_limit,
+        };
+
+        if limit == 0 {
+            None
+        } else {
+            Some(limit)
+        }
+    }
+
+    /// Returns true if `alias` is synchronized with `peer_address`, according to its [Config::topology]
+    pub(crate) fn syncs_alias_with(&self, alias: &str, peer_address: &str) -> bool {
+        let topology = match self.topology.get(alias) {
+            Some(topology) => topology,
+            None => return true,
+        };
+
+        let in_group = topology.group.as_ref().is_none_or(|group| {
+            self.peer_groups
+                .get(group)
+                .is_some_and(|peers| peers.iter().any(|peer| peer == peer_address))
+        });
+
+        match (topology.mode, &topology.hub) {
+            // spokes only talk to the hub, the hub talks to everyone
+            (TopologyMode::HubAndSpoke, Some(hub))
+                if self.peers.iter().flatten().any(|peer| peer == hub) =>
+            {
+                peer_address == hub
+            }
+            _ => in_group,
+        }
+    }
+
+    /// Returns the seconds between full synchronizations of `alias`, [None] if it is not synchronized periodically
+    pub(crate) fn scan_interval(&self, alias: &str) -> Option<u64> {
+        match self.scan_schedule.get(alias) {
+            Some(schedule) if schedule.manual => None,
+            Some(ScanSchedule {
+                interval_seconds: Some(interval),
+                ..
+            }) => Some(*interval),
+            _ => self.scan_interval_seconds,
+        }
+    }
+
+    /// Fails with [IronCarrierError::FileTooLarge] when `size` exceeds the [AliasLimits::max_file_size] of `alias`
+    pub(crate) fn check_file_size(&self, alias: &str, size: u64) -> Result<(), IronCarrierError> {
+        match self
+            .limits
+            .get(alias)
+            .and_then(|limits| limits.max_file_size)
+        {
+            Some(limit) if size > limit => Err(IronCarrierError::FileTooLarge(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the [AliasLimits::max_total_size] of `alias`
+    pub(crate) fn quota(&self, alias: &str) -> Option<u64> {
+        self.limits
+            .get(alias)
+            .and_then(|limits| limits.max_total_size)
+            .filter(|_| self.is_local_storage(alias))
+    }
+
+    /// Returns true if the deleted files of `alias` are kept, see [Config::archive_mode]
+    pub(crate) fn is_archive(&self, alias: &str) -> bool {
+        self.archive_mode && self.is_local_storage(alias)
+    }
+
+    /// Returns true if `alias` is only synchronized when requested
+    pub(crate) fn is_manual_scan(&self, alias: &str) -> bool {
+        self.scan_schedule
+            .get(alias)
+            .is_some_and(|schedule| schedule.manual)
+    }
+
+    /// Returns the [AliasLimits::max_depth] of `alias`
+    pub(crate) fn max_depth(&self, alias: &str) -> Option<usize> {
+        self.limits.get(alias).and_then(|limits| limits.max_depth)
+    }
+
+    /// Returns the [AliasHashing] of `alias`
+    pub(crate) fn hashing(&self, alias: &str) -> AliasHashing {
+        self.hashing.get(alias).copied().unwrap_or_default()
+    }
+
+    /// Returns the size of the blocks the versions of `alias` are split into, see [AliasHashing::block_size]
+    pub(crate) fn block_size(&self, alias: &str) -> usize {
+        self.hashing(alias)
+            .block_size
+            .unwrap_or(crate::block_store::BLOCK_SIZE)
+    }
+
+    /// Returns true if `path`, relative to the root of `alias`, is deeper than [AliasLimits::max_depth]
+    pub(crate) fn exceeds_max_depth(&self, alias: &str, path: &Path) -> bool {
+        self.max_depth(alias)
+            .is_some_and(|max_depth| path.components().count() > max_depth)
+    }
+
+    /// Returns true if `path`, relative to the root of `alias`, is left out by [Config::ignore_hidden]
+    pub(crate) fn ignores_hidden(&self, alias: &str, path: &Path) -> bool {
+        self.ignore_hidden.contains(alias) && crate::fs::is_hidden(path)
+    }
+
+    /// Returns which deletions are synchronized in `alias`, see [Config::propagate_deletes]
+    pub(crate) fn delete_propagation(&self, alias: &str) -> DeletePropagation {
+        self.propagate_deletes
+            .get(alias)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the index of the first [Config::atomic_groups] pattern of `alias` matching `path`
+    pub(crate) fn atomic_group(&self, alias: &str, path: &Path) -> Option<usize> {
+        self.atomic_groups
+            .get(alias)?
+            .iter()
+            .position(|pattern| Pattern::new(pattern).matches(path))
+    }
+
+    /// Returns true if `path`, relative to the root of `alias`, is synchronized according to [Config::include_only]
+    pub(crate) fn is_included(&self, alias: &str, path: &Path) -> bool {
+        self.include_only.get(alias).is_none_or(|patterns| {
+            patterns
+                .iter()
+                .any(|pattern| Pattern::new(pattern).matches(path))
+        })
+    }
+
+    /// Returns the [Config::extra_roots] of `alias`
+    pub(crate) fn extra_roots(&self, alias: &str) -> &[AliasRoot] {
+        match self.extra_roots.get(alias) {
+            Some(roots) if self.is_local_storage(alias) => roots,
+            _ => &[],
+        }
+    }
+
+    /// Returns every folder of `alias`, starting with the one in [Config::paths]
+    pub(crate) fn alias_roots(&self, alias: &str) -> Vec<&PathBuf> {
+        self.paths
+            .get(alias)
+            .into_iter()
+            .chain(self.extra_roots(alias).iter().map(|root| &root.path))
+            .collect()
+    }
+
+    /// Returns the folder of `alias` holding `path`  
+    /// The first folder where the path exists, otherwise the first of [Config::extra_roots] routing it, or the folder in
+    /// [Config::paths]
+    pub(crate) fn root_of(&self, alias: &str, path: &Path) -> Option<&PathBuf> {
+        let root = self.paths.get(alias)?;
+        let extra_roots = self.extra_roots(alias);
+        if extra_roots.is_empty() {
+            return Some(root);
+        }
+
+        let roots = std::iter::once(root).chain(extra_roots.iter().map(|root| &root.path));
+        for candidate in roots {
+            if candidate.join(path).symlink_metadata().is_ok() {
+                return Some(candidate);
+            }
+        }
+
+        let routed = extra_roots.iter().find(|root| {
+            root.route
+                .iter()
+                .any(|pattern| Pattern::new(pattern).matches(path))
+        });
+        Some(routed.map_or(root, |root| &root.path))
+    }
+
+    /// Returns true if the files of `alias` are in the local file system
+    pub(crate) fn is_local_storage(&self, alias: &str) -> bool {
+        !self.storages.contains_key(alias)
+    }
+
+    /// Returns the folders changed since the last scan of each alias, see [Config::incremental_scan]
+    pub(crate) fn scan_hints(&self) -> &ScanHints {
+        &self.scan_hints
+    }
+
+    /// Returns true if the scans of `alias` can read only the folders changed since the last one, see [Config::incremental_scan]
+    pub(crate) fn uses_scan_index(&self, alias: &str) -> bool {
+        self.incremental_scan
+            && !self.is_archive(alias)
+            && !self.preserve_hard_links.contains(alias)
+            && !self.follow_symlinks.contains(alias)
+            && self.reparse_points != ReparsePointPolicy::Follow
+            && self.extra_roots(alias).is_empty()
+    }
+
+    fn validate(mut self) -> crate::Result<Self> {
+        let mut named_addresses = HashSet::new();
+        for (name, address) in &self.peer_names {
+            if name.is_empty() || !named_addresses.insert(address) {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "peer name {} is empty or its address has another name",
+                    name
+                ))
+                .into());
+            }
+        }
+        self.apply_peer_names();
+
+        if let Some(peers) = &self.peers {
+            let mut listed = HashSet::new();
+            if let Some(duplicate) = peers.iter().find(|address| !listed.insert(*address)) {
+                log::error!("peer {} is listed more than once", duplicate);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "peer {} is listed more than once in peers, by its address or by its name",
+                    duplicate
+                ))
+                .into());
+            }
+        }
+
+        if let Some(peers) = self.peers.as_mut() {
+            for address in peers
+                .iter()
+                .filter(|address| address.starts_with("sftp://"))
+            {
+                match SftpPeer::parse(address) {
+                    Some(sftp_peer) => self.sftp_peers.push(sftp_peer),
+                    None => {
+                        log::error!("invalid sftp peer {}", address);
+                        return Err(IronCarrierError::InvalidPeerAddress.into());
+                    }
+                }
+            }
+
+            peers.retain(|address| !address.starts_with("sftp://"));
+        }
+
+        if 0 == self.port || self.port > MAX_PORT {
+            log::error!("Invalid port number");
+            return Err(IronCarrierError::ConfigFileIsInvalid("invalid port number".into()).into());
+        }
+
+        if self.transfer_chunk_size == 0 {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "transfer_chunk_size must be at least 1".into(),
+            )
+            .into());
+        }
+
+        if self.scan_interval_seconds == Some(0) {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "scan_interval_seconds must be at least 1".into(),
+            )
+            .into());
+        }
+
+        for window in &self.bandwidth_schedule {
+            if parse_time_of_day(&window.from).is_none() || parse_time_of_day(&window.to).is_none()
+            {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "invalid bandwidth schedule window {} - {}, times must be in the format HH:MM",
+                    window.from, window.to
+                ))
+                .into());
+            }
+        }
+
+        for (alias, schedule) in &self.scan_schedule {
+            if !self.paths.contains_key(alias) {
+                log::error!("scan schedule configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "scan schedule for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            if schedule.interval_seconds == Some(0)
+                || (schedule.manual && schedule.interval_seconds.is_some())
+            {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "invalid scan schedule for alias {}, interval_seconds must be at least 1 and can't be used with manual",
+                    alias
+                ))
+                .into());
+            }
+        }
+
+        if let Some(alias) = self
+            .limits
+            .keys()
+            .find(|alias| !self.paths.contains_key(*alias))
+        {
+            log::error!("limits configured for unknown alias {}", alias);
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "limits for unknown alias: {}",
+                alias
+            ))
+            .into());
+        }
+
+        for (alias, hashing) in &self.hashing {
+            if !self.paths.contains_key(alias) {
+                log::error!("hashing configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "hashing for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            if hashing
+                .block_size
+                .is_some_and(|block_size| !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size))
+            {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "block_size of alias {} must be between {} and {} bytes",
+                    alias, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+                ))
+                .into());
+            }
+        }
+
+        for (alias, patterns) in &self.include_only {
+            if !self.paths.contains_key(alias) || patterns.is_empty() {
+                log::error!("include only patterns of alias {} are invalid", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "include only patterns for unknown alias, or without patterns: {}",
+                    alias
+                ))
+                .into());
+            }
+        }
+
+        if let Some(alias) = self
+            .atomic_groups
+            .keys()
+            .find(|alias| !self.paths.contains_key(*alias))
+        {
+            log::error!("atomic groups configured for unknown alias {}", alias);
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "atomic groups for unknown alias: {}",
+                alias
+            ))
+            .into());
+        }
+
+        if let Some(alias) = self
+            .limits
+            .iter()
+            .find(|(_, limits)| limits.max_depth == Some(0))
+            .map(|(alias, _)| alias)
+        {
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "max_depth of alias {} must be at least 1",
+                alias
+            ))
+            .into());
+        }
+
+        for (alias, topology) in &self.topology {
+            if !self.paths.contains_key(alias) {
+                log::error!("topology configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "topology for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            if topology.mode == TopologyMode::HubAndSpoke && topology.hub.is_none() {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "hub_and_spoke topology without hub for alias {}",
+                    alias
+                ))
+                .into());
+            }
 
-const MAX_PORT: u32 = 65535;
-/// Represents the configuration for the current machine
-#[derive(Deserialize)]
-pub struct Config {
-    /// Contains the folder that will be watched for synchronization  
-    /// **Key** is the path alias  
-    /// **Value** is the path itself  
-    pub paths: HashMap<String, PathBuf>,
-    /// contains the address for the other peers  
-    /// in the format IPV4:PORT (**192.168.1.1:9090**)
-    pub peers: Option<Vec<String>>,
+            if let Some(group) = topology
+                .group
+                .as_ref()
+                .filter(|group| !self.peer_groups.contains_key(*group))
+            {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "unknown peer group {} for alias {}",
+                    group, alias
+                ))
+                .into());
+            }
+        }
 
-    /// Port to listen to connections, defaults to 8090
-    #[serde(default = "default_port")]
-    pub port: u32,
+        if let Some(introducer) = self
+            .introducers
+            .iter()
+            .find(|introducer| !self.peers.iter().flatten().any(|peer| peer == *introducer))
+        {
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "introducer is not a peer: {}",
+                introducer
+            ))
+            .into());
+        }
 
-    /// Enable file watchers for real time syncs, defaults to true
-    #[serde(default = "default_enable_watcher")]
-    pub enable_file_watcher: bool,
+        if self.log_level.is_some() && self.log_level_filter().is_none() {
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "invalid log_level: {}",
+                self.log_level.as_deref().unwrap_or_default()
+            ))
+            .into());
+        }
 
-    /// Seconds to debounce file events, defaults to 10 seconds
-    #[serde(default = "default_watcher_debounce")]
-    pub delay_watcher_events: u64,
-}
+        if self.archive_mode && self.block_store_path.is_none() {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "archive_mode requires block_store_path".into(),
+            )
+            .into());
+        }
 
-impl Config {
-    /// creates a new [Config] reading the contents from the given path
-    ///
-    /// [Ok]`(`[Config]`)` if successful  
-    /// [IronCarrierError::ConfigFileNotFound] if the provided path doesn't exists   
-    /// [IronCarrierError::ConfigFileIsInvalid] if the provided configuration is not valid   
-    pub fn new(config_path: &str) -> crate::Result<Self> {
-        log::debug!("reading config file {}", config_path);
+        if !self.merge_text_files.is_empty() && self.block_store_path.is_none() {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "merge_text_files requires block_store_path".into(),
+            )
+            .into());
+        }
 
-        Config::parse_content(read_to_string(config_path)?)
-    }
+        if self.max_concurrent_file_hooks == 0 {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "max_concurrent_file_hooks must be at least 1".into(),
+            )
+            .into());
+        }
 
-    /// Parses the given content into [Config]
-    pub(crate) fn parse_content(content: String) -> crate::Result<Self> {
-        toml::from_str::<Config>(&content)?.validate()
-    }
+        if let Some(alias) = self
+            .file_hooks
+            .iter()
+            .filter_map(|hook| hook.alias.as_ref())
+            .find(|alias| !self.paths.contains_key(*alias))
+        {
+            log::error!("file hook configured for unknown alias {}", alias);
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "file hook for unknown alias: {}",
+                alias
+            ))
+            .into());
+        }
 
-    fn validate(self) -> crate::Result<Self> {
-        if 0 == self.port || self.port > MAX_PORT {
-            log::error!("Invalid port number");
-            return Err(IronCarrierError::ConfigFileIsInvalid("invalid port number".into()).into());
+        if self.max_concurrent_peers == 0 {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "max_concurrent_peers must be at least 1".into(),
+            )
+            .into());
+        }
+
+        if self.max_parallel_transfers == 0 {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "max_parallel_transfers must be at least 1".into(),
+            )
+            .into());
+        }
+
+        if self.memory_budget_mb == Some(0) {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "memory_budget_mb must be at least 1".into(),
+            )
+            .into());
+        }
+
+        if self.scan_throttle.io_percent == Some(0) {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "scan_throttle io_percent must be at least 1".into(),
+            )
+            .into());
+        }
+
+        if self.scan_workers == 0 {
+            return Err(IronCarrierError::ConfigFileIsInvalid(
+                "scan_workers must be at least 1".into(),
+            )
+            .into());
         }
 
-        for (alias, path) in &self.paths {
+        let mut aliases: Vec<(&String, &PathBuf)> = self.paths.iter().collect();
+        aliases.sort();
+        let mut roots: HashMap<PathBuf, &String> = HashMap::new();
+        for (alias, path) in aliases {
             if !path.exists() {
                 log::info!("creating directory for alias {}", alias);
                 std::fs::create_dir_all(path)?;
@@ -76,10 +1614,277 @@ impl Config {
                 ))
                 .into());
             }
+
+            // the same folder written differently, or through a link, is still the same folder
+            let root = path.canonicalize()?;
+            if let Some(other) = roots.insert(root, alias) {
+                log::error!("aliases {} and {} have the same path", other, alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "aliases {} and {} have the same path: {:?}",
+                    other, alias, path
+                ))
+                .into());
+            }
+        }
+
+        if let Some(address) = &self.grpc_address {
+            if address.parse::<std::net::SocketAddr>().is_err() {
+                log::error!("invalid grpc address {}", address);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "invalid grpc address: {}",
+                    address
+                ))
+                .into());
+            }
+        }
+
+        for (option, aliases) in [
+            ("one_file_system", &self.one_file_system),
+            ("preserve_creation_time", &self.preserve_creation_time),
+            ("preserve_file_attributes", &self.preserve_file_attributes),
+            ("preserve_permissions", &self.preserve_permissions),
+            ("preserve_hard_links", &self.preserve_hard_links),
+            ("follow_symlinks", &self.follow_symlinks),
+            ("ignore_hidden", &self.ignore_hidden),
+            ("merge_text_files", &self.merge_text_files),
+            ("streaming_scan", &self.streaming_scan),
+            ("on_demand", &self.on_demand),
+            ("paused_aliases", &self.paused_aliases),
+        ] {
+            for alias in aliases {
+                if !self.paths.contains_key(alias) {
+                    log::error!("{} contains unknown alias {}", option, alias);
+                    return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                        "{} with unknown alias: {}",
+                        option, alias
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        for (alias, folders) in &self.subscriptions {
+            if !self.paths.contains_key(alias) {
+                log::error!("subscriptions configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "subscriptions for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            let invalid = folders.iter().find(|folder| {
+                folder
+                    .components()
+                    .any(|component| !matches!(component, std::path::Component::Normal(_)))
+                    || folder.as_os_str().is_empty()
+            });
+            if let Some(folder) = invalid {
+                log::error!("invalid subscription {:?} for alias {}", folder, alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "invalid subscription {:?} for alias {}, it must be a folder relative to the alias root",
+                    folder, alias
+                ))
+                .into());
+            }
+        }
+
+        if let Some(alias) = self
+            .propagate_deletes
+            .keys()
+            .find(|alias| !self.paths.contains_key(*alias))
+        {
+            log::error!("propagate_deletes contains unknown alias {}", alias);
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "propagate_deletes with unknown alias: {}",
+                alias
+            ))
+            .into());
+        }
+
+        if let Some(alias) = self
+            .hooks
+            .keys()
+            .find(|alias| !self.paths.contains_key(*alias))
+        {
+            log::error!("hooks configured for unknown alias {}", alias);
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "hooks for unknown alias: {}",
+                alias
+            ))
+            .into());
+        }
+
+        for (alias, mirror) in &self.mirrors {
+            if !self.paths.contains_key(alias) {
+                log::error!("mirror configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "mirror for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            let url = match mirror {
+                MirrorConfig::S3(s3) => &s3.endpoint,
+                MirrorConfig::WebDav(webdav) => &webdav.url,
+            };
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "invalid mirror url for alias {}",
+                    alias
+                ))
+                .into());
+            }
+        }
+
+        for (alias, roots) in &self.extra_roots {
+            if !self.paths.contains_key(alias) {
+                log::error!("extra roots configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "extra roots for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            for root in roots {
+                if !root.path.exists() {
+                    log::info!("creating extra root {:?} for alias {}", root.path, alias);
+                    std::fs::create_dir_all(&root.path)?;
+                }
+                let inside = |a: &Path, b: &Path| match (a.canonicalize(), b.canonicalize()) {
+                    (Ok(a), Ok(b)) => a.starts_with(&b) || b.starts_with(&a),
+                    _ => false,
+                };
+                let overlaps = self
+                    .alias_roots(alias)
+                    .into_iter()
+                    .any(|other| !std::ptr::eq(other, &root.path) && inside(other, &root.path));
+                if !root.path.is_dir() || overlaps {
+                    log::error!("extra root {:?} of alias {} is invalid", root.path, alias);
+                    return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                        "invalid extra root for alias {}, it must be a folder outside the other folders of the alias",
+                        alias
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        for (alias, id) in &self.alias_ids {
+            if !self.paths.contains_key(alias) {
+                log::error!("alias id configured for unknown alias {}", alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "alias id for unknown alias: {}",
+                    alias
+                ))
+                .into());
+            }
+
+            let taken = self
+                .paths
+                .keys()
+                .filter(|other| *other != alias)
+                .any(|other| self.alias_id(other) == *id);
+            if id.is_empty() || taken {
+                log::error!("alias id {} of alias {} is invalid", id, alias);
+                return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                    "alias id {} of alias {} is empty or used by another alias",
+                    id, alias
+                ))
+                .into());
+            }
         }
 
+        self.apply_alias_ids();
         Ok(self)
     }
+
+    /// Replaces the peer names in every option with their addresses, see [Config::peer_names]
+    fn apply_peer_names(&mut self) {
+        let names = &self.peer_names;
+        let resolve = |peer: &mut String| {
+            if let Some(address) = names.get(peer.as_str()) {
+                *peer = address.clone();
+            }
+        };
+
+        self.peers.iter_mut().flatten().for_each(resolve);
+        self.peer_groups.values_mut().flatten().for_each(resolve);
+        self.topology
+            .values_mut()
+            .filter_map(|topology| topology.hub.as_mut())
+            .for_each(resolve);
+        self.introducers = self
+            .introducers
+            .drain()
+            .map(|mut introducer| {
+                resolve(&mut introducer);
+                introducer
+            })
+            .collect();
+        self.peer_sockets = self
+            .peer_sockets
+            .drain()
+            .map(|(mut peer, options)| {
+                resolve(&mut peer);
+                (peer, options)
+            })
+            .collect();
+    }
+
+    /// Replaces the aliases in every option with their [Config::alias_ids], from here on the aliases are known by their ids
+    fn apply_alias_ids(&mut self) {
+        fn rekey<T>(values: &mut HashMap<String, T>, ids: &HashMap<String, String>) {
+            let renamed: Vec<(String, T)> = ids
+                .iter()
+                .filter_map(|(alias, id)| values.remove(alias).map(|value| (id.clone(), value)))
+                .collect();
+            values.extend(renamed);
+        }
+
+        fn rename(aliases: &mut HashSet<String>, ids: &HashMap<String, String>) {
+            *aliases = aliases
+                .drain()
+                .map(|alias| ids.get(&alias).cloned().unwrap_or(alias))
+                .collect();
+        }
+
+        let ids = &self.alias_ids;
+        rekey(&mut self.paths, ids);
+        rekey(&mut self.extra_roots, ids);
+        rekey(&mut self.subscriptions, ids);
+        rekey(&mut self.topology, ids);
+        rekey(&mut self.mirrors, ids);
+        rekey(&mut self.hooks, ids);
+        rekey(&mut self.scan_schedule, ids);
+        rekey(&mut self.limits, ids);
+        rekey(&mut self.hashing, ids);
+        rekey(&mut self.propagate_deletes, ids);
+        rekey(&mut self.atomic_groups, ids);
+        rekey(&mut self.include_only, ids);
+        for aliases in [
+            &mut self.one_file_system,
+            &mut self.preserve_creation_time,
+            &mut self.preserve_file_attributes,
+            &mut self.preserve_permissions,
+            &mut self.preserve_hard_links,
+            &mut self.follow_symlinks,
+            &mut self.ignore_hidden,
+            &mut self.merge_text_files,
+            &mut self.streaming_scan,
+            &mut self.on_demand,
+            &mut self.paused_aliases,
+        ] {
+            rename(aliases, ids);
+        }
+        for hook in self.file_hooks.iter_mut() {
+            if let Some(id) = hook.alias.as_ref().and_then(|alias| ids.get(alias)) {
+                hook.alias = Some(id.clone());
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -110,4 +1915,369 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn can_parse_delete_propagation() -> crate::Result<()> {
+        let config_content = "
+        [paths]
+        a = \"./tmp/a\"
+        b = \"./tmp/b\"
+        c = \"./tmp/c\"
+
+        [propagate_deletes]
+        a = false
+        b = \"incoming\"
+        "
+        .to_owned();
+
+        let config = Config::parse_content(config_content)?;
+        assert_eq!(config.delete_propagation("a"), DeletePropagation::None);
+        assert!(!config.delete_propagation("b").outgoing());
+        assert!(config.delete_propagation("b").incoming());
+        assert_eq!(config.delete_propagation("c"), DeletePropagation::Both);
+
+        Ok(())
+    }
+
+    #[test]
+    fn renamed_aliases_keep_their_id() -> crate::Result<()> {
+        let config_content = "
+        ignore_hidden = [\"documents\"]
+
+        [paths]
+        documents = \"./tmp/documents\"
+        b = \"./tmp/b\"
+
+        [alias_ids]
+        documents = \"docs\"
+
+        [propagate_deletes]
+        documents = false
+        "
+        .to_owned();
+
+        let config = Config::parse_content(config_content)?;
+        assert_eq!(config.alias_id("documents"), "docs");
+        assert_eq!(config.alias_id("b"), "b");
+        assert!(config.paths.contains_key("docs"));
+        assert!(!config.paths.contains_key("documents"));
+        assert!(config.ignore_hidden.contains("docs"));
+        assert_eq!(config.delete_propagation("docs"), DeletePropagation::None);
+
+        let config_content = "
+        [paths]
+        a = \"./tmp/a\"
+        b = \"./tmp/b\"
+
+        [alias_ids]
+        a = \"b\"
+        "
+        .to_owned();
+        assert!(Config::parse_content(config_content).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_sftp_peers() -> crate::Result<()> {
+        let config_content = "
+        peers = [
+            \"127.0.0.1:8888\",
+            \"sftp://user@backup.lan:2222/srv/files\"
+        ]
+
+        [paths]
+        a = \"./tmp\"
+        "
+        .to_owned();
+
+        let config = Config::parse_content(config_content)?;
+        assert_eq!(config.peers.unwrap(), vec!["127.0.0.1:8888".to_string()]);
+        assert_eq!(
+            config.sftp_peers,
+            vec![SftpPeer {
+                destination: "user@backup.lan".into(),
+                port: Some(2222),
+                path: "/srv/files".into()
+            }]
+        );
+
+        assert_eq!(SftpPeer::parse("sftp://host").unwrap().path, ".");
+        assert!(SftpPeer::parse("sftp://user@host:port/path").is_none());
+        assert!(SftpPeer::parse("127.0.0.1:8888").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn topology_limits_peers_of_alias() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "peers = [\"hub:8090\", \"laptop:8090\", \"vps:8090\"]
+            [paths]
+            a = \"./tmp/topology/a\"
+            b = \"./tmp/topology/b\"
+            c = \"./tmp/topology/c\"
+
+            [peer_groups]
+            laptops = [\"laptop:8090\"]
+
+            [topology.a]
+            mode = \"hub_and_spoke\"
+            hub = \"hub:8090\"
+
+            [topology.b]
+            group = \"laptops\""
+                .to_string(),
+        )?;
+
+        assert!(config.syncs_alias_with("a", "hub:8090"));
+        assert!(!config.syncs_alias_with("a", "vps:8090"));
+        assert!(config.syncs_alias_with("b", "laptop:8090"));
+        assert!(!config.syncs_alias_with("b", "vps:8090"));
+        assert!(config.syncs_alias_with("c", "vps:8090"));
+        std::fs::remove_dir_all("./tmp/topology")?;
+
+        assert!(Config::parse_content(
+            "[paths]
+            a = \"./tmp\"
+            [topology.a]
+            group = \"missing\""
+                .to_string()
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_schedule_overrides_interval() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "scan_interval_seconds = 60
+            [paths]
+            docs = \"./tmp/scan_schedule/docs\"
+            archive = \"./tmp/scan_schedule/archive\"
+            inbox = \"./tmp/scan_schedule/inbox\"
+
+            [scan_schedule.archive]
+            interval_seconds = 3600
+
+            [scan_schedule.inbox]
+            manual = true"
+                .to_string(),
+        )?;
+
+        assert_eq!(config.scan_interval("docs"), Some(60));
+        assert_eq!(config.scan_interval("archive"), Some(3600));
+        assert_eq!(config.scan_interval("inbox"), None);
+        assert!(config.is_manual_scan("inbox"));
+        assert!(!config.is_manual_scan("archive"));
+        std::fs::remove_dir_all("./tmp/scan_schedule")?;
+
+        assert!(Config::parse_content(
+            "[paths]
+            a = \"./tmp\"
+            [scan_schedule.a]
+            manual = true
+            interval_seconds = 10"
+                .to_string()
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_mirrors() -> crate::Result<()> {
+        let config_content = "
+        [paths]
+        a = \"./tmp\"
+
+        [mirrors.a]
+        type = \"s3\"
+        endpoint = \"http://localhost:9000\"
+        bucket = \"backup\"
+        access_key = \"key\"
+        secret_key = \"secret\"
+        "
+        .to_owned();
+
+        let config = Config::parse_content(config_content)?;
+        match &config.mirrors["a"] {
+            MirrorConfig::S3(s3) => {
+                assert_eq!(s3.bucket, "backup");
+                assert_eq!(s3.region, "us-east-1");
+                assert_eq!(s3.prefix, "");
+            }
+            _ => panic!("expected a s3 mirror"),
+        }
+
+        let config_content = "
+        [paths]
+        a = \"./tmp\"
+
+        [mirrors.a]
+        type = \"webdav\"
+        url = \"https://cloud.example.com/remote.php/dav/files/user/backup\"
+        username = \"user\"
+        "
+        .to_owned();
+
+        let config = Config::parse_content(config_content)?;
+        match &config.mirrors["a"] {
+            MirrorConfig::WebDav(webdav) => {
+                assert_eq!(webdav.username.as_deref(), Some("user"));
+                assert!(webdav.password.is_none());
+            }
+            _ => panic!("expected a webdav mirror"),
+        }
+
+        let config_content = "
+        [paths]
+        a = \"./tmp\"
+
+        [mirrors.b]
+        type = \"s3\"
+        endpoint = \"http://localhost:9000\"
+        bucket = \"backup\"
+        access_key = \"key\"
+        secret_key = \"secret\"
+        "
+        .to_owned();
+        assert!(Config::parse_content(config_content).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn overrides_replace_options() -> crate::Result<()> {
+        let overrides = [
+            "port=9000".to_string(),
+            "log_level=debug".to_string(),
+            "lan_bandwidth_limit = 1024".to_string(),
+            "limits.a.max_depth=3".to_string(),
+        ];
+        let config = Config::parse_with_overrides(
+            "port = 8090
+            [paths]
+            a = \"./tmp\""
+                .to_string(),
+            &overrides,
+        )?;
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.log_level_filter(), Some(log::LevelFilter::Debug));
+        assert_eq!(config.lan_bandwidth_limit, 1024);
+        assert_eq!(config.max_depth("a"), Some(3));
+
+        for invalid in ["port", "port.number=1", "log_level=loud"] {
+            assert!(Config::parse_with_overrides(
+                "[paths]
+                a = \"./tmp\""
+                    .to_string(),
+                &[invalid.to_string()],
+            )
+            .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let error = |content: &str| match Config::parse_content(content.to_string()) {
+            Ok(_) => panic!("config should be invalid: {}", content),
+            Err(err) => err.to_string(),
+        };
+
+        assert!(error(
+            "[paths]
+            a = \"./tmp/duplicates/a\"
+            a = \"./tmp/duplicates/b\""
+        )
+        .contains("duplicate key"));
+        assert!(error(
+            "peers = [ \"laptop\", \"192.168.1.10:8090\" ]
+            [peer_names]
+            laptop = \"192.168.1.10:8090\"
+            [paths]
+            a = \"./tmp/duplicates/a\""
+        )
+        .contains("peer 192.168.1.10:8090 is listed more than once"));
+        assert!(error(
+            "[paths]
+            a = \"./tmp/duplicates/a\"
+            b = \"./tmp/duplicates/../duplicates/a\""
+        )
+        .contains("aliases a and b have the same path"));
+
+        std::fs::remove_dir_all("./tmp/duplicates").ok();
+    }
+
+    #[test]
+    fn peers_can_be_referenced_by_name() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "peers = [ \"laptop\", \"192.168.1.11:8090\" ]
+            [peer_names]
+            laptop = \"192.168.1.10:8090\"
+            [peer_groups]
+            home = [ \"laptop\" ]
+            [paths]
+            a = \"./tmp\""
+                .to_string(),
+        )?;
+        assert_eq!(
+            config.peers,
+            Some(vec![
+                "192.168.1.10:8090".to_string(),
+                "192.168.1.11:8090".to_string()
+            ])
+        );
+        assert_eq!(config.peer_groups["home"], vec!["192.168.1.10:8090"]);
+        assert_eq!(config.peer_name("192.168.1.10:8090"), "laptop");
+        // connections from a peer come from another port
+        assert_eq!(config.peer_name("192.168.1.10:51234"), "laptop");
+        assert_eq!(config.peer_name("192.168.1.11:8090"), "192.168.1.11:8090");
+        assert_eq!(config.peer_address("laptop"), "192.168.1.10:8090");
+
+        assert!(Config::parse_content(
+            "[peer_names]
+            laptop = \"192.168.1.10:8090\"
+            desktop = \"192.168.1.10:8090\"
+            [paths]
+            a = \"./tmp\""
+                .to_string(),
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_throttle_pauses_in_proportion_to_the_reads() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "scan_throttle = { io_percent = 25 }
+            [paths]
+            a = \"./tmp\""
+                .to_string(),
+        )?;
+        let busy = Duration::from_millis(10);
+        assert_eq!(
+            config.scan_throttle.pause_after(busy),
+            Some(Duration::from_millis(30))
+        );
+        assert_eq!(
+            config.scan_throttle.pause_after(Duration::from_secs(5)),
+            Some(MAX_SCAN_PAUSE)
+        );
+        assert_eq!(ScanThrottle::default().pause_after(busy), None);
+
+        assert!(Config::parse_content(
+            "scan_throttle = { io_percent = 0 }
+            [paths]
+            a = \"./tmp\""
+                .to_string(),
+        )
+        .is_err());
+
+        Ok(())
+    }
 }
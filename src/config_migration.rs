@@ -0,0 +1,131 @@
+//! Upgrades of configuration files written for older versions of the format
+//!
+//! Every configuration declares the version of the format it was written for in [Config::version], files without it
+//! are from before the format had versions. Older files are upgraded in memory when they are read, one version at a
+//! time, so renamed or reshaped options keep working. `--upgrade-config` writes the upgraded file back
+//!
+//! To change the format, increase [CONFIG_VERSION] and add the step from the previous version to [MIGRATIONS]
+
+use std::path::Path;
+
+use crate::{config::Config, IronCarrierError};
+
+/// Version of the configuration format read by this build
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Upgrades a configuration file from one version to the next one
+type Migration = fn(&mut toml::value::Table) -> crate::Result<()>;
+
+/// Steps of the upgrade, the step at index `n` upgrades a file from version `n` to `n + 1`
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [from_unversioned];
+
+/// Version 1 only introduced [Config::version], the options are the same
+fn from_unversioned(_config: &mut toml::value::Table) -> crate::Result<()> {
+    Ok(())
+}
+
+/// Upgrades `config` to [CONFIG_VERSION], returns true if it was written for an older version
+pub(crate) fn upgrade(config: &mut toml::Value) -> crate::Result<bool> {
+    upgrade_with(config, &MIGRATIONS)
+}
+
+/// Upgrades `config` to the version after the last of `migrations`
+fn upgrade_with(config: &mut toml::Value, migrations: &[Migration]) -> crate::Result<bool> {
+    let table = config.as_table_mut().ok_or_else(|| {
+        IronCarrierError::ConfigFileIsInvalid("the configuration is not a table".into())
+    })?;
+
+    let version = match table.get("version") {
+        None => 0,
+        Some(toml::Value::Integer(version)) if *version >= 0 => *version as usize,
+        Some(version) => {
+            return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+                "invalid config version: {}",
+                version
+            ))
+            .into())
+        }
+    };
+
+    if version > migrations.len() {
+        return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+            "config version {} is newer than the supported version {}",
+            version,
+            migrations.len()
+        ))
+        .into());
+    }
+
+    for (from, migration) in migrations.iter().enumerate().skip(version) {
+        log::debug!("upgrading config from version {} to {}", from, from + 1);
+        migration(table)?;
+    }
+    table.insert(
+        "version".to_owned(),
+        toml::Value::Integer(migrations.len() as i64),
+    );
+
+    Ok(version < migrations.len())
+}
+
+/// Rewrites the configuration file at `config_path` in the current version, the original is kept with the `.bak` extension  
+/// Comments are not kept in the upgraded file. Returns false, without changing the file, if it is already up to date
+pub async fn upgrade_file(config_path: &Path) -> crate::Result<bool> {
+    let content = tokio::fs::read_to_string(config_path).await?;
+    let mut config: toml::Value = toml::from_str(&content)?;
+    if !upgrade(&mut config)? {
+        return Ok(false);
+    }
+
+    // the upgraded file must be a valid configuration before it replaces the original one
+    let upgraded = toml::to_string(&config)?;
+    Config::parse_content(upgraded.clone())?;
+
+    tokio::fs::copy(config_path, config_path.with_extension("bak")).await?;
+    tokio::fs::write(config_path, upgraded).await?;
+    log::info!(
+        "config file {:?} upgraded to version {}",
+        config_path,
+        CONFIG_VERSION
+    );
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename_port(config: &mut toml::value::Table) -> crate::Result<()> {
+        if let Some(port) = config.remove("listen_port") {
+            config.insert("port".to_owned(), port);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn older_configs_are_upgraded() -> crate::Result<()> {
+        let mut config: toml::Value = toml::from_str("listen_port = 9000")?;
+        assert!(upgrade_with(&mut config, &[from_unversioned, rename_port])?);
+        assert_eq!(config.get("port"), Some(&toml::Value::Integer(9000)));
+        assert_eq!(config.get("version"), Some(&toml::Value::Integer(2)));
+
+        // already up to date
+        assert!(!upgrade_with(
+            &mut config,
+            &[from_unversioned, rename_port]
+        )?);
+
+        let mut newer: toml::Value = toml::from_str("version = 3")?;
+        assert!(upgrade_with(&mut newer, &[from_unversioned, rename_port]).is_err());
+
+        let parsed = Config::parse_content(
+            "[paths]
+            a = \"./tmp/config_migration\""
+                .to_string(),
+        )?;
+        assert_eq!(parsed.version, CONFIG_VERSION);
+
+        Ok(())
+    }
+}
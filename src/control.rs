@@ -0,0 +1,599 @@
+//! gRPC control service
+//!
+//! Lets external tools and user interfaces, written in any language, query the node status, follow its events,
+//! pause or resume the synchronization, halt it in an emergency, start a synchronization with a peer, authorize
+//! the unknown hosts that try to connect, list the changes waiting for offline peers and follow or cancel the transfers
+//! running with the peers.
+//! The service is described in `proto/control.proto`, it is started when [crate::config::Config::grpc_address] is set
+
+use std::{pin::Pin, sync::Arc};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    config::Config,
+    events::{Event, EventBus, PeerActivity, TransferPreview},
+    network::authorization::PeerAuthorizations,
+    outbox,
+    quarantine::{self, QuarantinedFile},
+    sync::{
+        in_flight::AfterCancel,
+        pause_switch::{self, PauseSwitch},
+        sync_state::SyncStates,
+        AliasSyncState, InFlightTransfer, SyncEvent, SyncPhase, SyncState, TransferDirection,
+    },
+};
+
+/// Messages and server generated from `proto/control.proto`
+#[allow(missing_docs, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("ironcarrier.control.v1");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+struct ControlService {
+    config: Arc<Config>,
+    events: Arc<EventBus>,
+    pause_switch: Arc<PauseSwitch>,
+    sync_states: Arc<SyncStates>,
+    authorizations: Arc<PeerAuthorizations>,
+    sync_events: Sender<SyncEvent>,
+}
+
+impl From<TransferPreview> for proto::TransferPreview {
+    fn from(preview: TransferPreview) -> Self {
+        Self {
+            files_added: preview.files_added,
+            files_updated: preview.files_updated,
+            files_deleted: preview.files_deleted,
+            bytes: preview.bytes,
+            eta_seconds: preview.eta_seconds,
+        }
+    }
+}
+
+impl From<InFlightTransfer> for proto::Transfer {
+    fn from(transfer: InFlightTransfer) -> Self {
+        Self {
+            id: transfer.id,
+            alias: transfer.alias,
+            path: transfer.path.to_string_lossy().into_owned(),
+            peer: transfer.peer,
+            direction: match transfer.direction {
+                TransferDirection::Send => "send",
+                TransferDirection::Receive => "receive",
+            }
+            .to_owned(),
+            bytes_done: transfer.bytes_done,
+            size: transfer.size,
+            bytes_per_second: transfer.bytes_per_second,
+        }
+    }
+}
+
+impl From<QuarantinedFile> for proto::QuarantinedFile {
+    fn from(file: QuarantinedFile) -> Self {
+        Self {
+            alias: file.alias,
+            path: file.path.to_string_lossy().into_owned(),
+            until: file.until,
+            errors: file
+                .errors
+                .into_iter()
+                .map(|error| proto::FailedAttempt {
+                    at: error.at,
+                    reason: error.reason,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<SyncPhase> for proto::SyncPhase {
+    fn from(phase: SyncPhase) -> Self {
+        let mut proto_phase = proto::SyncPhase::default();
+        proto_phase.name = match phase {
+            SyncPhase::Idle => "idle",
+            SyncPhase::Scanning => "scanning",
+            SyncPhase::Comparing => "comparing",
+            SyncPhase::Transferring { progress } => {
+                proto_phase.transfers_done = progress.done;
+                proto_phase.transfers_total = progress.total;
+                "transferring"
+            }
+            SyncPhase::Conflicted { files } => {
+                proto_phase.conflicted_files = files;
+                "conflicted"
+            }
+            SyncPhase::Error { reason } => {
+                proto_phase.reason = reason;
+                "error"
+            }
+        }
+        .to_owned();
+
+        proto_phase
+    }
+}
+
+impl From<AliasSyncState> for proto::AliasState {
+    fn from(alias_state: AliasSyncState) -> Self {
+        let (state, since, reason) = match alias_state.state {
+            SyncState::UpToDate => ("up_to_date", 0, String::new()),
+            SyncState::Syncing => ("syncing", 0, String::new()),
+            SyncState::OutOfSync { since } => ("out_of_sync", since, String::new()),
+            SyncState::Error { reason } => ("error", 0, reason),
+        };
+
+        Self {
+            alias: alias_state.alias,
+            peer: alias_state.peer,
+            state: state.to_owned(),
+            since,
+            reason,
+            updated_at: alias_state.updated_at,
+            preview: alias_state.preview.map(Into::into),
+            phase: Some(alias_state.phase.into()),
+        }
+    }
+}
+
+impl From<Event> for proto::Event {
+    fn from(event: Event) -> Self {
+        use proto::event::Event as Kind;
+
+        let event = match event {
+            Event::InboundTransfersPaused { alias, reason } => {
+                Kind::InboundTransfersPaused(proto::InboundTransfersPaused { alias, reason })
+            }
+            Event::InboundTransfersResumed { alias } => {
+                Kind::InboundTransfersResumed(proto::InboundTransfersResumed { alias })
+            }
+            Event::SynchronizationPaused => {
+                Kind::SynchronizationPaused(proto::SynchronizationPaused {})
+            }
+            Event::SynchronizationResumed => {
+                Kind::SynchronizationResumed(proto::SynchronizationResumed {})
+            }
+            Event::SynchronizationHalted => {
+                Kind::SynchronizationHalted(proto::SynchronizationHalted {})
+            }
+            Event::AliasPaused { alias } => Kind::AliasPaused(proto::AliasPaused { alias }),
+            Event::AliasResumed { alias } => Kind::AliasResumed(proto::AliasResumed { alias }),
+            Event::PeerIntroduced {
+                address,
+                introducer,
+                approved,
+            } => Kind::PeerIntroduced(proto::PeerIntroduced {
+                address,
+                introducer,
+                approved,
+            }),
+            Event::PeerAwaitingAuthorization { address } => {
+                Kind::PeerAwaitingAuthorization(proto::PeerAwaitingAuthorization { address })
+            }
+            Event::QuotaExceeded { alias, used, quota } => {
+                Kind::QuotaExceeded(proto::QuotaExceeded { alias, used, quota })
+            }
+            Event::TransferPlanned {
+                alias,
+                peer,
+                preview,
+            } => Kind::TransferPlanned(proto::TransferPlanned {
+                alias,
+                peer,
+                preview: Some(preview.into()),
+            }),
+            Event::SyncPhaseChanged {
+                alias,
+                peer,
+                from,
+                to,
+            } => Kind::SyncPhaseChanged(proto::SyncPhaseChanged {
+                alias,
+                peer,
+                from: Some(from.into()),
+                to: Some(to.into()),
+            }),
+            Event::FilesSkipped {
+                operation,
+                reason,
+                files,
+                examples,
+            } => Kind::FilesSkipped(proto::FilesSkipped {
+                operation,
+                reason,
+                files,
+                examples: examples
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+            }),
+            Event::PeerSession {
+                peer,
+                address,
+                activity,
+            } => {
+                let (activity, reason) = match activity {
+                    PeerActivity::Connected => ("connected", None),
+                    PeerActivity::Accepted => ("accepted", None),
+                    PeerActivity::Refused { reason } => ("refused", Some(reason)),
+                    PeerActivity::Disconnected { reason } => ("disconnected", reason),
+                    PeerActivity::ProtocolViolation { reason } => {
+                        ("protocol_violation", Some(reason))
+                    }
+                };
+                Kind::PeerSession(proto::PeerSession {
+                    peer,
+                    address,
+                    activity: activity.to_string(),
+                    reason: reason.unwrap_or_default(),
+                })
+            }
+        };
+
+        proto::Event { event: Some(event) }
+    }
+}
+
+impl ControlService {
+    async fn enqueue_sync(&self, peers: Vec<String>) -> Result<(), Status> {
+        for peer in peers {
+            self.sync_events
+                .send(SyncEvent::EnqueueSyncToPeer(peer, false))
+                .await
+                .map_err(|_| Status::unavailable("synchronization is not running"))?;
+        }
+
+        Ok(())
+    }
+
+    fn configured_peers(&self) -> Vec<String> {
+        self.config.peers.clone().unwrap_or_default()
+    }
+
+    /// Returns the id of `alias`, [None] if it isn't configured
+    fn known_alias(&self, alias: &str) -> Option<String> {
+        let alias = self.config.alias_id(alias);
+        self.config.paths.contains_key(&alias).then_some(alias)
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn get_status(
+        &self,
+        _request: Request<proto::GetStatusRequest>,
+    ) -> Result<Response<proto::Status>, Status> {
+        let mut aliases: Vec<proto::Alias> = self
+            .config
+            .paths
+            .iter()
+            .map(|(name, path)| proto::Alias {
+                name: name.clone(),
+                path: path.to_string_lossy().into_owned(),
+                paused: self.pause_switch.is_alias_paused(name),
+            })
+            .collect();
+        aliases.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Response::new(proto::Status {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            paused: self.pause_switch.is_paused(),
+            aliases,
+            peers: self
+                .configured_peers()
+                .iter()
+                .map(|peer| self.config.peer_name(peer))
+                .collect(),
+            alias_states: self
+                .sync_states
+                .states()
+                .into_iter()
+                .map(proto::AliasState::from)
+                .collect(),
+            halted: self.pause_switch.is_halted(),
+            quarantined_files: quarantine::quarantined_files(&self.config)
+                .await
+                .into_iter()
+                .map(proto::QuarantinedFile::from)
+                .collect(),
+        }))
+    }
+
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn stream_events(
+        &self,
+        _request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        // events missed by a slow client are skipped
+        let stream = BroadcastStream::new(self.events.subscribe())
+            .filter_map(|event| event.ok())
+            .map(proto::Event::from)
+            .map(Ok);
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn pause(
+        &self,
+        _request: Request<proto::PauseRequest>,
+    ) -> Result<Response<proto::PauseResponse>, Status> {
+        self.pause_switch.pause();
+        Ok(Response::new(proto::PauseResponse {}))
+    }
+
+    async fn emergency_stop(
+        &self,
+        _request: Request<proto::EmergencyStopRequest>,
+    ) -> Result<Response<proto::EmergencyStopResponse>, Status> {
+        self.pause_switch.halt();
+
+        let manifests = pause_switch::record_manifests(&self.config)
+            .await
+            .map_err(|err| Status::internal(format!("cannot write the manifests: {}", err)))?;
+        Ok(Response::new(proto::EmergencyStopResponse {
+            manifests: manifests
+                .into_iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+        }))
+    }
+
+    async fn resume(
+        &self,
+        _request: Request<proto::ResumeRequest>,
+    ) -> Result<Response<proto::ResumeResponse>, Status> {
+        if self.pause_switch.resume() {
+            // changes made while paused were not sent, a full synchronization catches up with them
+            self.enqueue_sync(self.configured_peers()).await?;
+        }
+
+        Ok(Response::new(proto::ResumeResponse {}))
+    }
+
+    async fn trigger_sync(
+        &self,
+        request: Request<proto::TriggerSyncRequest>,
+    ) -> Result<Response<proto::TriggerSyncResponse>, Status> {
+        if self.pause_switch.is_paused() {
+            return Err(Status::failed_precondition("synchronization is paused"));
+        }
+
+        let peer = request.into_inner().peer;
+        let peers = if peer.is_empty() {
+            self.configured_peers()
+        } else {
+            vec![self.config.peer_address(&peer)]
+        };
+
+        self.enqueue_sync(peers).await?;
+        Ok(Response::new(proto::TriggerSyncResponse {}))
+    }
+
+    async fn list_unauthorized_peers(
+        &self,
+        _request: Request<proto::ListUnauthorizedPeersRequest>,
+    ) -> Result<Response<proto::ListUnauthorizedPeersResponse>, Status> {
+        Ok(Response::new(proto::ListUnauthorizedPeersResponse {
+            addresses: self.authorizations.pending(),
+        }))
+    }
+
+    async fn list_pending_changes(
+        &self,
+        _request: Request<proto::ListPendingChangesRequest>,
+    ) -> Result<Response<proto::ListPendingChangesResponse>, Status> {
+        let changes = outbox::pending_changes(&self.config)
+            .await
+            .into_iter()
+            .map(|change| proto::PendingChange {
+                peer: change.peer,
+                alias: change.alias,
+                path: change.path.to_string_lossy().into_owned(),
+                deleted: change.deleted,
+            })
+            .collect();
+
+        Ok(Response::new(proto::ListPendingChangesResponse { changes }))
+    }
+
+    async fn authorize_peer(
+        &self,
+        request: Request<proto::AuthorizePeerRequest>,
+    ) -> Result<Response<proto::AuthorizePeerResponse>, Status> {
+        let request = request.into_inner();
+        self.authorizations
+            .decide(&request.address, request.approved)
+            .await
+            .map_err(|err| Status::failed_precondition(err.to_string()))?;
+
+        Ok(Response::new(proto::AuthorizePeerResponse {}))
+    }
+
+    async fn pause_alias(
+        &self,
+        request: Request<proto::PauseAliasRequest>,
+    ) -> Result<Response<proto::PauseAliasResponse>, Status> {
+        let alias = self
+            .known_alias(&request.into_inner().alias)
+            .ok_or_else(|| Status::not_found("unknown alias"))?;
+        self.pause_switch.pause_alias(&alias);
+        Ok(Response::new(proto::PauseAliasResponse {}))
+    }
+
+    async fn resume_alias(
+        &self,
+        request: Request<proto::ResumeAliasRequest>,
+    ) -> Result<Response<proto::ResumeAliasResponse>, Status> {
+        let alias = self
+            .known_alias(&request.into_inner().alias)
+            .ok_or_else(|| Status::not_found("unknown alias"))?;
+        if self.pause_switch.resume_alias(&alias) {
+            // changes made while paused were not sent, a synchronization with every peer catches up with them
+            self.sync_events
+                .send(SyncEvent::SyncAlias(alias))
+                .await
+                .map_err(|_| Status::unavailable("synchronization is not running"))?;
+        }
+
+        Ok(Response::new(proto::ResumeAliasResponse {}))
+    }
+
+    async fn list_transfers(
+        &self,
+        _request: Request<proto::ListTransfersRequest>,
+    ) -> Result<Response<proto::ListTransfersResponse>, Status> {
+        let transfers = self
+            .sync_states
+            .in_flight()
+            .list()
+            .into_iter()
+            .map(proto::Transfer::from)
+            .collect();
+
+        Ok(Response::new(proto::ListTransfersResponse { transfers }))
+    }
+
+    async fn cancel_transfer(
+        &self,
+        request: Request<proto::CancelTransferRequest>,
+    ) -> Result<Response<proto::CancelTransferResponse>, Status> {
+        let request = request.into_inner();
+        let after = if request.skip {
+            AfterCancel::Skip
+        } else {
+            AfterCancel::Requeue
+        };
+        if !self.sync_states.in_flight().cancel(request.id, after) {
+            return Err(Status::not_found("unknown transfer"));
+        }
+
+        Ok(Response::new(proto::CancelTransferResponse {}))
+    }
+}
+
+/// Starts the control service at `address`, in the background, returns the task running the service
+pub(crate) fn start(
+    address: &str,
+    config: Arc<Config>,
+    events: Arc<EventBus>,
+    pause_switch: Arc<PauseSwitch>,
+    sync_states: Arc<SyncStates>,
+    authorizations: Arc<PeerAuthorizations>,
+    sync_events: Sender<SyncEvent>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let address = match address.parse() {
+        Ok(address) => address,
+        Err(err) => {
+            log::error!("invalid grpc address {}: {}", address, err);
+            return None;
+        }
+    };
+
+    let service = ControlService {
+        config,
+        events,
+        pause_switch,
+        sync_states,
+        authorizations,
+        sync_events,
+    };
+
+    let task = tokio::spawn(async move {
+        log::info!("gRPC control service listening on {}", address);
+        if let Err(err) = tonic::transport::Server::builder()
+            .add_service(ControlServer::new(service))
+            .serve(address)
+            .await
+        {
+            log::error!("gRPC control service stopped: {}", err);
+        }
+    });
+
+    Some(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn can_pause_and_resume() -> crate::Result<()> {
+        let config = Arc::new(Config::parse_content(
+            "
+            peers = [ \"127.0.0.1:8091\" ]
+
+            [paths]
+            a = \"./tmp/control\""
+                .to_string(),
+        )?);
+        let events = Arc::new(EventBus::new());
+        let (sync_events, mut sync_events_receiver) = mpsc::channel(10);
+        let service = ControlService {
+            config: config.clone(),
+            events: events.clone(),
+            pause_switch: Arc::new(PauseSwitch::new(events.clone(), Default::default())),
+            sync_states: Arc::new(SyncStates::new(events.clone())),
+            authorizations: Arc::new(PeerAuthorizations::new(&config, Default::default())),
+            sync_events,
+        };
+
+        let mut event_stream = service
+            .stream_events(Request::new(proto::StreamEventsRequest {}))
+            .await?
+            .into_inner();
+
+        service.pause(Request::new(proto::PauseRequest {})).await?;
+        let status = service
+            .get_status(Request::new(proto::GetStatusRequest {}))
+            .await?
+            .into_inner();
+        assert!(status.paused);
+        assert_eq!(status.aliases[0].name, "a");
+        assert!(service
+            .trigger_sync(Request::new(proto::TriggerSyncRequest::default()))
+            .await
+            .is_err());
+
+        service
+            .resume(Request::new(proto::ResumeRequest {}))
+            .await?;
+        match sync_events_receiver.recv().await {
+            Some(SyncEvent::EnqueueSyncToPeer(peer, false)) => assert_eq!(peer, "127.0.0.1:8091"),
+            event => panic!("unexpected sync event {:?}", event),
+        }
+
+        assert_eq!(
+            event_stream.next().await.unwrap()?,
+            Event::SynchronizationPaused.into()
+        );
+        assert_eq!(
+            event_stream.next().await.unwrap()?,
+            Event::SynchronizationResumed.into()
+        );
+
+        let stopped = service
+            .emergency_stop(Request::new(proto::EmergencyStopRequest {}))
+            .await?
+            .into_inner();
+        assert_eq!(stopped.manifests.len(), 1);
+        let status = service
+            .get_status(Request::new(proto::GetStatusRequest {}))
+            .await?
+            .into_inner();
+        assert!(status.paused && status.halted);
+        assert_eq!(
+            event_stream.next().await.unwrap()?,
+            Event::SynchronizationHalted.into()
+        );
+
+        std::fs::remove_dir_all("./tmp/control")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+//! Content already present in this node, copied instead of downloading it again, see [Config::deduplicate_transfers]
+//!
+//! Before a file is requested from a peer, the local files with the same size, in any alias, are compared with the
+//! content hash of the peer file. The content hashes of the local files are kept in the alias root, so each file is
+//! only read again after it changes
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, HashAlgorithm},
+    fs::FileInfo,
+};
+
+/// Files smaller than this are always downloaded, the comparison would cost more than the transfer
+pub(crate) const MIN_SIZE: u64 = 1024 * 1024;
+
+/// Content hashes are read, changed and written back, the hashes of every alias are changed one at a time
+static CONTENT_HASHES_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Content hash of a file, valid while the file keeps the same size and modification time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ContentHash {
+    size: u64,
+    modified_at: u64,
+    sha256: String,
+}
+
+type Entries = BTreeMap<PathBuf, ContentHash>;
+
+/// Content hashes of the files of an alias, by path
+struct ContentHashes {
+    state_path: PathBuf,
+}
+
+impl ContentHashes {
+    fn new(alias_root_path: &Path) -> Self {
+        ContentHashes {
+            state_path: alias_root_path.join(".content.ironcarrier"),
+        }
+    }
+
+    async fn get(&self) -> Entries {
+        if !self.state_path.exists() {
+            return Entries::new();
+        }
+
+        match tokio::fs::read(&self.state_path).await {
+            Ok(contents) => bincode::deserialize(&contents).unwrap_or_else(|err| {
+                log::error!("content hashes are invalid, ignoring them: {}", err);
+                Entries::new()
+            }),
+            Err(err) => {
+                log::error!("cannot read content hashes: {}", err);
+                Entries::new()
+            }
+        }
+    }
+
+    /// Records the hashes of `computed`, keeping the other entries
+    async fn record(&self, computed: Entries) -> crate::Result<()> {
+        if computed.is_empty() {
+            return Ok(());
+        }
+
+        let _lock = CONTENT_HASHES_LOCK.lock().await;
+        let mut entries = self.get().await;
+        entries.extend(computed);
+        tokio::fs::write(&self.state_path, bincode::serialize(&entries)?).await?;
+        Ok(())
+    }
+}
+
+/// Local files of every alias, by size, listed the first time they are needed
+#[derive(Default)]
+pub(crate) struct LocalContent {
+    files: Option<HashMap<u64, Vec<FileInfo>>>,
+}
+
+impl LocalContent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lists the files of every local alias, placeholders and deleted files are left out
+    async fn list(config: &Config) -> HashMap<u64, Vec<FileInfo>> {
+        let mut files: HashMap<u64, Vec<FileInfo>> = HashMap::new();
+        for (alias, root_path) in config.paths.iter() {
+            if !config.is_local_storage(alias) {
+                continue;
+            }
+
+            match crate::fs::walk_path(root_path, alias, config).await {
+                Ok(alias_files) => {
+                    for file in alias_files {
+                        if let (None, Some(size)) = (file.deleted_at, file.size) {
+                            files.entry(size).or_default().push(file);
+                        }
+                    }
+                }
+                Err(err) => log::warn!("cannot list files of alias {}: {}", alias, err),
+            }
+        }
+
+        files
+    }
+
+    /// Adds a file received after the files were listed
+    pub fn add(&mut self, file_info: &FileInfo) {
+        if let (Some(files), None, Some(size)) =
+            (self.files.as_mut(), file_info.deleted_at, file_info.size)
+        {
+            let same_size = files.entry(size).or_default();
+            same_size.retain(|file| file.alias != file_info.alias || file.path != file_info.path);
+            same_size.push(file_info.clone());
+        }
+    }
+
+    /// Returns the absolute path of a local file with the same content as `file_info`, whose content hash is `sha256`  
+    /// The local files are hashed with the algorithm of `sha256`
+    pub async fn find(
+        &mut self,
+        config: &Config,
+        file_info: &FileInfo,
+        sha256: &str,
+    ) -> Option<PathBuf> {
+        let size = file_info.size?;
+        let algorithm = HashAlgorithm::of_hash(sha256);
+        if self.files.is_none() {
+            self.files = Some(Self::list(config).await);
+        }
+
+        let candidates = self.files.as_ref()?.get(&size)?.clone();
+        let mut aliases: BTreeMap<&str, Vec<&FileInfo>> = BTreeMap::new();
+        for candidate in candidates.iter() {
+            aliases.entry(&candidate.alias).or_default().push(candidate);
+        }
+
+        for (alias, candidates) in aliases {
+            let root_path = match config.paths.get(alias) {
+                Some(root_path) => root_path,
+                None => continue,
+            };
+            let hashes = ContentHashes::new(root_path);
+            let known = hashes.get().await;
+            let mut computed = Entries::new();
+            let mut found = None;
+
+            for candidate in candidates {
+                let path = match candidate.get_absolute_path(config) {
+                    Ok(path) => path,
+                    Err(_) => continue,
+                };
+                // files changed since they were listed are left out
+                let modified_at = match path.metadata() {
+                    Ok(metadata) if metadata.len() == size => metadata
+                        .modified()
+                        .ok()
+                        .and_then(crate::fs::system_time_to_secs),
+                    _ => None,
+                };
+                let modified_at = match modified_at {
+                    Some(modified_at) => modified_at,
+                    None => continue,
+                };
+
+                let candidate_hash = match known.get(&candidate.path) {
+                    Some(hash)
+                        if hash.size == size
+                            && hash.modified_at == modified_at
+                            && HashAlgorithm::of_hash(&hash.sha256) == algorithm =>
+                    {
+                        hash.sha256.clone()
+                    }
+                    _ => match crate::manifest::hash_file_throttled(
+                        &path,
+                        algorithm,
+                        config.scan_throttle,
+                    )
+                    .await
+                    {
+                        Ok(sha256) => {
+                            computed.insert(
+                                candidate.path.clone(),
+                                ContentHash {
+                                    size,
+                                    modified_at,
+                                    sha256: sha256.clone(),
+                                },
+                            );
+                            sha256
+                        }
+                        Err(err) => {
+                            log::debug!("cannot hash {:?}: {}", path, err);
+                            continue;
+                        }
+                    },
+                };
+
+                if candidate_hash == sha256 {
+                    found = Some(path);
+                    break;
+                }
+            }
+
+            if let Err(err) = hashes.record(computed).await {
+                log::warn!("cannot record content hashes of alias {}: {}", alias, err);
+            }
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finds_content_in_other_aliases() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/dedup/a/photos").await?;
+        tokio::fs::create_dir_all("./tmp/dedup/b").await?;
+        tokio::fs::write("./tmp/dedup/a/photos/original", b"same content").await?;
+        tokio::fs::write("./tmp/dedup/a/other", b"other content").await?;
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/dedup/a\"
+            b = \"./tmp/dedup/b\""
+                .to_string(),
+        )?;
+
+        let sha256 = crate::manifest::hash_file(
+            Path::new("./tmp/dedup/a/photos/original"),
+            HashAlgorithm::Sha256,
+        )
+        .await?;
+        let mut requested = FileInfo::new_deleted("b".to_owned(), PathBuf::from("copy"), None);
+        requested.deleted_at = None;
+        requested.modified_at = Some(10);
+        requested.size = Some(12);
+
+        let mut local_content = LocalContent::new();
+        let found = local_content.find(&config, &requested, &sha256).await;
+        assert_eq!(
+            found.map(|path| path.ends_with("photos/original")),
+            Some(true)
+        );
+        assert!(Path::new("./tmp/dedup/a/.content.ironcarrier").exists());
+        assert!(local_content
+            .find(&config, &requested, "not the hash")
+            .await
+            .is_none());
+
+        tokio::fs::remove_dir_all("./tmp/dedup").await?;
+        Ok(())
+    }
+}
@@ -34,7 +34,7 @@ impl DeletionTracker {
         let contents = std::str::from_utf8(&contents)?;
 
         log::debug!("parsing deletiong log");
-        let log_entries = self.parse_log(&contents);
+        let log_entries = self.parse_log(contents);
         return self.clean_and_rewrite(log_entries).await;
     }
 
@@ -47,7 +47,7 @@ impl DeletionTracker {
         let limit_date = SystemTime::now() - Duration::from_secs(FOURTEEN_DAYS_AS_SECS);
         log_entries.retain(|_, v| *v >= limit_date);
 
-        if log_entries.len() == 0 {
+        if log_entries.is_empty() {
             log::debug!(
                 "dropped {} entries from log file, removing log file",
                 count_before
@@ -62,7 +62,7 @@ impl DeletionTracker {
 
             for (path, time) in &log_entries {
                 log_file
-                    .write_all(&self.create_line(&path, &time).as_bytes())
+                    .write_all(self.create_line(path, time).as_bytes())
                     .await?;
             }
             log_file.flush().await?;
@@ -103,7 +103,7 @@ impl DeletionTracker {
             .await?;
 
         log_file
-            .write_all(&self.create_line(path, &SystemTime::now()).as_bytes())
+            .write_all(self.create_line(path, &SystemTime::now()).as_bytes())
             .await?;
 
         log_file.flush().await?;
@@ -123,7 +123,7 @@ impl DeletionTracker {
             .await?;
 
         log_file
-            .write_all(&self.create_line(path, &SystemTime::UNIX_EPOCH).as_bytes())
+            .write_all(self.create_line(path, &SystemTime::UNIX_EPOCH).as_bytes())
             .await?;
 
         log_file.flush().await?;
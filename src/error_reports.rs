@@ -0,0 +1,167 @@
+//! Collapses repeated failures in the logs
+//!
+//! Failures of files in the same folder, with the same cause, are logged once per [REPORT_WINDOW], the repeats are
+//! counted and reported with the next failure logged for the folder, or when [ErrorReports::flush] is called at the end
+//! of a synchronization. A folder with thousands of unreadable files produces a few lines, instead of one per file
+//!
+//! Each engine has its own reports, kept by its [crate::events::EventBus], so engines embedded in the same process
+//! don't flush the failures of each other
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Time a failure is kept from the logs after the same failure was logged for the folder
+const REPORT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Failures logged for a folder, with the repeats not logged yet
+struct Report {
+    level: log::Level,
+    action: &'static str,
+    folder: PathBuf,
+    cause: String,
+    logged_at: Instant,
+    repeats: u64,
+}
+
+/// Failures reported by a synchronization engine
+pub(crate) struct ErrorReports {
+    reports: Mutex<Vec<Report>>,
+}
+
+impl ErrorReports {
+    pub const fn new() -> Self {
+        Self {
+            reports: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Logs "`action` `path`: `cause`" as an error, unless it was logged for the folder of `path` in the last [REPORT_WINDOW]
+    pub fn error<C: Display>(&self, action: &'static str, path: &Path, cause: C) {
+        self.report(log::Level::Error, action, path, &cause.to_string());
+    }
+
+    /// Logs "`action` `path`: `cause`" as a warning, like [ErrorReports::error]
+    pub fn warn<C: Display>(&self, action: &'static str, path: &Path, cause: C) {
+        self.report(log::Level::Warn, action, path, &cause.to_string());
+    }
+
+    /// Returns true if the failure was logged, false if it was counted as a repeat
+    fn report(&self, level: log::Level, action: &'static str, path: &Path, cause: &str) -> bool {
+        let folder = path.parent().unwrap_or(path);
+        let mut reports = self.reports.lock().unwrap();
+        let report = reports.iter_mut().find(|report| {
+            report.action == action && report.folder == folder && report.cause == cause
+        });
+
+        match report {
+            Some(report) if report.logged_at.elapsed() < REPORT_WINDOW => {
+                report.repeats += 1;
+                false
+            }
+            Some(report) => {
+                if report.repeats > 0 {
+                    log::log!(
+                        level,
+                        "{} {:?}: {}, and {} more in {:?} since the last report",
+                        action,
+                        path,
+                        cause,
+                        report.repeats,
+                        folder
+                    );
+                } else {
+                    log::log!(level, "{} {:?}: {}", action, path, cause);
+                }
+                report.logged_at = Instant::now();
+                report.repeats = 0;
+                true
+            }
+            None => {
+                log::log!(level, "{} {:?}: {}", action, path, cause);
+                // reports without repeats are only kept while they can collapse the next failures
+                reports.retain(|report| {
+                    report.repeats > 0 || report.logged_at.elapsed() < REPORT_WINDOW
+                });
+                reports.push(Report {
+                    level,
+                    action,
+                    folder: folder.to_owned(),
+                    cause: cause.to_owned(),
+                    logged_at: Instant::now(),
+                    repeats: 0,
+                });
+                true
+            }
+        }
+    }
+
+    /// Logs the repeats not reported yet, and forgets the failures logged
+    pub fn flush(&self) {
+        for report in self.reports.lock().unwrap().drain(..) {
+            if report.repeats > 0 {
+                log::log!(
+                    report.level,
+                    "{} {} more files in {:?}: {}",
+                    report.action,
+                    report.repeats,
+                    report.folder,
+                    report.cause
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_failures_are_collapsed() {
+        let reports = ErrorReports::new();
+        let report = |path: &str, cause: &str| {
+            reports.report(
+                log::Level::Error,
+                "cannot read file",
+                Path::new(path),
+                cause,
+            )
+        };
+
+        assert!(report("folder/a", "permission denied"));
+        for file in 0..1000 {
+            assert!(!report(&format!("folder/{}", file), "permission denied"));
+        }
+        assert!(report("folder/a", "the file is locked"));
+        assert!(report("other/a", "permission denied"));
+        assert_eq!(reports.reports.lock().unwrap()[0].repeats, 1000);
+
+        reports.flush();
+        assert!(reports.reports.lock().unwrap().is_empty());
+        assert!(report("folder/a", "permission denied"));
+    }
+
+    #[test]
+    fn engines_keep_their_own_reports() {
+        let engine = crate::events::EventBus::new();
+        let other_engine = crate::events::EventBus::new();
+
+        engine.error_reports().error(
+            "cannot read file",
+            Path::new("folder/a"),
+            "permission denied",
+        );
+        engine.error_reports().error(
+            "cannot read file",
+            Path::new("folder/b"),
+            "permission denied",
+        );
+        other_engine.error_reports().flush();
+
+        assert_eq!(engine.error_reports().reports.lock().unwrap()[0].repeats, 1);
+    }
+}
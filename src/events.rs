@@ -0,0 +1,347 @@
+//! Events emitted while synchronizing
+//!
+//! Events are informative only, they can be observed using [crate::sync::Synchronizer::subscribe]  
+//! A [SyncObserver] is called synchronously, as the synchronization happens, and can refuse some of the changes  
+//! [Event::PeerSession] is also written to the [AuditLog], when there is one
+
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::sync::broadcast;
+
+use crate::{
+    audit_log::AuditLog, error_reports::ErrorReports, fs::FileInfo, sync::SyncPhase,
+    version_store::VersionStores,
+};
+
+/// Max number of events kept for slow subscribers, older events are dropped
+const EVENTS_CAPACITY: usize = 100;
+
+/// Notifications about what is happening in the synchronization
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Inbound transfers for an alias are on hold, they are retried periodically
+    InboundTransfersPaused {
+        /// Alias receiving the files
+        alias: String,
+        /// Reason why the transfers were paused
+        reason: String,
+    },
+    /// Inbound transfers for an alias were resumed
+    InboundTransfersResumed {
+        /// Alias receiving the files
+        alias: String,
+    },
+    /// New synchronizations won't start until the synchronization is resumed
+    SynchronizationPaused,
+    /// Synchronization was resumed
+    SynchronizationResumed,
+    /// New synchronizations won't start, the ones in progress were interrupted and the connections from the peers are
+    /// refused, until the synchronization is resumed
+    SynchronizationHalted,
+    /// The synchronization of an alias was paused, see [crate::config::Config::paused_aliases]
+    AliasPaused {
+        /// Alias paused
+        alias: String,
+    },
+    /// The synchronization of an alias was resumed
+    AliasResumed {
+        /// Alias resumed
+        alias: String,
+    },
+    /// An introducer shared the address of a peer that wasn't known yet, see [crate::config::Config::introducers]
+    PeerIntroduced {
+        /// Address of the new peer
+        address: String,
+        /// Address of the introducer
+        introducer: String,
+        /// False when the peer is waiting for [crate::IronCarrier::approve_peer]
+        approved: bool,
+    },
+    /// A host that is not a configured peer tried to connect, it is refused until [crate::IronCarrier::authorize_peer]
+    PeerAwaitingAuthorization {
+        /// Address of the host
+        address: String,
+    },
+    /// An alias reached its [crate::config::AliasLimits::max_total_size], the files received for it are refused
+    QuotaExceeded {
+        /// Alias over its quota
+        alias: String,
+        /// Bytes used by the alias
+        used: u64,
+        /// Quota of the alias, in bytes
+        quota: u64,
+    },
+    /// An alias was compared with a peer, the changes are about to be applied
+    TransferPlanned {
+        /// Alias being synchronized
+        alias: String,
+        /// Name of the peer, see [crate::config::Config::peer_name]
+        peer: String,
+        /// What the synchronization is about to do
+        preview: TransferPreview,
+    },
+    /// The synchronization of an alias with a peer moved to another [SyncPhase]
+    SyncPhaseChanged {
+        /// Alias being synchronized
+        alias: String,
+        /// Name of the peer, see [crate::config::Config::peer_name]
+        peer: String,
+        /// Phase the alias left
+        from: SyncPhase,
+        /// Phase the alias entered
+        to: SyncPhase,
+    },
+    /// Files were skipped for the same reason, sent once per reason at the end of a synchronization with a peer
+    FilesSkipped {
+        /// What was being done, like the synchronization with a peer
+        operation: String,
+        /// Why the files were skipped
+        reason: String,
+        /// Number of files skipped
+        files: u64,
+        /// Some of the files skipped, paths are relative to the alias root
+        examples: Vec<PathBuf>,
+    },
+    /// Something happened in a session with a peer, recorded in [crate::config::Config::audit_log_path]
+    PeerSession {
+        /// Name of the peer, see [crate::config::Config::peer_name]
+        peer: String,
+        /// Address of the peer
+        address: String,
+        /// What happened
+        activity: PeerActivity,
+    },
+}
+
+/// What happened in a session with a peer, see [Event::PeerSession]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "activity", rename_all = "snake_case")]
+pub enum PeerActivity {
+    /// A connection with the peer was opened, it is authorized next
+    Connected,
+    /// The peer is authorized, or accepted the connection opened by this node, its session started
+    Accepted,
+    /// The connection was refused
+    Refused {
+        /// Why the connection was refused
+        reason: String,
+    },
+    /// The session ended
+    Disconnected {
+        /// Error that ended the session, [None] when the peer closed it
+        reason: Option<String>,
+    },
+    /// The peer sent something the protocol doesn't allow, like an unknown command or an unexpected response
+    ProtocolViolation {
+        /// What the peer sent
+        reason: String,
+    },
+}
+
+/// Changes found by comparing an alias with a peer, before they are applied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferPreview {
+    /// Files missing in one of the peers
+    pub files_added: u64,
+    /// Files changed in one of the peers
+    pub files_updated: u64,
+    /// Files deleted in one of the peers
+    pub files_deleted: u64,
+    /// Bytes to send and to receive
+    pub bytes: u64,
+    /// Rough estimate of the seconds the transfers take, from the recent throughput with the peer  
+    /// [None] until some files were transferred
+    pub eta_seconds: Option<u64>,
+}
+
+impl TransferPreview {
+    /// Records a transfer, `existing` is true when the file being replaced exists
+    pub(crate) fn record_transfer(&mut self, existing: bool, bytes: u64) {
+        match existing {
+            true => self.files_updated += 1,
+            false => self.files_added += 1,
+        }
+        self.bytes += bytes;
+    }
+}
+
+/// Answer of the hooks of a [SyncObserver] that can refuse a change
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Decision {
+    /// The change is applied
+    #[default]
+    Proceed,
+    /// The change is refused, the file is reported as skipped in the synchronization
+    Veto,
+}
+
+/// Hooks called during the synchronization, see [crate::IronCarrierBuilder::observer]
+///
+/// Hooks are called in the synchronization task, so they must return quickly  
+/// A change is refused when any of the observers returns [Decision::Veto]
+pub trait SyncObserver: Send + Sync {
+    /// Called before `alias` is scanned to be compared with a peer
+    fn on_scan_start(&self, _alias: &str) {}
+
+    /// Called before a file received from a peer replaces the local one, [Decision::Veto] discards the received file
+    fn on_file_received(&self, _file: &FileInfo) -> Decision {
+        Decision::Proceed
+    }
+
+    /// Called after a file received from a peer replaced the local one
+    fn on_file_written(&self, _file: &FileInfo) {}
+
+    /// Called before a local file is deleted because it was deleted in a peer, [Decision::Veto] keeps the local file
+    fn on_delete(&self, _file: &FileInfo) -> Decision {
+        Decision::Proceed
+    }
+
+    /// Called when a file received from a peer collides with the local file at `existing`,
+    /// [Decision::Veto] skips the received file, otherwise [crate::config::Config::case_collision_policy] is applied
+    fn on_conflict(&self, _file: &FileInfo, _existing: &Path) -> Decision {
+        Decision::Proceed
+    }
+
+    /// Called when the synchronization with `peer_address` fails
+    fn on_error(&self, _peer_address: &str, _error: &(dyn std::error::Error + Send + Sync)) {}
+
+    /// Called when the synchronization with `peer_address` completes
+    fn on_cycle_complete(&self, _peer_address: &str) {}
+}
+
+/// Broadcasts [Event] to all subscribers and calls the [SyncObserver]s
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<Event>,
+    observers: RwLock<Vec<Arc<dyn SyncObserver>>>,
+    audit_log: Option<AuditLog>,
+    /// Repeated failures of the engine owning the bus, see [ErrorReports]
+    error_reports: ErrorReports,
+    /// Previous versions kept by the sessions of the engine owning the bus, see [VersionStores]
+    versions: VersionStores,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self {
+            sender,
+            observers: RwLock::new(Vec::new()),
+            audit_log: None,
+            error_reports: ErrorReports::new(),
+            versions: VersionStores::new(),
+        }
+    }
+
+    /// Returns the failures reported by the engine owning the bus, they are collapsed apart from other engines
+    pub fn error_reports(&self) -> &ErrorReports {
+        &self.error_reports
+    }
+
+    /// Returns the version stores shared by the sessions of the engine owning the bus
+    pub fn versions(&self) -> &VersionStores {
+        &self.versions
+    }
+
+    /// Writes the [Event::PeerSession] events to `audit_log`
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    pub fn add_observer(&self, observer: Arc<dyn SyncObserver>) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    /// Calls `hook` for every observer
+    pub fn notify(&self, hook: impl Fn(&dyn SyncObserver)) {
+        for observer in self.observers.read().unwrap().iter() {
+            hook(observer.as_ref());
+        }
+    }
+
+    /// Calls `hook` for every observer, returns [Decision::Veto] if any of them refuses the change
+    pub fn decide(&self, hook: impl Fn(&dyn SyncObserver) -> Decision) -> Decision {
+        let observers = self.observers.read().unwrap();
+        match observers
+            .iter()
+            .any(|observer| hook(observer.as_ref()) == Decision::Veto)
+        {
+            true => Decision::Veto,
+            false => Decision::Proceed,
+        }
+    }
+
+    /// Sends `event` to all current subscribers, the event is dropped if there are no subscribers
+    pub fn emit(&self, event: Event) {
+        log::debug!("emitting event {:?}", event);
+        if let (
+            Some(audit_log),
+            Event::PeerSession {
+                peer,
+                address,
+                activity,
+            },
+        ) = (&self.audit_log, &event)
+        {
+            if let Err(err) = audit_log.record(peer, address, activity) {
+                log::error!("cannot write to audit log: {}", err);
+            }
+        }
+        self.sender.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_receive_events() {
+        let events = EventBus::new();
+        events.emit(Event::InboundTransfersResumed { alias: "a".into() });
+
+        let mut subscriber = events.subscribe();
+        events.emit(Event::InboundTransfersResumed { alias: "b".into() });
+
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::InboundTransfersResumed { alias: "b".into() }
+        );
+    }
+
+    /// Refuses to delete files in the `keep` folder
+    struct KeepFolder;
+
+    impl SyncObserver for KeepFolder {
+        fn on_delete(&self, file: &FileInfo) -> Decision {
+            match file.path.starts_with("keep") {
+                true => Decision::Veto,
+                false => Decision::Proceed,
+            }
+        }
+    }
+
+    #[test]
+    fn any_observer_can_veto() {
+        let events = EventBus::new();
+        let file = |path: &str| FileInfo::new_deleted("a".into(), path.into(), None);
+        let deletes = |file: &FileInfo| events.decide(|observer| observer.on_delete(file));
+
+        assert_eq!(deletes(&file("keep/file")), Decision::Proceed);
+
+        struct Everything;
+        impl SyncObserver for Everything {}
+        events.add_observer(Arc::new(Everything));
+        events.add_observer(Arc::new(KeepFolder));
+
+        assert_eq!(deletes(&file("keep/file")), Decision::Veto);
+        assert_eq!(deletes(&file("other/file")), Decision::Proceed);
+    }
+}
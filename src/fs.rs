@@ -3,15 +3,32 @@
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ord,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     hash::Hash,
-    path::{Path, PathBuf},
-    time::Duration,
+    path::{Component, Path, PathBuf},
     time::SystemTime,
+    time::{Duration, Instant},
 };
-use tokio::fs::{self, File};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt},
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::{config::Config, deletion_tracker::DeletionTracker, IronCarrierError};
+use crate::{
+    config::{Config, HashAlgorithm, ReparsePointPolicy, ScanThrottle, SpecialFilePolicy},
+    deletion_tracker::DeletionTracker,
+    locked_files::LockedFiles,
+    merge,
+    on_demand::Placeholders,
+    pattern::Pattern,
+    scan_index::{ScanIndex, ScanPlan},
+    skipped_files::SkippedFiles,
+    spool::{SortedList, Spool},
+    storage::{StorageFile, StorageMetadata},
+    version_store::VersionStores,
+    IronCarrierError,
+};
 
 /// Holds the information for a file inside a mapped folder  
 ///
@@ -27,30 +44,212 @@ pub struct FileInfo {
     /// The relative path will always be the same, no matter the machine
     pub path: PathBuf,
 
+    /// Modification time, in seconds since the unix epoch
     pub modified_at: Option<u64>,
+    /// Creation time, in seconds since the unix epoch
     pub created_at: Option<u64>,
+    /// Deletion time, in seconds since the unix epoch, only present for deleted files
     pub deleted_at: Option<u64>,
+    /// Size of the file, in bytes
     pub size: Option<u64>,
+    /// Type of the file, only regular files have content
+    #[serde(default)]
+    pub kind: FileKind,
+    /// Additional attributes of the file, like extended attributes or custom tags, empty by default  
+    /// New attributes are added as new keys, so the wire format doesn't change, peers ignore the keys they don't know
+    #[serde(default)]
+    pub extra: HashMap<String, Vec<u8>>,
+}
+
+/// Type of a synchronized file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FileKind {
+    /// File with content
+    #[default]
+    Regular,
+    /// Named pipe, synchronized without content, see [SpecialFilePolicy::PreserveFifos]
+    Fifo,
+    /// Hard link to `target`, a path in the same alias, synchronized without content, see [Config::preserve_hard_links]
+    HardLink {
+        /// Path of the linked file, relative to the alias root
+        target: PathBuf,
+    },
+}
+
+impl FileKind {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if metadata.file_type().is_fifo() {
+                return FileKind::Fifo;
+            }
+        }
+
+        FileKind::Regular
+    }
+}
+
+/// Returns `name` in the unicode normalization form used to compare paths between peers (NFC)
+fn normalize_name(name: &str) -> std::borrow::Cow<'_, str> {
+    if name.is_ascii() {
+        name.into()
+    } else {
+        icu_normalizer::ComposingNormalizerBorrowed::new_nfc().normalize(name)
+    }
+}
+
+/// Returns `path` with every name in NFC, the form used to compare paths between peers
+///
+/// macOS stores names in NFD, while other systems keep them as they were typed, usually NFC,
+/// so the same name could show up as two different files  
+/// Names that aren't valid unicode are kept as they are
+fn normalize_path(path: PathBuf) -> PathBuf {
+    if path.to_str().map(|path| path.is_ascii()).unwrap_or(true) {
+        return path;
+    }
+
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => match name.to_str() {
+                Some(name) => PathBuf::from(normalize_name(name).as_ref()),
+                None => PathBuf::from(name),
+            },
+            component => PathBuf::from(component.as_os_str()),
+        })
+        .collect()
+}
+
+/// Returns `relative_path` inside `root_path` as it is stored on disk
+///
+/// Paths are compared in NFC, but the local names are kept in the form they were created,
+/// when a name doesn't exist as it is, the folder is searched for a name with the same normalized form  
+/// Names not found on disk are kept as they are
+fn resolve_on_disk(root_path: PathBuf, relative_path: &Path) -> PathBuf {
+    let mut resolved = root_path;
+    for component in relative_path.components() {
+        let candidate = resolved.join(component);
+        if candidate.exists() {
+            resolved = candidate;
+            continue;
+        }
+
+        let name = component.as_os_str().to_str().unwrap_or_default();
+        let on_disk = std::fs::read_dir(&resolved).ok().and_then(|entries| {
+            entries.filter_map(|entry| entry.ok()).find(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .map(|entry_name| normalize_name(entry_name) == name)
+                    .unwrap_or_default()
+            })
+        });
+
+        resolved = match on_disk {
+            Some(entry) => entry.path(),
+            None => candidate,
+        };
+    }
+
+    resolved
 }
 
-fn system_time_to_secs(time: SystemTime) -> Option<u64> {
+pub(crate) fn system_time_to_secs(time: SystemTime) -> Option<u64> {
     time.duration_since(SystemTime::UNIX_EPOCH)
         .map(|duration| duration.as_secs())
         .ok()
 }
 
+/// Returns the identity of the file content when it has more than one hard link, only available in unix systems
+#[cfg(unix)]
+fn hard_link_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.is_file() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+/// Returns the identity of the file content when it has more than one hard link, only available in unix systems
+#[cfg(not(unix))]
+fn hard_link_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
 impl FileInfo {
+    /// Returns the number of bytes sent when the file is transferred, only regular files have content
+    pub fn content_size(&self) -> u64 {
+        match self.kind {
+            FileKind::Regular => self.size.unwrap_or_default(),
+            _ => 0,
+        }
+    }
+
+    /// Creates the [FileInfo] of an existing file, `relative_path` is relative to the alias root
     pub fn new(alias: String, relative_path: PathBuf, metadata: std::fs::Metadata) -> Self {
         FileInfo {
             alias,
-            path: relative_path,
+            path: normalize_path(relative_path),
             created_at: metadata.created().ok().and_then(system_time_to_secs),
             modified_at: metadata.modified().ok().and_then(system_time_to_secs),
             size: Some(metadata.len()),
             deleted_at: None,
+            kind: FileKind::of(&metadata),
+            extra: Default::default(),
+        }
+    }
+
+    /// Keeps the Windows `attributes` of the file in [FileInfo::extra], see [Config::preserve_file_attributes]  
+    /// Files without any of the attributes are left as they are
+    fn keep_attributes(&mut self, attributes: u8) {
+        if attributes != 0 {
+            self.extra
+                .insert(WINDOWS_ATTRIBUTES.to_owned(), vec![attributes]);
+        }
+    }
+
+    /// Keeps the permission bits and the access ACL of the file at `path` in [FileInfo::extra], see [Config::preserve_permissions]
+    fn keep_permissions(&mut self, path: &Path, metadata: &std::fs::Metadata) {
+        if let Some(mode) = unix_mode(metadata) {
+            self.extra
+                .insert(UNIX_MODE.to_owned(), mode.to_le_bytes().to_vec());
+        }
+        if let Some(acl) = read_acl(path) {
+            self.extra.insert(POSIX_ACL.to_owned(), acl);
+        }
+    }
+
+    /// Returns the permission bits kept by [FileInfo::keep_permissions]
+    fn mode(&self) -> Option<u32> {
+        let mode = self.extra.get(UNIX_MODE)?;
+        let mode: [u8; 4] = std::convert::TryInto::try_into(mode.as_slice()).ok()?;
+        Some(u32::from_le_bytes(mode))
+    }
+
+    /// Returns the Windows attributes kept by [FileInfo::keep_attributes]
+    fn attributes(&self) -> u8 {
+        self.extra
+            .get(WINDOWS_ATTRIBUTES)
+            .and_then(|attributes| attributes.first().copied())
+            .unwrap_or_default()
+    }
+
+    /// Creates the [FileInfo] of a file kept in a custom [crate::storage::Storage]
+    fn from_storage(alias: String, relative_path: PathBuf, metadata: &StorageMetadata) -> Self {
+        FileInfo {
+            alias,
+            path: normalize_path(relative_path),
+            created_at: metadata.created_at,
+            modified_at: metadata.modified_at,
+            size: Some(metadata.len),
+            deleted_at: None,
+            kind: FileKind::Regular,
+            extra: Default::default(),
         }
     }
 
+    /// Creates the [FileInfo] of a deleted file, deleted now unless `deleted_at` is provided
     pub fn new_deleted(
         alias: String,
         relative_path: PathBuf,
@@ -58,53 +257,81 @@ impl FileInfo {
     ) -> Self {
         FileInfo {
             alias,
-            path: relative_path,
+            path: normalize_path(relative_path),
             created_at: None,
             modified_at: None,
             size: None,
             deleted_at: deleted_at
                 .or_else(|| Some(SystemTime::now()))
                 .and_then(system_time_to_secs),
+            kind: FileKind::Regular,
+            extra: Default::default(),
         }
     }
 
+    /// Returns true if the local copy of the file was modified after this one  
+    /// Files in custom storages are never newer, their metadata can't be read here
     pub fn is_local_file_newer(&self, config: &Config) -> bool {
         if self.deleted_at.is_some() {
             true
+        } else if !config.is_local_storage(&self.alias) {
+            false
         } else {
             self.get_absolute_path(config)
                 .ok()
                 .and_then(|path| path.metadata().ok())
                 .and_then(|metadata| metadata.modified().ok())
                 .and_then(system_time_to_secs)
-                .and_then(|created_at| Some(created_at > self.modified_at.unwrap()))
+                .map(|created_at| created_at > self.modified_at.unwrap())
                 .unwrap_or(false)
         }
     }
 
     /// Returns the absolute path of the file for this file system  
-    /// Using the provided root path for the alias in [Config]
+    /// Using the provided root path for the alias in [Config]  
+    /// Names stored on disk in a different unicode normalization than `path` are resolved to their on-disk form  
+    /// For aliases in custom storages, the root path is used as it is
     pub fn get_absolute_path(&self, config: &Config) -> crate::Result<PathBuf> {
-        match config.paths.get(&self.alias) {
-            Some(path) => match path.canonicalize() {
-                Ok(mut root_path) => {
-                    root_path.extend(self.path.components());
-                    Ok(root_path)
-                }
-                Err(_) => {
-                    log::error!(
-                        "cannot get absolute path for alias {}, check if the path is valid",
-                        self.alias
-                    );
-                    Err(IronCarrierError::AliasNotAvailable(self.alias.to_owned()).into())
-                }
-            },
+        match config.root_of(&self.alias, &self.path) {
+            Some(root) => self.absolute_path_in(root, config),
             None => {
                 log::error!("provided alias does not exist in this node: {}", self.alias);
                 Err(IronCarrierError::AliasNotAvailable(self.alias.to_owned()).into())
             }
         }
     }
+
+    /// Returns the absolute path of the file inside `root`, one of the folders of its alias, see [FileInfo::get_absolute_path]
+    fn absolute_path_in(&self, root: &Path, config: &Config) -> crate::Result<PathBuf> {
+        if !config.is_local_storage(&self.alias) {
+            let mut root_path = root.to_owned();
+            root_path.extend(self.path.components());
+            return Ok(root_path);
+        }
+
+        match root.canonicalize() {
+            Ok(mut root_path) => {
+                if self
+                    .path
+                    .to_str()
+                    .map(|path| path.is_ascii())
+                    .unwrap_or(true)
+                {
+                    root_path.extend(self.path.components());
+                    return Ok(root_path);
+                }
+
+                Ok(resolve_on_disk(root_path, &self.path))
+            }
+            Err(_) => {
+                log::error!(
+                    "cannot get absolute path for alias {}, check if the path is valid",
+                    self.alias
+                );
+                Err(IronCarrierError::AliasNotAvailable(self.alias.to_owned()).into())
+            }
+        }
+    }
 }
 
 impl Hash for FileInfo {
@@ -113,6 +340,15 @@ impl Hash for FileInfo {
         self.path.hash(state);
         self.modified_at.hash(state);
         self.size.hash(state);
+        // regular files without extra attributes hash as they did before these fields existed
+        if self.kind != FileKind::Regular {
+            self.kind.hash(state);
+        }
+        if !self.extra.is_empty() {
+            let mut extra: Vec<_> = self.extra.iter().collect();
+            extra.sort();
+            extra.hash(state);
+        }
     }
 }
 
@@ -126,7 +362,7 @@ impl PartialEq for FileInfo {
 
 impl PartialOrd for FileInfo {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.path.partial_cmp(&other.path)
+        Some(self.cmp(other))
     }
 }
 
@@ -136,222 +372,2804 @@ impl Ord for FileInfo {
     }
 }
 
-/// Returns a sorted vector with the entire folder structure for the given path
-///
-/// This function will look for deletes files in the [DeletionTracker] log and append all entries to the return list  
-/// files with name or extension `.ironcarrier` will be ignored
-pub async fn walk_path<'a>(root_path: &Path, alias: &'a str) -> crate::Result<Vec<FileInfo>> {
-    let mut paths = vec![root_path.to_owned()];
+/// Content of a single folder, read by [read_dir_entries]
+#[derive(Default)]
+struct DirEntries {
+    dirs: Vec<PathBuf>,
+    files: Vec<(PathBuf, std::fs::Metadata)>,
+    skipped: Vec<(PathBuf, std::io::Error)>,
+    /// Junction found with [ReparsePointPolicy::Error], the scan stops
+    reparse_point: Option<PathBuf>,
+}
 
-    let deletion_tracker = DeletionTracker::new(root_path);
-    let mut files: Vec<FileInfo> = deletion_tracker
-        .get_files()
-        .await?
-        .into_iter()
-        .map(|(k, v)| FileInfo::new_deleted(alias.to_owned(), k, Some(v)))
-        .collect();
+impl DirEntries {
+    /// Fails if the folder has a junction with [ReparsePointPolicy::Error]
+    fn check_reparse_point(&self) -> crate::Result<()> {
+        match &self.reparse_point {
+            Some(path) => {
+                Err(IronCarrierError::ReparsePointFound(path.display().to_string()).into())
+            }
+            None => Ok(()),
+        }
+    }
+}
 
-    while let Some(path) = paths.pop() {
-        let mut entries = fs::read_dir(path).await?;
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
+/// Returns the id of the device that contains the file, only available in unix systems
+#[cfg(unix)]
+fn device_id(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
 
-            if is_special_file(&path) {
-                continue;
-            }
+/// Returns the id of the device that contains the file, only available in unix systems
+#[cfg(not(unix))]
+fn device_id(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
 
-            if path.is_dir() {
-                paths.push(path);
-                continue;
-            }
+/// Returns true if the special file should be kept according to `policy`
+#[cfg(unix)]
+fn keep_special_file(metadata: &std::fs::Metadata, policy: SpecialFilePolicy) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    policy == SpecialFilePolicy::PreserveFifos && metadata.file_type().is_fifo()
+}
 
-            let metadata = path.metadata()?;
-            files.push(FileInfo::new(
-                alias.to_owned(),
-                path.strip_prefix(root_path)?.to_owned(),
-                metadata,
-            ));
-        }
-    }
+/// Returns true if the special file should be kept according to `policy`
+#[cfg(not(unix))]
+fn keep_special_file(_metadata: &std::fs::Metadata, _policy: SpecialFilePolicy) -> bool {
+    false
+}
 
-    files.sort();
+/// Identity of a folder, used to detect folders visited more than once through symbolic links
+#[cfg(unix)]
+type DirId = (u64, u64);
+/// Identity of a folder, used to detect folders visited more than once through symbolic links
+#[cfg(not(unix))]
+type DirId = PathBuf;
 
-    return Ok(files);
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    path.metadata()
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
 }
 
-/// This function returns the result of [walk_path] along with the hash for the file list
-pub async fn get_files_with_hash<'a>(
-    path: &Path,
-    alias: &'a str,
-) -> crate::Result<(u64, Vec<FileInfo>)> {
-    let files = walk_path(path, alias).await?;
-    let hash = crate::crypto::calculate_hash(&files);
+#[cfg(not(unix))]
+fn dir_id(path: &Path) -> Option<DirId> {
+    path.canonicalize().ok()
+}
 
-    log::debug!(
-        "found {} files for alias {} with hash {}",
-        files.len(),
-        alias,
-        hash
-    );
+/// Returns true if `path` is a junction, or another folder redirected to a different place, but not a symbolic link  
+/// Redirections are reparse points with a name surrogate tag, other reparse points, like cloud files, are read normally
+#[cfg(windows)]
+fn is_junction(path: &Path) -> bool {
+    use std::os::windows::{fs::OpenOptionsExt, io::AsRawHandle};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAttributeTagInfo, GetFileInformationByHandleEx, FILE_ATTRIBUTE_REPARSE_POINT,
+        FILE_ATTRIBUTE_TAG_INFO, FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT,
+    };
+    const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000000C;
+    const NAME_SURROGATE: u32 = 0x20000000;
+
+    let file = match std::fs::OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+
+    let mut info = FILE_ATTRIBUTE_TAG_INFO {
+        FileAttributes: 0,
+        ReparseTag: 0,
+    };
+    let result = unsafe {
+        GetFileInformationByHandleEx(
+            file.as_raw_handle(),
+            FileAttributeTagInfo,
+            &mut info as *mut FILE_ATTRIBUTE_TAG_INFO as *mut std::ffi::c_void,
+            std::mem::size_of::<FILE_ATTRIBUTE_TAG_INFO>() as u32,
+        )
+    };
 
-    return Ok((hash, files));
+    result != 0
+        && info.FileAttributes & FILE_ATTRIBUTE_REPARSE_POINT != 0
+        && info.ReparseTag & NAME_SURROGATE != 0
+        && info.ReparseTag != IO_REPARSE_TAG_SYMLINK
 }
 
-/// This function will return a [HashMap] containing the alias as key and the hash as value
-pub async fn get_hash_for_alias(
-    alias_path: &HashMap<String, PathBuf>,
-) -> crate::Result<HashMap<String, u64>> {
-    let mut result = HashMap::new();
+/// Junctions only exist in Windows
+#[cfg(not(windows))]
+fn is_junction(_path: &Path) -> bool {
+    false
+}
 
-    for (alias, path) in alias_path {
-        let (hash, _) = get_files_with_hash(path.as_path(), alias).await?;
-        result.insert(alias.to_string(), hash);
+/// Options of an alias scan, used by every folder read
+#[derive(Debug, Clone, Copy, Default)]
+struct ScanOptions {
+    /// When provided, folders in other devices, like mount points, are left out
+    device: Option<u64>,
+    /// Which special files are listed, the others are skipped
+    special_files: SpecialFilePolicy,
+    /// Symbolic links to folders are left out unless this is true
+    follow_symlinks: bool,
+    /// Hidden files and folders are left out when this is true
+    ignore_hidden: bool,
+    /// What to do with junctions
+    reparse_points: ReparsePointPolicy,
+}
+
+impl ScanOptions {
+    /// Returns true if the scan can reach a folder more than once, so each folder carries the identity of its parents
+    fn follows_links(&self) -> bool {
+        self.follow_symlinks || self.reparse_points == ReparsePointPolicy::Follow
     }
+}
 
-    Ok(result)
+/// Reads the folder at `dir_path` like [read_dir_entries], following `throttle`  
+/// Must run in a blocking thread, returns the time spent reading along with the entries
+fn read_dir_throttled(
+    dir_path: &Path,
+    options: ScanOptions,
+    throttle: ScanThrottle,
+) -> (std::io::Result<DirEntries>, Duration) {
+    with_idle_priority(throttle.idle_priority, || {
+        let started_at = Instant::now();
+        let entries = read_dir_entries(dir_path, options);
+        (entries, started_at.elapsed())
+    })
 }
 
-pub async fn delete_file(file_info: &FileInfo, config: &Config) -> crate::Result<()> {
-    let path = file_info.get_absolute_path(config)?;
-    if !path.exists() {
-        log::debug!("delete_file: given path doesn't exist ({:?})", path);
-        return Ok(());
-    } else if path.is_dir() {
-        log::debug!("delete_file: {:?} is dir, removing whole dir", path);
-        tokio::fs::remove_dir_all(&path).await?
-    } else {
-        log::debug!("delete_file: removing file {:?}", path);
-        tokio::fs::remove_file(&path).await?
+/// Runs `read` with the idle IO priority when `idle` is true, see [crate::config::ScanThrottle::idle_priority]  
+/// Must run in a blocking thread, the previous priority of the thread is restored afterwards, since the threads are reused
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn with_idle_priority<T>(idle: bool, read: impl FnOnce() -> T) -> T {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    if !idle {
+        return read();
     }
 
-    log::debug!("{:?} removed", path);
+    // thread 0 is the calling thread
+    let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+    let changed = previous >= 0
+        && unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        } == 0;
 
-    Ok(())
+    let result = read();
+    if changed {
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, previous) };
+    }
+
+    result
 }
 
-pub async fn move_file<'b>(
-    src_file: &'b FileInfo,
-    dest_file: &'b FileInfo,
-    config: &Config,
-) -> crate::Result<()> {
-    let src_path = src_file.get_absolute_path(config)?;
-    let dest_path = dest_file.get_absolute_path(config)?;
+/// IO priorities are not supported on this platform, `read` runs with the default priority
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn with_idle_priority<T>(_idle: bool, read: impl FnOnce() -> T) -> T {
+    read()
+}
 
-    log::debug!("moving file {:?} to {:?}", src_path, dest_path);
+/// Reads the entries of `dir_path`, blocking the current thread  
+/// Entries that can't be read, and special files left out by [ScanOptions::special_files], are returned in [DirEntries::skipped]
+fn read_dir_entries(dir_path: &Path, options: ScanOptions) -> std::io::Result<DirEntries> {
+    let mut dir_entries = DirEntries::default();
 
-    tokio::fs::rename(src_path, dest_path).await?;
+    for entry in std::fs::read_dir(dir_path)? {
+        let (path, is_symlink) = match entry {
+            Ok(entry) => (
+                entry.path(),
+                entry
+                    .file_type()
+                    .map(|file_type| file_type.is_symlink())
+                    .unwrap_or_default(),
+            ),
+            Err(err) => {
+                dir_entries.skipped.push((dir_path.to_owned(), err));
+                break;
+            }
+        };
 
-    Ok(())
-}
+        if is_special_file(&path) || (options.ignore_hidden && is_hidden_entry(&path)) {
+            continue;
+        }
 
-pub async fn get_temp_file(
-    file_info: &FileInfo,
-    config: &Config,
-) -> crate::Result<tokio::fs::File> {
-    let mut temp_path = file_info.get_absolute_path(config)?;
-    temp_path.set_extension("ironcarrier");
+        if path.is_dir() {
+            // std reports junctions as symbolic links
+            if is_symlink && is_junction(&path) {
+                match options.reparse_points {
+                    ReparsePointPolicy::Skip => {
+                        log::debug!("not following junction {:?}", path);
+                        continue;
+                    }
+                    ReparsePointPolicy::Follow => {}
+                    ReparsePointPolicy::Error => {
+                        dir_entries.reparse_point = Some(path);
+                        return Ok(dir_entries);
+                    }
+                }
+            } else if is_symlink && !options.follow_symlinks {
+                log::debug!("not following symbolic link {:?}", path);
+                continue;
+            }
+
+            let dir_device = path
+                .metadata()
+                .ok()
+                .and_then(|metadata| device_id(&metadata));
+            match (options.device, dir_device) {
+                (Some(device), Some(dir_device)) if device != dir_device => {
+                    log::info!(
+                        "not descending into {:?}, it is in another file system",
+                        path
+                    );
+                }
+                _ => dir_entries.dirs.push(path),
+            }
+            continue;
+        }
 
-    if let Some(parent) = temp_path.parent() {
-        if !parent.exists() {
-            log::debug!("creating folders {:?}", parent);
-            tokio::fs::create_dir_all(parent).await?;
+        match path.metadata() {
+            Ok(metadata)
+                if metadata.is_file() || keep_special_file(&metadata, options.special_files) =>
+            {
+                dir_entries.files.push((path, metadata))
+            }
+            Ok(_) => dir_entries.skipped.push((
+                path,
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "sockets, FIFOs and devices are not synchronized",
+                ),
+            )),
+            Err(err) => dir_entries.skipped.push((path, err)),
         }
     }
 
-    log::debug!("creating temp file {:?}", temp_path);
-    Ok(File::create(&temp_path).await?)
+    Ok(dir_entries)
 }
 
-pub async fn flush_temp_file(file_info: &FileInfo, config: &Config) -> crate::Result<()> {
-    let final_path = file_info.get_absolute_path(config)?;
-    let mut temp_path = final_path.clone();
+/// Returns a sorted vector with the regular files of the entire folder structure for the given path
+///
+/// The whole list is kept in memory, see [scan_path]  
+/// FIFOs and placeholders are left out and hard links are listed as regular files, this list is used to read the content of the files
+pub async fn walk_path(
+    root_path: &Path,
+    alias: &str,
+    config: &Config,
+) -> crate::Result<Vec<FileInfo>> {
+    let mut files = scan_alias(root_path, alias, config, &CancellationToken::new())
+        .await?
+        .to_vec()?;
+    files.retain(|file| file.kind != FileKind::Fifo);
+    for file in files.iter_mut() {
+        file.kind = FileKind::Regular;
+    }
 
-    temp_path.set_extension("ironcarrier");
+    Ok(files)
+}
 
-    log::debug!("moving temp file to {:?}", final_path);
-    tokio::fs::rename(&temp_path, &final_path).await?;
+/// Opens the content of `file_info` to be sent to a peer, FIFOs are sent without content
+pub async fn open_content(
+    file_info: &FileInfo,
+    config: &Config,
+) -> crate::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    match file_info.kind {
+        FileKind::Regular => {
+            let path = file_info.get_absolute_path(config)?;
+            Ok(config.storage(&file_info.alias).open(&path).await?)
+        }
+        FileKind::Fifo | FileKind::HardLink { .. } => Ok(Box::new(tokio::io::empty())),
+    }
+}
 
-    log::debug!("setting file modification time");
-    let mod_time = SystemTime::UNIX_EPOCH + Duration::from_secs(file_info.modified_at.unwrap());
-    filetime::set_file_mtime(&final_path, filetime::FileTime::from_system_time(mod_time))?;
+/// Returns the path of the content of `file_info`, [None] if it isn't a regular file in the local file system
+pub fn local_content_path(file_info: &FileInfo, config: &Config) -> Option<PathBuf> {
+    match file_info.kind {
+        FileKind::Regular if config.is_local_storage(&file_info.alias) => {
+            file_info.get_absolute_path(config).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Reads the content of `file_info` to be sent to a peer, FIFOs are sent without content
+pub async fn read_content(file_info: &FileInfo, config: &Config) -> crate::Result<Vec<u8>> {
+    match file_info.kind {
+        FileKind::Regular => {
+            let mut content = Vec::new();
+            open_content(file_info, config)
+                .await?
+                .read_to_end(&mut content)
+                .await?;
+            Ok(content)
+        }
+        FileKind::Fifo | FileKind::HardLink { .. } => Ok(Vec::new()),
+    }
+}
 
-    // TODO: Set File Permissions
+/// Replaces the empty file at `path` with a hard link to `target`
+fn replace_with_hard_link(path: &Path, target: &Path) -> std::io::Result<()> {
+    if !target.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("hard link target {:?} doesn't exist yet", target),
+        ));
+    }
 
-    Ok(())
+    std::fs::remove_file(path)?;
+    std::fs::hard_link(target, path)
 }
 
-/// Returns true if `path` name or extension are .ironcarrier
-pub fn is_special_file(path: &Path) -> bool {
-    path.file_name()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| ext.ends_with("ironcarrier"))
-        .unwrap_or_default()
+/// Replaces the empty file at `path` with a FIFO
+#[cfg(unix)]
+fn replace_with_fifo(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::fs::remove_file(path)?;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::crypto::calculate_hash;
+/// FIFOs can't be created on this platform
+#[cfg(not(unix))]
+fn replace_with_fifo(_path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "FIFOs are not supported on this platform",
+    ))
+}
 
-    #[tokio::test]
-    async fn can_read_local_files() -> Result<(), Box<dyn std::error::Error>> {
-        fs::create_dir_all("./tmp/fs/read_local_files").await?;
-        File::create("./tmp/fs/read_local_files/file_1").await?;
-        File::create("./tmp/fs/read_local_files/file_2").await?;
+/// Returns a sorted list with the entire folder structure for the given path
+///
+/// This function will look for deletes files in the [DeletionTracker] log and append all entries to the return list  
+/// files with name or extension `.ironcarrier` will be ignored  
+/// folders and files that can't be read are skipped and reported at the end of the scan  
+/// up to [Config::scan_workers] folders are read at the same time  
+/// folders in other file systems are left out for aliases in [Config::one_file_system]  
+/// files deleted by the peers are kept in archives, see [Config::archive_mode], they are listed as deleted  
+/// sockets and devices are skipped, FIFOs are listed according to [Config::special_files]  
+/// files hard linked together are listed as links to the first of them for aliases in [Config::preserve_hard_links]  
+/// symbolic links to folders are only followed for aliases in [Config::follow_symlinks], links to a parent folder are left out  
+/// hidden files and folders are left out for aliases in [Config::ignore_hidden]  
+/// folders deeper than [crate::config::AliasLimits::max_depth] are not read  
+/// the scan stops with [IronCarrierError::Cancelled] when `cancel` is cancelled  
+/// the list is kept on disk when it doesn't fit [Config::memory_budget_mb]  
+/// aliases in custom storages are read with [scan_storage]  
+/// only the folders changed since the last scan are read while the file watcher runs, see [Config::incremental_scan]  
+/// aliases with [Config::extra_roots] are read with [scan_roots]  
+/// placeholders of the aliases in [Config::on_demand] are listed as the files they stand for
+pub(crate) async fn scan_path(
+    root_path: &Path,
+    alias: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> crate::Result<SortedList<FileInfo>> {
+    let files = scan_alias(root_path, alias, config, cancel).await?;
+    if !config.on_demand.contains(alias) || !config.is_local_storage(alias) {
+        return Ok(files);
+    }
 
-        let files = walk_path(&PathBuf::from("./tmp/fs/read_local_files"), "a")
-            .await
-            .unwrap();
+    let placeholders = Placeholders::new(root_path).get().await;
+    if placeholders.is_empty() {
+        return Ok(files);
+    }
 
-        assert_eq!(files[0].path.to_str(), Some("file_1"));
-        assert_eq!(files[1].path.to_str(), Some("file_2"));
+    // files present locally take the place of their placeholders
+    let mut merged = Spool::new(config, |a: &FileInfo, b: &FileInfo| a.cmp(b));
+    let mut placeholders = placeholders.into_values().peekable();
+    let mut files = files.reader()?;
+    while let Some(file) = files.next_entry()? {
+        while let Some(placeholder) = placeholders.next_if(|placeholder| *placeholder < file) {
+            merged.push(placeholder)?;
+        }
+        placeholders.next_if(|placeholder| placeholder.path == file.path);
+        merged.push(file)?;
+    }
+    for placeholder in placeholders {
+        merged.push(placeholder)?;
+    }
 
-        fs::remove_dir_all("./tmp/fs/read_local_files").await?;
+    merged.finish()
+}
 
-        Ok(())
+/// Reads the files present in an alias, see [scan_path]
+async fn scan_alias(
+    root_path: &Path,
+    alias: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> crate::Result<SortedList<FileInfo>> {
+    if !config.is_local_storage(alias) {
+        return scan_storage(root_path, alias, config, cancel).await;
+    }
+    if !config.extra_roots(alias).is_empty() {
+        return scan_roots(root_path, alias, config, cancel).await;
+    }
+    if !config.uses_scan_index(alias) {
+        return scan_local(root_path, alias, config, cancel, &ScanPlan::Untracked, None).await;
     }
 
-    #[test]
-    fn calc_hash() {
-        let file = FileInfo {
-            alias: "a".to_owned(),
-            created_at: Some(0),
-            modified_at: Some(0),
-            path: Path::new("./some_file_path").to_owned(),
-            size: Some(100),
-            deleted_at: None,
-        };
+    let hints = config.scan_hints();
+    let _scan = hints.lock(alias).await;
+    let plan = hints.begin(alias);
+    let index = match plan {
+        ScanPlan::Incremental { .. } => ScanIndex::new(root_path).read().await,
+        _ => None,
+    };
 
-        let files = vec![file];
-        assert_eq!(calculate_hash(&files), 1762848629165523426);
+    let files = match scan_local(root_path, alias, config, cancel, &plan, index).await {
+        Ok(files) => files,
+        Err(err) => {
+            // the changes taken by the plan are lost with the scan
+            hints.invalidate(alias);
+            return Err(err);
+        }
+    };
+    if plan == ScanPlan::Untracked {
+        return Ok(files);
     }
 
-    #[test]
-    fn test_is_special_file() {
-        assert!(!is_special_file(Path::new("some_file.txt")));
-        assert!(is_special_file(Path::new("some_file.ironcarrier")));
-        assert!(is_special_file(Path::new(".ironcarrier")));
+    let present: Vec<FileInfo> = files
+        .to_vec()?
+        .into_iter()
+        .filter(|file| file.deleted_at.is_none())
+        .collect();
+    match ScanIndex::new(root_path).write(&present).await {
+        Ok(_) => {
+            if let ScanPlan::Complete { invalidations } = plan {
+                hints.indexed(alias, invalidations);
+            }
+        }
+        Err(err) => {
+            log::error!("cannot write scan index of alias {}: {}", alias, err);
+            hints.invalidate(alias);
+        }
     }
 
-    #[test]
-    fn is_local_file_newer() {
-        std::fs::create_dir_all("./tmp/fs").unwrap();
+    Ok(files)
+}
 
-        let path = PathBuf::from("./tmp/fs/mtime");
-        std::fs::File::create(&path).unwrap();
-        filetime::set_file_mtime(
-            &path,
-            filetime::FileTime::from_system_time(SystemTime::UNIX_EPOCH),
+/// Scans every folder of an alias with [Config::extra_roots], see [scan_path]
+///
+/// The lists are merged in memory, a path present in more than one folder is taken from the first of them  
+/// Deletions are tracked in the folder of [Config::paths], a file present in any folder is not deleted
+async fn scan_roots(
+    root_path: &Path,
+    alias: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> crate::Result<SortedList<FileInfo>> {
+    let mut files = scan_local(root_path, alias, config, cancel, &ScanPlan::Untracked, None)
+        .await?
+        .to_vec()?;
+    for root in config.extra_roots(alias) {
+        let root_files = scan_local(
+            &root.path,
+            alias,
+            config,
+            cancel,
+            &ScanPlan::Untracked,
+            None,
         )
-        .unwrap();
+        .await?;
+        files.extend(
+            root_files
+                .to_vec()?
+                .into_iter()
+                .filter(|file| file.deleted_at.is_none()),
+        );
+    }
 
-        let file = FileInfo {
-            alias: "a".to_string(),
-            modified_at: system_time_to_secs(SystemTime::now()),
+    // the sort is stable, so the folders keep their order for the same path
+    files.sort_by(|a, b| {
+        a.path
+            .cmp(&b.path)
+            .then(a.deleted_at.is_some().cmp(&b.deleted_at.is_some()))
+    });
+    files.dedup_by(|a, b| a.path == b.path);
+
+    let mut merged = Spool::new(config, |a: &FileInfo, b: &FileInfo| a.cmp(b));
+    for file in files {
+        merged.push(file)?;
+    }
+    merged.finish()
+}
+
+/// Scans an alias in the local file system, see [scan_path]
+///
+/// With an `index`, only the folders read again by `plan` are read, the other files are taken from the index
+async fn scan_local(
+    root_path: &Path,
+    alias: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+    plan: &ScanPlan,
+    index: Option<Vec<FileInfo>>,
+) -> crate::Result<SortedList<FileInfo>> {
+    let deletion_tracker = DeletionTracker::new(root_path);
+    let root_path = long_path(root_path);
+    let root_path = root_path.as_path();
+    let mut skipped = SkippedFiles::new();
+    let preserve_hard_links = config.preserve_hard_links.contains(alias);
+    let preserve_attributes = config.preserve_file_attributes.contains(alias);
+    let preserve_permissions = config.preserve_permissions.contains(alias);
+    let mut hard_links: HashMap<(u64, u64), Vec<FileInfo>> = HashMap::new();
+    let options = ScanOptions {
+        device: if config.one_file_system.contains(alias) {
+            root_path
+                .metadata()
+                .ok()
+                .and_then(|metadata| device_id(&metadata))
+        } else {
+            None
+        },
+        special_files: config.special_files,
+        follow_symlinks: config.follow_symlinks.contains(alias),
+        ignore_hidden: config.ignore_hidden.contains(alias),
+        reparse_points: config.reparse_points,
+    };
+    // each folder carries the identity of its parents when links are followed, so links to a parent are detected
+    let root_ancestors: Vec<DirId> = if options.follows_links() {
+        dir_id(root_path).into_iter().collect()
+    } else {
+        Vec::new()
+    };
+    let mut files = Spool::new(config, |a: &FileInfo, b: &FileInfo| a.cmp(b));
+    let mut file_count = 0;
+    // folders are read with their subfolders, unless only their own files changed
+    let mut paths = match (index, plan) {
+        (Some(index), ScanPlan::Incremental { folders, trees }) => {
+            for file in index.into_iter().filter(|file| !plan.affects(&file.path)) {
+                file_count += 1;
+                files.push(file)?;
+            }
+
+            let folders = folders.iter().map(|folder| (folder, false));
+            let trees = trees.iter().map(|tree| (tree, true));
+            folders
+                .chain(trees)
+                .map(|(path, recursive)| (root_path.join(path), Vec::new(), recursive))
+                .filter(|(path, _, _)| path.is_dir())
+                .collect()
+        }
+        _ => vec![(root_path.to_owned(), root_ancestors, true)],
+    };
+
+    let deleted_files = deletion_tracker.get_files().await?;
+    for (path, deleted_at) in deleted_files.iter() {
+        files.push(FileInfo::new_deleted(
+            alias.to_owned(),
+            path.clone(),
+            Some(*deleted_at),
+        ))?;
+    }
+    // archived files are kept on disk after being deleted, they are only listed as deleted
+    let is_archive = config.is_archive(alias);
+    let max_depth = config.max_depth(alias);
+
+    let throttle = config.scan_throttle;
+    let mut reading = tokio::task::JoinSet::new();
+    loop {
+        while reading.len() < config.scan_workers {
+            match paths.pop() {
+                Some((dir_path, ancestors, recursive)) => {
+                    reading.spawn_blocking(move || {
+                        let (entries, busy) = read_dir_throttled(&dir_path, options, throttle);
+                        (dir_path, ancestors, recursive, entries, busy)
+                    });
+                }
+                None => break,
+            }
+        }
+
+        if cancel.is_cancelled() {
+            log::info!("scan of alias {} was cancelled", alias);
+            return Err(IronCarrierError::Cancelled.into());
+        }
+
+        let (dir_path, ancestors, recursive, entries, busy) = match reading.join_next().await {
+            Some(result) => result?,
+            None => break,
+        };
+        if let Some(pause) = throttle.pause_after(busy) {
+            tokio::time::sleep(pause).await;
+        }
+
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(err) if dir_path != root_path => {
+                skipped.add(&dir_path, err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        entries.check_reparse_point()?;
+
+        for dir in entries.dirs.into_iter().filter(|_| recursive) {
+            if let Some(dir_ancestors) = descend(root_path, &dir, &ancestors, options, max_depth)? {
+                paths.push((dir, dir_ancestors, true));
+            }
+        }
+        for (path, err) in entries.skipped {
+            skipped.add(&path, err);
+        }
+        for (path, metadata) in entries.files {
+            let relative_path = path.strip_prefix(root_path)?;
+            if !config.is_included(alias, relative_path) {
+                continue;
+            }
+            if let Err(err) = config.check_file_size(alias, metadata.len()) {
+                skipped.add(&path, err);
+                continue;
+            }
+            if is_archive && deleted_files.contains_key(relative_path) {
+                continue;
+            }
+
+            file_count += 1;
+            let link_id = if preserve_hard_links {
+                hard_link_id(&metadata)
+            } else {
+                None
+            };
+            let attributes = windows_attributes(&metadata);
+            let permissions = preserve_permissions.then(|| metadata.clone());
+            let mut file = FileInfo::new(alias.to_owned(), relative_path.to_owned(), metadata);
+            if preserve_attributes {
+                file.keep_attributes(attributes);
+            }
+            if let Some(metadata) = permissions {
+                file.keep_permissions(&path, &metadata);
+            }
+
+            match link_id {
+                Some(link_id) => hard_links.entry(link_id).or_default().push(file),
+                None => files.push(file)?,
+            }
+        }
+    }
+
+    // the first path of each group keeps the content, the others link to it
+    for mut group in hard_links.into_values() {
+        group.sort();
+        let target = group[0].path.clone();
+        for (index, mut file) in group.into_iter().enumerate() {
+            if index > 0 {
+                file.kind = FileKind::HardLink {
+                    target: target.clone(),
+                };
+            }
+            files.push(file)?;
+        }
+    }
+
+    skipped.log_summary(&format!("scanning alias {}", alias));
+    warn_file_count(alias, file_count, config);
+
+    files.finish()
+}
+
+/// Returns the ancestors of `dir` when the scan descends into it, [None] when it is deeper than
+/// [crate::config::AliasLimits::max_depth] or links to one of its `ancestors`
+fn descend(
+    root_path: &Path,
+    dir: &Path,
+    ancestors: &[DirId],
+    options: ScanOptions,
+    max_depth: Option<usize>,
+) -> crate::Result<Option<Vec<DirId>>> {
+    // files inside `dir` are one level deeper than the folder itself
+    let depth = dir.strip_prefix(root_path)?.components().count();
+    if max_depth.is_some_and(|max_depth| depth >= max_depth) {
+        return Ok(None);
+    }
+
+    if !options.follows_links() {
+        return Ok(Some(Vec::new()));
+    }
+
+    match dir_id(dir) {
+        Some(id) if ancestors.contains(&id) => {
+            log::info!("not following {:?}, it links to one of its parents", dir);
+            Ok(None)
+        }
+        Some(id) => {
+            let mut dir_ancestors = ancestors.to_vec();
+            dir_ancestors.push(id);
+            Ok(Some(dir_ancestors))
+        }
+        None => Ok(Some(ancestors.to_vec())),
+    }
+}
+
+/// Logs a warning when `alias` has more than [crate::config::AliasLimits::max_files]
+fn warn_file_count(alias: &str, file_count: u64, config: &Config) {
+    if let Some(max_files) = config
+        .limits
+        .get(alias)
+        .and_then(|limits| limits.max_files)
+        .filter(|max_files| file_count > *max_files)
+    {
+        log::warn!(
+            "alias {} has {} files, more than the limit of {}",
+            alias,
+            file_count,
+            max_files
+        );
+    }
+}
+
+/// Returns the size, in bytes, of the files inside `path`, links are not followed
+pub(crate) fn disk_usage(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+    }
+
+    Ok(size)
+}
+
+/// Returns a sorted list with the files of an alias kept in a custom [crate::storage::Storage]
+///
+/// Folders are read one at a time, there are no special files, hard links or deletion log in these storages  
+/// folders that can't be read are skipped and reported at the end of the scan, as in [scan_path]
+async fn scan_storage(
+    root_path: &Path,
+    alias: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> crate::Result<SortedList<FileInfo>> {
+    let storage = config.storage(alias);
+    let mut skipped = SkippedFiles::new();
+    let mut files = Spool::new(config, |a: &FileInfo, b: &FileInfo| a.cmp(b));
+    let mut paths = vec![root_path.to_owned()];
+    let mut file_count = 0;
+    let max_depth = config.max_depth(alias);
+
+    while let Some(dir_path) = paths.pop() {
+        if cancel.is_cancelled() {
+            log::info!("scan of alias {} was cancelled", alias);
+            return Err(IronCarrierError::Cancelled.into());
+        }
+
+        let entries = match storage.read_dir(&dir_path).await {
+            Ok(entries) => entries,
+            Err(err) if dir_path != root_path => {
+                skipped.add(&dir_path, err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        for (path, metadata) in entries {
+            if is_special_file(&path) || config.ignores_hidden(alias, path.strip_prefix(root_path)?)
+            {
+                continue;
+            }
+
+            if metadata.is_dir {
+                let depth = path.strip_prefix(root_path)?.components().count();
+                if max_depth.is_none_or(|max_depth| depth < max_depth) {
+                    paths.push(path);
+                }
+            } else if !config.is_included(alias, path.strip_prefix(root_path)?) {
+                continue;
+            } else if let Err(err) = config.check_file_size(alias, metadata.len) {
+                skipped.add(&path, err);
+            } else {
+                file_count += 1;
+                files.push(FileInfo::from_storage(
+                    alias.to_owned(),
+                    path.strip_prefix(root_path)?.to_owned(),
+                    &metadata,
+                ))?;
+            }
+        }
+    }
+
+    skipped.log_summary(&format!("scanning alias {}", alias));
+    warn_file_count(alias, file_count, config);
+
+    files.finish()
+}
+
+/// Entry of a folder read by [FileStream], waiting for its turn
+enum StreamEntry {
+    Dir(PathBuf, Vec<DirId>),
+    File(FileInfo),
+}
+
+impl StreamEntry {
+    fn path(&self) -> &Path {
+        match self {
+            StreamEntry::Dir(path, _) => path,
+            StreamEntry::File(file) => &file.path,
+        }
+    }
+}
+
+/// Files of an alias in the local file system, read in path order as they are requested, see [Config::streaming_scan]
+///
+/// Lists the same files as [scan_path], but folders are read one at a time, when the previous files were taken,
+/// so the files of the first folders can be compared and transferred while the rest of the alias is not read yet
+pub(crate) struct FileStream<'a> {
+    root_path: PathBuf,
+    alias: &'a str,
+    config: &'a Config,
+    cancel: &'a CancellationToken,
+    options: ScanOptions,
+    /// Entries of the folders being read, each one sorted from the last to the first path
+    pending: Vec<Vec<StreamEntry>>,
+    /// Files deleted from the alias, sorted from the last to the first path
+    deleted: Vec<FileInfo>,
+    /// Files kept on disk after being deleted, only for archives, see [Config::archive_mode]
+    archived: HashSet<PathBuf>,
+    /// First path of each group of hard linked files, the others link to it
+    hard_links: HashMap<(u64, u64), PathBuf>,
+    skipped: SkippedFiles,
+    file_count: u64,
+    finished: bool,
+}
+
+impl<'a> FileStream<'a> {
+    pub async fn new(
+        root_path: &Path,
+        alias: &'a str,
+        config: &'a Config,
+        cancel: &'a CancellationToken,
+    ) -> crate::Result<FileStream<'a>> {
+        let root_path = long_path(root_path);
+        let options = ScanOptions {
+            device: if config.one_file_system.contains(alias) {
+                root_path
+                    .metadata()
+                    .ok()
+                    .and_then(|metadata| device_id(&metadata))
+            } else {
+                None
+            },
+            special_files: config.special_files,
+            follow_symlinks: config.follow_symlinks.contains(alias),
+            ignore_hidden: config.ignore_hidden.contains(alias),
+            reparse_points: config.reparse_points,
+        };
+        let root_ancestors: Vec<DirId> = if options.follows_links() {
+            dir_id(&root_path).into_iter().collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut deleted: Vec<FileInfo> = DeletionTracker::new(&root_path)
+            .get_files()
+            .await?
+            .into_iter()
+            .map(|(path, deleted_at)| {
+                FileInfo::new_deleted(alias.to_owned(), path, Some(deleted_at))
+            })
+            .collect();
+        deleted.sort_by(|a, b| b.cmp(a));
+        let archived = if config.is_archive(alias) {
+            deleted.iter().map(|file| file.path.clone()).collect()
+        } else {
+            HashSet::new()
+        };
+
+        let mut stream = FileStream {
+            root_path: root_path.clone(),
+            alias,
+            config,
+            cancel,
+            options,
+            pending: Vec::new(),
+            deleted,
+            archived,
+            hard_links: HashMap::new(),
+            skipped: SkippedFiles::new(),
+            file_count: 0,
+            finished: false,
+        };
+        stream.read_dir(root_path, root_ancestors).await?;
+
+        Ok(stream)
+    }
+
+    /// Reads the folder at `dir_path`, its entries are returned before the rest of the folders being read
+    async fn read_dir(&mut self, dir_path: PathBuf, ancestors: Vec<DirId>) -> crate::Result<()> {
+        let options = self.options;
+        let throttle = self.config.scan_throttle;
+        let (dir_path, entries, busy) = tokio::task::spawn_blocking(move || {
+            let (entries, busy) = read_dir_throttled(&dir_path, options, throttle);
+            (dir_path, entries, busy)
+        })
+        .await?;
+        if let Some(pause) = throttle.pause_after(busy) {
+            tokio::time::sleep(pause).await;
+        }
+
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(err) if dir_path != self.root_path => {
+                self.skipped.add(&dir_path, err);
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        entries.check_reparse_point()?;
+
+        let max_depth = self.config.max_depth(self.alias);
+        let preserve_hard_links = self.config.preserve_hard_links.contains(self.alias);
+        let preserve_attributes = self.config.preserve_file_attributes.contains(self.alias);
+        let preserve_permissions = self.config.preserve_permissions.contains(self.alias);
+        let mut dir_entries = Vec::new();
+        for dir in entries.dirs {
+            if let Some(dir_ancestors) =
+                descend(&self.root_path, &dir, &ancestors, options, max_depth)?
+            {
+                dir_entries.push(StreamEntry::Dir(dir, dir_ancestors));
+            }
+        }
+        for (path, err) in entries.skipped {
+            self.skipped.add(&path, err);
+        }
+        for (path, metadata) in entries.files {
+            let relative_path = path.strip_prefix(&self.root_path)?;
+            if !self.config.is_included(self.alias, relative_path) {
+                continue;
+            }
+            if let Err(err) = self.config.check_file_size(self.alias, metadata.len()) {
+                self.skipped.add(&path, err);
+                continue;
+            }
+            if self.archived.contains(relative_path) {
+                continue;
+            }
+
+            self.file_count += 1;
+            let link_id = if preserve_hard_links {
+                hard_link_id(&metadata)
+            } else {
+                None
+            };
+            let attributes = windows_attributes(&metadata);
+            let permissions = preserve_permissions.then(|| metadata.clone());
+            let mut file = FileInfo::new(self.alias.to_owned(), relative_path.to_owned(), metadata);
+            if preserve_attributes {
+                file.keep_attributes(attributes);
+            }
+            if let Some(metadata) = permissions {
+                file.keep_permissions(&path, &metadata);
+            }
+
+            // folders are read in path order, so the first path of a group is the first one found
+            if let Some(link_id) = link_id {
+                match self.hard_links.get(&link_id) {
+                    Some(target) => {
+                        file.kind = FileKind::HardLink {
+                            target: target.clone(),
+                        }
+                    }
+                    None => {
+                        self.hard_links.insert(link_id, file.path.clone());
+                    }
+                }
+            }
+            dir_entries.push(StreamEntry::File(file));
+        }
+
+        // every entry has the same parent, so their names give the order of their paths
+        dir_entries.sort_by(|a, b| b.path().file_name().cmp(&a.path().file_name()));
+        self.pending.push(dir_entries);
+
+        Ok(())
+    }
+
+    /// Returns the next file of the alias, in path order, reading the next folders when needed
+    pub async fn next_entry(&mut self) -> crate::Result<Option<FileInfo>> {
+        loop {
+            if self.cancel.is_cancelled() {
+                log::info!("scan of alias {} was cancelled", self.alias);
+                return Err(IronCarrierError::Cancelled.into());
+            }
+
+            let entry = match self.pending.last_mut() {
+                Some(entries) => entries.pop(),
+                None => {
+                    if !self.finished {
+                        self.finished = true;
+                        self.skipped
+                            .log_summary(&format!("scanning alias {}", self.alias));
+                        warn_file_count(self.alias, self.file_count, self.config);
+                    }
+                    return Ok(self.deleted.pop());
+                }
+            };
+
+            match entry {
+                None => {
+                    self.pending.pop();
+                }
+                Some(StreamEntry::Dir(dir_path, ancestors)) => {
+                    self.read_dir(dir_path, ancestors).await?
+                }
+                Some(StreamEntry::File(file)) => {
+                    // deleted files go in their place in the list
+                    if self.deleted.last().is_some_and(|deleted| *deleted < file) {
+                        if let Some(entries) = self.pending.last_mut() {
+                            entries.push(StreamEntry::File(file));
+                        }
+                        return Ok(self.deleted.pop());
+                    }
+
+                    return Ok(Some(file));
+                }
+            }
+        }
+    }
+}
+
+/// This function returns the result of [walk_path] along with the hash for the file list
+pub async fn get_files_with_hash(
+    path: &Path,
+    alias: &str,
+    config: &Config,
+) -> crate::Result<(u64, Vec<FileInfo>)> {
+    let files = walk_path(path, alias, config).await?;
+    let hash = crate::crypto::calculate_hash(&files);
+
+    log::debug!(
+        "found {} files for alias {} with hash {}",
+        files.len(),
+        alias,
+        hash
+    );
+
+    Ok((hash, files))
+}
+
+/// This function returns the result of [scan_path] along with the hash for the file list  
+/// The hash is the same returned by [get_files_with_hash]
+pub(crate) async fn get_file_list_with_hash(
+    path: &Path,
+    alias: &str,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> crate::Result<(u64, SortedList<FileInfo>)> {
+    let files = scan_path(path, alias, config, cancel).await?;
+    let hash = files.hash()?;
+
+    log::debug!(
+        "found {} files for alias {} with hash {}",
+        files.len(),
+        alias,
+        hash
+    );
+
+    Ok((hash, files))
+}
+
+/// This function will return a [HashMap] containing the alias as key and the hash as value
+pub async fn get_hash_for_alias(config: &Config) -> crate::Result<HashMap<String, u64>> {
+    let mut result = HashMap::new();
+
+    for (alias, path) in &config.paths {
+        let (hash, _) =
+            get_file_list_with_hash(path.as_path(), alias, config, &CancellationToken::new())
+                .await?;
+        result.insert(alias.to_string(), hash);
+    }
+
+    Ok(result)
+}
+
+/// Stores the current content of the file at `path` in the version store, if one is configured
+async fn keep_previous_version(
+    file_info: &FileInfo,
+    path: &Path,
+    config: &Config,
+    versions: &VersionStores,
+) -> crate::Result<()> {
+    if !config.is_local_storage(&file_info.alias) || !path.is_file() {
+        return Ok(());
+    }
+
+    log::debug!("keeping previous version of {:?}", file_info.path);
+    versions
+        .add_version(config, &file_info.alias, &file_info.path, path)
+        .await
+}
+
+/// Files larger than this are never merged, the newest one replaces the other
+const MERGE_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Returns true if `file_info` can be merged, see [Config::merge_text_files]
+fn is_mergeable(file_info: &FileInfo, config: &Config) -> bool {
+    config.merge_text_files.contains(&file_info.alias)
+        && config.is_local_storage(&file_info.alias)
+        && file_info.kind == FileKind::Regular
+        && file_info.content_size() <= MERGE_MAX_SIZE
+}
+
+/// Records the local content of `file_info` as its merge base, after the file is sent to or received from a peer
+///
+/// Failures are only logged, the file is merged with an older base, or replaced, the next time
+pub async fn keep_merge_base(file_info: &FileInfo, config: &Config, versions: &VersionStores) {
+    if !is_mergeable(file_info, config) {
+        return;
+    }
+
+    let result = async {
+        let path = file_info.get_absolute_path(config)?;
+        log::debug!("keeping merge base of {:?}", file_info.path);
+        versions
+            .set_base(
+                config,
+                &file_info.alias,
+                &file_info.path,
+                &path,
+                file_info.modified_at,
+            )
+            .await
+    }
+    .await;
+
+    if let Err(err) = result {
+        log::warn!("cannot keep merge base of {:?}: {}", file_info.path, err);
+    }
+}
+
+/// Merges the local file with the content received in the temp file of `file_info`, see [Config::merge_text_files]
+///
+/// Returns the [FileInfo] of the merged content, written to the temp file, or [None] if the files are not merged because
+/// there is no merge base, only one of the peers changed the file since then, or the files are not text
+pub async fn merge_temp_file(
+    file_info: &FileInfo,
+    config: &Config,
+    versions: &VersionStores,
+) -> crate::Result<Option<FileInfo>> {
+    if !is_mergeable(file_info, config) {
+        return Ok(None);
+    }
+
+    let path = file_info.get_absolute_path(config)?;
+    let metadata = match path.metadata() {
+        Ok(metadata) if metadata.is_file() && metadata.len() <= MERGE_MAX_SIZE => metadata,
+        _ => return Ok(None),
+    };
+    let base = match versions
+        .base(config, &file_info.alias, &file_info.path)
+        .await?
+    {
+        Some(base) => base,
+        None => return Ok(None),
+    };
+
+    let local_changed = metadata.len() != base.size
+        || system_time_to_secs(metadata.modified()?) != base.modified_at;
+    if !local_changed || file_info.modified_at == base.modified_at {
+        return Ok(None);
+    }
+
+    let base_content = versions.read(config, &base).await?;
+
+    let temp_path = get_temp_path(file_info, config)?;
+    let merged = match config
+        .merge_drivers
+        .iter()
+        .find(|driver| Pattern::new(&driver.pattern).matches(&file_info.path))
+    {
+        Some(driver) => {
+            match run_merge_driver(&driver.command, &base_content, &path, &temp_path).await {
+                Ok(merged) => {
+                    log::info!(
+                        "{:?} changed in both peers, merged by {}",
+                        file_info.path,
+                        driver.command
+                    );
+                    merged
+                }
+                Err(err) => {
+                    let copy = conflict_copy_path(&path);
+                    log::warn!(
+                        "merge driver failed for {:?}, keeping the local file as {:?}: {}",
+                        file_info.path,
+                        copy,
+                        err
+                    );
+                    fs::rename(&path, &copy).await?;
+                    return Ok(None);
+                }
+            }
+        }
+        None => {
+            let contents = (
+                String::from_utf8(base_content),
+                String::from_utf8(fs::read(&path).await?),
+                String::from_utf8(fs::read(&temp_path).await?),
+            );
+            let (base_content, local, remote) = match contents {
+                (Ok(base_content), Ok(local), Ok(remote)) => (base_content, local, remote),
+                _ => return Ok(None),
+            };
+
+            let merged = merge::merge(&base_content, &local, &remote);
+            if merged.conflicts > 0 {
+                log::warn!(
+                    "{:?} changed in both peers, merged with {} conflicts",
+                    file_info.path,
+                    merged.conflicts
+                );
+            } else {
+                log::info!("{:?} changed in both peers, merged", file_info.path);
+            }
+            merged.content.into_bytes()
+        }
+    };
+
+    // the merged content includes the received one, so it becomes the base for the next merge
+    versions
+        .set_base(
+            config,
+            &file_info.alias,
+            &file_info.path,
+            &temp_path,
+            file_info.modified_at,
+        )
+        .await?;
+    fs::write(&temp_path, &merged).await?;
+
+    Ok(Some(FileInfo {
+        modified_at: system_time_to_secs(SystemTime::now()),
+        size: Some(merged.len() as u64),
+        ..file_info.clone()
+    }))
+}
+
+/// Runs the merge driver `command` for the local file at `path` and the received content at `remote_path`
+///
+/// The base and local contents are copied to temp files, the command must write the merged content to the local copy  
+/// Returns the merged content, the command fails when it exits with an error
+async fn run_merge_driver(
+    command: &str,
+    base_content: &[u8],
+    path: &Path,
+    remote_path: &Path,
+) -> crate::Result<Vec<u8>> {
+    let scratch_path = |name: &str| {
+        let mut scratch = path.file_name().unwrap_or_default().to_owned();
+        scratch.push(format!(".{}.ironcarrier", name));
+        path.with_file_name(scratch)
+    };
+    let (base_path, local_path) = (scratch_path("base"), scratch_path("local"));
+
+    let result = async {
+        fs::write(&base_path, base_content).await?;
+        copy_file(path, &local_path).await?;
+
+        let status = crate::sync::hooks::shell(command)
+            .current_dir(path.parent().unwrap_or(path))
+            .env("IRON_CARRIER_BASE", &base_path)
+            .env("IRON_CARRIER_LOCAL", &local_path)
+            .env("IRON_CARRIER_REMOTE", remote_path)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(IronCarrierError::HookFailed(format!(
+                "merge driver exited with {}",
+                status
+            ))
+            .into());
+        }
+
+        crate::Result::Ok(fs::read(&local_path).await?)
+    }
+    .await;
+
+    fs::remove_file(&base_path).await.ok();
+    fs::remove_file(&local_path).await.ok();
+    result
+}
+
+/// Removes the file or folder for `file_info`  
+/// The file content is kept as a previous version when the block store is configured, folders are removed without versioning  
+/// Archives keep files and folders on disk, every file deleted is only recorded in the [DeletionTracker]
+pub async fn delete_file(
+    file_info: &FileInfo,
+    config: &Config,
+    versions: &VersionStores,
+) -> crate::Result<()> {
+    let path = file_info.get_absolute_path(config)?;
+    let storage = config.storage(&file_info.alias);
+    match storage.metadata(&path).await {
+        Err(_) => {
+            log::debug!("delete_file: given path doesn't exist ({:?})", path);
+            if config.on_demand.contains(&file_info.alias) {
+                Placeholders::new(&config.paths[&file_info.alias])
+                    .remove(&file_info.path)
+                    .await?;
+            }
+            return Ok(());
+        }
+        Ok(metadata) if config.is_archive(&file_info.alias) => {
+            log::debug!("delete_file: keeping archived {:?}", path);
+            let archived = match metadata.is_dir {
+                true => files_under(&path, &file_info.path).await?,
+                false => vec![file_info.path.clone()],
+            };
+            let deletion_tracker = DeletionTracker::new(&config.paths[&file_info.alias]);
+            for archived_path in archived {
+                deletion_tracker.add_entry(&archived_path).await?;
+            }
+            return Ok(());
+        }
+        Ok(metadata) if metadata.is_dir => {
+            log::debug!("delete_file: {:?} is dir, removing whole dir", path);
+            storage.remove_dir_all(&path).await?
+        }
+        Ok(_) => {
+            keep_previous_version(file_info, &path, config, versions).await?;
+            if config.preserve_file_attributes.contains(&file_info.alias) {
+                make_writable(&path)?;
+            }
+            log::debug!("delete_file: removing file {:?}", path);
+            storage.remove_file(&path).await?
+        }
+    }
+
+    log::debug!("{:?} removed", path);
+
+    Ok(())
+}
+
+/// Returns the paths of the files in the folder at `path`, and its subfolders, relative to the alias root  
+/// `relative_path` is the path of the folder relative to the alias root, special files are left out
+async fn files_under(path: &Path, relative_path: &Path) -> crate::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut folders = vec![(path.to_owned(), relative_path.to_owned())];
+
+    while let Some((folder, relative_folder)) = folders.pop() {
+        let mut entries = fs::read_dir(&folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let relative_path = relative_folder.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                folders.push((entry.path(), relative_path));
+            } else if !is_special_file(&relative_path) {
+                files.push(relative_path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+pub async fn move_file<'b>(
+    src_file: &'b FileInfo,
+    dest_file: &'b FileInfo,
+    config: &Config,
+) -> crate::Result<()> {
+    let src_path = src_file.get_absolute_path(config)?;
+    // moved files stay in the folder of the source, see [Config::extra_roots]
+    let dest_path = match config.root_of(&src_file.alias, &src_file.path) {
+        Some(root) => dest_file.absolute_path_in(root, config)?,
+        None => dest_file.get_absolute_path(config)?,
+    };
+
+    log::debug!("moving file {:?} to {:?}", src_path, dest_path);
+    if config.on_demand.contains(&src_file.alias)
+        && Placeholders::new(&config.paths[&src_file.alias])
+            .rename(src_file, dest_file)
+            .await?
+    {
+        return Ok(());
+    }
+
+    config
+        .storage(&src_file.alias)
+        .rename(&src_path, &dest_path)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the temp file path for `final_path`, the full name is kept, so files that only differ by extension don't share a temp file
+fn temp_path_for(final_path: &Path) -> PathBuf {
+    let mut name = final_path.file_name().unwrap_or_default().to_owned();
+    name.push(".ironcarrier");
+    final_path.with_file_name(name)
+}
+
+/// Returns the path of the temp file used to receive the file described by `file_info`
+pub fn get_temp_path(file_info: &FileInfo, config: &Config) -> crate::Result<PathBuf> {
+    Ok(temp_path_for(&file_info.get_absolute_path(config)?))
+}
+
+/// Creates the temp file of `file_info`, along with its missing folders
+pub async fn get_temp_file(
+    file_info: &FileInfo,
+    config: &Config,
+) -> crate::Result<Box<dyn StorageFile>> {
+    let temp_path = get_temp_path(file_info, config)?;
+
+    log::debug!("creating temp file {:?}", temp_path);
+    Ok(config.storage(&file_info.alias).create(&temp_path).await?)
+}
+
+/// Opens the existing temp file of `file_info` for writing, keeping its content  
+/// Used when parts of the file are received separately
+pub async fn open_temp_file(
+    file_info: &FileInfo,
+    config: &Config,
+) -> crate::Result<Box<dyn StorageFile>> {
+    let temp_path = get_temp_path(file_info, config)?;
+    Ok(config
+        .storage(&file_info.alias)
+        .open_write(&temp_path)
+        .await?)
+}
+
+/// Returns the hash of the content of the temp file of `file_info`, as a hex string
+pub async fn hash_temp_file(
+    file_info: &FileInfo,
+    config: &Config,
+    algorithm: HashAlgorithm,
+) -> crate::Result<String> {
+    let temp_path = get_temp_path(file_info, config)?;
+    let content = config.storage(&file_info.alias).open(&temp_path).await?;
+    crate::manifest::hash_content(content, algorithm).await
+}
+
+/// Reserves `size` bytes on disk for `file`, so a full disk is noticed before any content is written  
+/// The file length is not changed, file systems without support for preallocation are ignored
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if size == 0 {
+        return Ok(());
+    }
+
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            size as libc::off_t,
+        )
+    };
+
+    if result == 0 {
+        return Ok(());
+    }
+
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+/// Reserves `size` bytes on disk for `file`, so a full disk is noticed before any content is written  
+/// The file length is not changed
+#[cfg(windows)]
+pub fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAllocationInfo, SetFileInformationByHandle, FILE_ALLOCATION_INFO,
+    };
+
+    let info = FILE_ALLOCATION_INFO {
+        AllocationSize: size as i64,
+    };
+    let result = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FileAllocationInfo,
+            &info as *const FILE_ALLOCATION_INFO as *const std::ffi::c_void,
+            std::mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+        )
+    };
+
+    if result == 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Preallocation is not supported on this platform, space is taken as the content is written
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn preallocate(_file: &File, _size: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Copies the content of `src` to `dest`, replacing `dest` if it exists  
+/// File systems with copy on write, like Btrfs, XFS and APFS, share the content of both files until one of them changes,
+/// so the copy takes no space. Otherwise the content is copied, with `copy_file_range` where available
+pub async fn copy_file(src: &Path, dest: &Path) -> crate::Result<()> {
+    let (src, dest) = (src.to_owned(), dest.to_owned());
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = reflink(&src, &dest) {
+            log::trace!("cannot reflink {:?}, copying it: {}", src, err);
+            std::fs::copy(&src, &dest)?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Makes `dest` share the content of `src`, fails if the file system doesn't support it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn reflink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src_file = std::fs::File::open(src)?;
+    let dest_file = std::fs::File::create(dest)?;
+    let result = unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    dest_file.set_permissions(src_file.metadata()?.permissions())
+}
+
+/// Makes `dest` share the content of `src`, fails if the file system doesn't support it
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let src_path = std::ffi::CString::new(src.as_os_str().as_bytes())?;
+    let dest_path = std::ffi::CString::new(dest.as_os_str().as_bytes())?;
+    // clonefile only creates new files
+    match std::fs::remove_file(dest) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    if unsafe { libc::clonefile(src_path.as_ptr(), dest_path.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Copy on write is not supported on this platform, files are always copied
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn reflink(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Key of [FileInfo::extra] with the Windows attributes of the file, see [Config::preserve_file_attributes]  
+/// The value is a single byte, with the same bits of the Windows attributes: 1 is read only, 2 is hidden and 4 is system
+const WINDOWS_ATTRIBUTES: &str = "windows_attributes";
+/// Attributes kept in [WINDOWS_ATTRIBUTES]
+#[cfg(windows)]
+const SYNCED_ATTRIBUTES: u32 = 0b111;
+/// Read only attribute, the only one applied outside of Windows
+const READONLY_ATTRIBUTE: u8 = 1;
+
+/// Returns the read only, hidden and system attributes of a file
+#[cfg(windows)]
+fn windows_attributes(metadata: &std::fs::Metadata) -> u8 {
+    use std::os::windows::fs::MetadataExt;
+    (metadata.file_attributes() & SYNCED_ATTRIBUTES) as u8
+}
+
+/// Windows attributes are only read in Windows
+#[cfg(not(windows))]
+fn windows_attributes(_metadata: &std::fs::Metadata) -> u8 {
+    0
+}
+
+/// Sets the read only, hidden and system `attributes` of the file at `path`, keeping its other attributes
+#[cfg(windows)]
+fn set_attributes(path: &Path, attributes: u8) -> std::io::Result<()> {
+    use std::os::windows::{ffi::OsStrExt, fs::MetadataExt};
+    use windows_sys::Win32::Storage::FileSystem::SetFileAttributesW;
+
+    let current = path.metadata()?.file_attributes();
+    let attributes = (current & !SYNCED_ATTRIBUTES) | u32::from(attributes);
+    let wide_path: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+
+    if unsafe { SetFileAttributesW(wide_path.as_ptr(), attributes) } == 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Applies the read only attribute by removing the write permissions, unix systems have no hidden or system attributes
+#[cfg(not(windows))]
+fn set_attributes(path: &Path, attributes: u8) -> std::io::Result<()> {
+    if attributes & READONLY_ATTRIBUTE == 0 {
+        return Ok(());
+    }
+
+    let mut permissions = path.metadata()?.permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// Removes the read only attribute of the file at `path`, Windows doesn't replace or delete read only files
+#[cfg(windows)]
+fn make_writable(path: &Path) -> std::io::Result<()> {
+    let attributes = windows_attributes(&path.metadata()?);
+    if attributes & READONLY_ATTRIBUTE == 0 {
+        return Ok(());
+    }
+
+    set_attributes(path, attributes & !READONLY_ATTRIBUTE)
+}
+
+/// Read only files can be replaced and deleted outside of Windows
+#[cfg(not(windows))]
+fn make_writable(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Key of [FileInfo::extra] with the permission bits of the file, see [Config::preserve_permissions]  
+/// The value is the mode of the file, in 4 little endian bytes
+const UNIX_MODE: &str = "unix_mode";
+/// Key of [FileInfo::extra] with the access ACL of the file, as stored in its `system.posix_acl_access` attribute
+const POSIX_ACL: &str = "posix_acl_access";
+/// Name of the extended attribute with the access ACL, as a C string
+#[cfg(target_os = "linux")]
+const ACL_ATTRIBUTE: &[u8] = b"system.posix_acl_access\0";
+
+/// Returns the permission bits of a file, without its type
+#[cfg(unix)]
+fn unix_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Permission bits only exist in unix systems
+#[cfg(not(unix))]
+fn unix_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Returns the access ACL of the file at `path`, [None] if the file only has its permission bits
+#[cfg(target_os = "linux")]
+fn read_acl(path: &Path) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let name = ACL_ATTRIBUTE.as_ptr().cast::<libc::c_char>();
+    let size = unsafe { libc::getxattr(c_path.as_ptr(), name, std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return None;
+    }
+
+    let mut acl = vec![0u8; size as usize];
+    let size = unsafe { libc::getxattr(c_path.as_ptr(), name, acl.as_mut_ptr().cast(), acl.len()) };
+    if size < 0 {
+        log::debug!(
+            "cannot read ACL of {:?}: {}",
+            path,
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+
+    acl.truncate(size as usize);
+    Some(acl)
+}
+
+/// ACLs are only read in Linux
+#[cfg(not(target_os = "linux"))]
+fn read_acl(_path: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+/// Sets the access ACL of the file at `path`
+#[cfg(target_os = "linux")]
+fn write_acl(path: &Path, acl: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let name = ACL_ATTRIBUTE.as_ptr().cast::<libc::c_char>();
+    let result =
+        unsafe { libc::setxattr(c_path.as_ptr(), name, acl.as_ptr().cast(), acl.len(), 0) };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// ACLs are only applied in Linux, other systems keep the permission bits
+#[cfg(not(target_os = "linux"))]
+fn write_acl(_path: &Path, _acl: &[u8]) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Sets the permission bits of the file at `path`
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+/// Permission bits are ignored outside of unix systems
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Applies the permissions kept by [FileInfo::keep_permissions] to the file at `path`, the ACL is set after the
+/// permission bits, since it also changes the group bits
+fn apply_permissions(path: &Path, file_info: &FileInfo) -> std::io::Result<()> {
+    if let Some(mode) = file_info.mode() {
+        set_mode(path, mode)?;
+    }
+    if let Some(acl) = file_info.extra.get(POSIX_ACL) {
+        write_acl(path, acl)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the creation time of the file at `path`, in seconds since the unix epoch
+#[cfg(target_os = "macos")]
+pub fn set_creation_time(path: &Path, created_at: u64) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut attributes = libc::attrlist {
+        bitmapcount: libc::ATTR_BIT_MAP_COUNT,
+        reserved: 0,
+        commonattr: libc::ATTR_CMN_CRTIME,
+        volattr: 0,
+        dirattr: 0,
+        fileattr: 0,
+        forkattr: 0,
+    };
+    let mut time = libc::timespec {
+        tv_sec: created_at as libc::time_t,
+        tv_nsec: 0,
+    };
+
+    let result = unsafe {
+        libc::setattrlist(
+            path.as_ptr(),
+            &mut attributes as *mut libc::attrlist as *mut libc::c_void,
+            &mut time as *mut libc::timespec as *mut libc::c_void,
+            std::mem::size_of::<libc::timespec>(),
+            0,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Sets the creation time of the file at `path`, in seconds since the unix epoch
+#[cfg(windows)]
+pub fn set_creation_time(path: &Path, created_at: u64) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::{Foundation::FILETIME, Storage::FileSystem::SetFileTime};
+
+    // FILETIME counts 100 nanoseconds intervals since 1601-01-01
+    let intervals = (created_at + 11_644_473_600) * 10_000_000;
+    let time = FILETIME {
+        dwLowDateTime: intervals as u32,
+        dwHighDateTime: (intervals >> 32) as u32,
+    };
+
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let result = unsafe {
+        SetFileTime(
+            file.as_raw_handle(),
+            &time,
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+
+    if result == 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// The creation time can't be set on this platform, files keep the time they were written
+#[cfg(not(any(target_os = "macos", windows)))]
+pub fn set_creation_time(_path: &Path, _created_at: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Returns the space available to this process in the file system of `path`, in bytes
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::statvfs(path.as_ptr(), &mut stat) };
+
+    if result == 0 {
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Returns the space available to this process in the file system of `path`, in bytes
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut available = 0u64;
+    let result = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+
+    if result == 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(available)
+    }
+}
+
+/// The available space can't be read on this platform
+#[cfg(not(any(unix, windows)))]
+pub fn available_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Removes the temp file of `file_info`, used when a received file is discarded
+pub async fn remove_temp_file(file_info: &FileInfo, config: &Config) -> crate::Result<()> {
+    let temp_path = temp_path_for(&file_info.get_absolute_path(config)?);
+
+    log::debug!("removing temp file {:?}", temp_path);
+    config
+        .storage(&file_info.alias)
+        .remove_file(&temp_path)
+        .await?;
+
+    Ok(())
+}
+
+/// Replaces the file described by `file_info` with its temp file
+///
+/// The modification time is set before the rename, so the final file never shows up with a wrong timestamp,
+/// the creation time is also set for aliases in [Config::preserve_creation_time]  
+/// The permissions of aliases in [Config::preserve_permissions] are also set before the rename  
+/// The attributes of aliases in [Config::preserve_file_attributes] are set after the rename, read only files are replaced  
+/// The content being replaced is kept as a previous version when the block store is configured  
+/// When [Config::enable_fsync] is true, the temp file is flushed to disk before the rename and the parent folder right after it,
+/// this way a power loss can't leave an empty file in place of the original one  
+/// Aliases in custom storages only get the modification time before the rename
+pub async fn flush_temp_file(
+    file_info: &FileInfo,
+    config: &Config,
+    versions: &VersionStores,
+) -> crate::Result<()> {
+    let final_path = file_info.get_absolute_path(config)?;
+    let temp_path = temp_path_for(&final_path);
+
+    if !config.is_local_storage(&file_info.alias) {
+        let storage = config.storage(&file_info.alias);
+        storage
+            .set_modified(&temp_path, file_info.modified_at.unwrap())
+            .await?;
+        storage.rename(&temp_path, &final_path).await?;
+        return Ok(());
+    }
+
+    let replaced = match &file_info.kind {
+        FileKind::Regular => Ok(()),
+        FileKind::Fifo => {
+            log::debug!("creating FIFO {:?}", temp_path);
+            replace_with_fifo(&temp_path)
+        }
+        FileKind::HardLink { target } => {
+            log::debug!("linking {:?} to {:?}", temp_path, target);
+            let target = FileInfo {
+                path: target.clone(),
+                ..file_info.clone()
+            }
+            .get_absolute_path(config)?;
+            replace_with_hard_link(&temp_path, &target)
+        }
+    };
+    if let Err(err) = replaced {
+        fs::remove_file(&temp_path).await.ok();
+        return Err(err.into());
+    }
+
+    // the handle is opened before the permissions are applied, a read only mode would make it fail otherwise.
+    // Opening a FIFO would wait for a reader, and the content of hard links is flushed with their target
+    let sync_handle = if config.enable_fsync && file_info.kind == FileKind::Regular {
+        Some(fs::OpenOptions::new().write(true).open(&temp_path).await?)
+    } else {
+        None
+    };
+
+    log::debug!("setting file modification time");
+    let mod_time = SystemTime::UNIX_EPOCH + Duration::from_secs(file_info.modified_at.unwrap());
+    filetime::set_file_mtime(&temp_path, filetime::FileTime::from_system_time(mod_time))?;
+
+    if let Some(created_at) = file_info.created_at {
+        if config.preserve_creation_time.contains(&file_info.alias) {
+            log::debug!("setting file creation time");
+            if let Err(err) = set_creation_time(&temp_path, created_at) {
+                log::warn!("failed to set creation time of {:?}: {}", final_path, err);
+            }
+        }
+    }
+
+    if config.preserve_permissions.contains(&file_info.alias) {
+        log::debug!("setting file permissions");
+        if let Err(err) = apply_permissions(&temp_path, file_info) {
+            log::warn!("failed to set permissions of {:?}: {}", final_path, err);
+        }
+    }
+
+    if let Some(handle) = sync_handle {
+        log::debug!("syncing temp file {:?} to disk", temp_path);
+        handle.sync_all().await?;
+    }
+
+    keep_previous_version(file_info, &final_path, config, versions).await?;
+
+    let preserve_attributes = config.preserve_file_attributes.contains(&file_info.alias);
+    if preserve_attributes && final_path.exists() {
+        make_writable(&final_path)?;
+    }
+
+    log::debug!("moving temp file to {:?}", final_path);
+    replace_locked_file(file_info, &temp_path, &final_path, config).await?;
+
+    if preserve_attributes && file_info.attributes() != 0 {
+        log::debug!("setting file attributes");
+        if let Err(err) = set_attributes(&final_path, file_info.attributes()) {
+            log::warn!("failed to set attributes of {:?}: {}", final_path, err);
+        }
+    }
+
+    // the received file replaces the archived one, it isn't deleted anymore
+    if config.is_archive(&file_info.alias) {
+        DeletionTracker::new(&config.paths[&file_info.alias])
+            .remove_entry(&file_info.path)
+            .await?;
+    }
+
+    if config.enable_fsync {
+        sync_parent_dir(&final_path).await?;
+    }
+
+    Ok(())
+}
+
+/// Wait before the first retry to replace a locked file, see [Config::locked_file_retries]
+const LOCKED_FILE_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Returns true if `err` was caused by another process holding the file open
+#[cfg(windows)]
+fn is_locked(err: &std::io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION and ERROR_ACCESS_DENIED, a file opened without sharing delete
+    // can't be replaced and reports access denied
+    matches!(err.raw_os_error(), Some(32) | Some(33) | Some(5))
+}
+
+/// Returns true if `err` was caused by another process holding the file open
+#[cfg(not(windows))]
+fn is_locked(_err: &std::io::Error) -> bool {
+    false
+}
+
+/// Moves `temp_path` over `final_path`, retrying with backoff while the local file is locked by another process
+///
+/// A file still locked after [Config::locked_file_retries] is scheduled to be replaced on the next start, when
+/// [Config::replace_locked_files_on_start] is enabled, otherwise its temp file is removed
+async fn replace_locked_file(
+    file_info: &FileInfo,
+    temp_path: &Path,
+    final_path: &Path,
+    config: &Config,
+) -> crate::Result<()> {
+    let mut delay = LOCKED_FILE_RETRY_DELAY;
+    for retry in 0..=config.locked_file_retries {
+        match tokio::fs::rename(temp_path, final_path).await {
+            Ok(_) => return Ok(()),
+            Err(err) if !is_locked(&err) => return Err(err.into()),
+            Err(_) if retry < config.locked_file_retries => {
+                log::warn!(
+                    "{:?} is locked by another process, retrying in {:?}",
+                    final_path,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(_) => {}
+        }
+    }
+
+    if config.replace_locked_files_on_start {
+        log::warn!(
+            "{:?} is still locked, it will be replaced on the next start",
+            final_path
+        );
+        LockedFiles::new(&config.paths[&file_info.alias])
+            .add(file_info)
+            .await?;
+    } else {
+        log::warn!("{:?} is still locked, discarding received file", final_path);
+        fs::remove_file(temp_path).await.ok();
+    }
+
+    Err(IronCarrierError::FileLocked(config.replace_locked_files_on_start).into())
+}
+
+/// Flushes the folder containing `path` to disk, making a rename inside it durable
+#[cfg(unix)]
+async fn sync_parent_dir(path: &Path) -> crate::Result<()> {
+    if let Some(parent) = path.parent() {
+        log::debug!("syncing folder {:?} to disk", parent);
+        File::open(parent).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
+
+/// Folders can't be opened as files on this platform, renames are flushed by the file system
+#[cfg(not(unix))]
+async fn sync_parent_dir(_path: &Path) -> crate::Result<()> {
+    Ok(())
+}
+
+/// Returns the name in `names` that is the same as `name` ignoring case, unless `name` itself is present
+fn colliding_name(name: &str, names: impl Iterator<Item = String>) -> Option<String> {
+    let normalized = normalize_name(name);
+    let folded = normalized.to_lowercase();
+
+    let mut collision = None;
+    for entry in names {
+        let entry_normalized = normalize_name(&entry);
+        if entry_normalized == normalized {
+            return None;
+        }
+        if entry_normalized.to_lowercase() == folded {
+            collision = Some(entry);
+        }
+    }
+
+    collision
+}
+
+/// Returns the local file that `path` would overwrite because the file system ignores case, like `Readme.md` for `README.md`
+///
+/// In case sensitive file systems, `path` doesn't exist when only a name in a different case does, so there is no collision
+pub fn find_case_collision(path: &Path) -> Option<PathBuf> {
+    if !path.exists() {
+        return None;
+    }
+
+    let name = path.file_name()?.to_str()?;
+    let parent = path.parent()?;
+    let names = std::fs::read_dir(parent)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned());
+
+    colliding_name(name, names).map(|name| parent.join(name))
+}
+
+/// Returns `path` with ` (suffix)` added to the file name, before the extension
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, suffix),
+    };
+
+    path.with_file_name(name)
+}
+
+/// Returns `path` with ` (case conflict)` added to the file name, before the extension
+pub fn case_conflict_path(path: &Path) -> PathBuf {
+    path_with_suffix(path, "case conflict")
+}
+
+/// Returns a path that doesn't exist yet for a conflict copy of `path`, like `notes (conflict).txt`
+fn conflict_copy_path(path: &Path) -> PathBuf {
+    let mut copy = path_with_suffix(path, "conflict");
+    let mut counter = 2;
+    while copy.exists() {
+        copy = path_with_suffix(path, &format!("conflict {}", counter));
+        counter += 1;
+    }
+
+    copy
+}
+
+/// Names reserved by Windows, with or without extension
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Returns why `name` can't be used as a file or folder name on Windows, [None] if it can
+fn windows_name_issue(name: &str) -> Option<&'static str> {
+    let base_name = name.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base_name))
+    {
+        Some("the name is reserved")
+    } else if name.ends_with('.') || name.ends_with(' ') {
+        Some("the name ends with a dot or space")
+    } else if name
+        .chars()
+        .any(|c| c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+    {
+        Some("the name contains characters that are not allowed")
+    } else {
+        None
+    }
+}
+
+/// Returns an error if the relative `path`, received from a peer, can't be written in this file system
+///
+/// Only Windows restricts names that are valid elsewhere, like reserved names (CON, NUL, ...) and names ending with a dot or space,
+/// files with those names are reported and left out of the synchronization, instead of failing it
+pub fn check_representable(path: &Path) -> Result<(), IronCarrierError> {
+    if !cfg!(windows) {
+        return Ok(());
+    }
+
+    for component in path.components() {
+        let name = component.as_os_str().to_string_lossy();
+        if let Some(issue) = windows_name_issue(&name) {
+            return Err(IronCarrierError::UnrepresentablePath(format!(
+                "{}: {}",
+                name, issue
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns `path` with the `\\?\` prefix, so paths longer than MAX_PATH can be read and written on Windows
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    use std::{ffi::OsString, path::Component, path::Prefix};
+
+    let path = match std::path::absolute(path) {
+        Ok(path) => path,
+        Err(_) => return path.to_owned(),
+    };
+
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => {
+                let mut prefixed = OsString::from(r"\\?\");
+                prefixed.push(path.as_os_str());
+                PathBuf::from(prefixed)
+            }
+            Prefix::UNC(server, share) => {
+                let mut prefixed = OsString::from(r"\\?\UNC\");
+                prefixed.push(server);
+                prefixed.push(r"\");
+                prefixed.push(share);
+
+                let mut prefixed = PathBuf::from(prefixed);
+                prefixed.extend(components.filter(|c| matches!(c, Component::Normal(_))));
+                prefixed
+            }
+            // verbatim and device paths are used as they are
+            _ => path,
+        },
+        _ => path,
+    }
+}
+
+/// Paths have no length limit to work around on this platform
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+/// Returns true if `path`, relative to the alias root, is hidden or is inside a hidden folder, their names start with a dot
+pub fn is_hidden(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+/// Returns true if the name of `path` starts with a dot or, in Windows, if it has the hidden attribute
+fn is_hidden_entry(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        || has_hidden_attribute(path)
+}
+
+/// Returns true if `path` has the hidden attribute
+#[cfg(windows)]
+pub(crate) fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    std::fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        .unwrap_or_default()
+}
+
+/// Files have no hidden attribute in this platform
+#[cfg(not(windows))]
+pub(crate) fn has_hidden_attribute(_path: &Path) -> bool {
+    false
+}
+
+/// Returns true if `path` name or extension are .ironcarrier
+pub fn is_special_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.ends_with("ironcarrier"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::calculate_hash;
+
+    #[tokio::test]
+    async fn can_read_local_files() -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all("./tmp/fs/read_local_files").await?;
+        File::create("./tmp/fs/read_local_files/file_1").await?;
+        File::create("./tmp/fs/read_local_files/file_2").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/read_local_files\""
+                .to_string(),
+        )
+        .unwrap();
+        let files = walk_path(&PathBuf::from("./tmp/fs/read_local_files"), "a", &config)
+            .await
+            .unwrap();
+
+        assert_eq!(files[0].path.to_str(), Some("file_1"));
+        assert_eq!(files[1].path.to_str(), Some("file_2"));
+
+        fs::remove_dir_all("./tmp/fs/read_local_files").await?;
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn junctions_follow_their_policy() -> crate::Result<()> {
+        let root = Path::new("./tmp/fs/junctions");
+        std::fs::create_dir_all(root.join("target"))?;
+        std::fs::write(root.join("target/file"), b"content")?;
+        let status = std::process::Command::new("cmd")
+            .args(["/C", "mklink", "/J"])
+            .arg(root.join("junction"))
+            .arg(root.join("target"))
+            .status()?;
+        assert!(status.success());
+
+        let read = |reparse_points| {
+            read_dir_entries(
+                root,
+                ScanOptions {
+                    reparse_points,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(
+            read(ReparsePointPolicy::Skip)?.dirs,
+            vec![root.join("target")]
+        );
+        assert_eq!(read(ReparsePointPolicy::Follow)?.dirs.len(), 2);
+        assert!(read(ReparsePointPolicy::Error)?
+            .check_reparse_point()
+            .is_err());
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_file_replaces_the_destination() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/copy_file").await?;
+        fs::write("./tmp/fs/copy_file/source", b"content").await?;
+        fs::write("./tmp/fs/copy_file/dest", b"previous content").await?;
+
+        copy_file(
+            Path::new("./tmp/fs/copy_file/source"),
+            Path::new("./tmp/fs/copy_file/dest"),
+        )
+        .await?;
+        assert_eq!(fs::read("./tmp/fs/copy_file/dest").await?, b"content");
+        assert_eq!(fs::read("./tmp/fs/copy_file/source").await?, b"content");
+
+        fs::remove_dir_all("./tmp/fs/copy_file").await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn walk_path_skips_unreadable_files() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/skip_unreadable_files").await?;
+        File::create("./tmp/fs/skip_unreadable_files/file_1").await?;
+        std::os::unix::fs::symlink(
+            "./does_not_exist",
+            "./tmp/fs/skip_unreadable_files/broken_link",
+        )?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/skip_unreadable_files\""
+                .to_string(),
+        )?;
+        let files = walk_path(
+            &PathBuf::from("./tmp/fs/skip_unreadable_files"),
+            "a",
+            &config,
+        )
+        .await?;
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.to_str(), Some("file_1"));
+
+        fs::remove_dir_all("./tmp/fs/skip_unreadable_files").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_path_merges_extra_roots() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/extra_roots/main").await?;
+        fs::create_dir_all("./tmp/fs/extra_roots/drive_2/2019").await?;
+        fs::write("./tmp/fs/extra_roots/main/file_1", b"main").await?;
+        fs::write("./tmp/fs/extra_roots/drive_2/file_1", b"drive 2").await?;
+        fs::write("./tmp/fs/extra_roots/drive_2/2019/file_2", b"drive 2").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/extra_roots/main\"
+
+        [[extra_roots.a]]
+        path = \"./tmp/fs/extra_roots/drive_2\"
+        route = [ \"2020/**\" ]"
+                .to_string(),
+        )?;
+        let files = walk_path(&PathBuf::from("./tmp/fs/extra_roots/main"), "a", &config).await?;
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| (file.path.to_str().unwrap(), file.size))
+                .collect::<Vec<_>>(),
+            vec![("2019/file_2", Some(7)), ("file_1", Some(4))]
+        );
+
+        let drive_2 = PathBuf::from("./tmp/fs/extra_roots/drive_2");
+        assert_eq!(
+            config.root_of("a", Path::new("2019/file_2")),
+            Some(&drive_2)
+        );
+        assert_eq!(config.root_of("a", Path::new("2020/new")), Some(&drive_2));
+        assert_eq!(
+            config.root_of("a", Path::new("2021/new")),
+            Some(&PathBuf::from("./tmp/fs/extra_roots/main"))
+        );
+
+        fs::remove_dir_all("./tmp/fs/extra_roots").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_path_skips_files_over_the_limit() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/file_limits").await?;
+        fs::write("./tmp/fs/file_limits/small", b"small").await?;
+        fs::write("./tmp/fs/file_limits/large", b"larger than the limit").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/file_limits\"
+
+        [limits.a]
+        max_file_size = 10"
+                .to_string(),
+        )?;
+        let files = walk_path(&PathBuf::from("./tmp/fs/file_limits"), "a", &config).await?;
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.to_str(), Some("small"));
+        assert!(config.check_file_size("a", 11).is_err());
+
+        fs::remove_dir_all("./tmp/fs/file_limits").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_path_skips_hidden_files() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/hidden/.git").await?;
+        fs::write("./tmp/fs/hidden/.git/config", b"config").await?;
+        fs::write("./tmp/fs/hidden/.bashrc", b"bashrc").await?;
+        fs::write("./tmp/fs/hidden/notes", b"notes").await?;
+
+        let config = Config::parse_content(
+            "ignore_hidden = [ \"a\" ]
+        [paths]
+        a = \"./tmp/fs/hidden\""
+                .to_string(),
+        )?;
+        let files = walk_path(&PathBuf::from("./tmp/fs/hidden"), "a", &config).await?;
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path.to_str(), Some("notes"));
+        assert!(config.ignores_hidden("a", Path::new(".git/config")));
+        assert!(!config.ignores_hidden("a", Path::new("notes")));
+
+        fs::remove_dir_all("./tmp/fs/hidden").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_path_stops_at_max_depth() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/max_depth/a/b").await?;
+        fs::write("./tmp/fs/max_depth/root", b"root").await?;
+        fs::write("./tmp/fs/max_depth/a/first", b"first").await?;
+        fs::write("./tmp/fs/max_depth/a/b/second", b"second").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/max_depth\"
+
+        [limits.a]
+        max_depth = 2"
+                .to_string(),
+        )?;
+        let files = walk_path(&PathBuf::from("./tmp/fs/max_depth"), "a", &config).await?;
+
+        let paths: Vec<&Path> = files.iter().map(|file| file.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("a/first"), Path::new("root")]);
+        assert!(config.exceeds_max_depth("a", Path::new("a/b/second")));
+
+        fs::remove_dir_all("./tmp/fs/max_depth").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_path_lists_only_included_files() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/include_only/2024").await?;
+        fs::write("./tmp/fs/include_only/2024/photo.raw", b"raw").await?;
+        fs::write("./tmp/fs/include_only/2024/photo.xmp", b"xmp").await?;
+        fs::write("./tmp/fs/include_only/2024/photo.jpg", b"jpg").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/include_only\"
+
+        [include_only]
+        a = [ \"*.raw\", \"*.xmp\" ]"
+                .to_string(),
+        )?;
+        let files = walk_path(&PathBuf::from("./tmp/fs/include_only"), "a", &config).await?;
+
+        let paths: Vec<&Path> = files.iter().map(|file| file.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![Path::new("2024/photo.raw"), Path::new("2024/photo.xmp")]
+        );
+        assert!(!config.is_included("a", Path::new("2024/photo.jpg")));
+        assert!(config.is_included("b", Path::new("2024/photo.jpg")));
+
+        fs::remove_dir_all("./tmp/fs/include_only").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_stream_lists_files_in_path_order() -> crate::Result<()> {
+        for folder in ["a/b", "a.d", "b/c/d"] {
+            fs::create_dir_all(format!("./tmp/fs/file_stream/{}", folder)).await?;
+        }
+        for file in [
+            "a.txt",
+            "a/z",
+            "a/b/file",
+            "a.d/file",
+            "b/c/d/file",
+            "b/c.txt",
+            "0",
+        ] {
+            fs::write(format!("./tmp/fs/file_stream/{}", file), file).await?;
+        }
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/file_stream\""
+                .to_string(),
+        )?;
+        let cancel = CancellationToken::new();
+        let root_path = PathBuf::from("./tmp/fs/file_stream");
+        let mut stream = FileStream::new(&root_path, "a", &config, &cancel).await?;
+        let mut streamed = Vec::new();
+        while let Some(file) = stream.next_entry().await? {
+            streamed.push(file.path);
+        }
+
+        let scanned: Vec<PathBuf> = scan_path(&root_path, "a", &config, &cancel)
+            .await?
+            .to_vec()?
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(streamed.len(), 7);
+        assert_eq!(streamed, scanned);
+
+        fs::remove_dir_all("./tmp/fs/file_stream").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archives_keep_deleted_files() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/archive").await?;
+        fs::write("./tmp/fs/archive/file", b"content").await?;
+
+        let config = Config::parse_content(
+            "archive_mode = true
+        block_store_path = \"./tmp/fs/archive_store\"
+        [paths]
+        a = \"./tmp/fs/archive\""
+                .to_string(),
+        )?;
+        let file = FileInfo::new_deleted("a".to_string(), "file".into(), None);
+        delete_file(&file, &config, &VersionStores::new()).await?;
+
+        assert!(Path::new("./tmp/fs/archive/file").exists());
+        let files = scan_path(
+            Path::new("./tmp/fs/archive"),
+            "a",
+            &config,
+            &CancellationToken::new(),
+        )
+        .await?
+        .to_vec()?;
+        assert_eq!(files.len(), 1);
+        assert!(files[0].deleted_at.is_some());
+
+        fs::remove_dir_all("./tmp/fs/archive").await?;
+        fs::remove_dir_all("./tmp/fs/archive_store").await.ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archives_keep_deleted_folders() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/archive_folder/folder/nested").await?;
+        fs::write("./tmp/fs/archive_folder/folder/file", b"content").await?;
+        fs::write("./tmp/fs/archive_folder/folder/nested/file", b"content").await?;
+
+        let config = Config::parse_content(
+            "archive_mode = true
+        block_store_path = \"./tmp/fs/archive_folder_store\"
+        [paths]
+        a = \"./tmp/fs/archive_folder\""
+                .to_string(),
+        )?;
+        let folder = FileInfo::new_deleted("a".to_string(), "folder".into(), None);
+        delete_file(&folder, &config, &VersionStores::new()).await?;
+
+        assert!(Path::new("./tmp/fs/archive_folder/folder/file").exists());
+        assert!(Path::new("./tmp/fs/archive_folder/folder/nested/file").exists());
+        let deleted = DeletionTracker::new(Path::new("./tmp/fs/archive_folder"))
+            .get_files()
+            .await?;
+        assert!(deleted.contains_key(Path::new("folder/file")));
+        assert!(deleted.contains_key(Path::new("folder/nested/file")));
+
+        fs::remove_dir_all("./tmp/fs/archive_folder").await?;
+        fs::remove_dir_all("./tmp/fs/archive_folder_store").await.ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_path_reads_folders_in_parallel() -> crate::Result<()> {
+        for folder in 0..10 {
+            let folder_path = PathBuf::from(format!("./tmp/fs/parallel_walk/{}/nested", folder));
+            fs::create_dir_all(&folder_path).await?;
+            File::create(folder_path.join("file")).await?;
+        }
+
+        let config = Config::parse_content(
+            "
+        scan_workers = 3
+
+        [paths]
+        a = \"./tmp/fs/parallel_walk\""
+                .to_string(),
+        )?;
+        let files = walk_path(&PathBuf::from("./tmp/fs/parallel_walk"), "a", &config).await?;
+
+        let expected: Vec<PathBuf> = (0..10)
+            .map(|folder| PathBuf::from(format!("{}/nested/file", folder)))
+            .collect();
+        let paths: Vec<PathBuf> = files.into_iter().map(|file| file.path).collect();
+        assert_eq!(paths, expected);
+
+        fs::remove_dir_all("./tmp/fs/parallel_walk").await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn calc_hash() {
+        let file = FileInfo {
+            alias: "a".to_owned(),
+            created_at: Some(0),
+            modified_at: Some(0),
+            path: Path::new("./some_file_path").to_owned(),
+            size: Some(100),
+            deleted_at: None,
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        // the value of the hash depends on the toolchain, only equal lists must have equal hashes
+        let files = vec![file.clone()];
+        assert_eq!(calculate_hash(&files), calculate_hash(&vec![file.clone()]));
+
+        let modified = FileInfo {
+            modified_at: Some(1),
+            ..file
+        };
+        assert_ne!(calculate_hash(&files), calculate_hash(&vec![modified]));
+    }
+
+    #[test]
+    fn extra_attributes_are_kept_and_hashed() -> crate::Result<()> {
+        let mut file = FileInfo::new_deleted("a".to_owned(), PathBuf::from("file"), None);
+        let without_extra = calculate_hash(&file);
+
+        file.extra
+            .insert("xattr.user.tag".to_owned(), b"blue".to_vec());
+        file.extra.insert("acl".to_owned(), vec![1, 2, 3]);
+        assert_ne!(calculate_hash(&file), without_extra);
+
+        let received: FileInfo = bincode::deserialize(&bincode::serialize(&file)?)?;
+        assert_eq!(received.extra, file.extra);
+        assert_eq!(calculate_hash(&received), calculate_hash(&file));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_special_file() {
+        assert!(!is_special_file(Path::new("some_file.txt")));
+        assert!(is_special_file(Path::new("some_file.ironcarrier")));
+        assert!(is_special_file(Path::new(".ironcarrier")));
+    }
+
+    #[tokio::test]
+    async fn can_flush_temp_file() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/flush_temp_file").await?;
+        fs::write("./tmp/fs/flush_temp_file/file.ironcarrier", "new content").await?;
+        fs::write("./tmp/fs/flush_temp_file/file", "old content").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/flush_temp_file\""
+                .to_string(),
+        )?;
+        assert!(config.enable_fsync);
+
+        let file = FileInfo {
+            alias: "a".to_string(),
+            modified_at: Some(1000),
+            created_at: None,
+            deleted_at: None,
+            path: PathBuf::from("file"),
+            size: Some(11),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        flush_temp_file(&file, &config, &VersionStores::new()).await?;
+
+        let final_path = Path::new("./tmp/fs/flush_temp_file/file");
+        assert_eq!(fs::read_to_string(final_path).await?, "new content");
+        assert_eq!(
+            final_path.metadata()?.modified()?,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000)
+        );
+        assert!(!Path::new("./tmp/fs/flush_temp_file/file.ironcarrier").exists());
+
+        fs::remove_dir_all("./tmp/fs/flush_temp_file").await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn is_local_file_newer() {
+        std::fs::create_dir_all("./tmp/fs").unwrap();
+
+        let path = PathBuf::from("./tmp/fs/mtime");
+        std::fs::File::create(&path).unwrap();
+        filetime::set_file_mtime(
+            &path,
+            filetime::FileTime::from_system_time(SystemTime::UNIX_EPOCH),
+        )
+        .unwrap();
+
+        let file = FileInfo {
+            alias: "a".to_string(),
+            modified_at: system_time_to_secs(SystemTime::now()),
             created_at: None,
             deleted_at: None,
             path: PathBuf::from("mtime"),
             size: None,
+            kind: FileKind::Regular,
+            extra: Default::default(),
         };
 
         let config = Config::parse_content(
@@ -364,4 +3182,422 @@ mod tests {
 
         assert!(!file.is_local_file_newer(&config));
     }
+
+    #[tokio::test]
+    async fn preallocate_keeps_file_length() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/preallocate").await?;
+        let file = File::create("./tmp/fs/preallocate/file").await?;
+
+        preallocate(&file, 1024 * 1024)?;
+        preallocate(&file, 0)?;
+        assert_eq!(file.metadata().await?.len(), 0);
+
+        fs::remove_dir_all("./tmp/fs/preallocate").await?;
+        Ok(())
+    }
+
+    #[test]
+    fn detects_names_windows_cant_represent() {
+        assert!(windows_name_issue("notes.txt").is_none());
+        assert!(windows_name_issue("console.log").is_none());
+        assert!(windows_name_issue("CON").is_some());
+        assert!(windows_name_issue("nul.txt").is_some());
+        assert!(windows_name_issue("Com1 .tar.gz").is_some());
+        assert!(windows_name_issue("file.").is_some());
+        assert!(windows_name_issue("folder ").is_some());
+        assert!(windows_name_issue("what?.md").is_some());
+
+        if cfg!(windows) {
+            assert!(check_representable(Path::new("docs/aux.md")).is_err());
+        } else {
+            assert!(check_representable(Path::new("docs/aux.md")).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn paths_are_compared_in_nfc() -> crate::Result<()> {
+        // "café" with the accent as a combining character, like macOS stores it
+        let nfd_name = "cafe\u{301}";
+        let nfc_name = "caf\u{e9}";
+
+        fs::create_dir_all("./tmp/fs/nfc").await?;
+        fs::write(Path::new("./tmp/fs/nfc").join(nfd_name), "content").await?;
+
+        let config = Config::parse_content(
+            "
+        [paths]
+        a = \"./tmp/fs/nfc\""
+                .to_string(),
+        )?;
+
+        let files = walk_path(Path::new("./tmp/fs/nfc"), "a", &config).await?;
+        assert_eq!(files[0].path, Path::new(nfc_name));
+        assert_eq!(
+            files[0].get_absolute_path(&config)?.file_name().unwrap(),
+            nfd_name
+        );
+
+        fs::remove_dir_all("./tmp/fs/nfc").await?;
+        Ok(())
+    }
+
+    #[test]
+    fn detects_names_differing_by_case() {
+        let names = || vec!["Readme.md".to_string(), "notes.txt".to_string()].into_iter();
+        assert_eq!(
+            colliding_name("README.md", names()),
+            Some("Readme.md".into())
+        );
+        assert_eq!(colliding_name("Readme.md", names()), None);
+        assert_eq!(colliding_name("other.md", names()), None);
+
+        assert_eq!(
+            case_conflict_path(Path::new("docs/README.md")),
+            Path::new("docs/README (case conflict).md")
+        );
+        assert_eq!(
+            case_conflict_path(Path::new("LICENSE")),
+            Path::new("LICENSE (case conflict)")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folders_in_other_devices_are_left_out() -> crate::Result<()> {
+        let root = Path::new("./tmp/fs/one_file_system");
+        std::fs::create_dir_all(root.join("nested"))?;
+        let device = device_id(&root.metadata()?);
+
+        let entries = read_dir_entries(
+            root,
+            ScanOptions {
+                device,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(entries.dirs, vec![root.join("nested")]);
+
+        let entries = read_dir_entries(
+            root,
+            ScanOptions {
+                device: device.map(|device| device + 1),
+                ..Default::default()
+            },
+        )?;
+        assert!(entries.dirs.is_empty());
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creation_time_is_set_where_supported() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/creation_time").await?;
+        let path = Path::new("./tmp/fs/creation_time/file");
+        fs::write(path, "content").await?;
+
+        set_creation_time(path, 1_000_000_000)?;
+        if cfg!(any(target_os = "macos", windows)) {
+            let created = path.metadata()?.created()?;
+            assert_eq!(system_time_to_secs(created), Some(1_000_000_000));
+        }
+
+        fs::remove_dir_all("./tmp/fs/creation_time").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn windows_attributes_are_applied() -> crate::Result<()> {
+        fs::create_dir_all("./tmp/fs/attributes").await?;
+        let path = Path::new("./tmp/fs/attributes/file");
+        fs::write(path, "content").await?;
+
+        let mut file = FileInfo::new("a".to_owned(), PathBuf::from("file"), path.metadata()?);
+        assert_eq!(file.attributes(), 0);
+        file.keep_attributes(0);
+        assert!(file.extra.is_empty());
+        // read only and hidden
+        file.keep_attributes(3);
+        assert_eq!(file.attributes(), 3);
+
+        set_attributes(path, file.attributes())?;
+        assert!(path.metadata()?.permissions().readonly());
+        if cfg!(windows) {
+            assert_eq!(windows_attributes(&path.metadata()?), 3);
+        }
+
+        make_writable(path)?;
+        fs::remove_dir_all("./tmp/fs/attributes").await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn permissions_are_applied() -> crate::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::create_dir_all("./tmp/fs/permissions").await?;
+        let source = Path::new("./tmp/fs/permissions/source");
+        let received = Path::new("./tmp/fs/permissions/received");
+        fs::write(source, "content").await?;
+        fs::write(received, "content").await?;
+        std::fs::set_permissions(source, std::fs::Permissions::from_mode(0o640))?;
+
+        let mut file = FileInfo::new("a".to_owned(), PathBuf::from("source"), source.metadata()?);
+        file.keep_permissions(source, &source.metadata()?);
+        assert_eq!(file.mode(), Some(0o640));
+        // a file without extended entries has no ACL
+        assert!(!file.extra.contains_key(POSIX_ACL));
+
+        apply_permissions(received, &file)?;
+        assert_eq!(received.metadata()?.permissions().mode() & 0o7777, 0o640);
+
+        fs::remove_dir_all("./tmp/fs/permissions").await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn read_only_files_are_flushed() -> crate::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = Path::new("./tmp/fs/read_only_flush");
+        fs::create_dir_all(root).await?;
+        fs::write(root.join("file.ironcarrier"), "new content").await?;
+
+        let config = Config::parse_content(
+            "preserve_permissions = [\"a\"]
+            [paths]
+            a = \"./tmp/fs/read_only_flush\""
+                .to_string(),
+        )?;
+        assert!(config.enable_fsync);
+
+        let mut file = FileInfo {
+            alias: "a".to_string(),
+            modified_at: Some(1000),
+            created_at: None,
+            deleted_at: None,
+            path: PathBuf::from("file"),
+            size: Some(11),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+        file.extra
+            .insert(UNIX_MODE.to_owned(), 0o444u32.to_le_bytes().to_vec());
+
+        flush_temp_file(&file, &config, &VersionStores::new()).await?;
+
+        let final_path = root.join("file");
+        assert_eq!(fs::read_to_string(&final_path).await?, "new content");
+        assert_eq!(final_path.metadata()?.permissions().mode() & 0o7777, 0o444);
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fifos_are_skipped_or_preserved() -> crate::Result<()> {
+        let root = Path::new("./tmp/fs/special_files");
+        fs::create_dir_all(root).await?;
+        fs::write(root.join("file"), "content").await?;
+        fs::write(root.join("pipe"), "").await?;
+        replace_with_fifo(&root.join("pipe"))?;
+
+        let config = |policy: &str| {
+            Config::parse_content(format!(
+                "special_files = \"{}\"
+                [paths]
+                a = \"./tmp/fs/special_files\"",
+                policy
+            ))
+        };
+
+        let files = scan_path(root, "a", &config("skip")?, &CancellationToken::new())
+            .await?
+            .to_vec()?;
+        assert_eq!(files.len(), 1);
+
+        let preserve = config("preserve_fifos")?;
+        let files = scan_path(root, "a", &preserve, &CancellationToken::new())
+            .await?
+            .to_vec()?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].kind, FileKind::Fifo);
+        assert!(read_content(&files[1], &preserve).await?.is_empty());
+        assert_eq!(walk_path(root, "a", &preserve).await?.len(), 1);
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_links_are_preserved() -> crate::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = Path::new("./tmp/fs/hard_links");
+        fs::create_dir_all(root).await?;
+        fs::write(root.join("a"), "content").await?;
+        fs::hard_link(root.join("a"), root.join("b")).await?;
+
+        let config = Config::parse_content(
+            "preserve_hard_links = [\"a\"]
+            [paths]
+            a = \"./tmp/fs/hard_links\""
+                .to_string(),
+        )?;
+
+        let files = scan_path(root, "a", &config, &CancellationToken::new())
+            .await?
+            .to_vec()?;
+        assert_eq!(files[0].kind, FileKind::Regular);
+        assert_eq!(
+            files[1].kind,
+            FileKind::HardLink {
+                target: PathBuf::from("a")
+            }
+        );
+        assert_eq!(files[1].content_size(), 0);
+        assert_eq!(
+            walk_path(root, "a", &config).await?[1].kind,
+            FileKind::Regular
+        );
+
+        let mut link = files[1].clone();
+        link.path = PathBuf::from("c");
+        get_temp_file(&link, &config).await?;
+        flush_temp_file(&link, &config, &VersionStores::new()).await?;
+        assert_eq!(
+            root.join("c").metadata()?.ino(),
+            root.join("a").metadata()?.ino()
+        );
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_are_followed_without_loops() -> crate::Result<()> {
+        let root = Path::new("./tmp/fs/follow_symlinks");
+        fs::create_dir_all(root.join("real")).await?;
+        fs::write(root.join("real/file"), "content").await?;
+        std::os::unix::fs::symlink("real", root.join("link"))?;
+        std::os::unix::fs::symlink("..", root.join("real/parent"))?;
+
+        let config = |follow: &str| {
+            Config::parse_content(format!(
+                "follow_symlinks = [{}]
+                [paths]
+                a = \"./tmp/fs/follow_symlinks\"",
+                follow
+            ))
+        };
+
+        let paths = |files: Vec<FileInfo>| -> Vec<PathBuf> {
+            files.into_iter().map(|file| file.path).collect()
+        };
+
+        let files = walk_path(root, "a", &config("")?).await?;
+        assert_eq!(paths(files), vec![PathBuf::from("real/file")]);
+
+        let files = walk_path(root, "a", &config("\"a\"")?).await?;
+        assert_eq!(
+            paths(files),
+            vec![PathBuf::from("link/file"), PathBuf::from("real/file")]
+        );
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_stops_when_cancelled() -> crate::Result<()> {
+        let root = Path::new("./tmp/fs/cancelled_scan");
+        fs::create_dir_all(root.join("dir")).await?;
+        fs::write(root.join("dir/file"), "content").await?;
+
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/fs/cancelled_scan\""
+                .to_string(),
+        )?;
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result = scan_path(root, "a", &config, &cancel).await;
+        assert!(matches!(
+            result.err().and_then(|err| err.downcast::<IronCarrierError>().ok()),
+            Some(err) if matches!(*err, IronCarrierError::Cancelled)
+        ));
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn merge_drivers_merge_or_keep_both_files() -> crate::Result<()> {
+        let root = Path::new("./tmp/fs/merge_drivers");
+        fs::create_dir_all(root.join("a")).await?;
+        let config = Config::parse_content(
+            "block_store_path = \"./tmp/fs/merge_drivers/store\"
+            merge_text_files = [\"a\"]
+            [paths]
+            a = \"./tmp/fs/merge_drivers/a\"
+
+            [[merge_drivers]]
+            pattern = \"*.json\"
+            command = \"cat $IRON_CARRIER_REMOTE >> $IRON_CARRIER_LOCAL\"
+
+            [[merge_drivers]]
+            pattern = \"*.yaml\"
+            command = \"exit 1\""
+                .to_string(),
+        )?;
+
+        let versions = VersionStores::new();
+        for name in ["data.json", "data.yaml"].iter() {
+            let path = root.join("a").join(name);
+            let file = |modified_at| FileInfo {
+                alias: "a".to_string(),
+                modified_at: Some(modified_at),
+                created_at: None,
+                deleted_at: None,
+                path: PathBuf::from(name),
+                size: Some(6),
+                kind: FileKind::Regular,
+                extra: Default::default(),
+            };
+
+            // both peers change the file after agreeing on its content
+            fs::write(&path, "base\n").await?;
+            filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(1000, 0))?;
+            keep_merge_base(&file(1000), &config, &versions).await;
+            fs::write(&path, "local\n").await?;
+            filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(2000, 0))?;
+            fs::write(temp_path_for(&path), "remote\n").await?;
+
+            let merged = merge_temp_file(&file(3000), &config, &versions).await?;
+            if *name == "data.json" {
+                assert!(merged.unwrap().modified_at.unwrap() > 3000);
+                assert_eq!(
+                    fs::read_to_string(temp_path_for(&path)).await?,
+                    "local\nremote\n"
+                );
+            } else {
+                assert!(merged.is_none());
+                assert!(!path.exists());
+                assert_eq!(
+                    fs::read_to_string(root.join("a/data (conflict).yaml")).await?,
+                    "local\n"
+                );
+            }
+        }
+
+        fs::remove_dir_all(root).await?;
+        Ok(())
+    }
 }
@@ -4,16 +4,60 @@
 //! Keep your files in sync!
 //!
 //! Synchronize your files in differents machines on the same network
+//!
+//! Applications can embed the synchronization engine with [IronCarrier], built from a [config::Config]
 
 use serde::{Deserialize, Serialize};
 use std::{error::Error, fmt::Display};
 
+mod audit_log;
+mod block_store;
+pub mod bundle;
+mod carrier;
 pub mod config;
+pub mod config_migration;
+#[cfg(feature = "grpc")]
+pub mod control;
 mod crypto;
+mod dedup;
 mod deletion_tracker;
+mod error_reports;
+pub mod events;
 mod fs;
+mod locked_files;
+pub mod manifest;
+mod merge;
+pub mod migration;
+#[cfg(feature = "fuse")]
+pub mod mount;
 mod network;
+pub mod on_demand;
+mod outbox;
+mod pattern;
+mod peer_sync_state;
+mod quarantine;
+pub mod remote_aliases;
+pub mod repair;
+mod scan_index;
+pub mod secrets;
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
+mod skipped_files;
+pub mod snapshot;
+mod spool;
+pub mod storage;
 pub mod sync;
+mod transfer_journal;
+mod version_store;
+
+pub use carrier::{IronCarrier, IronCarrierBuilder};
+pub use fs::{FileInfo, FileKind};
+pub use network::streaming::codec;
+pub use network::transport::{
+    BoxedStream, TcpTransport, Transport, TransportListener, TransportStream,
+};
+pub use outbox::PendingChange;
+pub use quarantine::{FailedAttempt, QuarantinedFile};
 
 /// Result<T, IronCarrierError> alias
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + 'static + Send + Sync>>;
@@ -47,6 +91,51 @@ pub enum IronCarrierError {
     ParseCommandError,
     /// It wasn't possible to parse the log file
     ParseLogError,
+    /// The operation requires a block store, but no block_store_path was configured
+    BlockStoreNotConfigured,
+    /// Snapshot with the provided name doesn't exist
+    SnapshotNotFound(String),
+    /// The remote mirror rejected a request
+    MirrorError(String),
+    /// The path received from a peer can't be used in this file system, like reserved names on Windows
+    UnrepresentablePath(String),
+    /// A file with the same name, in a different case, exists in a case insensitive file system
+    CaseCollision(String),
+    /// The synchronization engine must be started before this operation
+    NotStarted,
+    /// The operation was cancelled, like when the synchronization engine is stopped
+    Cancelled,
+    /// The synchronization engine was already started, a stopped engine can't be started again
+    AlreadyStarted,
+    /// The change was refused by a [events::SyncObserver]
+    Vetoed,
+    /// A pre or post synchronization command failed
+    HookFailed(String),
+    /// The peer was not shared by any introducer
+    UnknownPeer(String),
+    /// The file is larger than the [config::AliasLimits::max_file_size] of its alias
+    FileTooLarge(u64),
+    /// The alias reached its [config::AliasLimits::max_total_size] in the receiving peer
+    QuotaExceeded(String),
+    /// The content received doesn't match the checksum sent by the peer
+    ChecksumMismatch,
+    /// The peer is below its [config::Config::min_free_space], it doesn't take new files or updates
+    PeerLowOnDiskSpace(String),
+    /// The local file is locked by another process, `true` when it is replaced on the next start, see
+    /// [config::Config::replace_locked_files_on_start]
+    FileLocked(bool),
+    /// The peer refused a path sent to it, with the reason
+    PathRejected(String),
+    /// The peer doesn't know the command, it runs an older version
+    UnsupportedCommand(String),
+    /// The operation requires [config::Config::authorized_peers_path]
+    PeerAuthorizationNotConfigured,
+    /// There is no placeholder in the path, see [config::Config::on_demand]
+    PlaceholderNotFound(String),
+    /// The scan found a junction with [config::ReparsePointPolicy::Error]
+    ReparsePointFound(String),
+    /// The disk of the receiving peer doesn't have room for the files of the alias
+    StorageFull(String),
 }
 
 impl Display for IronCarrierError {
@@ -98,15 +187,101 @@ impl Display for IronCarrierError {
             IronCarrierError::ParseLogError => {
                 write!(f, "There was an error parsing the log")
             }
+            IronCarrierError::BlockStoreNotConfigured => {
+                write!(
+                    f,
+                    "Block store is not configured, block_store_path is required"
+                )
+            }
+            IronCarrierError::SnapshotNotFound(name) => {
+                write!(f, "Snapshot {} not found", name)
+            }
+            IronCarrierError::MirrorError(reason) => {
+                write!(
+                    f,
+                    "There was an error communicating with the mirror: {}",
+                    reason
+                )
+            }
+            IronCarrierError::UnrepresentablePath(reason) => {
+                write!(f, "Path can't be used in this file system, {}", reason)
+            }
+            IronCarrierError::Cancelled => {
+                write!(f, "The operation was cancelled")
+            }
+            IronCarrierError::NotStarted => {
+                write!(f, "The synchronization engine is not running")
+            }
+            IronCarrierError::AlreadyStarted => {
+                write!(f, "The synchronization engine was already started")
+            }
+            IronCarrierError::Vetoed => {
+                write!(f, "The change was refused by an observer")
+            }
+            IronCarrierError::HookFailed(reason) => {
+                write!(f, "Synchronization command failed, {}", reason)
+            }
+            IronCarrierError::UnknownPeer(address) => {
+                write!(f, "Peer {} was not introduced by any peer", address)
+            }
+            IronCarrierError::FileTooLarge(limit) => {
+                write!(f, "File is larger than the limit of {} bytes", limit)
+            }
+            IronCarrierError::QuotaExceeded(alias) => {
+                write!(f, "Alias {} is over its quota", alias)
+            }
+            IronCarrierError::ChecksumMismatch => {
+                write!(f, "Received content doesn't match its checksum")
+            }
+            IronCarrierError::PeerLowOnDiskSpace(peer_address) => {
+                write!(f, "Peer {} is low on disk space", peer_address)
+            }
+            IronCarrierError::FileLocked(scheduled) => {
+                write!(f, "File is locked by another process")?;
+                if *scheduled {
+                    write!(f, ", it will be replaced on the next start")?;
+                }
+                Ok(())
+            }
+            IronCarrierError::PathRejected(reason) => {
+                write!(f, "Path was refused by the peer, {}", reason)
+            }
+            IronCarrierError::UnsupportedCommand(command) => {
+                write!(
+                    f,
+                    "Peer doesn't support {}, it may run an older version",
+                    command
+                )
+            }
+            IronCarrierError::PeerAuthorizationNotConfigured => {
+                write!(
+                    f,
+                    "Peer authorization is not configured, authorized_peers_path is required"
+                )
+            }
+            IronCarrierError::PlaceholderNotFound(path) => {
+                write!(f, "There are no placeholders in {}", path)
+            }
+            IronCarrierError::ReparsePointFound(path) => {
+                write!(f, "Found a junction or redirected folder at {}", path)
+            }
+            IronCarrierError::StorageFull(alias) => {
+                write!(f, "Disk doesn't have room for the files of alias {}", alias)
+            }
+            IronCarrierError::CaseCollision(existing) => {
+                write!(
+                    f,
+                    "File collides with {}, the file system ignores case",
+                    existing
+                )
+            }
         }
     }
 }
 
 impl Error for IronCarrierError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match *self {
-            _ => None,
-        }
+        None
     }
 }
 
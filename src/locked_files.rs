@@ -0,0 +1,140 @@
+//! Received files that couldn't replace a local file locked by another process
+//!
+//! When [Config::replace_locked_files_on_start] is enabled, a received file still locked after the retries is kept in its
+//! temp file and recorded here, the local file is replaced by [replace_locked_files] the next time the engine starts
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Config, fs, fs::FileInfo, version_store::VersionStores};
+
+pub(crate) struct LockedFiles {
+    state_path: PathBuf,
+}
+
+impl LockedFiles {
+    pub fn new(alias_root_path: &Path) -> Self {
+        LockedFiles {
+            state_path: alias_root_path.join(".locked.ironcarrier"),
+        }
+    }
+
+    async fn read_state(&self) -> HashMap<PathBuf, FileInfo> {
+        if !self.state_path.exists() {
+            return HashMap::new();
+        }
+
+        match tokio::fs::read(&self.state_path).await {
+            Ok(contents) => bincode::deserialize(&contents).unwrap_or_else(|err| {
+                log::error!("locked files list is invalid, ignoring it: {}", err);
+                HashMap::new()
+            }),
+            Err(err) => {
+                log::error!("cannot read locked files list: {}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    async fn write_state(&self, state: &HashMap<PathBuf, FileInfo>) -> crate::Result<()> {
+        if state.is_empty() {
+            if self.state_path.exists() {
+                tokio::fs::remove_file(&self.state_path).await?;
+            }
+            return Ok(());
+        }
+
+        let contents = bincode::serialize(state)?;
+        tokio::fs::write(&self.state_path, contents).await?;
+
+        Ok(())
+    }
+
+    /// Records that the temp file of `file_info` must replace the local file on the next start
+    pub async fn add(&self, file_info: &FileInfo) -> crate::Result<()> {
+        let mut state = self.read_state().await;
+        state.insert(file_info.path.clone(), file_info.clone());
+
+        self.write_state(&state).await
+    }
+
+    /// Returns the recorded files, forgetting them
+    pub async fn take(&self) -> crate::Result<Vec<FileInfo>> {
+        let state = self.read_state().await;
+        self.write_state(&HashMap::new()).await?;
+
+        Ok(state.into_values().collect())
+    }
+}
+
+/// Replaces the local files with the received files recorded as locked, see [Config::replace_locked_files_on_start]
+///
+/// Files still locked are recorded again, recorded files without a temp file are ignored
+pub(crate) async fn replace_locked_files(
+    config: &Config,
+    versions: &VersionStores,
+) -> crate::Result<()> {
+    for (alias, root_path) in &config.paths {
+        if !config.is_local_storage(alias) {
+            continue;
+        }
+
+        for file_info in LockedFiles::new(root_path).take().await? {
+            if !fs::get_temp_path(&file_info, config)?.exists() {
+                continue;
+            }
+
+            match fs::flush_temp_file(&file_info, config, versions).await {
+                Ok(_) => log::info!("replaced locked file {:?}", file_info.path),
+                Err(err) => log::warn!("cannot replace locked file {:?}: {}", file_info.path, err),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn locked_files_are_replaced_on_start() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/locked_files").await?;
+        let config = Config::parse_content(
+            "port = 8091
+            replace_locked_files_on_start = true
+            [paths]
+            a = \"./tmp/locked_files\""
+                .to_string(),
+        )?;
+
+        let mut file_info = FileInfo::new_deleted("a".into(), "file".into(), None);
+        file_info.deleted_at = None;
+        file_info.modified_at = Some(1);
+        file_info.size = Some(8);
+
+        tokio::fs::write("./tmp/locked_files/file", b"previous").await?;
+        tokio::fs::write(fs::get_temp_path(&file_info, &config)?, b"received").await?;
+
+        let locked_files = LockedFiles::new(Path::new("./tmp/locked_files"));
+        locked_files.add(&file_info).await?;
+        locked_files
+            .add(&FileInfo::new_deleted("a".into(), "missing".into(), None))
+            .await?;
+
+        replace_locked_files(&config, &VersionStores::new()).await?;
+
+        assert_eq!(
+            tokio::fs::read("./tmp/locked_files/file").await?,
+            b"received"
+        );
+        assert!(locked_files.take().await?.is_empty());
+        assert!(!Path::new("./tmp/locked_files/.locked.ironcarrier").exists());
+
+        tokio::fs::remove_dir_all("./tmp/locked_files").await?;
+        Ok(())
+    }
+}
@@ -1,10 +1,13 @@
-use clap::{App, Arg};
-use iron_carrier::config::Config;
-use std::process::exit;
+use clap::{App, Arg, ArgMatches};
+use iron_carrier::{
+    bundle, config::Config, config_migration, manifest::Manifest, migration, on_demand,
+    remote_aliases, repair, snapshot,
+};
+use std::{path::Path, process::exit};
 
 #[tokio::main]
 async fn main() {
-    let matches = App::new("Iron Carrier")
+    let app = App::new("Iron Carrier")
         .version("0.1")
         .author("Ilson Roberto Balliego Junior <ilson.balliego@gmail.com>")
         .about("Synchronize your files")
@@ -21,13 +24,109 @@ async fn main() {
                 .long("auto-exit")
                 .short("e"),
         )
+        .arg(
+            Arg::with_name("snapshot")
+                .help("Records a snapshot of an alias and exits")
+                .long("snapshot")
+                .value_names(&["alias", "name"]),
+        )
+        .arg(
+            Arg::with_name("restore")
+                .help("Restores an alias to a snapshot and exits")
+                .long("restore")
+                .value_names(&["alias", "name"]),
+        )
+        .arg(
+            Arg::with_name("remove-snapshot")
+                .help("Removes a snapshot of an alias and exits")
+                .long("remove-snapshot")
+                .value_names(&["alias", "name"]),
+        )
+        .arg(
+            Arg::with_name("list-snapshots")
+                .help("Lists the snapshots of an alias and exits")
+                .long("list-snapshots")
+                .value_name("alias")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export-manifest")
+                .help("Writes the manifest of an alias to a JSON file and exits")
+                .long("export-manifest")
+                .value_names(&["alias", "file"]),
+        )
+        .arg(
+            Arg::with_name("diff-manifest")
+                .help("Compares an alias with a manifest JSON file and exits")
+                .long("diff-manifest")
+                .value_names(&["alias", "file"]),
+        )
+        .arg(
+            Arg::with_name("export-bundle")
+                .help("Writes the changes pending for a peer to a bundle folder and exits")
+                .long("export-bundle")
+                .value_names(&["peer", "folder"]),
+        )
+        .arg(
+            Arg::with_name("import-bundle")
+                .help("Applies a bundle exported by a peer and exits")
+                .long("import-bundle")
+                .value_names(&["peer", "folder"]),
+        )
+        .arg(
+            Arg::with_name("move-alias")
+                .help("Moves an alias to another folder, along with its state, and exits")
+                .long("move-alias")
+                .value_names(&["alias", "folder"]),
+        )
+        .arg(
+            Arg::with_name("fetch")
+                .help("Downloads the placeholders of an on demand alias inside a path and exits")
+                .long("fetch")
+                .value_names(&["alias", "path"]),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .help("Fetches again from the peers the local files that don't match their content and exits")
+                .long("repair"),
+        )
+        .arg(
+            Arg::with_name("remote-aliases")
+                .help("Lists the aliases shared with a peer, with their files, size and whether they are in sync, and exits")
+                .long("remote-aliases")
+                .value_name("peer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .help("Replaces an option of the config file, like port=9090 or limits.my_docs.max_depth=3")
+                .long("set")
+                .value_name("key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("upgrade-config")
+                .help("Rewrites the configuration file in the current version of the format and exits")
+                .long("upgrade-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
                 .multiple(true)
                 .help("Sets the level of verbosity"),
-        )
-        .get_matches();
+        );
+
+    #[cfg(feature = "fuse")]
+    let app = app.arg(
+        Arg::with_name("mount")
+            .help("Mounts an alias of a peer, read-only, until it is unmounted with fusermount -u")
+            .long("mount")
+            .value_names(&["peer", "alias", "mount_point"]),
+    );
+
+    let matches = app.get_matches();
 
     let config = matches
         .value_of("config")
@@ -35,20 +134,49 @@ async fn main() {
     let verbosity = matches.occurrences_of("v") as usize;
     let auto_exit = matches.is_present("auto-exit");
 
+    // the logger allows every level, the level in use is set by the max level, which the config may change
     stderrlog::new()
         .module(module_path!())
-        .verbosity(verbosity)
+        .verbosity(4)
         .timestamp(stderrlog::Timestamp::Second)
         .init()
         .unwrap();
+    log::set_max_level(level_filter(verbosity));
+
+    if matches.is_present("upgrade-config") {
+        match config_migration::upgrade_file(Path::new(config)).await {
+            Ok(true) => println!("configuration upgraded, the original was kept as a .bak file"),
+            Ok(false) => println!("configuration is already up to date"),
+            Err(e) => {
+                log::error!("{}", e);
+                exit(-1)
+            }
+        }
+        return;
+    }
 
-    let config = match Config::new(config) {
+    let overrides: Vec<String> = matches
+        .values_of("set")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    let config = match Config::with_overrides(config, &overrides) {
         Ok(config) => config,
         Err(e) => {
             log::error!("{}", e);
             exit(-1)
         }
     };
+    if let (0, Some(level)) = (verbosity, config.log_level_filter()) {
+        log::set_max_level(level);
+    }
+
+    if let Some(result) = run_command(&matches, &config).await {
+        if let Err(e) = result {
+            log::error!("{}", e);
+            exit(-1)
+        }
+        return;
+    }
 
     let mut s = iron_carrier::sync::Synchronizer::new(config);
     if let Err(e) = s.start(auto_exit).await {
@@ -56,3 +184,193 @@ async fn main() {
         exit(-1)
     };
 }
+
+/// Returns the log level of the `-v` flags, each flag shows one more level
+fn level_filter(verbosity: usize) -> log::LevelFilter {
+    match verbosity {
+        0 => log::LevelFilter::Error,
+        1 => log::LevelFilter::Warn,
+        2 => log::LevelFilter::Info,
+        3 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Runs the operation requested in the command line, if any  
+/// Returns [None] when no operation was requested and the synchronization should start
+async fn run_command(
+    matches: &ArgMatches<'_>,
+    config: &Config,
+) -> Option<iron_carrier::Result<()>> {
+    if let Some(result) = run_snapshot_command(matches, config).await {
+        return Some(result);
+    }
+
+    if let Some(result) = run_manifest_command(matches, config).await {
+        return Some(result);
+    }
+
+    if let Some((peer, folder)) = alias_and_value(matches, "export-bundle") {
+        return Some(
+            bundle::export_bundle(config, &config.peer_address(&peer), Path::new(&folder)).await,
+        );
+    }
+
+    if let Some((peer, folder)) = alias_and_value(matches, "import-bundle") {
+        return Some(
+            bundle::import_bundle(config, &config.peer_address(&peer), Path::new(&folder)).await,
+        );
+    }
+
+    if let Some((alias, folder)) = alias_and_value(matches, "move-alias") {
+        return Some(migration::move_alias(config, &alias, Path::new(&folder)).await);
+    }
+
+    if let Some((alias, path)) = alias_and_value(matches, "fetch") {
+        return Some(fetch_files(config, &alias, &path).await);
+    }
+
+    if matches.is_present("repair") {
+        return Some(repair_files(config).await);
+    }
+
+    if let Some(peer) = matches.value_of("remote-aliases") {
+        return Some(list_remote_aliases(config, peer).await);
+    }
+
+    run_mount_command(matches, config).await
+}
+
+async fn fetch_files(config: &Config, alias: &str, path: &str) -> iron_carrier::Result<()> {
+    for path in on_demand::fetch(config, alias, Path::new(path)).await? {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+async fn repair_files(config: &Config) -> iron_carrier::Result<()> {
+    let report = repair::repair(config).await?;
+    for file in report.files {
+        println!("{}", file);
+    }
+
+    Ok(())
+}
+
+async fn list_remote_aliases(config: &Config, peer: &str) -> iron_carrier::Result<()> {
+    let remote = remote_aliases::query_peer_aliases(config, &config.peer_address(peer)).await?;
+    let aliases: Vec<String> = remote.iter().map(|stats| stats.alias.clone()).collect();
+    let local = remote_aliases::alias_stats(config, &aliases).await?;
+
+    for stats in remote {
+        let in_sync = local
+            .iter()
+            .any(|local| local.alias == stats.alias && local.hash == stats.hash);
+        println!(
+            "{}\t{}",
+            stats,
+            if in_sync { "in sync" } else { "different" }
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the two values of `arg`, used by the arguments in the form `--arg alias value` or `--arg peer value`
+fn alias_and_value(matches: &ArgMatches<'_>, arg: &str) -> Option<(String, String)> {
+    let mut values = matches.values_of(arg)?;
+    Some((values.next()?.to_owned(), values.next()?.to_owned()))
+}
+
+/// Runs the manifest operation requested in the command line, if any
+async fn run_manifest_command(
+    matches: &ArgMatches<'_>,
+    config: &Config,
+) -> Option<iron_carrier::Result<()>> {
+    if let Some((alias, file)) = alias_and_value(matches, "export-manifest") {
+        return Some(export_manifest(config, &alias, &file).await);
+    }
+
+    let (alias, file) = alias_and_value(matches, "diff-manifest")?;
+    Some(diff_manifest(config, &alias, &file).await)
+}
+
+async fn export_manifest(config: &Config, alias: &str, file: &str) -> iron_carrier::Result<()> {
+    let manifest = Manifest::generate(config, &config.alias_id(alias)).await?;
+    tokio::fs::write(file, manifest.to_json()?).await?;
+
+    log::info!("manifest of alias {} written to {}", alias, file);
+    Ok(())
+}
+
+async fn diff_manifest(config: &Config, alias: &str, file: &str) -> iron_carrier::Result<()> {
+    let other = Manifest::from_json(&tokio::fs::read_to_string(file).await?)?;
+    let local = Manifest::generate(config, &config.alias_id(alias)).await?;
+
+    for difference in local.diff(&other) {
+        println!("{}", difference);
+    }
+
+    Ok(())
+}
+
+/// Runs the snapshot operation requested in the command line, if any
+async fn run_snapshot_command(
+    matches: &ArgMatches<'_>,
+    config: &Config,
+) -> Option<iron_carrier::Result<()>> {
+    if let Some((alias, name)) = alias_and_value(matches, "snapshot") {
+        return Some(snapshot::create_snapshot(config, &config.alias_id(&alias), &name).await);
+    }
+
+    if let Some((alias, name)) = alias_and_value(matches, "restore") {
+        return Some(snapshot::restore_snapshot(config, &config.alias_id(&alias), &name).await);
+    }
+
+    if let Some((alias, name)) = alias_and_value(matches, "remove-snapshot") {
+        return Some(snapshot::remove_snapshot(config, &config.alias_id(&alias), &name).await);
+    }
+
+    let alias = matches.value_of("list-snapshots")?;
+    Some(
+        snapshot::list_snapshots(config, &config.alias_id(alias))
+            .await
+            .map(|snapshots| {
+                for snapshot in snapshots {
+                    println!(
+                        "{}\t{} files\tcreated at {}",
+                        snapshot.name, snapshot.files, snapshot.created_at
+                    );
+                }
+            }),
+    )
+}
+
+/// Mounts the peer alias requested in the command line, if any
+#[cfg(feature = "fuse")]
+async fn run_mount_command(
+    matches: &ArgMatches<'_>,
+    config: &Config,
+) -> Option<iron_carrier::Result<()>> {
+    let mut values = matches.values_of("mount")?;
+    let (peer, alias, mount_point) = (values.next()?, values.next()?, values.next()?);
+
+    Some(
+        iron_carrier::mount::mount(
+            config,
+            &config.peer_address(peer),
+            alias,
+            Path::new(mount_point),
+        )
+        .await,
+    )
+}
+
+#[cfg(not(feature = "fuse"))]
+async fn run_mount_command(
+    _matches: &ArgMatches<'_>,
+    _config: &Config,
+) -> Option<iron_carrier::Result<()>> {
+    None
+}
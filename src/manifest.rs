@@ -0,0 +1,477 @@
+//! Portable manifests of an alias
+//!
+//! A manifest lists every file of an alias, with its size, times and content hash, including the tombstones of deleted files.
+//! Manifests are written as JSON, so they can be compared offline or consumed by external tools
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512_256};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::Path,
+    time::{Instant, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    config::{Config, HashAlgorithm, ScanThrottle},
+    fs,
+    fs::FileInfo,
+    skipped_files::SkippedFiles,
+    IronCarrierError,
+};
+
+/// Size of the buffer used to hash the files
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Every file of an alias at the moment the manifest was generated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Alias the manifest was generated from
+    pub alias: String,
+    /// Generation time, in seconds since the unix epoch
+    pub generated_at: u64,
+    /// [crate::config::AliasLimits::max_depth] of the alias, files deeper than it are not listed  
+    /// Only the files within the depth of both manifests are compared by [Manifest::diff]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// Files of the alias, sorted by path
+    pub files: Vec<ManifestEntry>,
+}
+
+/// A file in a [Manifest]
+///
+/// If the file exists, `size`, `modified_at` and `sha256` will be [Some]  
+/// Otherwise, only `deleted_at` will be [Some]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the alias root, always using `/` as separator
+    pub path: String,
+    /// File size, in bytes
+    pub size: Option<u64>,
+    /// Modification time, in seconds since the unix epoch
+    pub modified_at: Option<u64>,
+    /// Creation time, in seconds since the unix epoch
+    pub created_at: Option<u64>,
+    /// Deletion time, in seconds since the unix epoch
+    pub deleted_at: Option<u64>,
+    /// Hex encoded hash of the file content, SHA-256 unless it is prefixed by another [HashAlgorithm]  
+    /// Manifests of aliases with different algorithms show every file as different
+    pub sha256: Option<String>,
+}
+
+impl ManifestEntry {
+    fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    fn depth(&self) -> usize {
+        self.path.split('/').count()
+    }
+}
+
+/// Difference between the local alias and another manifest, for a single path
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestDifference {
+    /// File exists only in the local alias
+    OnlyLocal(String),
+    /// File exists only in the other manifest
+    MissingLocally(String),
+    /// File exists in both, with different contents
+    ContentDiffers(String),
+    /// File exists locally, but was deleted in the other manifest
+    DeletedInManifest(String),
+    /// File was deleted locally, but exists in the other manifest
+    DeletedLocally(String),
+}
+
+impl Display for ManifestDifference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestDifference::OnlyLocal(path) => write!(f, "only local    {}", path),
+            ManifestDifference::MissingLocally(path) => write!(f, "missing       {}", path),
+            ManifestDifference::ContentDiffers(path) => write!(f, "differs       {}", path),
+            ManifestDifference::DeletedInManifest(path) => write!(f, "deleted there {}", path),
+            ManifestDifference::DeletedLocally(path) => write!(f, "deleted here  {}", path),
+        }
+    }
+}
+
+/// Returns the portable representation of a relative path, [None] if the path is not valid unicode
+pub(crate) fn portable_path(path: &Path) -> Option<String> {
+    let components: Option<Vec<&str>> = path
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+
+    components.map(|components| components.join("/"))
+}
+
+/// Hasher of file contents, for a [HashAlgorithm]
+enum ContentHasher {
+    Sha256(Sha256),
+    Sha512Trunc256(Sha512_256),
+}
+
+impl ContentHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => ContentHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512Trunc256 => ContentHasher::Sha512Trunc256(Sha512_256::new()),
+        }
+    }
+
+    fn update(&mut self, content: &[u8]) {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.update(content),
+            ContentHasher::Sha512Trunc256(hasher) => hasher.update(content),
+        }
+    }
+
+    /// Returns the hex encoded hash, prefixed by [HashAlgorithm::prefix]
+    fn finish(self) -> String {
+        match self {
+            ContentHasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            ContentHasher::Sha512Trunc256(hasher) => format!(
+                "{}{}",
+                HashAlgorithm::Sha512Trunc256.prefix(),
+                to_hex(&hasher.finalize())
+            ),
+        }
+    }
+}
+
+/// Returns the hex encoded hash of the file at `path`
+pub(crate) async fn hash_file(path: &Path, algorithm: HashAlgorithm) -> crate::Result<String> {
+    hash_content(tokio::fs::File::open(path).await?, algorithm).await
+}
+
+/// Returns the hex encoded hash of the file at `path`, reading it in the background, following `throttle`
+pub(crate) async fn hash_file_throttled(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    throttle: ScanThrottle,
+) -> crate::Result<String> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        fs::with_idle_priority(throttle.idle_priority, || {
+            let mut file = std::fs::File::open(&path)?;
+            let mut hasher = ContentHasher::new(algorithm);
+            let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+
+            loop {
+                let started_at = Instant::now();
+                let size = std::io::Read::read(&mut file, &mut buf)?;
+                if size == 0 {
+                    break;
+                }
+                hasher.update(&buf[..size]);
+                if let Some(pause) = throttle.pause_after(started_at.elapsed()) {
+                    std::thread::sleep(pause);
+                }
+            }
+
+            Ok(hasher.finish())
+        })
+    })
+    .await?
+}
+
+/// Returns the hash of the content read from `reader`, as a hex string
+pub(crate) async fn hash_content(
+    mut file: impl AsyncRead + Unpin,
+    algorithm: HashAlgorithm,
+) -> crate::Result<String> {
+    let mut hasher = ContentHasher::new(algorithm);
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            size => hasher.update(&buf[..size]),
+        }
+    }
+
+    Ok(hasher.finish())
+}
+
+fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Manifest {
+    /// Generates the manifest for `alias`, hashing the content of every file  
+    /// Files that can't be read are left out of the manifest and reported at the end
+    pub async fn generate(config: &Config, alias: &str) -> crate::Result<Self> {
+        let root_path = config
+            .paths
+            .get(alias)
+            .ok_or_else(|| IronCarrierError::AliasNotAvailable(alias.to_owned()))?;
+
+        let mut skipped = SkippedFiles::new();
+        let mut portable_files = Vec::new();
+        for file in fs::walk_path(root_path, alias, config).await? {
+            match portable_path(&file.path) {
+                Some(path) => portable_files.push((path, file)),
+                None => skipped.add(&file.path, "path is not valid unicode"),
+            }
+        }
+
+        // files are hashed by up to scan_workers at the same time, keeping the order
+        let algorithm = config.hashing(alias).algorithm;
+        let mut hashed = futures::stream::iter(portable_files)
+            .map(|(path, file)| async move {
+                let entry = Manifest::entry_for_file(
+                    root_path,
+                    path,
+                    &file,
+                    algorithm,
+                    config.scan_throttle,
+                )
+                .await;
+                (file, entry)
+            })
+            .buffered(config.scan_workers);
+
+        let mut files = Vec::new();
+        while let Some((file, entry)) = hashed.next().await {
+            match entry {
+                Ok(entry) => files.push(entry),
+                Err(err) => skipped.add(&file.path, err),
+            }
+        }
+
+        skipped.log_summary(&format!("generating manifest for alias {}", alias));
+
+        Ok(Manifest {
+            alias: alias.to_owned(),
+            generated_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            max_depth: config.max_depth(alias),
+            files,
+        })
+    }
+
+    async fn entry_for_file(
+        root_path: &Path,
+        path: String,
+        file: &FileInfo,
+        algorithm: HashAlgorithm,
+        throttle: ScanThrottle,
+    ) -> crate::Result<ManifestEntry> {
+        let sha256 = match file.deleted_at {
+            Some(_) => None,
+            None => {
+                Some(hash_file_throttled(&root_path.join(&file.path), algorithm, throttle).await?)
+            }
+        };
+
+        Ok(ManifestEntry {
+            path,
+            size: file.size,
+            modified_at: file.modified_at,
+            created_at: file.created_at,
+            deleted_at: file.deleted_at,
+            sha256,
+        })
+    }
+
+    /// Serializes the manifest as pretty printed JSON
+    pub fn to_json(&self) -> crate::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parses a manifest from JSON
+    pub fn from_json(content: &str) -> crate::Result<Self> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    /// Compares this manifest, the local one, with `other`  
+    /// Paths are compared by content hash, paths deleted on both sides are not reported  
+    /// Paths deeper than the [Manifest::max_depth] of any of the manifests are not reported
+    pub fn diff(&self, other: &Manifest) -> Vec<ManifestDifference> {
+        let max_depth = match (self.max_depth, other.max_depth) {
+            (Some(depth), Some(other_depth)) => Some(depth.min(other_depth)),
+            (depth, other_depth) => depth.or(other_depth),
+        };
+        let in_scope =
+            |entry: &&ManifestEntry| max_depth.is_none_or(|max_depth| entry.depth() <= max_depth);
+
+        let mut others: HashMap<&str, &ManifestEntry> = other
+            .files
+            .iter()
+            .filter(in_scope)
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect();
+
+        let mut differences = Vec::new();
+        for local in self.files.iter().filter(in_scope) {
+            let path = local.path.clone();
+            let difference = match (others.remove(local.path.as_str()), local.is_deleted()) {
+                (None, false) => Some(ManifestDifference::OnlyLocal(path)),
+                (None, true) => None,
+                (Some(other), false) if other.is_deleted() => {
+                    Some(ManifestDifference::DeletedInManifest(path))
+                }
+                (Some(other), false) if other.sha256 != local.sha256 => {
+                    Some(ManifestDifference::ContentDiffers(path))
+                }
+                (Some(other), true) if !other.is_deleted() => {
+                    Some(ManifestDifference::DeletedLocally(path))
+                }
+                _ => None,
+            };
+
+            differences.extend(difference);
+        }
+
+        let mut missing: Vec<String> = others
+            .into_values()
+            .filter(|other| !other.is_deleted())
+            .map(|other| other.path.clone())
+            .collect();
+        missing.sort();
+        differences.extend(missing.into_iter().map(ManifestDifference::MissingLocally));
+
+        differences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, sha256: Option<&str>) -> ManifestEntry {
+        ManifestEntry {
+            path: path.into(),
+            size: sha256.map(|_| 1),
+            modified_at: sha256.map(|_| 1),
+            created_at: None,
+            deleted_at: if sha256.is_none() { Some(1) } else { None },
+            sha256: sha256.map(|hash| hash.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_generate_manifest() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "
+            [paths]
+            a = \"./tmp/manifest/a\""
+                .to_string(),
+        )?;
+        tokio::fs::create_dir_all("./tmp/manifest/a/folder").await?;
+        tokio::fs::write("./tmp/manifest/a/folder/file", "content").await?;
+
+        let manifest = Manifest::generate(&config, "a").await?;
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].path, "folder/file");
+        assert_eq!(
+            manifest.files[0].sha256.as_deref(),
+            Some("ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73")
+        );
+        assert_eq!(Manifest::from_json(&manifest.to_json()?)?, manifest);
+
+        tokio::fs::remove_dir_all("./tmp/manifest").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn manifests_use_the_alias_hashing() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "
+            [paths]
+            a = \"./tmp/manifest_hashing/a\"
+
+            [hashing.a]
+            algorithm = \"sha512_256\"
+            block_size = 8192"
+                .to_string(),
+        )?;
+        assert_eq!(config.block_size("a"), 8192);
+        tokio::fs::create_dir_all("./tmp/manifest_hashing/a").await?;
+        tokio::fs::write("./tmp/manifest_hashing/a/file", "content").await?;
+
+        let manifest = Manifest::generate(&config, "a").await?;
+        let hash = manifest.files[0].sha256.clone().unwrap_or_default();
+        assert!(hash.starts_with("sha512_256:"));
+        assert_eq!(HashAlgorithm::of_hash(&hash), HashAlgorithm::Sha512Trunc256);
+        assert_eq!(
+            hash_content(&b"content"[..], HashAlgorithm::of_hash(&hash)).await?,
+            hash
+        );
+        assert_eq!(
+            HashAlgorithm::of_hash(&hash_content(&b"content"[..], HashAlgorithm::Sha256).await?),
+            HashAlgorithm::Sha256
+        );
+
+        assert!(Config::parse_content(
+            "
+            [paths]
+            a = \"./tmp/manifest_hashing/a\"
+
+            [hashing.a]
+            block_size = 0"
+                .to_string(),
+        )
+        .is_err());
+
+        tokio::fs::remove_dir_all("./tmp/manifest_hashing").await?;
+        Ok(())
+    }
+
+    #[test]
+    fn can_diff_manifests() {
+        let local = Manifest {
+            alias: "a".into(),
+            generated_at: 0,
+            max_depth: None,
+            files: vec![
+                entry("changed", Some("1")),
+                entry("deleted_here", None),
+                entry("deleted_there", Some("1")),
+                entry("local", Some("1")),
+                entry("same", Some("1")),
+            ],
+        };
+        let other = Manifest {
+            alias: "a".into(),
+            generated_at: 0,
+            max_depth: None,
+            files: vec![
+                entry("changed", Some("2")),
+                entry("deleted_here", Some("1")),
+                entry("deleted_there", None),
+                entry("missing", Some("1")),
+                entry("same", Some("1")),
+            ],
+        };
+
+        assert_eq!(
+            local.diff(&other),
+            vec![
+                ManifestDifference::ContentDiffers("changed".into()),
+                ManifestDifference::DeletedLocally("deleted_here".into()),
+                ManifestDifference::DeletedInManifest("deleted_there".into()),
+                ManifestDifference::OnlyLocal("local".into()),
+                ManifestDifference::MissingLocally("missing".into()),
+            ]
+        );
+
+        // files deeper than one of the manifests are out of scope
+        let local = Manifest {
+            max_depth: Some(1),
+            files: vec![entry("same", Some("1"))],
+            ..local
+        };
+        let other = Manifest {
+            files: vec![entry("same", Some("1")), entry("folder/deep", Some("1"))],
+            ..other
+        };
+        assert!(local.diff(&other).is_empty());
+    }
+}
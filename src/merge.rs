@@ -0,0 +1,212 @@
+//! Three-way merge of text files
+//!
+//! Both sides are compared, line by line, with their common ancestor. Changes made by only one of the sides are applied,
+//! conflict markers are only written when both sides changed the same lines differently
+
+/// Marker written before the local lines of a conflict
+const LOCAL_MARKER: &str = "<<<<<<< local\n";
+/// Marker written between the local and the remote lines of a conflict
+const SEPARATOR_MARKER: &str = "=======\n";
+/// Marker written after the remote lines of a conflict
+const REMOTE_MARKER: &str = ">>>>>>> remote\n";
+
+/// Result of [merge]
+#[derive(Debug)]
+pub(crate) struct Merged {
+    pub content: String,
+    /// Number of conflicts written with markers
+    pub conflicts: usize,
+}
+
+/// Splits `text` in lines, keeping the line endings, so the merge doesn't change them
+fn lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// Returns the pairs of equal lines of `a` and `b`, in order, found with Myers' diff algorithm
+fn common_lines(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    // lines in common at the start and at the end are matched before running the algorithm on what is left
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    pairs.extend(
+        shortest_edit(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix])
+            .into_iter()
+            .map(|(x, y)| (x + prefix, y + prefix)),
+    );
+    pairs.extend((0..suffix).map(|i| (a.len() - suffix + i, b.len() - suffix + i)));
+    pairs
+}
+
+/// Myers' algorithm, keeping only the diagonals reached at each step to recover the matched lines
+fn shortest_edit(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // trace[d] holds the furthest x of the diagonals -(d + 1)..=(d + 1) before step d
+    let mut trace = Vec::new();
+
+    'steps: for d in 0..=max {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+
+            if x >= n && y >= m {
+                break 'steps;
+            }
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = at(previous_k);
+        let previous_y = previous_x - previous_k;
+
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+
+        if d > 0 {
+            x = previous_x;
+            y = previous_y;
+        }
+    }
+
+    pairs.reverse();
+    pairs
+}
+
+/// For each line of `base`, the index of the same line in `other`, if it wasn't changed
+fn matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    for (base_index, other_index) in common_lines(base, other) {
+        matches[base_index] = Some(other_index);
+    }
+    matches
+}
+
+/// Appends `lines` to `content`, making sure the last one ends the line before a marker is written
+fn push_conflict_side(content: &mut String, lines: &[&str]) {
+    content.extend(lines.iter().copied());
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+}
+
+/// Merges the changes made to `base` in `local` and in `remote`
+pub(crate) fn merge(base: &str, local: &str, remote: &str) -> Merged {
+    let (base, local, remote) = (lines(base), lines(local), lines(remote));
+    let (local_matches, remote_matches) = (matches(&base, &local), matches(&base, &remote));
+
+    let mut merged = Merged {
+        content: String::new(),
+        conflicts: 0,
+    };
+    let (mut o, mut l, mut r) = (0, 0, 0);
+    loop {
+        // lines unchanged in both sides
+        let mut stable = 0;
+        while o + stable < base.len()
+            && local_matches[o + stable] == Some(l + stable)
+            && remote_matches[o + stable] == Some(r + stable)
+        {
+            stable += 1;
+        }
+        if stable > 0 {
+            merged.content.extend(base[o..o + stable].iter().copied());
+            o += stable;
+            l += stable;
+            r += stable;
+            continue;
+        }
+
+        // lines changed in at least one side, up to the next line unchanged in both
+        let (end_o, end_l, end_r) = match (o..base.len())
+            .find_map(|index| Some((index, local_matches[index]?, remote_matches[index]?)))
+        {
+            Some(end) => end,
+            None => (base.len(), local.len(), remote.len()),
+        };
+        if (o, l, r) == (end_o, end_l, end_r) {
+            break;
+        }
+
+        let (base_chunk, local_chunk, remote_chunk) =
+            (&base[o..end_o], &local[l..end_l], &remote[r..end_r]);
+        if local_chunk == base_chunk || local_chunk == remote_chunk {
+            merged.content.extend(remote_chunk.iter().copied());
+        } else if remote_chunk == base_chunk {
+            merged.content.extend(local_chunk.iter().copied());
+        } else {
+            merged.conflicts += 1;
+            push_conflict_side(&mut merged.content, &[]);
+            merged.content.push_str(LOCAL_MARKER);
+            push_conflict_side(&mut merged.content, local_chunk);
+            merged.content.push_str(SEPARATOR_MARKER);
+            push_conflict_side(&mut merged.content, remote_chunk);
+            merged.content.push_str(REMOTE_MARKER);
+        }
+
+        o = end_o;
+        l = end_l;
+        r = end_r;
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_changes_from_both_sides() {
+        let base = "title\none\ntwo\nthree\nfour\n";
+        let local = "title\none\nTWO\nthree\nfour\nfive\n";
+        let remote = "new title\none\ntwo\nthree\nfour\n";
+
+        let merged = merge(base, local, remote);
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(merged.content, "new title\none\nTWO\nthree\nfour\nfive\n");
+
+        let merged = merge(
+            base,
+            "title\none\n2\nthree\nfour\n",
+            "title\none\nTWO\nthree\nfour\n",
+        );
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.content,
+            "title\none\n<<<<<<< local\n2\n=======\nTWO\n>>>>>>> remote\nthree\nfour\n"
+        );
+    }
+}
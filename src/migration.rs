@@ -0,0 +1,96 @@
+//! Moving an alias to another folder without losing its state
+//!
+//! The state of an alias, the deletions, the agreed files with each peer and the index of the last scan, is kept in
+//! the alias root. Moving the root along with the state keeps the history, so the peers see no change at all
+
+use std::path::Path;
+
+use crate::{config::Config, fs::is_special_file, IronCarrierError};
+
+/// Moves the root of `alias` to `new_root`, along with its state  
+/// When `new_root` already exists, the files are expected to be there, only the state is moved into it  
+/// [Config::paths] must point to `new_root` before the node starts again
+pub async fn move_alias(config: &Config, alias: &str, new_root: &Path) -> crate::Result<()> {
+    let alias = config.alias_id(alias);
+    let root = config
+        .paths
+        .get(&alias)
+        .ok_or_else(|| IronCarrierError::AliasNotAvailable(alias.clone()))?;
+
+    if !new_root.exists() {
+        if let Some(parent) = new_root.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(root, new_root).await?;
+        log::info!("alias {} moved to {:?}", alias, new_root);
+        return Ok(());
+    }
+
+    let mut state_files = Vec::new();
+    let mut entries = tokio::fs::read_dir(root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() && is_special_file(&entry.path()) {
+            state_files.push(entry.file_name());
+        }
+    }
+
+    // the state of another alias is never replaced
+    if let Some(existing) = state_files.iter().find(|name| new_root.join(name).exists()) {
+        return Err(IronCarrierError::ConfigFileIsInvalid(format!(
+            "{:?} already has the state file {:?}",
+            new_root, existing
+        ))
+        .into());
+    }
+
+    for name in state_files {
+        let destination = new_root.join(&name);
+        if tokio::fs::rename(root.join(&name), &destination)
+            .await
+            .is_err()
+        {
+            tokio::fs::copy(root.join(&name), &destination).await?;
+            tokio::fs::remove_file(root.join(&name)).await?;
+        }
+    }
+
+    log::info!("state of alias {} moved to {:?}", alias, new_root);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn moving_an_alias_keeps_its_state() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/migration/old").await?;
+        tokio::fs::create_dir_all("./tmp/migration/copy").await?;
+        tokio::fs::write("./tmp/migration/old/file", b"content").await?;
+        tokio::fs::write("./tmp/migration/old/.ironcarrier", b"state").await?;
+        tokio::fs::write("./tmp/migration/copy/file", b"content").await?;
+
+        let config = Config::parse_content(
+            "[paths]
+            documents = \"./tmp/migration/old\"
+
+            [alias_ids]
+            documents = \"docs\""
+                .to_string(),
+        )?;
+
+        // the files were already copied, only the state is moved
+        move_alias(&config, "documents", Path::new("./tmp/migration/copy")).await?;
+        assert!(Path::new("./tmp/migration/copy/.ironcarrier").exists());
+        assert!(!Path::new("./tmp/migration/old/.ironcarrier").exists());
+        assert!(Path::new("./tmp/migration/old/file").exists());
+
+        // otherwise the whole root is moved
+        move_alias(&config, "docs", Path::new("./tmp/migration/new")).await?;
+        assert!(Path::new("./tmp/migration/new/file").exists());
+        assert!(!Path::new("./tmp/migration/old").exists());
+
+        tokio::fs::remove_dir_all("./tmp/migration").await?;
+        Ok(())
+    }
+}
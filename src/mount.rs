@@ -0,0 +1,447 @@
+//! Read-only FUSE mount of an alias from a remote peer
+//!
+//! The file list is fetched once, when the alias is mounted. The content of a file is requested from the peer the first time it is opened
+//! and kept in a local cache until the alias is unmounted
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    ReplyOpen, Request, FUSE_ROOT_ID,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    config::Config,
+    events::EventBus,
+    fs::FileInfo,
+    network::peer::{Peer, PeerFileList},
+    network::transport::TcpTransport,
+    sync::file_events_buffer::FileEventsBuffer,
+    sync::FileAction,
+};
+
+/// How long the kernel can cache attributes and entries, the mounted alias never changes
+const ATTR_TTL: Duration = Duration::from_secs(60);
+
+/// Asks the peer connection for the content of `file`
+struct FetchRequest {
+    file: FileInfo,
+    reply: oneshot::Sender<crate::Result<()>>,
+}
+
+enum Node {
+    Folder {
+        parent: u64,
+        children: Vec<(OsString, u64)>,
+    },
+    File {
+        parent: u64,
+        file: FileInfo,
+    },
+}
+
+/// The file tree of the remote alias, inode numbers are the node position plus one
+struct RemoteAlias {
+    nodes: Vec<Node>,
+    cache_path: PathBuf,
+    fetched: HashSet<u64>,
+    requests: mpsc::UnboundedSender<FetchRequest>,
+    mounted_at: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+fn secs_to_system_time(secs: Option<u64>) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs.unwrap_or_default())
+}
+
+impl RemoteAlias {
+    fn new(
+        files: Vec<FileInfo>,
+        cache_path: PathBuf,
+        requests: mpsc::UnboundedSender<FetchRequest>,
+        uid: u32,
+        gid: u32,
+    ) -> Self {
+        let mut remote_alias = RemoteAlias {
+            nodes: vec![Node::Folder {
+                parent: FUSE_ROOT_ID,
+                children: Vec::new(),
+            }],
+            cache_path,
+            fetched: HashSet::new(),
+            requests,
+            mounted_at: SystemTime::now(),
+            uid,
+            gid,
+        };
+
+        let mut folders = HashMap::new();
+        folders.insert(PathBuf::new(), FUSE_ROOT_ID);
+
+        for file in files.into_iter().filter(|file| file.deleted_at.is_none()) {
+            let name = match file.path.file_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+
+            let parent = remote_alias.folder_ino(
+                &mut folders,
+                file.path.parent().unwrap_or_else(|| Path::new("")),
+            );
+            remote_alias.add_node(name, Node::File { parent, file });
+        }
+
+        remote_alias
+    }
+
+    fn add_node(&mut self, name: OsString, node: Node) -> u64 {
+        let parent = match &node {
+            Node::Folder { parent, .. } | Node::File { parent, .. } => *parent,
+        };
+
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        if let Some(Node::Folder { children, .. }) = self.nodes.get_mut(parent as usize - 1) {
+            children.push((name, ino));
+        }
+
+        ino
+    }
+
+    /// Returns the inode for the folder at `path`, creating it and its parents if necessary
+    fn folder_ino(&mut self, folders: &mut HashMap<PathBuf, u64>, path: &Path) -> u64 {
+        if let Some(ino) = folders.get(path) {
+            return *ino;
+        }
+
+        let parent = self.folder_ino(folders, path.parent().unwrap_or_else(|| Path::new("")));
+        let name = path.file_name().unwrap_or_default().to_owned();
+        let ino = self.add_node(
+            name,
+            Node::Folder {
+                parent,
+                children: Vec::new(),
+            },
+        );
+
+        folders.insert(path.to_owned(), ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, mtime, crtime, perm, nlink) = match self.node(ino)? {
+            Node::Folder { .. } => (
+                FileType::Directory,
+                0,
+                self.mounted_at,
+                self.mounted_at,
+                0o555,
+                2,
+            ),
+            Node::File { file, .. } => (
+                FileType::RegularFile,
+                file.size.unwrap_or_default(),
+                secs_to_system_time(file.modified_at),
+                secs_to_system_time(file.created_at.or(file.modified_at)),
+                0o444,
+                1,
+            ),
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    /// Requests the content of `ino` from the peer, unless it was already fetched
+    fn fetch(&mut self, ino: u64) -> crate::Result<()> {
+        if self.fetched.contains(&ino) {
+            return Ok(());
+        }
+
+        let file = match self.node(ino) {
+            Some(Node::File { file, .. }) => file.clone(),
+            _ => return Ok(()),
+        };
+
+        log::debug!("fetching {:?} from peer", file.path);
+        let (reply, response) = oneshot::channel();
+        self.requests.send(FetchRequest { file, reply })?;
+        response.blocking_recv()??;
+
+        self.fetched.insert(ino);
+        Ok(())
+    }
+}
+
+impl Filesystem for RemoteAlias {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = match self.node(parent) {
+            Some(Node::Folder { children, .. }) => children
+                .iter()
+                .find(|(child_name, _)| child_name == name)
+                .map(|(_, ino)| *ino),
+            _ => None,
+        };
+
+        match ino.and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&ATTR_TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&ATTR_TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let (parent, children) = match self.node(ino) {
+            Some(Node::Folder { parent, children }) => (*parent, children),
+            Some(Node::File { .. }) => return reply.error(libc::ENOTDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        let entries = vec![
+            (ino, FileType::Directory, OsStr::new(".")),
+            (parent, FileType::Directory, OsStr::new("..")),
+        ]
+        .into_iter()
+        .chain(children.iter().map(|(name, child)| {
+            let kind = match self.node(*child) {
+                Some(Node::Folder { .. }) => FileType::Directory,
+                _ => FileType::RegularFile,
+            };
+            (*child, kind, name.as_os_str())
+        }));
+
+        for (index, (ino, kind, name)) in entries.enumerate().skip(offset as usize) {
+            if reply.add(ino, index as i64 + 1, kind, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return reply.error(libc::EROFS);
+        }
+
+        match self.fetch(ino) {
+            Ok(_) => reply.opened(0, 0),
+            Err(err) => {
+                log::error!("cannot fetch file from peer: {}", err);
+                reply.error(libc::EIO)
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let path = match self.node(ino) {
+            Some(Node::File { file, .. }) => self.cache_path.join(&file.path),
+            _ => return reply.error(libc::ENOENT),
+        };
+
+        let mut buf = vec![0u8; size as usize];
+        let mut read = 0;
+        let result = std::fs::File::open(path).and_then(|file| {
+            while read < buf.len() {
+                match file.read_at(&mut buf[read..], offset as u64 + read as u64)? {
+                    0 => break,
+                    size => read += size,
+                }
+            }
+            Ok(())
+        });
+
+        match result {
+            Ok(_) => reply.data(&buf[..read]),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+}
+
+/// Connects to the peer, sends back the file list of `alias` and then serves the fetch requests until the alias is unmounted
+async fn serve_fetch_requests(
+    config: Arc<Config>,
+    peer_address: String,
+    alias: String,
+    files_sender: oneshot::Sender<crate::Result<Vec<FileInfo>>>,
+    mut requests: mpsc::UnboundedReceiver<FetchRequest>,
+) {
+    let events_buffer = FileEventsBuffer::new(config.clone());
+    let events = EventBus::new();
+
+    let transport = TcpTransport::new(&config);
+    let mut peer =
+        match Peer::new(&peer_address, &transport, &config, &events_buffer, &events).await {
+            Ok(peer) => peer,
+            Err(err) => {
+                files_sender.send(Err(err)).ok();
+                return;
+            }
+        };
+
+    let mut file_list = PeerFileList::remote(&alias);
+    let mut files = Vec::new();
+    let result = loop {
+        match peer.next_file(&mut file_list).await {
+            Ok(Some(file)) => files.push(file),
+            Ok(None) => break Ok(files),
+            Err(err) => break Err(err),
+        }
+    };
+
+    if files_sender.send(result).is_err() {
+        return;
+    }
+
+    while let Some(request) = requests.recv().await {
+        let result = peer.sync_action(&FileAction::Request(request.file)).await;
+        request.reply.send(result).ok();
+    }
+}
+
+/// Mounts `alias` of the peer at `peer_address` in `mount_point`, read-only
+///
+/// Returns when the alias is unmounted, with `fusermount -u`
+pub async fn mount(
+    config: &Config,
+    peer_address: &str,
+    alias: &str,
+    mount_point: &Path,
+) -> crate::Result<()> {
+    let cache_path =
+        std::env::temp_dir().join(format!("iron-carrier-mount-{}", std::process::id()));
+    tokio::fs::create_dir_all(&cache_path).await?;
+
+    // received files go to the cache, without being versioned or mirrored
+    let mut mount_config = config.clone();
+    mount_config.paths = HashMap::new();
+    mount_config
+        .paths
+        .insert(alias.to_owned(), cache_path.clone());
+    mount_config.block_store_path = None;
+    mount_config.enable_fsync = false;
+
+    let (files_sender, files_receiver) = oneshot::channel();
+    let (requests, requests_receiver) = mpsc::unbounded_channel();
+    let worker = tokio::spawn(serve_fetch_requests(
+        Arc::new(mount_config),
+        peer_address.to_owned(),
+        alias.to_owned(),
+        files_sender,
+        requests_receiver,
+    ));
+
+    let files = files_receiver.await??;
+    let metadata = mount_point.metadata()?;
+    let filesystem = RemoteAlias::new(
+        files,
+        cache_path.clone(),
+        requests,
+        metadata.uid(),
+        metadata.gid(),
+    );
+
+    log::info!(
+        "mounting alias {} of peer {} at {:?}",
+        alias,
+        peer_address,
+        mount_point
+    );
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(format!("iron-carrier:{}", alias)),
+    ];
+    let mount_point = mount_point.to_owned();
+    let result =
+        tokio::task::spawn_blocking(move || fuser::mount2(filesystem, mount_point, &options))
+            .await?;
+
+    worker.await.ok();
+    tokio::fs::remove_dir_all(&cache_path).await.ok();
+
+    log::info!("alias {} unmounted", alias);
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FileKind;
+
+    #[test]
+    fn can_build_file_tree() {
+        let files = vec![
+            FileInfo::new_deleted("a".into(), "deleted".into(), None),
+            FileInfo {
+                alias: "a".into(),
+                path: "folder/sub/file".into(),
+                modified_at: Some(10),
+                created_at: None,
+                deleted_at: None,
+                size: Some(5),
+                kind: FileKind::Regular,
+                extra: Default::default(),
+            },
+        ];
+
+        let (requests, _) = mpsc::unbounded_channel();
+        let remote_alias = RemoteAlias::new(files, PathBuf::new(), requests, 0, 0);
+
+        assert_eq!(remote_alias.nodes.len(), 4);
+        let attr = remote_alias.attr(4).unwrap();
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.size, 5);
+        assert_eq!(remote_alias.attr(2).unwrap().kind, FileType::Directory);
+        assert!(remote_alias.attr(5).is_none());
+    }
+}
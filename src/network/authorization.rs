@@ -0,0 +1,295 @@
+//! Connections from hosts that are not configured peers, see [Config::authorized_peers_path]
+//!
+//! Unknown hosts are refused and kept in a pending list until they are approved or rejected, the decisions are written
+//! to the authorized peers file, so they are kept across restarts
+//!
+//! The connection of a pending host is not held open while it waits, the peer connects again on its next
+//! synchronization and is accepted once approved. The decisions are not written to the configuration file, it is
+//! written by hand with comments that rewriting it would drop, so they live in their own file next to it
+//!
+//! Peers configured by name are matched by the addresses they resolve to, the names are resolved in the background,
+//! see [PeerAuthorizations::resolve_peers], so a slow name server never holds the incoming connections
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{config::Config, sync::introduced_peers::IntroducedPeers, IronCarrierError};
+
+/// Time between the resolutions of the peers configured by name
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+/// Time given to resolve each peer name, the peer keeps its previous addresses when it runs out
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decisions about the unknown hosts, as written in the authorized peers file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PeerDecisions {
+    #[serde(default)]
+    approved: BTreeSet<String>,
+    #[serde(default)]
+    rejected: BTreeSet<String>,
+}
+
+/// Whether a connection from a host is accepted, see [PeerAuthorizations::check]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Authorization {
+    /// The host is a configured peer, or it was approved
+    Accepted,
+    /// The host was rejected
+    Rejected,
+    /// The host is waiting for a decision, `true` the first time it connects
+    Pending(bool),
+}
+
+/// Hosts allowed to connect to this node
+pub(crate) struct PeerAuthorizations {
+    /// Authorized peers file, no host is refused without it
+    path: Option<PathBuf>,
+    decisions: Mutex<PeerDecisions>,
+    pending: Mutex<BTreeSet<String>>,
+    introduced_peers: Arc<IntroducedPeers>,
+    /// Addresses of the peers configured by name, see [PeerAuthorizations::resolve_peers]
+    resolved: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl PeerAuthorizations {
+    /// Reads the decisions from [Config::authorized_peers_path], an unreadable file is logged and ignored
+    pub fn new(config: &Config, introduced_peers: Arc<IntroducedPeers>) -> Self {
+        let decisions = match &config.authorized_peers_path {
+            Some(path) if path.exists() => std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|content| toml::from_str(&content).map_err(|err| err.to_string()))
+                .unwrap_or_else(|err| {
+                    log::error!("cannot read authorized peers from {:?}: {}", path, err);
+                    PeerDecisions::default()
+                }),
+            _ => PeerDecisions::default(),
+        };
+
+        Self {
+            path: config.authorized_peers_path.clone(),
+            decisions: Mutex::new(decisions),
+            pending: Mutex::new(BTreeSet::new()),
+            introduced_peers,
+            resolved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether the connection from `address`, the IP of the host, is accepted
+    /// Unknown hosts are added to the pending list
+    pub fn check(&self, address: &str, config: &Config) -> Authorization {
+        if self.path.is_none() || self.is_known(address, config) {
+            return Authorization::Accepted;
+        }
+
+        let decisions = self.decisions.lock().unwrap();
+        if decisions.approved.contains(address) {
+            Authorization::Accepted
+        } else if decisions.rejected.contains(address) {
+            Authorization::Rejected
+        } else {
+            Authorization::Pending(self.pending.lock().unwrap().insert(address.to_owned()))
+        }
+    }
+
+    /// Returns the configured peers and the approved introduced peers
+    fn peers(&self, config: &Config) -> Vec<String> {
+        config
+            .peers
+            .iter()
+            .flatten()
+            .cloned()
+            .chain(self.introduced_peers.approved())
+            .collect()
+    }
+
+    /// Returns true if `address` is the host of a configured peer, or of an approved introduced peer  
+    /// Peers configured by name are matched by the addresses they resolved to last
+    fn is_known(&self, address: &str, config: &Config) -> bool {
+        let resolved = self.resolved.lock().unwrap();
+        self.peers(config).iter().any(|peer| {
+            host_of(peer) == address
+                || resolved.get(peer).is_some_and(|addresses| {
+                    addresses
+                        .iter()
+                        .any(|resolved| resolved.to_string() == address)
+                })
+        })
+    }
+
+    /// Resolves the peers configured by name, right away and then every [RESOLVE_INTERVAL], until `cancel` is cancelled  
+    /// Pending hosts that turn out to be a peer are taken out of the pending list
+    pub async fn resolve_peers(&self, config: &Config, cancel: CancellationToken) {
+        if self.path.is_none() {
+            return;
+        }
+
+        loop {
+            for peer in self.peers(config) {
+                if host_of(&peer).parse::<IpAddr>().is_ok() {
+                    continue;
+                }
+
+                match tokio::time::timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host(peer.as_str()))
+                    .await
+                {
+                    Ok(Ok(addresses)) => {
+                        let addresses = addresses.map(|socket| socket.ip()).collect();
+                        self.resolved
+                            .lock()
+                            .unwrap()
+                            .insert(peer.clone(), addresses);
+                    }
+                    Ok(Err(err)) => log::debug!("cannot resolve peer {}: {}", peer, err),
+                    Err(_) => log::debug!("resolving peer {} timed out", peer),
+                }
+            }
+
+            let known: Vec<String> = self
+                .pending()
+                .into_iter()
+                .filter(|address| self.is_known(address, config))
+                .collect();
+            for address in known {
+                self.pending.lock().unwrap().remove(&address);
+            }
+
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(RESOLVE_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Returns the hosts waiting for [PeerAuthorizations::decide]
+    pub fn pending(&self) -> Vec<String> {
+        self.pending.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Approves or rejects the connections from `address`, the decision is written to the authorized peers file
+    pub async fn decide(&self, address: &str, approved: bool) -> crate::Result<()> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or(IronCarrierError::PeerAuthorizationNotConfigured)?;
+
+        let content = {
+            let mut decisions = self.decisions.lock().unwrap();
+            if approved {
+                decisions.rejected.remove(address);
+                decisions.approved.insert(address.to_owned());
+            } else {
+                decisions.approved.remove(address);
+                decisions.rejected.insert(address.to_owned());
+            }
+            self.pending.lock().unwrap().remove(address);
+            toml::to_string(&*decisions)?
+        };
+
+        log::info!(
+            "peer {} was {}",
+            address,
+            if approved { "approved" } else { "rejected" }
+        );
+        tokio::fs::write(path, content).await?;
+        Ok(())
+    }
+}
+
+/// Returns the host of `peer`, without the port and the brackets of IPv6 addresses
+fn host_of(peer: &str) -> &str {
+    let host = peer.rsplit_once(':').map_or(peer, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unknown_peers_wait_for_a_decision() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/authorization").await?;
+        let config = Config::parse_content(
+            "authorized_peers_path = \"./tmp/authorization/peers.toml\"
+            peers = [ \"10.0.0.2:8090\" ]
+
+            [paths]
+            a = \"./tmp/authorization\""
+                .to_string(),
+        )?;
+
+        let authorizations = PeerAuthorizations::new(&config, Arc::new(IntroducedPeers::new()));
+        assert_eq!(
+            authorizations.check("10.0.0.2", &config),
+            Authorization::Accepted
+        );
+        assert_eq!(
+            authorizations.check("10.0.0.3", &config),
+            Authorization::Pending(true)
+        );
+        assert_eq!(
+            authorizations.check("10.0.0.3", &config),
+            Authorization::Pending(false)
+        );
+        assert_eq!(
+            authorizations.check("10.0.0.4", &config),
+            Authorization::Pending(true)
+        );
+        assert_eq!(authorizations.pending(), vec!["10.0.0.3", "10.0.0.4"]);
+
+        authorizations.decide("10.0.0.3", true).await?;
+        authorizations.decide("10.0.0.4", false).await?;
+        assert!(authorizations.pending().is_empty());
+
+        // decisions are kept across restarts
+        let authorizations = PeerAuthorizations::new(&config, Arc::new(IntroducedPeers::new()));
+        assert_eq!(
+            authorizations.check("10.0.0.3", &config),
+            Authorization::Accepted
+        );
+        assert_eq!(
+            authorizations.check("10.0.0.4", &config),
+            Authorization::Rejected
+        );
+
+        tokio::fs::remove_dir_all("./tmp/authorization").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn peers_configured_by_name_are_resolved_in_the_background() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "authorized_peers_path = \"./tmp/authorization_names/peers.toml\"
+            peers = [ \"localhost:8090\" ]
+
+            [paths]
+            a = \"./tmp/authorization_names\""
+                .to_string(),
+        )?;
+
+        let authorizations = PeerAuthorizations::new(&config, Arc::new(IntroducedPeers::new()));
+        // names are not resolved while checking a connection
+        assert_eq!(
+            authorizations.check("127.0.0.1", &config),
+            Authorization::Pending(true)
+        );
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        authorizations.resolve_peers(&config, cancel).await;
+        assert!(authorizations.pending().is_empty());
+        assert_eq!(
+            authorizations.check("127.0.0.1", &config),
+            Authorization::Accepted
+        );
+
+        Ok(())
+    }
+}
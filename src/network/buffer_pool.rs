@@ -0,0 +1,102 @@
+//! Reusable buffers for file transfers
+//!
+//! Every transfer needs a buffer of the chunk size, buffers are returned to a process wide pool when dropped,
+//! so transfers don't allocate a new buffer for each file
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Max number of idle buffers kept in the pool, extra buffers are freed
+const MAX_POOLED_BUFFERS: usize = 16;
+
+/// Pool shared by all transfers
+pub(crate) static BUFFER_POOL: BufferPool = BufferPool::new();
+
+/// Idle buffers, waiting to be reused
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Buffer taken from a [BufferPool], it goes back to the pool when dropped
+pub(crate) struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: &'static BufferPool,
+}
+
+impl BufferPool {
+    pub const fn new() -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns a buffer of `size` bytes, reusing an idle buffer when one is available
+    pub fn get(&'static self, size: usize) -> PooledBuffer {
+        let pooled = self
+            .buffers
+            .lock()
+            .map(|mut buffers| buffers.pop())
+            .unwrap_or_default();
+
+        let mut buffer = pooled.unwrap_or_default();
+        buffer.resize(size, 0);
+
+        PooledBuffer { buffer, pool: self }
+    }
+}
+
+impl PooledBuffer {
+    /// Changes the buffer size, the memory already allocated is kept
+    pub fn resize(&mut self, size: usize) {
+        self.buffer.resize(size, 0);
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Ok(mut buffers) = self.pool.buffers.lock() {
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(std::mem::take(&mut self.buffer));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        static POOL: BufferPool = BufferPool::new();
+
+        let buffer = POOL.get(1024);
+        assert_eq!(buffer.len(), 1024);
+        let address = buffer.as_ptr();
+        drop(buffer);
+
+        let buffer = POOL.get(512);
+        assert_eq!(buffer.len(), 512);
+        assert_eq!(buffer.as_ptr(), address);
+
+        let buffers: Vec<PooledBuffer> = (0..MAX_POOLED_BUFFERS + 1).map(|_| POOL.get(8)).collect();
+        drop(buffers);
+        assert_eq!(POOL.buffers.lock().unwrap().len(), MAX_POOLED_BUFFERS);
+    }
+}
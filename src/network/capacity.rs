@@ -0,0 +1,185 @@
+//! Limits advertised by each peer, so a small device isn't flooded by a faster one
+//!
+//! Both sides of a connection exchange their limits when it is established: how many transfers can run with the node at
+//! the same time, the largest chunk it wants to receive and whether it is low on disk space. The chunks sent by each side
+//! are kept within the limit of the other, and the connecting side keeps its transfers with the peer within the peer limit
+//! with the process wide [TRANSFER_SLOTS]. The smallest file verified with a sampled checksum is agreed the same way, see
+//! [PeerCapacity::sampled_checksum_min_size]
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::streaming::{negotiate_sampled_checksum, MAX_CHUNK_SIZE};
+use crate::{config::Config, fs};
+
+/// Slots shared by all the connections of this process
+pub(crate) static TRANSFER_SLOTS: TransferSlots = TransferSlots::new();
+
+/// Limits of a peer, sent when a connection is established
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PeerCapacity {
+    /// Transfers that can run with the peer at the same time
+    pub max_parallel_transfers: usize,
+    /// Largest chunk, in bytes, the peer wants to receive
+    pub max_chunk_size: usize,
+    /// The peer is below its [Config::min_free_space], new files and updates are not sent to it
+    pub low_disk_space: bool,
+    /// [Config::sampled_checksum_min_size] of the peer, sampled checksums are only used when both sides set it
+    pub sampled_checksum_min_size: Option<u64>,
+}
+
+impl Default for PeerCapacity {
+    /// Capacity assumed until the peer advertises its own
+    fn default() -> Self {
+        Self {
+            max_parallel_transfers: 1,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            low_disk_space: false,
+            sampled_checksum_min_size: None,
+        }
+    }
+}
+
+impl PeerCapacity {
+    /// Returns the capacity of this node, to be advertised to the peers
+    pub fn local(config: &Config) -> Self {
+        Self {
+            max_parallel_transfers: config.max_parallel_transfers,
+            max_chunk_size: if config.adaptive_chunk_size {
+                MAX_CHUNK_SIZE.max(config.transfer_chunk_size)
+            } else {
+                config.transfer_chunk_size
+            },
+            low_disk_space: is_low_on_disk_space(config),
+            sampled_checksum_min_size: config.sampled_checksum_min_size,
+        }
+    }
+
+    /// Returns the smallest file sent and received with a sampled checksum between this node, with capacity `local`,
+    /// and the peer, [None] when either side wants the whole content hashed
+    pub fn sampled_checksum_with(&self, local: &PeerCapacity) -> Option<u64> {
+        negotiate_sampled_checksum(
+            local.sampled_checksum_min_size,
+            self.sampled_checksum_min_size,
+        )
+    }
+}
+
+/// Returns true if any alias in the local file system has less than [Config::min_free_space] available
+fn is_low_on_disk_space(config: &Config) -> bool {
+    if config.min_free_space == 0 {
+        return false;
+    }
+
+    config
+        .paths
+        .iter()
+        .filter(|(alias, _)| config.is_local_storage(alias))
+        .any(|(alias, path)| match fs::available_space(path) {
+            Ok(available) if available < config.min_free_space => {
+                log::warn!(
+                    "alias {} has {} bytes available, below min_free_space",
+                    alias,
+                    available
+                );
+                true
+            }
+            Ok(_) => false,
+            Err(err) => {
+                log::debug!(
+                    "cannot read the space available for alias {}: {}",
+                    alias,
+                    err
+                );
+                false
+            }
+        })
+}
+
+/// Transfers running with each peer, limited by the [PeerCapacity::max_parallel_transfers] of the peer
+pub(crate) struct TransferSlots {
+    peers: Mutex<Vec<(String, usize, Arc<Semaphore>)>>,
+}
+
+impl TransferSlots {
+    pub const fn new() -> Self {
+        Self {
+            peers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the slots of `address`, they are replaced when the peer advertises a different `limit`
+    fn slots(&self, address: &str, limit: usize) -> Arc<Semaphore> {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.iter_mut().find(|(peer, _, _)| peer == address) {
+            Some((_, peer_limit, slots)) => {
+                if *peer_limit != limit {
+                    *peer_limit = limit;
+                    *slots = Arc::new(Semaphore::new(limit));
+                }
+                slots.clone()
+            }
+            None => {
+                let slots = Arc::new(Semaphore::new(limit));
+                peers.push((address.to_owned(), limit, slots.clone()));
+                slots
+            }
+        }
+    }
+
+    /// Waits for a free slot with `address`, the slot is released when the permit is dropped
+    pub async fn acquire(&self, address: &str, limit: usize) -> OwnedSemaphorePermit {
+        self.slots(address, limit)
+            .acquire_owned()
+            .await
+            .expect("transfer slots are never closed")
+    }
+
+    /// Returns a free slot with `address`, if there is one
+    pub fn try_acquire(&self, address: &str, limit: usize) -> Option<OwnedSemaphorePermit> {
+        self.slots(address, limit).try_acquire_owned().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn transfers_are_limited_by_the_peer_capacity() {
+        let slots = TransferSlots::new();
+
+        let first = slots.acquire("peer:8090", 2).await;
+        let second = slots.try_acquire("peer:8090", 2);
+        assert!(second.is_some());
+        assert!(slots.try_acquire("peer:8090", 2).is_none());
+        assert!(slots.try_acquire("other:8090", 2).is_some());
+
+        drop(first);
+        assert!(slots.try_acquire("peer:8090", 2).is_some());
+
+        let config = Config::parse_content(
+            "port = 8090
+            transfer_chunk_size = 16384
+            max_parallel_transfers = 1
+            min_free_space = 9223372036854775807
+            [paths]
+            a = \"./tmp/peer_capacity\""
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            PeerCapacity::local(&config),
+            PeerCapacity {
+                max_parallel_transfers: 1,
+                max_chunk_size: 16384,
+                low_disk_space: true,
+                sampled_checksum_min_size: None
+            }
+        );
+
+        std::fs::remove_dir_all("./tmp/peer_capacity").unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Classification of the peers between the local network and the internet
+//!
+//! Peers are classified every time a connection is established, by their address, unless [Config::peer_networks] says
+//! otherwise. Host names are resolved, so a peer known by name moves between the networks along with the laptop running it
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use crate::config::{Config, NetworkClass};
+
+/// Time to wait for a host name to be resolved, peers that can't be resolved in time are outside the local network
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Returns the host of `address`, without the port
+pub(crate) fn host_of(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => address,
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Returns the network of the peer at `address`
+pub(crate) async fn classify(address: &str, config: &Config) -> NetworkClass {
+    let host = host_of(address);
+    let network = match config.peer_networks.get(host) {
+        Some(network) => *network,
+        None => classify_host(host).await,
+    };
+
+    log::debug!("peer {} is in the {:?} network", address, network);
+    network
+}
+
+/// Returns the bytes per second sent to the peer at `address`, according to its network, [None] if unlimited  
+/// Peers are not classified when there are no limits
+pub(crate) async fn bandwidth_limit(address: &str, config: &Config) -> Option<u64> {
+    if config.lan_bandwidth_limit == 0 && config.wan_bandwidth_limit == 0 {
+        return None;
+    }
+
+    config.bandwidth_limit(classify(address, config).await)
+}
+
+async fn classify_host(host: &str) -> NetworkClass {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return classify_ip(&ip);
+    }
+    if host == "localhost" || host.ends_with(".local") {
+        return NetworkClass::Lan;
+    }
+
+    match tokio::time::timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host((host, 0))).await {
+        Ok(Ok(mut addresses)) => addresses
+            .next()
+            .map(|address| classify_ip(&address.ip()))
+            .unwrap_or(NetworkClass::Wan),
+        _ => {
+            log::debug!(
+                "cannot resolve {}, assuming it is outside the local network",
+                host
+            );
+            NetworkClass::Wan
+        }
+    }
+}
+
+fn classify_ip(ip: &IpAddr) -> NetworkClass {
+    let local = match ip {
+        IpAddr::V4(ip) => is_local_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_ipv4(&ip),
+            None => is_local_ipv6(ip),
+        },
+    };
+
+    if local {
+        NetworkClass::Lan
+    } else {
+        NetworkClass::Wan
+    }
+}
+
+fn is_local_ipv4(ip: &Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local()
+}
+
+fn is_local_ipv6(ip: &Ipv6Addr) -> bool {
+    let segment = ip.segments()[0];
+    // unique local (fc00::/7) and link local (fe80::/10) addresses
+    ip.is_loopback() || segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn peers_are_classified_by_address() {
+        let config = Config::parse_content(
+            "port = 8090
+            [paths]
+            a = \"./tmp/peer_networks\"
+            [peer_networks]
+            \"100.64.0.1\" = \"lan\"
+            \"192.168.0.10\" = \"wan\""
+                .to_string(),
+        )
+        .unwrap();
+
+        for (address, network) in [
+            ("127.0.0.1:8090", NetworkClass::Lan),
+            ("192.168.1.20:8090", NetworkClass::Lan),
+            ("10.0.0.2", NetworkClass::Lan),
+            ("[fd00::1]:8090", NetworkClass::Lan),
+            ("[fe80::1]:8090", NetworkClass::Lan),
+            ("localhost:8090", NetworkClass::Lan),
+            ("8.8.8.8:8090", NetworkClass::Wan),
+            ("[2001:db8::1]:8090", NetworkClass::Wan),
+            ("100.64.0.1:8090", NetworkClass::Lan),
+            ("192.168.0.10:8090", NetworkClass::Wan),
+        ] {
+            assert_eq!(classify(address, &config).await, network, "{}", address);
+        }
+
+        std::fs::remove_dir_all("./tmp/peer_networks").unwrap();
+    }
+}
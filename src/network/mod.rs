@@ -1,3 +1,8 @@
+pub(crate) mod authorization;
+mod buffer_pool;
+pub(crate) mod capacity;
+pub(crate) mod locality;
 pub mod peer;
 pub mod server;
 pub mod streaming;
+pub mod transport;
@@ -1,24 +1,45 @@
+use super::capacity::{PeerCapacity, TRANSFER_SLOTS};
+use super::locality;
 use super::streaming::{
     file_streamers, frame_stream, FileReceiver, FileSender, FrameMessage, FrameReader, FrameWriter,
+    ZeroCopySocket,
 };
+use super::transport::{BoxedStream, Transport};
 use crate::{
-    config::Config, fs::FileInfo, sync::file_events_buffer::FileEventsBuffer, sync::FileAction,
+    config::{self, Config},
+    dedup::{self, LocalContent},
+    events::{Event, EventBus, PeerActivity},
+    fs::{self, FileInfo},
+    remote_aliases::AliasStats,
+    skipped_files::SkippedFiles,
+    sync::file_events_buffer::FileEventsBuffer,
+    sync::FileAction,
     IronCarrierError,
 };
-use std::{collections::HashMap};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
 use tokio::{
-    fs::File,
     io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
-    net::TcpStream,
+    sync::OwnedSemaphorePermit,
 };
+use tokio_util::sync::CancellationToken;
 
 type RpcResult<T> = Result<T, IronCarrierError>;
 
+/// Time to wait for the other peers when looking for sources of a file
+const SOURCE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Times a file is requested when its content doesn't match the checksum sent by the peer
+const CHECKSUM_ATTEMPTS: u32 = 3;
+
 macro_rules! send_message {
     ($self:expr, $func:ident()) => {
         if $self.status == PeerStatus::Disconnected {
             log::warn!("attempted to call disconnected peer");
-            return Err(IronCarrierError::PeerDisconectedError($self.address.to_owned()).into());
+            return Err(IronCarrierError::PeerDisconectedError($self.name.clone()).into());
         }
 
         log::debug!("sending message {} to peer", stringify!($func));
@@ -29,7 +50,7 @@ macro_rules! send_message {
     ($self:expr, $func:ident($($arg:expr),+)) => {
         if $self.status == PeerStatus::Disconnected {
             log::warn!("attempted to call disconnected peer");
-            return Err(IronCarrierError::PeerDisconectedError($self.address.to_owned()).into());
+            return Err(IronCarrierError::PeerDisconectedError($self.name.clone()).into());
         }
 
         log::debug!("sending message {} to peer", stringify!($func));
@@ -40,6 +61,15 @@ macro_rules! send_message {
     };
 }
 
+/// Reads the [IronCarrierError] the peer answered a call with, see [FrameMessage] `error`
+macro_rules! peer_error {
+    ($message:expr, $func:ident) => {{
+        let err = $message.next_arg::<IronCarrierError>()?;
+        log::warn!("peer failed {}: {}", stringify!($func), err);
+        err
+    }};
+}
+
 macro_rules! rpc_call {
     ($self:expr, $func:ident($($arg:expr),*)) => {{
         send_message!($self, $func($($arg),*));
@@ -47,12 +77,19 @@ macro_rules! rpc_call {
         log::debug!("waiting response for {}", stringify!($func));
         let response_message = $self.frame_reader.next_frame().await?;
         match response_message {
-            Some(message) => {
+            Some(mut message) => {
                 if message.frame_ident() == stringify!($func) {
                     log::debug!("received response from peer");
                     Ok(())
+                } else if message.frame_ident() == "error" {
+                    Err(peer_error!(message, $func))
                 } else {
                     log::error!("received wrong response {}", message.frame_ident());
+                    $self.protocol_violation(format!(
+                        "answered {} with {}",
+                        stringify!($func),
+                        message.frame_ident()
+                    ));
                     Err(IronCarrierError::ParseCommandError)
                 }
             }
@@ -73,8 +110,15 @@ macro_rules! rpc_call {
                 if message.frame_ident() == stringify!($func) {
                     log::debug!("received response from peer");
                     Ok(message.next_arg::<$t>()?)
+                } else if message.frame_ident() == "error" {
+                    Err(peer_error!(message, $func))
                 } else {
                     log::error!("received wrong response {}", message.frame_ident());
+                    $self.protocol_violation(format!(
+                        "answered {} with {}",
+                        stringify!($func),
+                        message.frame_ident()
+                    ));
                     Err(IronCarrierError::ParseCommandError)
                 }
             }
@@ -107,6 +151,39 @@ macro_rules! rpc_call {
     }}
 }
 
+/// File list of an alias of the peer, read one page at a time, in path order
+///
+/// Only the current page is kept in memory, the next one is requested when it is over
+pub(crate) struct PeerFileList {
+    alias: String,
+    page: std::vec::IntoIter<FileInfo>,
+    offset: u64,
+    last_page: bool,
+}
+
+impl PeerFileList {
+    /// List that is requested from the peer
+    pub fn remote(alias: &str) -> Self {
+        Self {
+            alias: alias.to_owned(),
+            page: Vec::new().into_iter(),
+            offset: 0,
+            last_page: false,
+        }
+    }
+
+    /// List already known, like the one agreed in the last synchronization
+    pub fn stored(alias: &str, mut files: Vec<FileInfo>) -> Self {
+        files.sort();
+        Self {
+            alias: alias.to_owned(),
+            page: files.into_iter(),
+            offset: 0,
+            last_page: true,
+        }
+    }
+}
+
 #[derive(PartialEq)]
 enum PeerStatus {
     Disconnected,
@@ -121,32 +198,76 @@ where
 {
     address: &'a str,
     config: &'a Config,
+    transport: &'a dyn Transport,
     status: PeerStatus,
     frame_writer: FrameWriter<TWriter>,
     frame_reader: FrameReader<TReader>,
     file_sender: FileSender<TWriter>,
     file_receiver: FileReceiver<'a, TReader>,
     events_buffer: &'a FileEventsBuffer,
+    events: &'a EventBus,
+    /// Name of the peer in the logs and errors, see [Config::peer_name]
+    name: String,
     peer_sync_hash: HashMap<String, u64>,
+    /// Limits advertised by the peer when the connection was established
+    capacity: PeerCapacity,
+    /// Folders of each alias synchronized by the peer, see [Config::subscriptions]
+    subscriptions: HashMap<String, Vec<PathBuf>>,
+    /// Local files the requested files can be copied from, see [Config::deduplicate_transfers]
+    local_content: LocalContent,
+    cancel: CancellationToken,
+}
+
+impl<'a, TReader, TWriter> Peer<'a, TReader, TWriter>
+where
+    TReader: AsyncRead + Unpin,
+    TWriter: AsyncWrite + Unpin,
+{
+    /// Emits an [Event::PeerSession] for the session this node opened with the peer
+    fn session_event(&self, activity: PeerActivity) {
+        self.events.emit(Event::PeerSession {
+            peer: self.name.clone(),
+            address: self.address.to_owned(),
+            activity,
+        });
+    }
 }
 
-impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
+impl<'a, TReader, TWriter> Drop for Peer<'a, TReader, TWriter>
+where
+    TReader: AsyncRead + Unpin,
+    TWriter: AsyncWrite + Unpin,
+{
+    fn drop(&mut self) {
+        self.session_event(PeerActivity::Disconnected { reason: None });
+    }
+}
+
+impl<'a> Peer<'a, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>> {
     pub async fn new(
         address: &'a str,
+        transport: &'a dyn Transport,
         config: &'a Config,
         events_buffer: &'a FileEventsBuffer,
-    ) -> crate::Result<Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>>> {
-        log::info!("connecting to peer {:?}", address);
+        events: &'a EventBus,
+    ) -> crate::Result<Peer<'a, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>> {
+        let name = config.peer_name(address);
+        log::info!("connecting to peer {} at {:?}", name, address);
 
-        let (frame_reader, frame_writer) = frame_stream(TcpStream::connect(address).await?);
+        let (frame_reader, frame_writer) = frame_stream(transport.connect(address).await?);
+        let file_stream = transport.connect(address).await?;
+        let zero_copy = ZeroCopySocket::of(&file_stream, config);
         let (file_receiver, file_sender) = file_streamers(
-            TcpStream::connect(address).await?,
-            config.clone(),
+            file_stream,
+            config,
+            events,
             address.split(':').next().unwrap().to_string(),
         );
+        let file_sender = file_sender.with_zero_copy(zero_copy);
 
-        Ok(Peer {
+        let mut peer = Peer {
             address,
+            name,
             frame_writer,
             frame_reader,
             file_sender,
@@ -154,14 +275,65 @@ impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
             peer_sync_hash: HashMap::new(),
             status: PeerStatus::Connected,
             config,
+            transport,
             events_buffer,
-        })
+            events,
+            capacity: PeerCapacity::default(),
+            subscriptions: HashMap::new(),
+            local_content: LocalContent::new(),
+            cancel: CancellationToken::new(),
+        };
+        peer.session_event(PeerActivity::Connected);
+
+        // a peer that doesn't authorize this node closes the connection before answering
+        let handshake = match peer.fetch_capacity().await {
+            Ok(()) => peer.exchange_subscriptions().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = handshake {
+            peer.session_event(PeerActivity::Refused {
+                reason: err.to_string(),
+            });
+            return Err(err);
+        }
+        peer.session_event(PeerActivity::Accepted);
+
+        peer.file_sender
+            .limit_bandwidth(locality::bandwidth_limit(address, config).await);
+
+        Ok(peer)
+    }
+
+    /// Records that the peer broke the protocol, see [Event::PeerSession]
+    fn protocol_violation(&self, reason: String) {
+        self.session_event(PeerActivity::ProtocolViolation { reason });
+    }
+
+    /// Interrupts the file transfers with this peer when `cancel` is cancelled
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.file_receiver.set_cancellation(cancel.clone());
+        self.file_sender.set_cancellation(cancel.clone());
+        self.cancel = cancel;
+        self
     }
 
     pub fn get_address(&'a self) -> &'a str {
-        &self.address
+        self.address
+    }
+
+    /// Adds the bytes of content sent and received to `progress`, [None] stops counting  
+    /// Used to follow a single transfer, see [crate::sync::in_flight::InFlightTransfers]
+    pub fn track_progress(&mut self, progress: Option<Arc<AtomicU64>>) {
+        self.file_sender.set_progress(progress.clone());
+        self.file_receiver.set_progress(progress);
+    }
+
+    /// Returns the name of the peer, see [Config::peer_name]
+    pub fn get_name(&self) -> &str {
+        &self.name
     }
 
+    #[allow(dead_code)]
     async fn send_server_port(&mut self) -> crate::Result<()> {
         log::debug!("setting peer port to {}", self.config.port);
 
@@ -169,7 +341,70 @@ impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
         Ok(())
     }
 
-    async fn fetch_peer_status(&mut self) -> crate::Result<()> {
+    /// Exchanges limits with the peer, the chunks sent by each side are kept within the largest chunk of the other
+    async fn fetch_capacity(&mut self) -> crate::Result<()> {
+        log::debug!("asking peer {} for its capacity", self.name);
+
+        let local_capacity = PeerCapacity::local(self.config);
+        self.capacity = rpc_call!(self, query_capacity(local_capacity), PeerCapacity)?;
+        self.file_sender
+            .limit_chunk_size(self.capacity.max_chunk_size);
+
+        let sampled_checksum = self.capacity.sampled_checksum_with(&local_capacity);
+        self.file_sender.set_sampled_checksum(sampled_checksum);
+        self.file_receiver.set_sampled_checksum(sampled_checksum);
+
+        Ok(())
+    }
+
+    /// Exchanges [Config::subscriptions] with the peer, peers that don't know subscriptions synchronize the whole aliases
+    async fn exchange_subscriptions(&mut self) -> crate::Result<()> {
+        let local_subscriptions = &self.config.subscriptions;
+        self.subscriptions = match rpc_call!(
+            self,
+            exchange_subscriptions(local_subscriptions),
+            HashMap<String, Vec<PathBuf>>
+        ) {
+            Ok(subscriptions) => subscriptions,
+            Err(IronCarrierError::UnsupportedCommand(_)) => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(())
+    }
+
+    /// Returns true if `path` of `alias` is synchronized with the peer, it is inside the subscriptions of both sides
+    pub fn in_scope(&self, alias: &str, path: &std::path::Path) -> bool {
+        config::is_subscribed(&self.config.subscriptions, alias, path)
+            && config::is_subscribed(&self.subscriptions, alias, path)
+    }
+
+    /// Returns `action` limited to the files in scope with the peer, [None] when nothing is left to do  
+    /// A file moved into the scope is created, and a file moved out of it is removed
+    fn action_in_scope(&self, action: &FileAction) -> Option<FileAction> {
+        let in_scope = |file: &FileInfo| self.in_scope(&file.alias, &file.path);
+        match action {
+            FileAction::Move(src, dest) => match (in_scope(src), in_scope(dest)) {
+                (true, true) => Some(action.clone()),
+                (true, false) => Some(FileAction::Remove(src.clone())),
+                (false, true) => Some(FileAction::Create(dest.clone())),
+                (false, false) => None,
+            },
+            FileAction::Create(file)
+            | FileAction::Update(file)
+            | FileAction::Remove(file)
+            | FileAction::Request(file) => in_scope(file).then(|| action.clone()),
+        }
+    }
+
+    /// Waits for a free transfer slot with the peer, see [PeerCapacity::max_parallel_transfers]
+    async fn transfer_slot(&self) -> OwnedSemaphorePermit {
+        TRANSFER_SLOTS
+            .acquire(self.address, self.capacity.max_parallel_transfers)
+            .await
+    }
+
+    pub async fn fetch_peer_status(&mut self) -> crate::Result<()> {
         log::debug!("asking peer for status");
 
         let sync_hash = rpc_call!(self, server_sync_hash(), RpcResult<HashMap<String, u64>>)?;
@@ -179,6 +414,34 @@ impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
         Ok(())
     }
 
+    /// Returns the addresses of the peers configured in the peer, excluding this one
+    pub async fn query_peers(&mut self) -> crate::Result<Vec<String>> {
+        log::debug!("asking peer {} for its peers", self.name);
+        Ok(rpc_call!(self, query_peers(), Vec<String>)?)
+    }
+
+    /// Returns the stats of the aliases the peer shares with this node, see [crate::remote_aliases]
+    pub async fn query_aliases(&mut self) -> crate::Result<Vec<AliasStats>> {
+        log::debug!("asking peer {} for its aliases", self.name);
+        let aliases: Vec<&String> = self
+            .config
+            .paths
+            .keys()
+            .filter(|alias| self.config.syncs_alias_with(alias, self.address))
+            .collect();
+
+        Ok(rpc_call!(
+            self,
+            query_aliases(aliases),
+            RpcResult<Vec<AliasStats>>
+        )??)
+    }
+
+    /// Returns the hash of the file list for `alias`, as reported by the peer in the last status
+    pub fn alias_hash(&self, alias: &str) -> Option<u64> {
+        self.peer_sync_hash.get(alias).copied()
+    }
+
     pub fn need_to_sync(&self, alias: &str, hash: u64) -> bool {
         self.peer_sync_hash
             .get(alias)
@@ -186,25 +449,61 @@ impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
             .unwrap_or(false)
     }
 
-    pub async fn fetch_files_for_alias(&mut self, alias: &str) -> crate::Result<Vec<FileInfo>> {
-        log::debug!("querying peer for list of files for alias {}", alias);
-        let result = rpc_call!(self, query_file_list(alias), RpcResult<Vec<FileInfo>>)?;
+    /// Returns the next file of `file_list`, requesting the next page from the peer when the current one is over
+    pub async fn next_file(
+        &mut self,
+        file_list: &mut PeerFileList,
+    ) -> crate::Result<Option<FileInfo>> {
+        loop {
+            if let Some(file) = file_list.page.next() {
+                return Ok(Some(file));
+            }
+
+            if file_list.last_page {
+                return Ok(None);
+            }
+
+            log::debug!(
+                "querying peer for files of alias {} from {}",
+                file_list.alias,
+                file_list.offset
+            );
+            let (files, last_page) = rpc_call!(
+                self,
+                query_file_list_page(file_list.alias, file_list.offset),
+                RpcResult<(Vec<FileInfo>, bool)>
+            )??;
 
-        match result {
-            Ok(files) => Ok(files),
-            Err(err) => Err(err.into()),
+            file_list.offset += files.len() as u64;
+            file_list.last_page = last_page;
+            file_list.page = files.into_iter();
         }
     }
 
-    pub async fn sync_action<'b>(&mut self, action: &'b FileAction) -> crate::Result<()> {
-        match action {
+    pub async fn sync_action(&mut self, action: &FileAction) -> crate::Result<()> {
+        let action = match self.action_in_scope(action) {
+            Some(action) => action,
+            None => {
+                log::debug!("{:?} is not synchronized with peer {}", action, self.name);
+                return Ok(());
+            }
+        };
+
+        match &action {
             FileAction::Create(file_info) | FileAction::Update(file_info) => {
-                self.send_file(&file_info).await?
+                if self.capacity.low_disk_space {
+                    return Err(
+                        IronCarrierError::PeerLowOnDiskSpace(self.address.to_owned()).into(),
+                    );
+                }
+
+                let _slot = self.transfer_slot().await;
+                self.send_file(file_info).await?
             }
             FileAction::Move(src, dest) => {
                 log::debug!(
                     "asking peer {} to move file {:?} to {:?}",
-                    self.address,
+                    self.name,
                     src.path,
                     dest.path
                 );
@@ -213,14 +512,16 @@ impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
             FileAction::Remove(file_info) => {
                 log::debug!(
                     "asking peer {} to remove file {:?}",
-                    self.address,
+                    self.name,
                     file_info.path
                 );
                 rpc_call!(self, delete_file(file_info))?
             }
             FileAction::Request(file_info) => {
-                log::debug!("asking peer {} for file {:?}", self.address, file_info.path);
-                self.request_file(&file_info).await?
+                log::debug!("asking peer {} for file {:?}", self.name, file_info.path);
+                let _slot = self.transfer_slot().await;
+                self.request_file(file_info).await?;
+                self.local_content.add(file_info);
             }
         }
 
@@ -228,40 +529,532 @@ impl<'a> Peer<'a, ReadHalf<TcpStream>, WriteHalf<TcpStream>> {
     }
 
     async fn send_file(&mut self, file_info: &FileInfo) -> crate::Result<()> {
-        log::debug!("sending file {:?} to peer {}", file_info.path, self.address);
-        let file_handle = rpc_call!(self, create_or_update_file(file_info), u64)?;
+        log::debug!("sending file {:?} to peer {}", file_info.path, self.name);
 
-        if file_handle > 0 {
-            let file_path = file_info.get_absolute_path(&self.config)?;
+        let mut file = fs::open_content(file_info, self.config)
+            .await
+            .map_err(|err| {
+                self.events
+                    .error_reports()
+                    .error("cannot read file", &file_info.path, &err);
+                IronCarrierError::IOReadingError
+            })?;
 
-            let mut file = File::open(file_path).await?;
-            self.file_sender.send_file(file_handle, &mut file).await?;
+        let file_handle = rpc_call!(self, create_or_update_file(file_info), RpcResult<u64>)??;
+
+        if file_handle > 0 {
+            let checksum = self.file_sender.checksum_mode(file_info.content_size());
+            match fs::local_content_path(file_info, self.config) {
+                Some(path) if self.file_sender.is_zero_copy() => {
+                    drop(file);
+                    self.file_sender
+                        .send_local_file(file_handle, &path, checksum)
+                        .await?
+                }
+                _ => {
+                    self.file_sender
+                        .send_file(file_handle, &mut file, checksum)
+                        .await?
+                }
+            }
+            fs::keep_merge_base(file_info, self.config, self.events.versions()).await;
         } else {
             log::debug!("peer refused file");
         }
         Ok(())
     }
 
-    async fn request_file(&mut self, file_info: &FileInfo) -> crate::Result<()> {
-        let file_handle = self.file_receiver.prepare_file_transfer(file_info.clone());
-        rpc_call!(self, request_file(file_info, file_handle))?;
+    /// Sends `files` to the peer in a single batch, the peer only replaces its files after the whole batch is received  
+    /// Files that can't be read are left out of the batch and recorded in `skipped`
+    pub async fn send_files(
+        &mut self,
+        files: Vec<FileInfo>,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        log::debug!("sending {} files to peer {}", files.len(), self.name);
+        if self.capacity.low_disk_space {
+            let err = IronCarrierError::PeerLowOnDiskSpace(self.address.to_owned());
+            for file_info in files.iter() {
+                skipped.add(&file_info.path, &err);
+            }
+            return Ok(());
+        }
+        let _slot = self.transfer_slot().await;
+
+        let mut batch = Vec::with_capacity(files.len());
+        let mut contents = Vec::with_capacity(files.len());
+        for mut file_info in files {
+            match fs::read_content(&file_info, self.config).await {
+                Ok(content) => {
+                    file_info.size = Some(content.len() as u64);
+                    batch.push(file_info);
+                    contents.push(content);
+                }
+                Err(err) => skipped.add(&file_info.path, err),
+            }
+        }
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let (batch_handle, accepted) = match rpc_call!(
+            self,
+            create_or_update_files(batch),
+            RpcResult<(u64, Vec<bool>)>
+        )? {
+            Ok(response) => response,
+            Err(err @ IronCarrierError::QuotaExceeded(_))
+            | Err(err @ IronCarrierError::StorageFull(_)) => {
+                log::warn!("peer refused {} files: {}", batch.len(), err);
+                for file_info in batch.iter() {
+                    skipped.add(&file_info.path, &err);
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if batch_handle == 0 {
+            log::debug!("peer refused all files");
+            return Ok(());
+        }
 
-        self.file_receiver.wait_files(&self.events_buffer).await?;
+        let (contents, sent): (Vec<Vec<u8>>, Vec<FileInfo>) = contents
+            .into_iter()
+            .zip(batch)
+            .zip(accepted)
+            .filter_map(|(sent, accepted)| if accepted { Some(sent) } else { None })
+            .unzip();
+        self.file_sender.send_batch(batch_handle, &contents).await?;
+
+        for file_info in sent.iter() {
+            fs::keep_merge_base(file_info, self.config, self.events.versions()).await;
+        }
+        Ok(())
+    }
+
+    /// Sends `files` to the peer in a single pack, without waiting for the peer between files  
+    /// Files that can't be read are skipped in the pack and recorded in `skipped`
+    pub async fn send_pack(
+        &mut self,
+        files: Vec<FileInfo>,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        self.send_entries(files, false, skipped).await
+    }
+
+    /// Sends `files` to the peer as an atomic group, see [crate::config::Config::atomic_groups]  
+    /// The group is sent like a pack, but the peer only replaces its files once all of them were received
+    pub async fn send_group(
+        &mut self,
+        files: Vec<FileInfo>,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        self.send_entries(files, true, skipped).await
+    }
+
+    async fn send_entries(
+        &mut self,
+        files: Vec<FileInfo>,
+        atomic: bool,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        log::debug!(
+            "sending {} of {} files to peer {}",
+            if atomic { "atomic group" } else { "pack" },
+            files.len(),
+            self.name
+        );
+        if self.capacity.low_disk_space {
+            let err = IronCarrierError::PeerLowOnDiskSpace(self.address.to_owned());
+            for file_info in files.iter() {
+                skipped.add(&file_info.path, &err);
+            }
+            return Ok(());
+        }
+        let _slot = self.transfer_slot().await;
+
+        let response = match atomic {
+            true => rpc_call!(self, create_group(files), RpcResult<(u64, Vec<bool>)>)?,
+            false => rpc_call!(self, create_pack(files), RpcResult<(u64, Vec<bool>)>)?,
+        };
+        let (pack_handle, accepted) = match response {
+            Ok(response) => response,
+            Err(err @ IronCarrierError::QuotaExceeded(_))
+            | Err(err @ IronCarrierError::StorageFull(_)) => {
+                log::warn!("peer refused pack of {} files: {}", files.len(), err);
+                for file_info in files.iter() {
+                    skipped.add(&file_info.path, &err);
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if pack_handle == 0 {
+            log::debug!("peer refused all files");
+            return Ok(());
+        }
+
+        self.file_sender.start_pack(pack_handle).await?;
+        let sent: Vec<&FileInfo> = files
+            .iter()
+            .zip(accepted)
+            .filter_map(|(file_info, accepted)| if accepted { Some(file_info) } else { None })
+            .collect();
+        let mut unreadable = Vec::new();
+        for file_info in sent.iter() {
+            match fs::open_content(file_info, self.config).await {
+                Ok(mut file) => {
+                    self.file_sender
+                        .send_pack_entry(Some((&mut file, file_info.content_size())))
+                        .await?;
+                    fs::keep_merge_base(file_info, self.config, self.events.versions()).await;
+                }
+                Err(err) => {
+                    skipped.add(&file_info.path, err);
+                    unreadable.push(&file_info.path);
+                    self.file_sender
+                        .send_pack_entry::<BoxedStream>(None)
+                        .await?;
+                }
+            }
+        }
+
+        // the peer discards the whole group when any of its files is missing
+        if atomic && !unreadable.is_empty() {
+            for file_info in sent.iter().filter(|file| !unreadable.contains(&&file.path)) {
+                skipped.add(
+                    &file_info.path,
+                    "other files of its atomic group couldn't be sent",
+                );
+            }
+        }
 
         Ok(())
     }
 
+    /// Requests `files` from the peer in a single pack, without waiting for the peer between files
+    pub async fn request_pack(&mut self, files: Vec<FileInfo>) -> crate::Result<()> {
+        self.request_entries(files, false).await
+    }
+
+    /// Requests `files` from the peer as an atomic group, they only replace the local files once all of them were received
+    pub async fn request_group(&mut self, files: Vec<FileInfo>) -> crate::Result<()> {
+        self.request_entries(files, true).await
+    }
+
+    async fn request_entries(&mut self, files: Vec<FileInfo>, atomic: bool) -> crate::Result<()> {
+        log::debug!(
+            "requesting {} of {} files from peer {}",
+            if atomic { "atomic group" } else { "pack" },
+            files.len(),
+            self.name
+        );
+
+        let _slot = self.transfer_slot().await;
+        let (pack_handle, result) = match atomic {
+            true => {
+                let handle = self.file_receiver.prepare_group_transfer(files.clone());
+                (
+                    handle,
+                    rpc_call!(self, request_group(files, handle), RpcResult<()>)?,
+                )
+            }
+            false => {
+                let handle = self.file_receiver.prepare_pack_transfer(files.clone());
+                (
+                    handle,
+                    rpc_call!(self, request_pack(files, handle), RpcResult<()>)?,
+                )
+            }
+        };
+        if let Err(err) = result {
+            log::error!("peer cannot provide pack: {}", err);
+            self.file_receiver.cancel_file_transfer(pack_handle);
+            return Err(err.into());
+        }
+
+        self.file_receiver.wait_files(self.events_buffer).await
+    }
+
+    async fn request_file(&mut self, file_info: &FileInfo) -> crate::Result<()> {
+        if let Some(received) = self.file_receiver.resume_offset(file_info).await {
+            match self.resume_file(file_info, received).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => log::warn!(
+                    "resumed file {:?} doesn't match, downloading it again",
+                    file_info.path
+                ),
+                Err(err) => log::warn!(
+                    "cannot resume the download of {:?}, downloading it again: {}",
+                    file_info.path,
+                    err
+                ),
+            }
+        }
+
+        if self.config.deduplicate_transfers
+            && file_info.content_size() >= dedup::MIN_SIZE
+            && self.config.is_local_storage(&file_info.alias)
+        {
+            match self.copy_local_content(file_info).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(err) => log::warn!(
+                    "cannot copy {:?} from a local file, downloading it: {}",
+                    file_info.path,
+                    err
+                ),
+            }
+        }
+
+        if file_info.content_size() >= self.config.multi_source_min_size {
+            match self.request_file_from_sources(file_info).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(err) => log::warn!(
+                    "cannot download {:?} from multiple peers, downloading from {}: {}",
+                    file_info.path,
+                    self.name,
+                    err
+                ),
+            }
+        }
+
+        for attempt in 1..=CHECKSUM_ATTEMPTS {
+            let file_handle = self.file_receiver.prepare_file_transfer(file_info.clone());
+            let result = rpc_call!(self, request_file(file_info, file_handle), RpcResult<()>)?;
+
+            if let Err(err) = result {
+                self.events.error_reports().error(
+                    "peer cannot provide file",
+                    &file_info.path,
+                    &err,
+                );
+                self.file_receiver.cancel_file_transfer(file_handle);
+                return Err(err.into());
+            }
+
+            self.file_receiver.wait_files(self.events_buffer).await?;
+            if !self.file_receiver.checksum_mismatched(&file_info.path) {
+                return Ok(());
+            }
+
+            log::warn!(
+                "file {:?} was corrupted in transit, attempt {} of {}",
+                file_info.path,
+                attempt,
+                CHECKSUM_ATTEMPTS
+            );
+        }
+
+        Err(IronCarrierError::ChecksumMismatch.into())
+    }
+
+    /// Copies the content of `file_info` from a local file with the same content hash as the peer file  
+    /// Returns false if there is no such file, nothing is copied in this case
+    async fn copy_local_content(&mut self, file_info: &FileInfo) -> crate::Result<bool> {
+        let sha256 = self.query_file_hash(file_info).await?;
+        let source = match self
+            .local_content
+            .find(self.config, file_info, &sha256)
+            .await
+        {
+            Some(source) => source,
+            None => return Ok(false),
+        };
+
+        log::info!(
+            "copying {:?} from local file {:?} instead of downloading it",
+            file_info.path,
+            source
+        );
+
+        self.file_receiver.prepare_temp_file(file_info).await?;
+        let temp_path = fs::get_temp_path(file_info, self.config)?;
+        if let Err(err) = fs::copy_file(&source, &temp_path).await {
+            fs::remove_temp_file(file_info, self.config).await.ok();
+            return Err(err);
+        }
+
+        // the local file may have changed after it was compared, the copy is verified like a downloaded file
+        self.file_receiver
+            .complete_temp_file(file_info, &sha256, self.events_buffer)
+            .await
+    }
+
+    pub async fn query_file_hash(&mut self, file_info: &FileInfo) -> crate::Result<String> {
+        Ok(rpc_call!(
+            self,
+            query_file_hash(file_info),
+            RpcResult<String>
+        )??)
+    }
+
+    /// Connects to the other configured peers that have the same content as this peer for `file_info`  
+    /// Peers without a free transfer slot are left out, each source is returned with its slot
+    async fn find_sources(
+        &self,
+        file_info: &FileInfo,
+        sha256: &str,
+    ) -> Vec<(
+        Peer<'a, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+        OwnedSemaphorePermit,
+    )> {
+        let mut sources = Vec::new();
+        let addresses = self
+            .config
+            .peers
+            .iter()
+            .flatten()
+            .filter(|address| address.as_str() != self.address)
+            .filter(|address| self.config.syncs_alias_with(&file_info.alias, address));
+
+        for address in addresses {
+            let connect = Peer::new(
+                address,
+                self.transport,
+                self.config,
+                self.events_buffer,
+                self.events,
+            );
+            let mut peer = match tokio::time::timeout(SOURCE_CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(peer)) => peer.with_cancellation(self.cancel.clone()),
+                _ => {
+                    log::debug!(
+                        "peer {} is not available as a source",
+                        self.config.peer_name(address)
+                    );
+                    continue;
+                }
+            };
+
+            let slot =
+                match TRANSFER_SLOTS.try_acquire(address, peer.capacity.max_parallel_transfers) {
+                    Some(slot) => slot,
+                    None => {
+                        log::debug!("peer {} has no free transfer slot", peer.name);
+                        continue;
+                    }
+                };
+
+            match peer.query_file_hash(file_info).await {
+                Ok(hash) if hash == sha256 => sources.push((peer, slot)),
+                _ => log::debug!("peer {} doesn't have {:?}", peer.name, file_info.path),
+            }
+        }
+
+        sources
+    }
+
+    /// Downloads `file_info` in parts, one from this peer and one from each other peer with the same content  
+    /// The parts are written to the temp file as they arrive, the file only replaces the local one after its hash is verified
+    ///
+    /// Returns false if no other peer has the file, nothing is downloaded in this case
+    async fn request_file_from_sources(&mut self, file_info: &FileInfo) -> crate::Result<bool> {
+        let sha256 = self.query_file_hash(file_info).await?;
+        let mut sources = self.find_sources(file_info, &sha256).await;
+        if sources.is_empty() {
+            return Ok(false);
+        }
+
+        log::info!(
+            "downloading {:?} from {} peers",
+            file_info.path,
+            sources.len() + 1
+        );
+
+        self.file_receiver.prepare_temp_file(file_info).await?;
+
+        let size = file_info.size.unwrap_or_default();
+        let part_size = size.div_ceil(sources.len() as u64 + 1);
+        let downloads = std::iter::once(&mut *self)
+            .chain(sources.iter_mut().map(|(peer, _)| peer))
+            .enumerate()
+            .map(|(index, peer)| {
+                let offset = (index as u64 * part_size).min(size);
+                let length = part_size.min(size - offset);
+                peer.request_range(file_info, offset, length)
+            });
+        // every part is waited, so no stream is left in the middle of a transfer
+        let results = futures::future::join_all(downloads).await;
+        if let Some(err) = results.into_iter().find_map(Result::err) {
+            crate::fs::remove_temp_file(file_info, self.config)
+                .await
+                .ok();
+            return Err(err);
+        }
+
+        if self
+            .file_receiver
+            .complete_temp_file(file_info, &sha256, self.events_buffer)
+            .await?
+        {
+            Ok(true)
+        } else {
+            Err(IronCarrierError::ChecksumMismatch.into())
+        }
+    }
+
+    /// Downloads the rest of `file_info`, after the `received` bytes kept in its temp file by an interrupted transfer  
+    /// Returns false if the assembled file doesn't match the peer hash, the temp file is discarded in this case
+    async fn resume_file(&mut self, file_info: &FileInfo, received: u64) -> crate::Result<bool> {
+        log::info!(
+            "resuming download of {:?} from peer {} at byte {}",
+            file_info.path,
+            self.name,
+            received
+        );
+
+        let sha256 = self.query_file_hash(file_info).await?;
+        self.request_range(file_info, received, file_info.content_size() - received)
+            .await?;
+
+        self.file_receiver
+            .complete_temp_file(file_info, &sha256, self.events_buffer)
+            .await
+    }
+
+    async fn request_range(
+        &mut self,
+        file_info: &FileInfo,
+        offset: u64,
+        length: u64,
+    ) -> crate::Result<()> {
+        let file_handle =
+            self.file_receiver
+                .prepare_range_transfer(file_info.clone(), offset, length);
+        let result = rpc_call!(
+            self,
+            request_file_range(file_info, file_handle, offset, length),
+            RpcResult<()>
+        )?;
+
+        if let Err(err) = result {
+            log::error!(
+                "peer {} cannot provide part of file {:?}: {}",
+                self.name,
+                file_info.path,
+                err
+            );
+            self.file_receiver.cancel_file_transfer(file_handle);
+            return Err(err.into());
+        }
+
+        self.file_receiver.wait_files(self.events_buffer).await
+    }
+
     pub async fn start_sync(&mut self) -> crate::Result<()> {
-        log::debug!("asking peer {} to start sync", self.address);
+        log::debug!("asking peer {} to start sync", self.name);
         rpc_call!(self, init_sync())?;
 
-        log::debug!("starting sync with peer {}", self.address);
+        log::debug!("starting sync with peer {}", self.name);
         self.status = PeerStatus::Syncing;
         self.fetch_peer_status().await
     }
 
     pub async fn finish_sync(&mut self, two_way_sync: bool) -> crate::Result<()> {
-        log::debug!("finishing sync with peer {}", self.address);
+        log::debug!("finishing sync with peer {}", self.name);
         rpc_call!(self, finish_sync(two_way_sync))?;
 
         self.status = PeerStatus::Connected;
@@ -1,11 +1,24 @@
 use std::{collections::HashMap, sync::Arc};
-use tokio::{net::TcpListener, net::TcpStream, sync::mpsc::Sender, sync::Mutex};
+use tokio::{sync::mpsc::Sender, sync::Mutex, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 
-use crate::{config::Config, sync::file_events_buffer::FileEventsBuffer, sync::SyncEvent};
+use crate::{
+    config::Config,
+    events::{Event, EventBus, PeerActivity},
+    sync::alias_locks::AliasLocks,
+    sync::file_events_buffer::FileEventsBuffer,
+    sync::pause_switch::PauseSwitch,
+    sync::SyncEvent,
+};
 
 use self::server_peer_handler::ServerPeerHandler;
 
-use super::streaming::{file_streamers, frame_stream};
+use super::{
+    authorization::{Authorization, PeerAuthorizations},
+    locality,
+    streaming::{file_streamers, frame_stream, ZeroCopySocket},
+    transport::{BoxedStream, Transport},
+};
 
 mod server_peer_handler;
 
@@ -13,78 +26,251 @@ pub(crate) struct Server {
     port: u32,
     config: Arc<Config>,
     file_events: Arc<FileEventsBuffer>,
-    handlers: Arc<Mutex<HashMap<String, TcpStream>>>,
+    events: Arc<EventBus>,
+    alias_locks: Arc<AliasLocks>,
+    /// Connections are refused while the synchronization is halted, the connected peers are closed when it is halted
+    pause_switch: Arc<PauseSwitch>,
+    /// Connections from unknown hosts are refused until they are approved
+    authorizations: Arc<PeerAuthorizations>,
+    transport: Arc<dyn Transport>,
+    handlers: Arc<Mutex<HashMap<String, BoxedStream>>>,
+    /// Task accepting connections, it is stopped when the server is dropped
+    listener: Option<JoinHandle<()>>,
+    /// Task resolving the peers configured by name, see [PeerAuthorizations::resolve_peers]
+    resolver: Option<JoinHandle<()>>,
+    /// Cancelled to stop accepting connections and to close the connected peers
+    cancel: CancellationToken,
 }
 
 impl Server {
-    pub fn new(config: Arc<Config>, file_events: Arc<FileEventsBuffer>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: Arc<Config>,
+        file_events: Arc<FileEventsBuffer>,
+        events: Arc<EventBus>,
+        alias_locks: Arc<AliasLocks>,
+        pause_switch: Arc<PauseSwitch>,
+        authorizations: Arc<PeerAuthorizations>,
+        transport: Arc<dyn Transport>,
+        cancel: CancellationToken,
+    ) -> Self {
         Server {
             port: config.port,
             config,
             file_events,
+            events,
+            alias_locks,
+            pause_switch,
+            authorizations,
+            transport,
             handlers: Arc::new(Mutex::new(HashMap::new())),
+            listener: None,
+            resolver: None,
+            cancel,
         }
     }
 
     pub async fn start(&mut self, sync_events: Sender<SyncEvent>) -> crate::Result<()> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
+        let mut listener = self.transport.listen(self.port).await?;
 
         log::info!("Server listening on port: {}", self.port);
 
         let config = self.config.clone();
         let file_events = self.file_events.clone();
+        let events = self.events.clone();
+        let alias_locks = self.alias_locks.clone();
+        let pause_switch = self.pause_switch.clone();
+        let authorizations = self.authorizations.clone();
         let handlers = self.handlers.clone();
+        let cancel = self.cancel.clone();
 
-        tokio::spawn(async move {
+        self.resolver = Some(tokio::spawn({
+            let authorizations = authorizations.clone();
+            let config = config.clone();
+            let cancel = cancel.clone();
+            async move { authorizations.resolve_peers(&config, cancel).await }
+        }));
+
+        self.listener = Some(tokio::spawn(async move {
             loop {
-                match listener.accept().await {
-                    Ok((stream, socket)) => {
-                        let sync_events = sync_events.clone();
-                        let config = config.clone();
-                        let file_events = file_events.clone();
-
-                        let socket_addr = socket.ip().to_string();
-                        log::info!("New connection from {}", &socket_addr);
-
-                        let mut handlers = handlers.lock().await;
-                        if !handlers.contains_key(&socket_addr) {
-                            handlers.insert(socket_addr, stream);
-                        } else {
-                            let command_stream = handlers.remove(&socket_addr).unwrap();
-                            let file_stream = stream;
-
-                            tokio::spawn(async move {
-                                let (frame_reader, frame_writer) = frame_stream(command_stream);
-                                let (file_receiver, file_sender) =
-                                    file_streamers(file_stream, &config, socket_addr.clone());
-
-                                let mut handler = ServerPeerHandler::new(
+                let accepted = tokio::select! {
+                    _ = cancel.cancelled() => {
+                        log::info!("Server stopped listening");
+                        break;
+                    }
+                    accepted = listener.accept() => accepted,
+                };
+
+                if let Ok((stream, socket_addr)) = accepted {
+                    // each session opens two connections, the session is recorded with the first one
+                    let opens_session = !handlers.lock().await.contains_key(&socket_addr);
+
+                    if pause_switch.is_halted() {
+                        log::warn!(
+                            "synchronization is halted, refusing connection from {}",
+                            config.peer_name(&socket_addr)
+                        );
+                        session_event(
+                            &events,
+                            &config,
+                            &socket_addr,
+                            PeerActivity::Refused {
+                                reason: "the synchronization is halted".to_string(),
+                            },
+                        );
+                        continue;
+                    }
+                    if opens_session {
+                        session_event(&events, &config, &socket_addr, PeerActivity::Connected);
+                    }
+                    match authorizations.check(&socket_addr, &config) {
+                        Authorization::Accepted => {
+                            if opens_session {
+                                session_event(
+                                    &events,
                                     &config,
-                                    frame_reader,
-                                    frame_writer,
-                                    file_receiver,
-                                    file_sender,
-                                    socket_addr.clone(),
+                                    &socket_addr,
+                                    PeerActivity::Accepted,
                                 );
+                            }
+                        }
+                        Authorization::Rejected => {
+                            log::debug!("refusing connection from rejected peer {}", socket_addr);
+                            session_event(
+                                &events,
+                                &config,
+                                &socket_addr,
+                                PeerActivity::Refused {
+                                    reason: "the host was rejected".to_string(),
+                                },
+                            );
+                            continue;
+                        }
+                        Authorization::Pending(first_time) => {
+                            log::warn!(
+                                "refusing connection from unknown peer {}, it is waiting for authorization",
+                                socket_addr
+                            );
+                            session_event(
+                                &events,
+                                &config,
+                                &socket_addr,
+                                PeerActivity::Refused {
+                                    reason: "the host is waiting for authorization".to_string(),
+                                },
+                            );
+                            if first_time {
+                                events.emit(Event::PeerAwaitingAuthorization {
+                                    address: socket_addr,
+                                });
+                            }
+                            continue;
+                        }
+                    }
+
+                    let sync_events = sync_events.clone();
+                    let config = config.clone();
+                    let file_events = file_events.clone();
+                    let events = events.clone();
+                    let alias_locks = alias_locks.clone();
+                    let cancel = pause_switch.session_token();
+                    let pause_switch = pause_switch.clone();
+
+                    log::info!("New connection from {}", config.peer_name(&socket_addr));
+
+                    let mut handlers = handlers.lock().await;
+                    if let Some(command_stream) = handlers.remove(&socket_addr) {
+                        let file_stream = stream;
 
-                                match handler.handle_events(sync_events, &file_events).await {
-                                    Ok(()) => {
-                                        log::info!("Peer connection closed: {}", socket_addr)
-                                    }
-                                    Err(err) => {
-                                        log::error!("Some error ocurred while handling events from peer: {}", err)
-                                    }
+                        tokio::spawn(async move {
+                            let (frame_reader, frame_writer) = frame_stream(command_stream);
+                            let zero_copy = ZeroCopySocket::of(&file_stream, &config);
+                            let (file_receiver, file_sender) =
+                                file_streamers(file_stream, &config, &events, socket_addr.clone());
+                            let file_receiver = file_receiver.with_cancellation(cancel.clone());
+                            let mut file_sender = file_sender
+                                .with_cancellation(cancel.clone())
+                                .with_zero_copy(zero_copy);
+                            file_sender.limit_bandwidth(
+                                locality::bandwidth_limit(&socket_addr, &config).await,
+                            );
+
+                            events.versions().start_session();
+                            let mut handler = ServerPeerHandler::new(
+                                &config,
+                                frame_reader,
+                                frame_writer,
+                                file_receiver,
+                                file_sender,
+                                socket_addr.clone(),
+                                &alias_locks,
+                                &events,
+                            )
+                            .with_cancellation(cancel)
+                            .with_pause_switch(pause_switch);
+
+                            let reason = match handler
+                                .handle_events(sync_events, &file_events)
+                                .await
+                            {
+                                Ok(()) => {
+                                    log::info!(
+                                        "Peer connection closed: {}",
+                                        config.peer_name(&socket_addr)
+                                    );
+                                    None
+                                }
+                                Err(err) => {
+                                    log::error!(
+                                        "Some error ocurred while handling events from peer: {}",
+                                        err
+                                    );
+                                    Some(err.to_string())
                                 }
+                            };
 
-                                handler.close().await;
-                            });
-                        }
+                            handler.close().await;
+                            if let Err(err) = events.versions().end_session().await {
+                                log::error!(
+                                    "cannot save the versions kept from {}: {}",
+                                    config.peer_name(&socket_addr),
+                                    err
+                                );
+                            }
+                            session_event(
+                                &events,
+                                &config,
+                                &socket_addr,
+                                PeerActivity::Disconnected { reason },
+                            );
+                        });
+                    } else {
+                        handlers.insert(socket_addr, stream);
                     }
-                    Err(_) => {}
                 }
             }
-        });
+        }));
 
         Ok(())
     }
 }
+
+/// Emits an [Event::PeerSession] for the peer at `address`
+fn session_event(events: &EventBus, config: &Config, address: &str, activity: PeerActivity) {
+    events.emit(Event::PeerSession {
+        peer: config.peer_name(address),
+        address: address.to_owned(),
+        activity,
+    });
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.abort();
+        }
+        if let Some(resolver) = self.resolver.take() {
+            resolver.abort();
+        }
+    }
+}
@@ -1,19 +1,37 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, io::SeekFrom, path::PathBuf, sync::Arc};
 use tokio::{
     fs::File,
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite},
     sync::mpsc::Sender,
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::Config, fs, fs::FileInfo, sync::file_events_buffer::FileEventsBuffer, sync::SyncEvent,
+    config::{self, CaseCollisionPolicy, Config},
+    events::{Decision, Event, EventBus, PeerActivity},
+    fs,
+    fs::FileInfo,
+    on_demand::Placeholders,
+    remote_aliases::{self, AliasStats},
+    sync::alias_locks::AliasLocks,
+    sync::file_events_buffer::FileEventsBuffer,
+    sync::pause_switch::PauseSwitch,
+    sync::SyncEvent,
     IronCarrierError,
 };
 
-use crate::network::streaming::{FileReceiver, FileSender, FrameMessage, FrameReader, FrameWriter};
+use crate::spool::SortedReader;
+
+use crate::network::capacity::PeerCapacity;
+use crate::network::streaming::{
+    ChecksumMode, FileReceiver, FileSender, FrameMessage, FrameReader, FrameWriter,
+};
 
 type RpcResult<T> = Result<T, IronCarrierError>;
 
+/// Number of files sent in each page of a file list
+const FILE_LIST_PAGE_SIZE: usize = 1000;
+
 pub(crate) struct ServerPeerHandler<'a, TReader, TWriter>
 where
     TReader: AsyncRead + Unpin,
@@ -27,6 +45,16 @@ where
     socket_addr: String,
     sync_notifier: Option<Arc<tokio::sync::Notify>>,
     bounce_invalid_messages: bool,
+    /// File list being sent in pages, for the alias, with the offset of the next file
+    file_list: Option<(String, SortedReader<FileInfo>, u64)>,
+    file_list_page_size: usize,
+    alias_locks: &'a AliasLocks,
+    events: &'a EventBus,
+    /// Folders of each alias synchronized by the peer, see [Config::subscriptions]
+    peer_subscriptions: HashMap<String, Vec<PathBuf>>,
+    /// Aliases paused here are hidden from the peer, see [Config::paused_aliases]
+    pause_switch: Option<Arc<PauseSwitch>>,
+    cancel: CancellationToken,
 }
 
 impl<'a, TReader, TWriter> ServerPeerHandler<'a, TReader, TWriter>
@@ -34,6 +62,7 @@ where
     TReader: AsyncRead + Unpin,
     TWriter: AsyncWrite + Unpin,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: &'a Config,
         frame_reader: FrameReader<TReader>,
@@ -41,6 +70,8 @@ where
         file_receiver: FileReceiver<'a, TReader>,
         file_sender: FileSender<TWriter>,
         socket_addr: String,
+        alias_locks: &'a AliasLocks,
+        events: &'a EventBus,
     ) -> Self {
         Self {
             config,
@@ -51,30 +82,291 @@ where
             socket_addr,
             sync_notifier: None,
             bounce_invalid_messages: false,
+            file_list: None,
+            file_list_page_size: FILE_LIST_PAGE_SIZE,
+            alias_locks,
+            events,
+            peer_subscriptions: HashMap::new(),
+            pause_switch: None,
+            cancel: CancellationToken::new(),
         }
     }
 
-    fn should_sync_file(&self, remote_file: &FileInfo) -> bool {
-        !remote_file.is_local_file_newer(&self.config)
+    /// Stops handling the peer requests when `cancel` is cancelled
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
     }
 
-    async fn get_file_list(&self, alias: &str) -> RpcResult<Vec<FileInfo>> {
-        let path = self
-            .config
+    /// Hides the aliases paused in `pause_switch` from the peer
+    pub fn with_pause_switch(mut self, pause_switch: Arc<PauseSwitch>) -> Self {
+        self.pause_switch = Some(pause_switch);
+        self
+    }
+
+    fn is_alias_paused(&self, alias: &str) -> bool {
+        self.pause_switch
+            .as_ref()
+            .is_some_and(|pause_switch| pause_switch.is_alias_paused(alias))
+    }
+
+    /// Returns the root of `alias`, failing if it isn't configured or it is paused
+    fn alias_path(&self, alias: &str) -> RpcResult<&'a PathBuf> {
+        let config: &'a Config = self.config;
+        config
             .paths
             .get(alias)
-            .ok_or_else(|| IronCarrierError::AliasNotAvailable(alias.to_owned()))?;
-        crate::fs::walk_path(path, alias)
+            .filter(|_| !self.is_alias_paused(alias))
+            .ok_or_else(|| IronCarrierError::AliasNotAvailable(alias.to_owned()))
+    }
+
+    fn should_sync_file(&self, remote_file: &FileInfo) -> bool {
+        self.accepts_file(remote_file) && !self.is_placeholder(remote_file)
+    }
+
+    /// Returns true if `remote_file` is kept as a placeholder instead of being received, see [Config::on_demand]
+    fn is_placeholder(&self, remote_file: &FileInfo) -> bool {
+        self.config.on_demand.contains(&remote_file.alias)
+            && !remote_file
+                .get_absolute_path(self.config)
+                .is_ok_and(|path| path.exists())
+    }
+
+    /// Keeps the metadata of the `remote_files` that are not downloaded, see [ServerPeerHandler::is_placeholder]
+    async fn keep_placeholders(&self, remote_files: &[FileInfo]) {
+        let placeholders: Vec<FileInfo> = remote_files
+            .iter()
+            .filter(|remote_file| {
+                self.accepts_file(remote_file) && self.is_placeholder(remote_file)
+            })
+            .cloned()
+            .collect();
+        let root_path = match placeholders
+            .first()
+            .and_then(|file| self.config.paths.get(&file.alias))
+        {
+            Some(root_path) => root_path,
+            None => return,
+        };
+
+        if let Err(err) = Placeholders::new(root_path).add(&placeholders).await {
+            log::error!("cannot keep placeholders: {}", err);
+        }
+    }
+
+    /// Returns true if `file` is synchronized with the peer, it is inside the subscriptions of both sides
+    fn in_scope(&self, file: &FileInfo) -> bool {
+        config::is_subscribed(&self.config.subscriptions, &file.alias, &file.path)
+            && config::is_subscribed(&self.peer_subscriptions, &file.alias, &file.path)
+    }
+
+    /// Returns true if `remote_file` can be written to this node
+    fn accepts_file(&self, remote_file: &FileInfo) -> bool {
+        if self.is_alias_paused(&remote_file.alias) {
+            log::debug!(
+                "ignoring file {:?}, alias {} is paused",
+                remote_file.path,
+                remote_file.alias
+            );
+            return false;
+        }
+
+        if !self.in_scope(remote_file) {
+            log::debug!("ignoring file {:?}, it is not subscribed", remote_file.path);
+            return false;
+        }
+
+        if let Err(err) = fs::check_representable(&remote_file.path) {
+            self.events
+                .error_reports()
+                .warn("ignoring file", &remote_file.path, &err);
+            return false;
+        }
+
+        if self
+            .config
+            .ignores_hidden(&remote_file.alias, &remote_file.path)
+        {
+            log::debug!("ignoring hidden file {:?}", remote_file.path);
+            return false;
+        }
+
+        if self
+            .config
+            .exceeds_max_depth(&remote_file.alias, &remote_file.path)
+        {
+            log::debug!("ignoring file {:?}, it is too deep", remote_file.path);
+            return false;
+        }
+
+        if !self
+            .config
+            .is_included(&remote_file.alias, &remote_file.path)
+        {
+            log::debug!("ignoring file {:?}, it is not included", remote_file.path);
+            return false;
+        }
+
+        if self.config.case_collision_policy == CaseCollisionPolicy::Skip {
+            let existing = remote_file
+                .get_absolute_path(self.config)
+                .ok()
+                .and_then(|path| fs::find_case_collision(&path));
+            if let Some(existing) = existing {
+                log::warn!(
+                    "ignoring file {:?}, it collides with {:?}",
+                    remote_file.path,
+                    existing
+                );
+                return false;
+            }
+        }
+
+        !remote_file.is_local_file_newer(self.config)
+    }
+
+    /// Checks the files the peer wants to send fit the alias quota and the disk, the peer is told when they don't
+    async fn check_quota(&self, files: &[FileInfo]) -> RpcResult<()> {
+        let checked = match self.file_receiver.check_quota(files).await {
+            Ok(()) => self.file_receiver.check_available_space(files),
+            Err(err) => Err(err),
+        };
+        match checked {
+            Ok(()) => Ok(()),
+            Err(err) => match err.downcast::<IronCarrierError>() {
+                Ok(err) => Err(*err),
+                Err(err) => {
+                    log::error!("cannot check quota: {}", err);
+                    Err(IronCarrierError::IOReadingError)
+                }
+            },
+        }
+    }
+
+    async fn get_file_list(&self, alias: &str) -> RpcResult<Vec<FileInfo>> {
+        let path = self.alias_path(alias)?;
+        let mut files = crate::fs::walk_path(path, alias, self.config)
+            .await
+            .map_err(|_| IronCarrierError::IOReadingError)?;
+        files.retain(|file| self.in_scope(file));
+        Ok(files)
+    }
+
+    /// Returns the files of `alias` starting at `offset`, and true if it is the last page  
+    /// The list is read when the first page is requested and kept until the last page is sent
+    async fn get_file_list_page(
+        &mut self,
+        alias: &str,
+        offset: u64,
+    ) -> RpcResult<(Vec<FileInfo>, bool)> {
+        let (mut files, mut next_offset) = match self.file_list.take() {
+            Some((cached_alias, files, next_offset))
+                if offset >= next_offset && cached_alias == alias =>
+            {
+                (files, next_offset)
+            }
+            _ => {
+                let path = self.alias_path(alias)?;
+                let files = crate::fs::scan_path(path, alias, self.config, &self.cancel)
+                    .await
+                    .and_then(|files| files.reader())
+                    .map_err(|_| IronCarrierError::IOReadingError)?;
+                (files, 0)
+            }
+        };
+
+        let mut page = Vec::with_capacity(self.file_list_page_size);
+        let mut last_page = false;
+        while page.len() < self.file_list_page_size {
+            match files
+                .next_entry()
+                .map_err(|_| IronCarrierError::IOReadingError)?
+            {
+                // files out of scope are not counted, the offsets of the pages stay the same
+                Some(file) if !self.in_scope(&file) => continue,
+                Some(_) if next_offset < offset => {}
+                Some(file) => page.push(file),
+                None => {
+                    last_page = true;
+                    break;
+                }
+            }
+            next_offset += 1;
+        }
+
+        if !last_page {
+            self.file_list = Some((alias.to_owned(), files, next_offset));
+        }
+
+        Ok((page, last_page))
+    }
+
+    /// Returns the content hash of the local file with the same path as `remote_file`
+    async fn get_file_hash(&self, remote_file: &FileInfo) -> RpcResult<String> {
+        let file_path = remote_file
+            .get_absolute_path(self.config)
+            .map_err(|_| IronCarrierError::AliasNotAvailable(remote_file.alias.to_owned()))?;
+
+        let algorithm = self.config.hashing(&remote_file.alias).algorithm;
+        crate::manifest::hash_file(&file_path, algorithm)
             .await
             .map_err(|_| IronCarrierError::IOReadingError)
     }
 
+    /// Opens the local file with the same path as `remote_file`, positioned at `offset`  
+    /// Fails if the local file doesn't have the expected size, since the requested range wouldn't match
+    async fn open_file_range(&self, remote_file: &FileInfo, offset: u64) -> RpcResult<File> {
+        let file_path = remote_file
+            .get_absolute_path(self.config)
+            .map_err(|_| IronCarrierError::AliasNotAvailable(remote_file.alias.to_owned()))?;
+
+        let mut file = File::open(file_path)
+            .await
+            .map_err(|_| IronCarrierError::IOReadingError)?;
+        let size = file
+            .metadata()
+            .await
+            .map_err(|_| IronCarrierError::IOReadingError)?
+            .len();
+        if Some(size) != remote_file.size {
+            return Err(IronCarrierError::IOReadingError);
+        }
+
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|_| IronCarrierError::IOReadingError)?;
+
+        Ok(file)
+    }
+
+    /// Returns the hash of each alias, paused aliases are left out, so the peer doesn't synchronize them
     async fn server_sync_hash(&self) -> RpcResult<HashMap<String, u64>> {
-        crate::fs::get_hash_for_alias(&self.config.paths)
+        let mut hashes = crate::fs::get_hash_for_alias(self.config)
+            .await
+            .map_err(|_| IronCarrierError::IOReadingError)?;
+        hashes.retain(|alias, _| !self.is_alias_paused(alias));
+        Ok(hashes)
+    }
+
+    /// Returns the stats of the `aliases` of the peer that this node has too, paused aliases are left out
+    async fn shared_alias_stats(&self, mut aliases: Vec<String>) -> RpcResult<Vec<AliasStats>> {
+        aliases.retain(|alias| !self.is_alias_paused(alias));
+        remote_aliases::alias_stats(self.config, &aliases)
             .await
             .map_err(|_| IronCarrierError::IOReadingError)
     }
 
+    /// Answers the last request with `err` instead of its usual response, the peer returns it from the call
+    async fn reply_error(&mut self, err: IronCarrierError) -> crate::Result<()> {
+        log::debug!(
+            "replying error to peer {}: {}",
+            self.config.peer_name(&self.socket_addr),
+            err
+        );
+        let response = FrameMessage::new("error").with_arg(&err)?;
+        self.frame_writer.write_frame(response).await
+    }
+
     pub async fn close(&mut self) {
         if self.sync_notifier.is_some() {
             self.sync_notifier.as_ref().unwrap().notify_one();
@@ -82,13 +374,22 @@ where
         }
     }
 
-    pub async fn handle_events<'b>(
+    pub async fn handle_events(
         &mut self,
         sync_events: Sender<SyncEvent>,
-        file_events_buffer: &'b FileEventsBuffer,
+        file_events_buffer: &FileEventsBuffer,
     ) -> crate::Result<()> {
         loop {
-            match self.frame_reader.next_frame().await? {
+            let frame = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => {
+                    log::info!("stopped handling events from peer {}", self.config.peer_name(&self.socket_addr));
+                    return Ok(());
+                }
+                frame = self.frame_reader.next_frame() => frame?,
+            };
+
+            match frame {
                 Some(mut message) => match message.frame_ident() {
                     "set_peer_port" => {
                         let port = message.next_arg::<u32>()?;
@@ -106,6 +407,53 @@ where
                         self.frame_writer.write_frame(response).await?;
                     }
 
+                    "query_capacity" => {
+                        let peer_capacity = message.next_arg::<PeerCapacity>()?;
+                        log::debug!("peer advertised capacity {:?}", peer_capacity);
+                        self.file_sender
+                            .limit_chunk_size(peer_capacity.max_chunk_size);
+
+                        let local_capacity = PeerCapacity::local(self.config);
+                        let sampled_checksum = peer_capacity.sampled_checksum_with(&local_capacity);
+                        self.file_sender.set_sampled_checksum(sampled_checksum);
+                        self.file_receiver.set_sampled_checksum(sampled_checksum);
+
+                        let response =
+                            FrameMessage::new("query_capacity").with_arg(&local_capacity)?;
+
+                        self.frame_writer.write_frame(response).await?;
+                    }
+
+                    "exchange_subscriptions" => {
+                        self.peer_subscriptions =
+                            message.next_arg::<HashMap<String, Vec<PathBuf>>>()?;
+                        log::debug!("peer subscribed to {:?}", self.peer_subscriptions);
+                        let response = FrameMessage::new("exchange_subscriptions")
+                            .with_arg(&self.config.subscriptions)?;
+                        self.frame_writer.write_frame(response).await?;
+                    }
+
+                    "query_peers" => {
+                        log::debug!("peer requested the peers list");
+                        let peers: Vec<&String> = self
+                            .config
+                            .peers
+                            .iter()
+                            .flatten()
+                            .filter(|address| **address != self.socket_addr)
+                            .collect();
+                        let response = FrameMessage::new("query_peers").with_arg(&peers)?;
+                        self.frame_writer.write_frame(response).await?;
+                    }
+
+                    "query_aliases" => {
+                        let aliases = message.next_arg::<Vec<String>>()?;
+                        log::debug!("peer requested the stats of aliases {:?}", aliases);
+                        let response = FrameMessage::new("query_aliases")
+                            .with_arg(&self.shared_alias_stats(aliases).await)?;
+                        self.frame_writer.write_frame(response).await?;
+                    }
+
                     "query_file_list" => {
                         let alias = message.next_arg::<String>()?;
                         log::debug!("peer requested file list for alias {}", alias);
@@ -114,20 +462,152 @@ where
                         self.frame_writer.write_frame(response).await?;
                     }
 
+                    "query_file_list_page" => {
+                        let alias = message.next_arg::<String>()?;
+                        let offset = message.next_arg::<u64>()?;
+                        log::debug!("peer requested files of alias {} from {}", alias, offset);
+                        let response = FrameMessage::new("query_file_list_page")
+                            .with_arg(&self.get_file_list_page(&alias, offset).await)?;
+                        self.frame_writer.write_frame(response).await?;
+                    }
+
                     "create_or_update_file" => {
                         let remote_file = message.next_arg::<FileInfo>()?;
                         log::debug!("peer request to send file {:?}", remote_file.path);
+                        self.keep_placeholders(std::slice::from_ref(&remote_file))
+                            .await;
 
-                        if self.should_sync_file(&remote_file) {
+                        if !self.should_sync_file(&remote_file) {
+                            let response = FrameMessage::new("create_or_update_file")
+                                .with_arg(&RpcResult::Ok(0u64))?;
+                            self.frame_writer.write_frame(response).await?;
+                        } else if let Err(err) =
+                            self.check_quota(std::slice::from_ref(&remote_file)).await
+                        {
+                            let response = FrameMessage::new("create_or_update_file")
+                                .with_arg(&RpcResult::<u64>::Err(err))?;
+                            self.frame_writer.write_frame(response).await?;
+                        } else {
+                            let _lock = self.alias_locks.lock(&remote_file.alias).await;
                             let file_handle = self.file_receiver.prepare_file_transfer(remote_file);
                             let response = FrameMessage::new("create_or_update_file")
-                                .with_arg(&file_handle)?;
+                                .with_arg(&RpcResult::Ok(file_handle))?;
+                            self.frame_writer.write_frame(response).await?;
+                            self.file_receiver.wait_files(file_events_buffer).await?;
+                        }
+                    }
+
+                    "create_or_update_files" => {
+                        let remote_files = message.next_arg::<Vec<FileInfo>>()?;
+                        log::debug!("peer request to send {} files", remote_files.len());
+                        self.keep_placeholders(&remote_files).await;
+
+                        let accepted: Vec<bool> = remote_files
+                            .iter()
+                            .map(|remote_file| self.should_sync_file(remote_file))
+                            .collect();
+                        let batch: Vec<FileInfo> = remote_files
+                            .into_iter()
+                            .zip(accepted.iter())
+                            .filter(|(_, accepted)| **accepted)
+                            .map(|(remote_file, _)| remote_file)
+                            .collect();
+
+                        if batch.is_empty() {
+                            let response = FrameMessage::new("create_or_update_files")
+                                .with_arg(&RpcResult::Ok((0u64, accepted)))?;
+                            self.frame_writer.write_frame(response).await?;
+                        } else if let Err(err) = self.check_quota(&batch).await {
+                            let response = FrameMessage::new("create_or_update_files")
+                                .with_arg(&RpcResult::<(u64, Vec<bool>)>::Err(err))?;
                             self.frame_writer.write_frame(response).await?;
-                            self.file_receiver.wait_files(&file_events_buffer).await?;
                         } else {
+                            let _lock = self.alias_locks.lock(&batch[0].alias).await;
+                            let batch_handle = self.file_receiver.prepare_batch_transfer(batch);
+                            let response = FrameMessage::new("create_or_update_files")
+                                .with_arg(&RpcResult::Ok((batch_handle, accepted)))?;
+                            self.frame_writer.write_frame(response).await?;
+                            self.file_receiver.wait_files(file_events_buffer).await?;
+                        }
+                    }
+
+                    "create_pack" | "create_group" => {
+                        // atomic groups are sent like packs, the receiver only applies them once complete
+                        let rpc = message.frame_ident().to_owned();
+                        let remote_files = message.next_arg::<Vec<FileInfo>>()?;
+                        log::debug!("peer request to send pack of {} files", remote_files.len());
+                        self.keep_placeholders(&remote_files).await;
+
+                        let accepted: Vec<bool> = remote_files
+                            .iter()
+                            .map(|remote_file| self.should_sync_file(remote_file))
+                            .collect();
+                        let pack: Vec<FileInfo> = remote_files
+                            .into_iter()
+                            .zip(accepted.iter())
+                            .filter(|(_, accepted)| **accepted)
+                            .map(|(remote_file, _)| remote_file)
+                            .collect();
+
+                        if pack.is_empty() {
+                            let response = FrameMessage::new(&rpc)
+                                .with_arg(&RpcResult::Ok((0u64, accepted)))?;
+                            self.frame_writer.write_frame(response).await?;
+                        } else if let Err(err) = self.check_quota(&pack).await {
                             let response =
-                                FrameMessage::new("create_or_update_file").with_arg(&0u64)?;
+                                FrameMessage::new(&rpc)
+                                    .with_arg(&RpcResult::<(u64, Vec<bool>)>::Err(err))?;
+                            self.frame_writer.write_frame(response).await?;
+                        } else {
+                            let _lock = self.alias_locks.lock(&pack[0].alias).await;
+                            let pack_handle = match rpc.as_str() {
+                                "create_group" => self.file_receiver.prepare_group_transfer(pack),
+                                _ => self.file_receiver.prepare_pack_transfer(pack),
+                            };
+                            let response = FrameMessage::new(&rpc)
+                                .with_arg(&RpcResult::Ok((pack_handle, accepted)))?;
                             self.frame_writer.write_frame(response).await?;
+                            self.file_receiver.wait_files(file_events_buffer).await?;
+                        }
+                    }
+
+                    "request_pack" | "request_group" => {
+                        let rpc = message.frame_ident().to_owned();
+                        let remote_files = message.next_arg::<Vec<FileInfo>>()?;
+                        let pack_handle = message.next_arg::<u64>()?;
+
+                        log::debug!("peer request pack of {} files", remote_files.len());
+
+                        let response = FrameMessage::new(&rpc).with_arg(&RpcResult::Ok(()))?;
+                        self.frame_writer.write_frame(response).await?;
+                        self.file_sender.start_pack(pack_handle).await?;
+                        for remote_file in remote_files.iter() {
+                            match crate::fs::open_content(remote_file, self.config).await {
+                                Ok(mut file) => {
+                                    self.file_sender
+                                        .send_pack_entry(Some((
+                                            &mut file,
+                                            remote_file.content_size(),
+                                        )))
+                                        .await?;
+                                    crate::fs::keep_merge_base(
+                                        remote_file,
+                                        self.config,
+                                        self.events.versions(),
+                                    )
+                                    .await;
+                                }
+                                Err(err) => {
+                                    self.events.error_reports().error(
+                                        "cannot read file",
+                                        &remote_file.path,
+                                        &err,
+                                    );
+                                    self.file_sender
+                                        .send_pack_entry::<crate::BoxedStream>(None)
+                                        .await?;
+                                }
+                            }
                         }
                     }
 
@@ -137,25 +617,159 @@ where
 
                         log::debug!("peer request file {:?}", remote_file.path);
 
-                        let file_path = remote_file.get_absolute_path(&self.config)?;
+                        match crate::fs::open_content(&remote_file, self.config).await {
+                            Ok(mut file) => {
+                                log::debug!("sending file to peer: {}", remote_file.size.unwrap());
+                                let response = FrameMessage::new("request_file")
+                                    .with_arg(&RpcResult::Ok(()))?;
+                                self.frame_writer.write_frame(response).await?;
+                                let checksum =
+                                    self.file_sender.checksum_mode(remote_file.content_size());
+                                match crate::fs::local_content_path(&remote_file, self.config) {
+                                    Some(path) if self.file_sender.is_zero_copy() => {
+                                        drop(file);
+                                        self.file_sender
+                                            .send_local_file(file_handle, &path, checksum)
+                                            .await?
+                                    }
+                                    _ => {
+                                        self.file_sender
+                                            .send_file(file_handle, &mut file, checksum)
+                                            .await?
+                                    }
+                                }
+                                crate::fs::keep_merge_base(
+                                    &remote_file,
+                                    self.config,
+                                    self.events.versions(),
+                                )
+                                .await;
+
+                                log::debug!("file sent {:?}", remote_file.path);
+                            }
+                            Err(err) => {
+                                self.events.error_reports().error(
+                                    "cannot read file",
+                                    &remote_file.path,
+                                    &err,
+                                );
+                                let response = FrameMessage::new("request_file").with_arg(
+                                    &RpcResult::<()>::Err(IronCarrierError::IOReadingError),
+                                )?;
+                                self.frame_writer.write_frame(response).await?;
+                            }
+                        }
+                    }
+
+                    "query_file_hash" => {
+                        let remote_file = message.next_arg::<FileInfo>()?;
+                        log::debug!("peer requested hash of file {:?}", remote_file.path);
+                        let response = FrameMessage::new("query_file_hash")
+                            .with_arg(&self.get_file_hash(&remote_file).await)?;
+                        self.frame_writer.write_frame(response).await?;
+                    }
 
-                        log::debug!("sending file to peer: {}", remote_file.size.unwrap());
-                        let mut file = File::open(file_path).await?;
+                    "request_file_range" => {
+                        let remote_file = message.next_arg::<FileInfo>()?;
+                        let file_handle = message.next_arg::<u64>()?;
+                        let offset = message.next_arg::<u64>()?;
+                        let length = message.next_arg::<u64>()?;
 
-                        self.frame_writer.write_frame("request_file".into()).await?;
-                        self.file_sender.send_file(file_handle, &mut file).await?;
+                        log::debug!(
+                            "peer request {} bytes of file {:?} from {}",
+                            length,
+                            remote_file.path,
+                            offset
+                        );
 
-                        log::debug!("file sent {:?}", remote_file.path);
+                        match self.open_file_range(&remote_file, offset).await {
+                            Ok(file) => {
+                                let response = FrameMessage::new("request_file_range")
+                                    .with_arg(&RpcResult::Ok(()))?;
+                                self.frame_writer.write_frame(response).await?;
+                                // parts are always hashed in full, the assembled file is verified by its content hash
+                                self.file_sender
+                                    .send_file(
+                                        file_handle,
+                                        &mut file.take(length),
+                                        ChecksumMode::Full,
+                                    )
+                                    .await?;
+                            }
+                            Err(err) => {
+                                self.events.error_reports().error(
+                                    "cannot read file",
+                                    &remote_file.path,
+                                    &err,
+                                );
+                                let response = FrameMessage::new("request_file_range")
+                                    .with_arg(&RpcResult::<()>::Err(err))?;
+                                self.frame_writer.write_frame(response).await?;
+                            }
+                        }
                     }
 
                     "delete_file" => {
                         let remote_file = message.next_arg::<FileInfo>()?;
 
                         log::debug!("peer requested to delete file {:?}", remote_file.path);
-
-                        file_events_buffer.add_event(&remote_file, &self.socket_addr);
-
-                        fs::delete_file(&remote_file, &self.config).await?;
+                        if self.alias_path(&remote_file.alias).is_err() {
+                            self.reply_error(IronCarrierError::AliasNotAvailable(
+                                remote_file.alias,
+                            ))
+                            .await?;
+                            continue;
+                        }
+                        let _lock = self.alias_locks.lock(&remote_file.alias).await;
+
+                        if self
+                            .config
+                            .ignores_hidden(&remote_file.alias, &remote_file.path)
+                            || self
+                                .config
+                                .exceeds_max_depth(&remote_file.alias, &remote_file.path)
+                            || !self
+                                .config
+                                .is_included(&remote_file.alias, &remote_file.path)
+                            || !self.in_scope(&remote_file)
+                        {
+                            log::debug!(
+                                "ignoring deletion of {:?}, it is not synchronized",
+                                remote_file.path
+                            );
+                        } else if !self
+                            .config
+                            .delete_propagation(&remote_file.alias)
+                            .incoming()
+                        {
+                            log::debug!(
+                                "ignoring deletion of {:?}, deletions of peers are not applied",
+                                remote_file.path
+                            );
+                        } else if self
+                            .events
+                            .decide(|observer| observer.on_delete(&remote_file))
+                            == Decision::Veto
+                        {
+                            log::info!(
+                                "deletion of {:?} was refused by an observer",
+                                remote_file.path
+                            );
+                        } else {
+                            file_events_buffer.add_event(&remote_file, &self.socket_addr);
+                            if let Err(err) =
+                                fs::delete_file(&remote_file, self.config, self.events.versions())
+                                    .await
+                            {
+                                self.events.error_reports().error(
+                                    "cannot delete file",
+                                    &remote_file.path,
+                                    &err,
+                                );
+                                self.reply_error(IronCarrierError::IOWritingError).await?;
+                                continue;
+                            }
+                        }
                         self.frame_writer.write_frame("delete_file".into()).await?;
                     }
 
@@ -169,10 +783,51 @@ where
                             dest_file.path
                         );
 
+                        if self.alias_path(&src_file.alias).is_err() {
+                            self.reply_error(IronCarrierError::AliasNotAvailable(src_file.alias))
+                                .await?;
+                            continue;
+                        }
+                        if let Err(err) = fs::check_representable(&dest_file.path) {
+                            log::warn!("refusing move to {:?}: {}", dest_file.path, err);
+                            self.reply_error(IronCarrierError::PathRejected(err.to_string()))
+                                .await?;
+                            continue;
+                        }
+                        if !self.in_scope(&src_file) || !self.in_scope(&dest_file) {
+                            log::warn!(
+                                "refusing move to {:?}, it is not subscribed",
+                                dest_file.path
+                            );
+                            self.reply_error(IronCarrierError::PathRejected(
+                                "the file is not in the subscribed folders".to_owned(),
+                            ))
+                            .await?;
+                            continue;
+                        }
+                        if !self.config.is_included(&src_file.alias, &src_file.path)
+                            || !self.config.is_included(&dest_file.alias, &dest_file.path)
+                        {
+                            log::warn!("refusing move to {:?}, it is not included", dest_file.path);
+                            self.reply_error(IronCarrierError::PathRejected(
+                                "the file is not included in the alias".to_owned(),
+                            ))
+                            .await?;
+                            continue;
+                        }
+
+                        let _lock = self.alias_locks.lock(&src_file.alias).await;
                         file_events_buffer.add_event(&src_file, &self.socket_addr);
                         file_events_buffer.add_event(&dest_file, &self.socket_addr);
-
-                        fs::move_file(&src_file, &dest_file, &self.config).await?;
+                        if let Err(err) = fs::move_file(&src_file, &dest_file, self.config).await {
+                            self.events.error_reports().error(
+                                "cannot move file",
+                                &src_file.path,
+                                &err,
+                            );
+                            self.reply_error(IronCarrierError::IOWritingError).await?;
+                            continue;
+                        }
 
                         self.frame_writer.write_frame("move_file".into()).await?;
                     }
@@ -221,7 +876,16 @@ where
                         if self.bounce_invalid_messages {
                             self.frame_writer.write_frame(message_name.into()).await?;
                         } else {
-                            return Err(IronCarrierError::ParseCommandError.into());
+                            log::warn!("peer sent unknown command {}", message_name);
+                            self.events.emit(Event::PeerSession {
+                                peer: self.config.peer_name(&self.socket_addr),
+                                address: self.socket_addr.clone(),
+                                activity: PeerActivity::ProtocolViolation {
+                                    reason: format!("sent unknown command {}", message_name),
+                                },
+                            });
+                            let err = IronCarrierError::UnsupportedCommand(message_name.to_owned());
+                            self.reply_error(err).await?;
                         }
                     }
                 },
@@ -246,7 +910,11 @@ mod tests {
     };
     use tokio::io::DuplexStream;
 
-    use crate::network::streaming::{file_streamers, frame_stream};
+    use crate::{
+        events::EventBus,
+        fs::FileKind,
+        network::streaming::{file_streamers, frame_stream},
+    };
 
     use super::*;
 
@@ -279,9 +947,11 @@ mod tests {
         let config = sample_config(test_folder);
         let (events_tx, _) = tokio::sync::mpsc::channel(10);
         let files_event_buffer = Arc::new(FileEventsBuffer::new(config.clone()));
+        let events = EventBus::new();
+        let alias_locks = AliasLocks::new(&config);
 
         let (frame_reader, frame_writer) = frame_stream(command_stream);
-        let (file_receiver, file_sender) = file_streamers(file_stream, &config, "".into());
+        let (file_receiver, file_sender) = file_streamers(file_stream, &config, &events, "".into());
 
         let mut server_peer_handler = ServerPeerHandler::new(
             &config,
@@ -290,9 +960,12 @@ mod tests {
             file_receiver,
             file_sender,
             "".to_owned(),
+            &alias_locks,
+            &events,
         );
 
         server_peer_handler.bounce_invalid_messages = true;
+        server_peer_handler.file_list_page_size = 2;
         server_peer_handler
             .handle_events(events_tx, &files_event_buffer)
             .await
@@ -363,13 +1036,111 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_sends_file_list_in_pages() -> crate::Result<()> {
+        for file in ["file_1", "file_2", "file_3"].iter() {
+            create_tmp_file(
+                &Path::new("./tmp/server_sends_file_list_in_pages").join(file),
+                "",
+            );
+        }
+
+        let (client_stream, server_stream) = tokio::io::duplex(10);
+        let (_, server_file_stream) = tokio::io::duplex(10);
+
+        tokio::spawn(async move {
+            create_peer_handler(
+                "server_sends_file_list_in_pages",
+                server_stream,
+                server_file_stream,
+            )
+            .await;
+        });
+
+        let (mut reader, mut writer) = frame_stream(client_stream);
+        let mut pages = Vec::new();
+        for offset in [0u64, 2].iter() {
+            let message = FrameMessage::new("query_file_list_page")
+                .with_arg(&"a")?
+                .with_arg(offset)?;
+            writer.write_frame(message).await?;
+
+            let mut response = reader.next_frame().await?.unwrap();
+            assert_eq!(response.frame_ident(), "query_file_list_page");
+            pages.push(response.next_arg::<RpcResult<(Vec<FileInfo>, bool)>>()??);
+        }
+
+        assert_eq!(pages[0].0.len(), 2);
+        assert!(!pages[0].1);
+        assert_eq!(pages[1].0.len(), 1);
+        assert_eq!(pages[1].0[0].path, Path::new("file_3"));
+        assert!(pages[1].1);
+
+        std::fs::remove_dir_all("./tmp/server_sends_file_list_in_pages")?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_only_lists_subscribed_folders() -> crate::Result<()> {
+        for file in ["2023/file_1", "2024/file_2", "2024/file_3", "file_4"].iter() {
+            create_tmp_file(
+                &Path::new("./tmp/server_only_lists_subscribed_folders").join(file),
+                "",
+            );
+        }
+
+        let (client_stream, server_stream) = tokio::io::duplex(10);
+        let (_, server_file_stream) = tokio::io::duplex(10);
+
+        tokio::spawn(async move {
+            create_peer_handler(
+                "server_only_lists_subscribed_folders",
+                server_stream,
+                server_file_stream,
+            )
+            .await;
+        });
+
+        let (mut reader, mut writer) = frame_stream(client_stream);
+        let subscriptions = HashMap::from([("a".to_owned(), vec![PathBuf::from("2024")])]);
+        let message = FrameMessage::new("exchange_subscriptions").with_arg(&subscriptions)?;
+        writer.write_frame(message).await?;
+        let mut response = reader.next_frame().await?.unwrap();
+        assert!(response
+            .next_arg::<HashMap<String, Vec<PathBuf>>>()?
+            .is_empty());
+
+        let message = FrameMessage::new("query_file_list_page")
+            .with_arg(&"a")?
+            .with_arg(&0u64)?;
+        writer.write_frame(message).await?;
+        let mut response = reader.next_frame().await?.unwrap();
+        let (files, last_page) = response.next_arg::<RpcResult<(Vec<FileInfo>, bool)>>()??;
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| file.path.clone())
+                .collect::<Vec<_>>(),
+            vec![PathBuf::from("2024/file_2"), PathBuf::from("2024/file_3")]
+        );
+        assert!(!last_page);
+
+        std::fs::remove_dir_all("./tmp/server_only_lists_subscribed_folders")?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn server_can_receive_files() -> crate::Result<()> {
         let (client_stream, server_stream) = tokio::io::duplex(10);
         let (client_file_stream, server_file_stream) = tokio::io::duplex(10);
 
         let (mut reader, mut writer) = frame_stream(client_stream);
-        let mut file_sender = FileSender::new(client_file_stream);
+        let mut file_sender = FileSender::new(
+            client_file_stream,
+            &sample_config("server_can_receive_files"),
+        );
 
         tokio::spawn(async move {
             create_peer_handler(
@@ -394,6 +1165,8 @@ mod tests {
             created_at: Some(0),
             modified_at: Some(modified_at),
             deleted_at: None,
+            kind: FileKind::Regular,
+            extra: Default::default(),
         };
 
         let message = FrameMessage::new("create_or_update_file").with_arg(&file_info)?;
@@ -402,9 +1175,9 @@ mod tests {
         let mut response = reader.next_frame().await?.unwrap();
         assert_eq!(response.frame_ident(), "create_or_update_file");
 
-        let file_handle: u64 = response.next_arg()?;
+        let file_handle: u64 = response.next_arg::<RpcResult<u64>>()??;
         file_sender
-            .send_file(file_handle, &mut file_content)
+            .send_file(file_handle, &mut file_content, ChecksumMode::Full)
             .await?;
 
         tokio::time::sleep(Duration::from_secs(1)).await;
@@ -436,6 +1209,8 @@ mod tests {
                 created_at: None,
                 modified_at: None,
                 deleted_at: None,
+                kind: FileKind::Regular,
+                extra: Default::default(),
             };
 
             let message = FrameMessage::new("delete_file").with_arg(&file_info)?;
@@ -450,6 +1225,39 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_replies_typed_errors() -> crate::Result<()> {
+        let (client_stream, server_stream) = tokio::io::duplex(10);
+        let (_, server_file_stream) = tokio::io::duplex(10);
+        let (mut reader, mut writer) = frame_stream(client_stream);
+
+        tokio::spawn(async move {
+            create_peer_handler(
+                "server_replies_typed_errors",
+                server_stream,
+                server_file_stream,
+            )
+            .await;
+        });
+
+        let file_info = FileInfo::new_deleted("b".to_owned(), PathBuf::from("file_1"), None);
+        let message = FrameMessage::new("delete_file").with_arg(&file_info)?;
+        writer.write_frame(message).await?;
+
+        let mut response = reader.next_frame().await?.unwrap();
+        assert_eq!(response.frame_ident(), "error");
+        assert!(matches!(
+            response.next_arg::<IronCarrierError>()?,
+            IronCarrierError::AliasNotAvailable(alias) if alias == "b"
+        ));
+
+        // the connection is still usable
+        writer.write_frame("ping".into()).await?;
+        assert_eq!(reader.next_frame().await?.unwrap().frame_ident(), "ping");
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn server_can_move_files() -> crate::Result<()> {
         create_tmp_file(Path::new("./tmp/server_can_move_files/file_1"), "");
@@ -470,6 +1278,8 @@ mod tests {
                 created_at: None,
                 modified_at: None,
                 deleted_at: None,
+                kind: FileKind::Regular,
+                extra: Default::default(),
             };
 
             let dst = FileInfo {
@@ -479,6 +1289,8 @@ mod tests {
                 created_at: None,
                 modified_at: None,
                 deleted_at: None,
+                kind: FileKind::Regular,
+                extra: Default::default(),
             };
 
             let message = FrameMessage::new("move_file")
@@ -498,6 +1310,131 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_reports_unreadable_files() -> crate::Result<()> {
+        let (client_stream, server_stream) = tokio::io::duplex(10);
+        let (_, server_file_stream) = tokio::io::duplex(10);
+        let (mut reader, mut writer) = frame_stream(client_stream);
+
+        tokio::spawn(async move {
+            create_peer_handler(
+                "server_reports_unreadable_files",
+                server_stream,
+                server_file_stream,
+            )
+            .await;
+        });
+
+        let file_info = FileInfo {
+            alias: "a".to_owned(),
+            path: PathBuf::from("missing_file"),
+            size: Some(10),
+            created_at: None,
+            modified_at: Some(0),
+            deleted_at: None,
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        let message = FrameMessage::new("request_file")
+            .with_arg(&file_info)?
+            .with_arg(&1u64)?;
+        writer.write_frame(message).await?;
+
+        let mut response = reader.next_frame().await?.unwrap();
+        assert_eq!(response.frame_ident(), "request_file");
+        assert!(response.next_arg::<RpcResult<()>>()?.is_err());
+
+        std::fs::remove_dir_all("./tmp/server_reports_unreadable_files")?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_lists_shared_aliases() -> crate::Result<()> {
+        create_tmp_file(
+            Path::new("./tmp/server_lists_shared_aliases/file_1"),
+            "some content",
+        );
+        create_tmp_file(
+            Path::new("./tmp/server_lists_shared_aliases/folder/file_2"),
+            "more",
+        );
+
+        let (client_stream, server_stream) = tokio::io::duplex(10);
+        let (_, server_file_stream) = tokio::io::duplex(10);
+
+        tokio::spawn(async move {
+            create_peer_handler(
+                "server_lists_shared_aliases",
+                server_stream,
+                server_file_stream,
+            )
+            .await;
+        });
+
+        let (mut reader, mut writer) = frame_stream(client_stream);
+        let message = FrameMessage::new("query_aliases").with_arg(&vec!["a", "b"])?;
+        writer.write_frame(message).await?;
+
+        let mut response = reader.next_frame().await?.unwrap();
+        assert_eq!(response.frame_ident(), "query_aliases");
+
+        let aliases = response.next_arg::<RpcResult<Vec<AliasStats>>>()??;
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases[0].alias, "a");
+        assert_eq!(aliases[0].files, 2);
+        assert_eq!(aliases[0].bytes, 16);
+        let config = sample_config("server_lists_shared_aliases");
+        assert_eq!(
+            aliases[0].hash,
+            crate::fs::get_hash_for_alias(&config).await?["a"]
+        );
+
+        std::fs::remove_dir_all("./tmp/server_lists_shared_aliases")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_hides_paused_aliases() -> crate::Result<()> {
+        create_tmp_file(Path::new("./tmp/server_hides_paused_aliases/file"), "");
+        let config = sample_config("server_hides_paused_aliases");
+        let events = EventBus::new();
+        let alias_locks = AliasLocks::new(&config);
+        let (command_stream, _) = tokio::io::duplex(10);
+        let (file_stream, _) = tokio::io::duplex(10);
+        let (frame_reader, frame_writer) = frame_stream(command_stream);
+        let (file_receiver, file_sender) = file_streamers(file_stream, &config, &events, "".into());
+        let pause_switch = Arc::new(PauseSwitch::new(
+            Arc::new(EventBus::new()),
+            CancellationToken::new(),
+        ));
+
+        let handler = ServerPeerHandler::new(
+            &config,
+            frame_reader,
+            frame_writer,
+            file_receiver,
+            file_sender,
+            "".to_owned(),
+            &alias_locks,
+            &events,
+        )
+        .with_pause_switch(pause_switch.clone());
+        assert!(handler.server_sync_hash().await?.contains_key("a"));
+
+        pause_switch.pause_alias("a");
+        assert!(handler.server_sync_hash().await?.is_empty());
+        assert!(handler.get_file_list("a").await.is_err());
+
+        let mut file = FileInfo::new_deleted("a".to_owned(), PathBuf::from("new_file"), None);
+        file.deleted_at = None;
+        assert!(!handler.accepts_file(&file));
+
+        std::fs::remove_dir_all("./tmp/server_hides_paused_aliases")?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn server_can_send_files() -> crate::Result<()> {
         let file_size = b"Some file content".len() as u64;
@@ -526,18 +1463,22 @@ mod tests {
                 created_at: None,
                 modified_at: Some(modified_at),
                 deleted_at: None,
+                kind: FileKind::Regular,
+                extra: Default::default(),
             };
 
             let config = sample_config("server_can_send_files_2");
-            let mut receiver = FileReceiver::new(client_file_stream, &config, "a".into());
+            let events = EventBus::new();
+            let mut receiver = FileReceiver::new(client_file_stream, &config, &events, "a".into());
 
             let message = FrameMessage::new("request_file")
                 .with_arg(&file_info)?
                 .with_arg(&receiver.prepare_file_transfer(file_info))?;
             writer.write_frame(message).await?;
 
-            let response = reader.next_frame().await?.unwrap();
+            let mut response = reader.next_frame().await?.unwrap();
             assert_eq!(response.frame_ident(), "request_file");
+            assert!(response.next_arg::<RpcResult<()>>()?.is_ok());
 
             let events_buffer = FileEventsBuffer::new(config.clone());
             receiver.wait_files(&events_buffer).await?;
@@ -0,0 +1,144 @@
+//! Checksum sent after the content of each file
+//!
+//! The whole content is hashed by default. Peers that trust their network can agree on sampled checksums for large
+//! files with [crate::config::Config::sampled_checksum_min_size], only the first [SAMPLE_SIZE] bytes of every
+//! [SAMPLE_INTERVAL] and the last [SAMPLE_SIZE] bytes of the file are hashed then, so corruption outside of these ranges
+//! is not detected
+
+use sha2::{Digest, Sha256};
+
+/// Bytes hashed in each sampled range
+pub(crate) const SAMPLE_SIZE: u64 = 64 * 1024;
+/// Distance between the start of two sampled ranges
+pub(crate) const SAMPLE_INTERVAL: u64 = 16 * 1024 * 1024;
+
+/// How the content of a file is hashed by both sides of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChecksumMode {
+    /// Every byte is hashed
+    Full,
+    /// Only the sampled ranges of a file with this size are hashed
+    Sampled(u64),
+}
+
+impl ChecksumMode {
+    /// Returns the mode of a file with `size` bytes, `sampled_min_size` is the size agreed with the peer, see [negotiate]
+    pub fn for_size(size: u64, sampled_min_size: Option<u64>) -> Self {
+        match sampled_min_size {
+            Some(min_size) if size >= min_size => ChecksumMode::Sampled(size),
+            _ => ChecksumMode::Full,
+        }
+    }
+}
+
+/// Returns the smallest file checked by samples with a peer, both peers must allow sampled checksums and the largest
+/// of their sizes is used
+pub(crate) fn negotiate(local: Option<u64>, peer: Option<u64>) -> Option<u64> {
+    Some(local?.max(peer?))
+}
+
+/// Hashes the content of a file as it is sent or received, according to its [ChecksumMode]
+pub(crate) struct ContentHasher {
+    hasher: Sha256,
+    /// Start of the last sampled range, the whole content is hashed from there
+    tail: u64,
+    /// Bytes of content seen so far
+    offset: u64,
+}
+
+impl ContentHasher {
+    /// Creates the hasher of a file sent or received with `mode`
+    pub fn new(mode: ChecksumMode) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            tail: match mode {
+                ChecksumMode::Full => 0,
+                ChecksumMode::Sampled(size) => size.saturating_sub(SAMPLE_SIZE),
+            },
+            offset: 0,
+        }
+    }
+
+    /// Hashes the next bytes of content, only the parts inside the sampled ranges with [ChecksumMode::Sampled]
+    pub fn update(&mut self, buf: &[u8]) {
+        let mut position = 0;
+        while position < buf.len() {
+            let (sampled, until) = self.range_at(self.offset + position as u64);
+            let end = std::cmp::min(buf.len() as u64, until - self.offset) as usize;
+            if sampled {
+                self.hasher.update(&buf[position..end]);
+            }
+            position = end;
+        }
+
+        self.offset += buf.len() as u64;
+    }
+
+    /// Returns the checksum of the content seen so far
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+
+    /// Returns whether the content at `offset` is hashed, and where its range ends
+    fn range_at(&self, offset: u64) -> (bool, u64) {
+        if offset >= self.tail {
+            return (true, u64::MAX);
+        }
+
+        let interval_start = offset - offset % SAMPLE_INTERVAL;
+        if offset - interval_start < SAMPLE_SIZE {
+            (true, (interval_start + SAMPLE_SIZE).min(self.tail))
+        } else {
+            (false, (interval_start + SAMPLE_INTERVAL).min(self.tail))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampled_checksums_hash_only_the_samples() {
+        assert_eq!(negotiate(Some(10), None), None);
+        assert_eq!(negotiate(Some(10), Some(20)), Some(20));
+        assert_eq!(ChecksumMode::for_size(15, Some(20)), ChecksumMode::Full);
+        assert_eq!(
+            ChecksumMode::for_size(20, Some(20)),
+            ChecksumMode::Sampled(20)
+        );
+
+        let size = SAMPLE_INTERVAL as usize * 2;
+        let content = vec![1u8; size];
+        let sampled = ChecksumMode::Sampled(size as u64);
+        let checksum = |content: &[u8], mode: ChecksumMode| {
+            let mut hasher = ContentHasher::new(mode);
+            // chunks crossing the sampled ranges
+            for chunk in content.chunks(100_000) {
+                hasher.update(chunk);
+            }
+            hasher.finalize()
+        };
+
+        let mut outside = content.clone();
+        outside[SAMPLE_SIZE as usize] = 2;
+        assert_eq!(checksum(&content, sampled), checksum(&outside, sampled));
+        assert_ne!(
+            checksum(&content, ChecksumMode::Full),
+            checksum(&outside, ChecksumMode::Full)
+        );
+
+        for position in [0, SAMPLE_INTERVAL as usize + 10, size - 1] {
+            let mut inside = content.clone();
+            inside[position] = 2;
+            assert_ne!(checksum(&content, sampled), checksum(&inside, sampled));
+        }
+
+        let mut full = Sha256::new();
+        full.update(&content);
+        assert_eq!(
+            checksum(&content, ChecksumMode::Full),
+            <[u8; 32]>::from(full.finalize())
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// Smallest chunk used by the adaptive mode
+const MIN_CHUNK_SIZE: usize = 4 * 1024;
+/// Largest chunk used by the adaptive mode
+pub(crate) const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Time the adaptive mode aims to spend sending each chunk
+const TARGET_CHUNK_TIME: Duration = Duration::from_millis(100);
+
+/// Size of the chunks used to send files
+///
+/// In adaptive mode, the size starts at [Config::transfer_chunk_size] and is doubled or halved according to the time taken by the last chunk,
+/// so fast links send larger chunks and slow links keep them small
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkSize {
+    size: usize,
+    adaptive: bool,
+    /// Largest size, lowered by [ChunkSize::limit]
+    max: usize,
+}
+
+impl ChunkSize {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            size: config.transfer_chunk_size,
+            adaptive: config.adaptive_chunk_size,
+            max: MAX_CHUNK_SIZE.max(config.transfer_chunk_size),
+        }
+    }
+
+    /// Keeps the chunks up to `max` bytes, like the largest chunk a peer wants to receive
+    pub fn limit(&mut self, max: usize) {
+        self.max = self.max.min(max.max(1));
+        self.size = self.size.min(self.max);
+    }
+
+    pub fn get(&self) -> usize {
+        self.size
+    }
+
+    /// Records that the last chunk took `elapsed` to be sent, adjusting the size in adaptive mode  
+    /// Chunks smaller than the current size, at the end of a file, are not considered
+    pub fn record(&mut self, chunk_len: usize, elapsed: Duration) {
+        if !self.adaptive || chunk_len < self.size {
+            return;
+        }
+
+        if elapsed < TARGET_CHUNK_TIME / 2 {
+            self.size = (self.size * 2).min(self.max);
+        } else if elapsed > TARGET_CHUNK_TIME * 2 {
+            self.size = (self.size / 2).max(MIN_CHUNK_SIZE.min(self.max));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adaptive_size_follows_chunk_time() {
+        let mut chunk_size = ChunkSize {
+            size: 8 * 1024,
+            adaptive: true,
+            max: MAX_CHUNK_SIZE,
+        };
+
+        chunk_size.record(8 * 1024, Duration::from_millis(10));
+        assert_eq!(chunk_size.get(), 16 * 1024);
+
+        chunk_size.record(100, Duration::from_millis(10));
+        assert_eq!(chunk_size.get(), 16 * 1024);
+
+        chunk_size.record(16 * 1024, TARGET_CHUNK_TIME);
+        assert_eq!(chunk_size.get(), 16 * 1024);
+
+        chunk_size.record(16 * 1024, Duration::from_secs(1));
+        assert_eq!(chunk_size.get(), 8 * 1024);
+
+        let mut fixed = ChunkSize {
+            size: 8 * 1024,
+            adaptive: false,
+            max: MAX_CHUNK_SIZE,
+        };
+        fixed.record(8 * 1024, Duration::from_millis(10));
+        assert_eq!(fixed.get(), 8 * 1024);
+    }
+}
@@ -0,0 +1,224 @@
+//! Encoding of the rpc frames, without any IO
+//!
+//! A frame is the length of its body, as a little endian u64, followed by the body: the frame name and its arguments,
+//! encoded with [bincode], each argument prefixed by its own length.
+//! The functions only work with buffers, so they can be fuzzed directly and used by transports that carry the frames
+//! in their own messages. [crate::network] reads and writes them from the peer streams
+
+use std::{convert::TryInto, fmt::Display};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Size of the length written before each frame and each argument
+pub const LENGTH_SIZE: usize = 8;
+/// Max size of the body of a frame, larger frames are refused before they are read
+pub const MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Errors encoding or decoding a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// The frame body is larger than [MAX_FRAME_SIZE]
+    FrameTooLarge(u64),
+    /// The frame body can't be decoded
+    InvalidFrame(String),
+    /// There are no more arguments in the frame, or the last one is incomplete
+    MissingArgument,
+    /// The argument can't be decoded as the requested type
+    InvalidArgument(String),
+    /// The argument can't be encoded
+    Encoding(String),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::FrameTooLarge(size) => write!(
+                f,
+                "Frame of {} bytes is larger than the limit of {} bytes",
+                size, MAX_FRAME_SIZE
+            ),
+            CodecError::InvalidFrame(reason) => write!(f, "Frame can't be decoded, {}", reason),
+            CodecError::MissingArgument => write!(f, "Frame doesn't have the expected argument"),
+            CodecError::InvalidArgument(reason) => {
+                write!(f, "Frame argument can't be decoded, {}", reason)
+            }
+            CodecError::Encoding(reason) => write!(f, "Frame can't be encoded, {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Same encoding of [bincode::serialize], with a limit to the size of the decoded values, so lengths inside a frame
+/// can't make the decoder allocate more than the frame size
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_FRAME_SIZE)
+}
+
+/// Reads the length at the start of `buf`, [None] if `buf` is too short
+fn read_length(buf: &[u8]) -> Option<u64> {
+    buf.get(..LENGTH_SIZE)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A Message to be serialized or deserialized for the rpc call
+///
+/// It uses [bincode] to serialize arguments
+/// #Examples
+///
+/// ``` ignore
+/// let mut message = FrameMessage::new("message_name".to_string());
+/// message.append_arg(1);
+/// message.append_arg("a");
+/// ```
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct FrameMessage {
+    ident: String,
+    data: Vec<u8>,
+}
+
+impl FrameMessage {
+    /// Creates a new [FrameMessage] without any args
+    pub fn new(ident: &str) -> Self {
+        Self {
+            ident: ident.to_owned(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Returns the name of this frame
+    pub fn frame_ident(&self) -> &str {
+        &self.ident
+    }
+
+    /// Add an argument of type `T` to this frame
+    ///
+    /// Returns [Ok] if successful
+    pub fn with_arg<A: Serialize>(mut self, arg: &A) -> Result<Self, CodecError> {
+        let ser_value = bincode_options()
+            .serialize(arg)
+            .map_err(|err| CodecError::Encoding(err.to_string()))?;
+
+        self.data
+            .extend((ser_value.len() as u64).to_le_bytes().iter());
+        self.data.extend(ser_value);
+
+        Ok(self)
+    }
+
+    /// Return the next argument in this frame
+    ///
+    /// This function may fail if the argument can't be deserialized to [`T`]
+    /// or if there isn't an argument to be retrieved
+    ///
+    /// [`OK`]`(`[T]`)` if the argument is correct
+    pub fn next_arg<A: DeserializeOwned>(&mut self) -> Result<A, CodecError> {
+        let size = read_length(&self.data).ok_or(CodecError::MissingArgument)?;
+        if size > (self.data.len() - LENGTH_SIZE) as u64 {
+            return Err(CodecError::MissingArgument);
+        }
+
+        let end = LENGTH_SIZE + size as usize;
+        let result = bincode_options()
+            .deserialize(&self.data[LENGTH_SIZE..end])
+            .map_err(|err| CodecError::InvalidArgument(err.to_string()))?;
+        self.data.drain(..end);
+
+        Ok(result)
+    }
+}
+
+impl From<&str> for FrameMessage {
+    /// Creates a new [`FrameMessage`] from a primitive [`str`]
+    fn from(name: &str) -> Self {
+        FrameMessage::new(name)
+    }
+}
+
+/// Encodes `frame`, with its length
+pub fn encode_frame(frame: &FrameMessage) -> Result<Vec<u8>, CodecError> {
+    let body = bincode_options()
+        .serialize(frame)
+        .map_err(|err| CodecError::Encoding(err.to_string()))?;
+    if body.len() as u64 > MAX_FRAME_SIZE {
+        return Err(CodecError::FrameTooLarge(body.len() as u64));
+    }
+
+    let mut encoded = Vec::with_capacity(LENGTH_SIZE + body.len());
+    encoded.extend((body.len() as u64).to_le_bytes().iter());
+    encoded.extend(body);
+    Ok(encoded)
+}
+
+/// Decodes the frame at the start of `buf`
+///
+/// Returns the frame and the number of bytes it used, or [None] if `buf` doesn't have the whole frame yet
+/// Fails as soon as the length is read when the frame is larger than [MAX_FRAME_SIZE]
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(FrameMessage, usize)>, CodecError> {
+    let size = match read_length(buf) {
+        Some(size) if size > MAX_FRAME_SIZE => return Err(CodecError::FrameTooLarge(size)),
+        Some(size) => size as usize,
+        None => return Ok(None),
+    };
+
+    let end = LENGTH_SIZE + size;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let frame = bincode_options()
+        .deserialize(&buf[LENGTH_SIZE..end])
+        .map_err(|err| CodecError::InvalidFrame(err.to_string()))?;
+    Ok(Some((frame, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_decoded_from_buffers() -> crate::Result<()> {
+        let frame = FrameMessage::new("message")
+            .with_arg(&1u64)?
+            .with_arg(&"a")?;
+        let mut buf = encode_frame(&frame)?;
+        // the old encoding is kept, peers with older versions can still talk
+        assert_eq!(buf, {
+            let body = bincode::serialize(&frame)?;
+            let mut old = bincode::serialize(&body.len())?;
+            old.extend(body);
+            old
+        });
+
+        assert_eq!(decode_frame(&buf[..buf.len() - 1])?, None);
+        buf.extend(encode_frame(&"next".into())?);
+        let (mut decoded, used) = decode_frame(&buf)?.unwrap();
+        assert_eq!(decoded, frame);
+        assert!(matches!(
+            decoded.next_arg::<String>(),
+            Err(CodecError::InvalidArgument(_))
+        ));
+        assert_eq!(decoded.next_arg::<u64>()?, 1);
+        assert_eq!(decoded.next_arg::<String>()?, "a");
+        assert_eq!(decoded.next_arg::<u8>(), Err(CodecError::MissingArgument));
+        assert_eq!(decode_frame(&buf[used..])?.unwrap().0.frame_ident(), "next");
+
+        assert_eq!(
+            decode_frame(&u64::MAX.to_le_bytes()),
+            Err(CodecError::FrameTooLarge(u64::MAX))
+        );
+        let mut garbage = 16u64.to_le_bytes().to_vec();
+        garbage.extend(u64::MAX.to_le_bytes().iter());
+        garbage.extend([0u8; 8].iter());
+        assert!(matches!(
+            decode_frame(&garbage),
+            Err(CodecError::InvalidFrame(_))
+        ));
+
+        Ok(())
+    }
+}
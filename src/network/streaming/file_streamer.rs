@@ -1,38 +1,317 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use super::{
+    checksum::{ChecksumMode, ContentHasher},
+    chunk_size::ChunkSize,
+    rate_limit::BandwidthLimit,
+    zero_copy::ZeroCopySocket,
+};
 use crate::{
-    config::Config,
+    config::{CaseCollisionPolicy, Config, HashAlgorithm},
+    events::{Decision, Event, EventBus},
     fs::{self, FileInfo},
+    network::buffer_pool::BUFFER_POOL,
+    skipped_files::SkippedFiles,
+    storage::StorageFile,
     sync::file_events_buffer::FileEventsBuffer,
+    transfer_journal::TransferJournal,
+    IronCarrierError,
 };
+use sha2::{Digest, Sha256};
 use tokio::{
     io::AsyncRead,
     io::AsyncReadExt,
+    io::AsyncSeekExt,
     io::AsyncWrite,
     io::AsyncWriteExt,
     io::{ReadHalf, WriteHalf},
 };
+use tokio_util::sync::CancellationToken;
 
-const BUFFER_SIZE: usize = 8 * 1024;
+/// Size of the sha256 checksum sent after the content of each file
+const CHECKSUM_SIZE: usize = 32;
+/// Length sent in a pack in place of a file the sender can't read, no content follows it
+const PACK_SKIPPED: u64 = u64::MAX;
+/// Bytes received between the checkpoints of a file, the transfer continues from the last one after a restart
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+/// Times a chunk is written to a full disk before the file is given up, see [Config::disk_full_retry_seconds]
+const DISK_FULL_ATTEMPTS: u32 = 5;
 
 pub struct Sender<T: AsyncWrite + Unpin> {
     stream: T,
+    chunk_size: ChunkSize,
+    bandwidth: BandwidthLimit,
+    cancel: CancellationToken,
+    /// Socket of the stream, local files are sent to it by the kernel, see [Sender::send_local_file]
+    zero_copy: Option<ZeroCopySocket>,
+    /// Smallest file sent with a sampled checksum, agreed with the peer, see [Sender::checksum_mode]
+    sampled_checksum_min_size: Option<u64>,
+    /// Counter of the bytes sent, see [Sender::set_progress]
+    progress: Option<Arc<AtomicU64>>,
 }
 
 impl<T: AsyncWrite + Unpin> Sender<T> {
-    pub fn new(stream: T) -> Self {
-        Self { stream }
+    pub fn new(stream: T, config: &Config) -> Self {
+        Self {
+            stream,
+            chunk_size: ChunkSize::new(config),
+            bandwidth: BandwidthLimit::new(config),
+            cancel: CancellationToken::new(),
+            zero_copy: None,
+            sampled_checksum_min_size: None,
+            progress: None,
+        }
+    }
+
+    /// Sends local files directly to `socket`, the socket of the stream, see [Config::zero_copy_send]
+    pub fn with_zero_copy(mut self, socket: Option<ZeroCopySocket>) -> Self {
+        self.zero_copy = socket;
+        self
+    }
+
+    /// Returns true if local files are sent without copying their content, see [Sender::send_local_file]
+    pub fn is_zero_copy(&self) -> bool {
+        self.zero_copy.is_some()
+    }
+
+    /// Stops sending files, between chunks, when `cancel` is cancelled
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.set_cancellation(cancel);
+        self
+    }
+
+    /// Same as [Sender::with_cancellation], for a sender that is borrowed
+    pub fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    /// Keeps the chunks sent up to `max` bytes, see [crate::network::capacity::PeerCapacity]
+    pub fn limit_chunk_size(&mut self, max: usize) {
+        self.chunk_size.limit(max);
+    }
+
+    /// Sends files of at least `min_size` bytes with a sampled checksum, [None] hashes the whole content of every file  
+    /// `min_size` must be the size agreed with the peer, see [crate::network::streaming::negotiate_sampled_checksum]
+    pub fn set_sampled_checksum(&mut self, min_size: Option<u64>) {
+        self.sampled_checksum_min_size = min_size;
     }
-    /// read `buf_size` from `buf_read` and write into internal stream
+
+    /// Adds the bytes of content sent by [Sender::send_file] and [Sender::send_local_file] to `progress`, [None] stops counting
+    pub fn set_progress(&mut self, progress: Option<Arc<AtomicU64>>) {
+        self.progress = progress;
+    }
+
+    fn count_bytes(&self, bytes: usize) {
+        if let Some(progress) = &self.progress {
+            progress.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns how the checksum of a file with `size` bytes of content is computed
+    pub fn checksum_mode(&self, size: u64) -> ChecksumMode {
+        ChecksumMode::for_size(size, self.sampled_checksum_min_size)
+    }
+
+    /// Keeps the content sent under `bytes_per_second`, [None] removes the limit  
+    /// The windows of [Config::bandwidth_schedule] replace it while they last
+    pub fn limit_bandwidth(&mut self, bytes_per_second: Option<u64>) {
+        self.bandwidth.set_network_limit(bytes_per_second);
+    }
+
+    /// Waits for the bandwidth limit after `bytes` of content are sent
+    async fn throttle(&mut self, bytes: usize) {
+        self.bandwidth.throttle(bytes).await;
+    }
+    /// read the content of `buf_read` and write into internal stream, one chunk at a time, followed by its checksum  
+    /// The checksum is computed according to `checksum`, the receiver must use the same mode
     pub async fn send_file<R: AsyncRead + Unpin>(
         &mut self,
         ident: u64,
         buf_read: &mut R,
+        checksum: ChecksumMode,
     ) -> crate::Result<()> {
         let buff = bincode::serialize(&ident)?;
+        self.stream.write_all(&buff).await?;
+
+        let mut hasher = ContentHasher::new(checksum);
+        let mut buffer = BUFFER_POOL.get(self.chunk_size.get());
+        loop {
+            buffer.resize(self.chunk_size.get());
+            let read = buf_read.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            if self.cancel.is_cancelled() {
+                return Err(IronCarrierError::Cancelled.into());
+            }
 
+            hasher.update(&buffer[..read]);
+            let started_at = Instant::now();
+            self.stream.write_all(&buffer[..read]).await?;
+            self.chunk_size.record(read, started_at.elapsed());
+            self.count_bytes(read);
+            self.throttle(read).await;
+        }
+
+        self.stream.write_all(&hasher.finalize()).await?;
+
+        Ok(())
+    }
+
+    /// Sends the content of the local file at `path` like [Sender::send_file]  
+    /// With [Sender::with_zero_copy], the content is written to the socket by the kernel, one chunk at a time
+    pub async fn send_local_file(
+        &mut self,
+        ident: u64,
+        path: &Path,
+        checksum: ChecksumMode,
+    ) -> crate::Result<()> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let socket = match self.zero_copy.take() {
+            Some(socket) => socket,
+            None => return self.send_file(ident, &mut file, checksum).await,
+        };
+
+        let result = self
+            .send_with_socket(&socket, ident, &mut file, checksum)
+            .await;
+        self.zero_copy = Some(socket);
+        result
+    }
+
+    async fn send_with_socket(
+        &mut self,
+        socket: &ZeroCopySocket,
+        ident: u64,
+        file: &mut tokio::fs::File,
+        checksum: ChecksumMode,
+    ) -> crate::Result<()> {
+        let buff = bincode::serialize(&ident)?;
         self.stream.write_all(&buff).await?;
-        tokio::io::copy(buf_read, &mut self.stream).await?;
+        // the content is written to the socket directly, anything buffered by the stream must go before it
+        self.stream.flush().await?;
+
+        let mut hasher = ContentHasher::new(checksum);
+        let mut buffer = BUFFER_POOL.get(self.chunk_size.get());
+        let mut offset = 0u64;
+        loop {
+            buffer.resize(self.chunk_size.get());
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            if self.cancel.is_cancelled() {
+                return Err(IronCarrierError::Cancelled.into());
+            }
+
+            hasher.update(&buffer[..read]);
+            let started_at = Instant::now();
+            socket.send(file, offset, read).await?;
+            self.chunk_size.record(read, started_at.elapsed());
+            self.count_bytes(read);
+            self.throttle(read).await;
+            offset += read as u64;
+        }
+
+        self.stream.write_all(&hasher.finalize()).await?;
+
+        Ok(())
+    }
+
+    /// Sends a batch of files in the stream, each one prefixed by its length and followed by its checksum
+    pub async fn send_batch(&mut self, ident: u64, contents: &[Vec<u8>]) -> crate::Result<()> {
+        let buff = bincode::serialize(&ident)?;
+        self.stream.write_all(&buff).await?;
+
+        for content in contents {
+            if self.cancel.is_cancelled() {
+                return Err(IronCarrierError::Cancelled.into());
+            }
+
+            let size = bincode::serialize(&(content.len() as u64))?;
+            self.stream.write_all(&size).await?;
+            self.stream.write_all(content).await?;
+            self.stream.write_all(&Sha256::digest(content)).await?;
+            self.throttle(content.len()).await;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a pack in the stream, its files follow with [Sender::send_pack_entry], in the order of the pack manifest
+    pub async fn start_pack(&mut self, ident: u64) -> crate::Result<()> {
+        let buff = bincode::serialize(&ident)?;
+        self.stream.write_all(&buff).await?;
+        Ok(())
+    }
+
+    /// Sends the next file of a pack, prefixed by its length and followed by its checksum  
+    /// `content` is [None] for a file that can't be read, the receiver skips it
+    ///
+    /// Only `size` bytes are sent, if the file is shorter now the content is padded and the checksum is left blank,
+    /// so the receiver discards it
+    pub async fn send_pack_entry<R: AsyncRead + Unpin>(
+        &mut self,
+        content: Option<(&mut R, u64)>,
+    ) -> crate::Result<()> {
+        let (buf_read, size) = match content {
+            Some(content) => content,
+            None => {
+                let buff = bincode::serialize(&PACK_SKIPPED)?;
+                self.stream.write_all(&buff).await?;
+                return Ok(());
+            }
+        };
+
+        let buff = bincode::serialize(&size)?;
+        self.stream.write_all(&buff).await?;
+
+        let mut hasher = Sha256::new();
+        let mut buffer = BUFFER_POOL.get(self.chunk_size.get());
+        let mut remaining = size;
+        let mut complete = true;
+        while remaining > 0 {
+            if self.cancel.is_cancelled() {
+                return Err(IronCarrierError::Cancelled.into());
+            }
+
+            buffer.resize(self.chunk_size.get());
+            let chunk = std::cmp::min(buffer.len() as u64, remaining) as usize;
+            let read = if complete {
+                buf_read.read(&mut buffer[..chunk]).await?
+            } else {
+                0
+            };
+            let read = if read == 0 {
+                complete = false;
+                buffer[..chunk].fill(0);
+                chunk
+            } else {
+                hasher.update(&buffer[..read]);
+                read
+            };
+
+            let started_at = Instant::now();
+            self.stream.write_all(&buffer[..read]).await?;
+            self.chunk_size.record(read, started_at.elapsed());
+            self.throttle(read).await;
+            remaining -= read as u64;
+        }
+
+        if complete {
+            self.stream.write_all(&hasher.finalize()).await?;
+        } else {
+            self.stream.write_all(&[0u8; CHECKSUM_SIZE]).await?;
+        }
 
         Ok(())
     }
@@ -42,58 +321,862 @@ pub(crate) struct Receiver<'a, T: AsyncRead + Unpin> {
     stream: T,
     ident: u64,
     files: HashMap<u64, FileInfo>,
+    batches: HashMap<u64, Vec<FileInfo>>,
+    /// Files of a pack, in the order they are sent
+    packs: HashMap<u64, Vec<FileInfo>>,
+    /// Files of an atomic group, sent like a pack
+    groups: HashMap<u64, Vec<FileInfo>>,
+    /// Parts of a file being received, with their offset and length
+    ranges: HashMap<u64, (FileInfo, u64, u64)>,
     config: &'a Config,
+    events: &'a EventBus,
     peer_address: String,
+    cancel: CancellationToken,
+    /// Bytes used by the aliases with a quota, read from disk the first time a file is received for the alias
+    usage: Mutex<HashMap<String, u64>>,
+    /// Aliases already reported over their quota
+    over_quota: Mutex<HashSet<String>>,
+    /// Files discarded by the last [Receiver::wait_files] because their content didn't match the checksum
+    mismatched: HashSet<PathBuf>,
+    /// Smallest file received with a sampled checksum, agreed with the peer, see [Sender::checksum_mode]
+    sampled_checksum_min_size: Option<u64>,
+    /// Counter of the bytes received, see [Receiver::set_progress]
+    progress: Option<Arc<AtomicU64>>,
 }
 
 impl<'a, T: AsyncRead + Unpin> Receiver<'a, T> {
-    pub fn new(stream: T, config: &'a Config, peer_address: String) -> Self {
+    pub fn new(stream: T, config: &'a Config, events: &'a EventBus, peer_address: String) -> Self {
         Receiver {
             stream,
             ident: 0,
             files: HashMap::new(),
+            batches: HashMap::new(),
+            packs: HashMap::new(),
+            groups: HashMap::new(),
+            ranges: HashMap::new(),
             config,
+            events,
             peer_address,
+            cancel: CancellationToken::new(),
+            usage: Default::default(),
+            over_quota: Default::default(),
+            mismatched: HashSet::new(),
+            sampled_checksum_min_size: None,
+            progress: None,
         }
     }
 
-    async fn read_file<'b>(
-        &mut self,
-        file_info: FileInfo,
-        events_buffer: &'b FileEventsBuffer,
+    /// Stops receiving files when `cancel` is cancelled, files partially received are kept in their temp file
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.set_cancellation(cancel);
+        self
+    }
+
+    /// Same as [Receiver::with_cancellation], for a receiver that is borrowed
+    pub fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    /// Receives files of at least `min_size` bytes with a sampled checksum, like [Sender::set_sampled_checksum]
+    pub fn set_sampled_checksum(&mut self, min_size: Option<u64>) {
+        self.sampled_checksum_min_size = min_size;
+    }
+
+    /// Adds the bytes of content received to `progress`, [None] stops counting
+    pub fn set_progress(&mut self, progress: Option<Arc<AtomicU64>>) {
+        self.progress = progress;
+    }
+
+    fn count_bytes(&self, bytes: usize) {
+        if let Some(progress) = &self.progress {
+            progress.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Fills `buf` with the stream content, or fails with [IronCarrierError::Cancelled] as soon as the receiver is cancelled
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> crate::Result<()> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(IronCarrierError::Cancelled.into()),
+            result = self.stream.read_exact(buf) => {
+                result?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Flushes the content received so far for `file_info`, so the temp file is left consistent when a transfer is interrupted
+    async fn checkpoint(
+        &self,
+        file_info: &FileInfo,
+        writer: &mut dyn StorageFile,
+        received: u64,
+    ) -> bool {
+        let result = match writer.flush().await {
+            Ok(_) => writer.sync_data().await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(_) => {
+                log::info!(
+                    "transfer of {:?} interrupted, {} bytes kept in its temp file",
+                    file_info.path,
+                    received
+                );
+                true
+            }
+            Err(err) => {
+                log::error!(
+                    "transfer of {:?} interrupted, failed to flush its temp file: {}",
+                    file_info.path,
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    /// Records in the [TransferJournal] of the alias that the first `received` bytes of `file_info` are in its temp file
+    async fn record_progress(&self, file_info: &FileInfo, received: u64) {
+        let path = match self.config.paths.get(&file_info.alias) {
+            Some(path) => path,
+            None => return,
+        };
+
+        if let Err(err) = TransferJournal::new(path)
+            .progress(&self.peer_address, file_info, received)
+            .await
+        {
+            log::error!(
+                "cannot record the progress of {:?}: {}",
+                file_info.path,
+                err
+            );
+        }
+    }
+
+    /// Returns the bytes used by `alias`
+    async fn used_space(&self, alias: &str) -> crate::Result<u64> {
+        if let Some(used) = self.usage.lock().unwrap().get(alias) {
+            return Ok(*used);
+        }
+
+        let roots: Vec<PathBuf> = self
+            .config
+            .alias_roots(alias)
+            .into_iter()
+            .cloned()
+            .collect();
+        let used = tokio::task::spawn_blocking(move || {
+            roots
+                .iter()
+                .map(|root| fs::disk_usage(root))
+                .sum::<std::io::Result<u64>>()
+        })
+        .await??;
+        self.usage.lock().unwrap().insert(alias.to_owned(), used);
+        Ok(used)
+    }
+
+    /// Records that `written` bytes replaced `replaced` bytes of `alias`
+    fn record_usage(&self, alias: &str, replaced: u64, written: u64) {
+        if let Some(used) = self.usage.lock().unwrap().get_mut(alias) {
+            *used = (*used + written).saturating_sub(replaced);
+        }
+    }
+
+    /// Returns the bytes `files` add to each alias, the size of the local files they replace is discounted
+    fn needed_space<'f>(&self, files: &'f [FileInfo]) -> HashMap<&'f str, u64> {
+        let mut needed: HashMap<&str, u64> = HashMap::new();
+        for file in files {
+            let replaced = local_size(file, self.config);
+            *needed.entry(&file.alias).or_default() += file.content_size().saturating_sub(replaced);
+        }
+
+        needed
+    }
+
+    /// Fails with [IronCarrierError::QuotaExceeded] when `files` don't fit the [crate::config::AliasLimits::max_total_size]
+    /// of their alias, the size of the local files they replace is discounted
+    pub async fn check_quota(&self, files: &[FileInfo]) -> crate::Result<()> {
+        for (alias, needed) in self.needed_space(files) {
+            let quota = match self.config.quota(alias) {
+                Some(quota) => quota,
+                None => continue,
+            };
+
+            let used = self.used_space(alias).await?;
+            if used + needed > quota {
+                if self.over_quota.lock().unwrap().insert(alias.to_owned()) {
+                    log::warn!(
+                        "alias {} is over its quota, using {} of {} bytes",
+                        alias,
+                        used,
+                        quota
+                    );
+                    self.events.emit(Event::QuotaExceeded {
+                        alias: alias.to_owned(),
+                        used,
+                        quota,
+                    });
+                }
+                return Err(IronCarrierError::QuotaExceeded(alias.to_owned()).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails with [IronCarrierError::StorageFull] when the disk of their alias doesn't have room for `files`,
+    /// so the sender is told before any content is streamed
+    pub fn check_available_space(&self, files: &[FileInfo]) -> crate::Result<()> {
+        for (alias, needed) in self.needed_space(files) {
+            if needed == 0 || !self.config.is_local_storage(alias) {
+                continue;
+            }
+
+            let path = match self.config.paths.get(alias) {
+                Some(path) => path,
+                None => continue,
+            };
+            match fs::available_space(path) {
+                Ok(available) if available < needed => {
+                    log::warn!(
+                        "alias {} has {} bytes available, {} bytes are needed",
+                        alias,
+                        available,
+                        needed
+                    );
+                    return Err(IronCarrierError::StorageFull(alias.to_owned()).into());
+                }
+                Ok(_) => {}
+                Err(err) => log::debug!("cannot read available space of alias {}: {}", alias, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pause_inbound_transfers(&self, alias: &str, err: &std::io::Error) {
+        log::warn!(
+            "disk is full, pausing inbound transfers for alias {}",
+            alias
+        );
+        self.events.emit(Event::InboundTransfersPaused {
+            alias: alias.to_owned(),
+            reason: err.to_string(),
+        });
+    }
+
+    fn resume_inbound_transfers(&self, alias: &str) {
+        log::info!("resuming inbound transfers for alias {}", alias);
+        self.events.emit(Event::InboundTransfersResumed {
+            alias: alias.to_owned(),
+        });
+    }
+
+    /// Reserves `size` bytes on disk for `buf_write`, before any content is written
+    ///
+    /// Fails with [IronCarrierError::StorageFull] when the disk doesn't have room for the whole file
+    fn reserve_space(
+        &self,
+        buf_write: &dyn StorageFile,
+        size: u64,
+        alias: &str,
+    ) -> crate::Result<()> {
+        match buf_write.preallocate(size) {
+            Err(err) if err.kind() == std::io::ErrorKind::StorageFull => {
+                log::warn!(
+                    "disk doesn't have room for {} bytes of alias {}",
+                    size,
+                    alias
+                );
+                Err(IronCarrierError::StorageFull(alias.to_owned()).into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Writes `chunk` at `offset` of `buf_write`
+    ///
+    /// When the disk is full, the inbound transfers for the alias are paused and the write is retried up to [DISK_FULL_ATTEMPTS] times,
+    /// the stream is not read in the meantime, so the connection is kept alive while the sender waits  
+    /// Fails with [IronCarrierError::StorageFull] when the disk is still full, or [IronCarrierError::Cancelled] as soon as the receiver is cancelled
+    async fn write_chunk(
+        &self,
+        buf_write: &mut dyn StorageFile,
+        chunk: &[u8],
+        offset: u64,
+        alias: &str,
     ) -> crate::Result<()> {
-        let mut buf = [0u8; BUFFER_SIZE];
-        let mut buf_size = file_info.size.unwrap() as usize;
+        let mut paused = false;
+        let mut attempt = 1;
+
+        let result = loop {
+            let result = match buf_write.write_all(chunk).await {
+                Ok(_) => buf_write.flush().await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(_) => break Ok(()),
+                Err(err) if err.kind() == std::io::ErrorKind::StorageFull => {
+                    if attempt == DISK_FULL_ATTEMPTS {
+                        break Err(IronCarrierError::StorageFull(alias.to_owned()).into());
+                    }
+                    attempt += 1;
+
+                    if !paused {
+                        self.pause_inbound_transfers(alias, &err);
+                        paused = true;
+                    }
+
+                    tokio::select! {
+                        _ = self.cancel.cancelled() => break Err(IronCarrierError::Cancelled.into()),
+                        _ = tokio::time::sleep(Duration::from_secs(self.config.disk_full_retry_seconds)) => {}
+                    }
+
+                    // the partial write is overwritten by the retry
+                    if let Err(err) = buf_write.seek(SeekFrom::Start(offset)).await {
+                        break Err(err.into());
+                    }
+                }
+                Err(err) => break Err(err.into()),
+            }
+        };
+
+        if paused {
+            self.resume_inbound_transfers(alias);
+        }
+
+        result
+    }
+
+    /// Returns where `file_info` must be written, according to [Config::case_collision_policy]
+    /// when the file system ignores case and a local file has the same name in a different case  
+    /// Files larger than the [crate::config::AliasLimits::max_file_size] of the alias, or left out by
+    /// [Config::include_only], are refused
+    fn destination(&self, file_info: &FileInfo) -> Result<FileInfo, IronCarrierError> {
+        self.config
+            .check_file_size(&file_info.alias, file_info.content_size())?;
+        if !self.config.is_included(&file_info.alias, &file_info.path) {
+            return Err(IronCarrierError::PathRejected(
+                "the file is not included in the alias".to_owned(),
+            ));
+        }
+
+        let existing = match file_info
+            .get_absolute_path(self.config)
+            .ok()
+            .and_then(|path| fs::find_case_collision(&path))
+        {
+            Some(existing) => existing,
+            None => return Ok(file_info.clone()),
+        };
+
+        if self
+            .events
+            .decide(|observer| observer.on_conflict(file_info, &existing))
+            == Decision::Veto
+        {
+            log::info!(
+                "{:?} collides with {:?}, it was refused by an observer",
+                file_info.path,
+                existing
+            );
+            return Err(IronCarrierError::Vetoed);
+        }
+
+        match self.config.case_collision_policy {
+            CaseCollisionPolicy::Skip => Err(IronCarrierError::CaseCollision(
+                existing.to_string_lossy().into_owned(),
+            )),
+            CaseCollisionPolicy::Rename => {
+                let mut renamed = file_info.clone();
+                renamed.path = fs::case_conflict_path(&file_info.path);
+                log::warn!(
+                    "{:?} collides with {:?}, receiving it as {:?}",
+                    file_info.path,
+                    existing,
+                    renamed.path
+                );
+                Ok(renamed)
+            }
+        }
+    }
+
+    /// Reads and drops `size` bytes of content from the stream, and their checksum, for files that can't be written
+    async fn discard_content(&mut self, size: u64) -> crate::Result<()> {
+        let mut buf = BUFFER_POOL.get(self.config.transfer_chunk_size);
+        let mut remaining = size;
+        while remaining > 0 {
+            let size = std::cmp::min(buf.len() as u64, remaining) as usize;
+            self.read_chunk(&mut buf[..size]).await?;
+            remaining -= size as u64;
+        }
+        self.read_checksum().await?;
+
+        Ok(())
+    }
+
+    /// Reads the checksum sent after the content of a file
+    async fn read_checksum(&mut self) -> crate::Result<[u8; CHECKSUM_SIZE]> {
+        let mut checksum = [0u8; CHECKSUM_SIZE];
+        self.read_chunk(&mut checksum[..]).await?;
+        Ok(checksum)
+    }
+
+    /// Reads `size` bytes of content from the stream and writes them to the temp file of `file_info`
+    ///
+    /// Returns false if the temp file couldn't be written, the error is recorded in `skipped` and the content is still consumed from the stream  
+    /// The temp file is discarded when the content doesn't match the checksum sent by the peer, computed according to `checksum`  
+    /// Only errors reading the stream are returned
+    async fn read_to_temp_file(
+        &mut self,
+        file_info: &FileInfo,
+        size: u64,
+        checksum: ChecksumMode,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<bool> {
+        let mut buf = BUFFER_POOL.get(self.config.transfer_chunk_size);
+        let mut buf_size = size as usize;
+        let mut offset = 0u64;
+        let mut hasher = ContentHasher::new(checksum);
+
+        let mut buf_write = match fs::get_temp_file(file_info, self.config).await {
+            Ok(buf_write) => Some(buf_write),
+            Err(err) => {
+                skipped.add(&file_info.path, err);
+                None
+            }
+        };
+
+        if let Some(writer) = buf_write.as_ref() {
+            if let Err(err) = self.reserve_space(writer.as_ref(), size, &file_info.alias) {
+                skipped.add(&file_info.path, err);
+                buf_write = None;
+            }
+        }
 
-        let mut buf_write = fs::get_temp_file(&file_info, &self.config).await?;
         while buf_size > 0 {
-            let size = std::cmp::min(BUFFER_SIZE, buf_size);
-            self.stream.read_exact(&mut buf[..size]).await?;
-            buf_write.write(&buf[..size]).await?;
+            let size = std::cmp::min(buf.len(), buf_size);
+            if let Err(err) = self.read_chunk(&mut buf[..size]).await {
+                if let Some(writer) = buf_write.as_mut() {
+                    if self.checkpoint(file_info, writer.as_mut(), offset).await {
+                        self.record_progress(file_info, offset).await;
+                    }
+                }
+                return Err(err);
+            }
+            hasher.update(&buf[..size]);
+            self.count_bytes(size);
+            if let Some(writer) = buf_write.as_mut() {
+                if let Err(err) = self
+                    .write_chunk(writer.as_mut(), &buf[..size], offset, &file_info.alias)
+                    .await
+                {
+                    skipped.add(&file_info.path, err);
+                    buf_write = None;
+                }
+            }
             buf_size -= size;
+            offset += size as u64;
+
+            // large files are flushed periodically, so a crash doesn't lose the content already received
+            if buf_size > 0
+                && offset / CHECKPOINT_INTERVAL != (offset - size as u64) / CHECKPOINT_INTERVAL
+            {
+                if let Some(writer) = buf_write.as_mut() {
+                    if writer.sync_data().await.is_ok() {
+                        self.record_progress(file_info, offset).await;
+                    }
+                }
+            }
+        }
+
+        if self.read_checksum().await? != hasher.finalize() {
+            log::error!(
+                "received file {:?} doesn't match its checksum",
+                file_info.path
+            );
+            if buf_write.take().is_some() {
+                fs::remove_temp_file(file_info, self.config).await.ok();
+            }
+            skipped.add(&file_info.path, IronCarrierError::ChecksumMismatch);
+            self.mismatched.insert(file_info.path.clone());
+        }
+
+        Ok(buf_write.is_some())
+    }
+
+    /// Reads `length` bytes from the stream and writes them at `offset` of the temp file of `file_info`
+    ///
+    /// The temp file must have been created by [Receiver::prepare_temp_file], errors writing it are recorded in `skipped`,
+    /// as are parts that don't match their checksum, the assembled file is verified by [Receiver::complete_temp_file]
+    async fn read_range(
+        &mut self,
+        file_info: FileInfo,
+        offset: u64,
+        length: u64,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        let mut buf = BUFFER_POOL.get(self.config.transfer_chunk_size);
+        let mut buf_write = match fs::open_temp_file(&file_info, self.config).await {
+            Ok(mut buf_write) => match buf_write.seek(SeekFrom::Start(offset)).await {
+                Ok(_) => Some(buf_write),
+                Err(err) => {
+                    skipped.add(&file_info.path, err);
+                    None
+                }
+            },
+            Err(err) => {
+                skipped.add(&file_info.path, err);
+                None
+            }
+        };
+
+        let mut remaining = length;
+        let mut offset = offset;
+        let mut hasher = Sha256::new();
+        while remaining > 0 {
+            let size = std::cmp::min(buf.len() as u64, remaining) as usize;
+            if let Err(err) = self.read_chunk(&mut buf[..size]).await {
+                if let Some(writer) = buf_write.as_mut() {
+                    self.checkpoint(&file_info, writer.as_mut(), offset).await;
+                }
+                return Err(err);
+            }
+            hasher.update(&buf[..size]);
+            self.count_bytes(size);
+            if let Some(writer) = buf_write.as_mut() {
+                if let Err(err) = self
+                    .write_chunk(writer.as_mut(), &buf[..size], offset, &file_info.alias)
+                    .await
+                {
+                    skipped.add(&file_info.path, err);
+                    buf_write = None;
+                }
+            }
+            remaining -= size as u64;
+            offset += size as u64;
+        }
+
+        if self.read_checksum().await?[..] != hasher.finalize()[..] {
+            log::error!(
+                "part of file {:?} received from {} doesn't match its checksum",
+                file_info.path,
+                self.peer_address
+            );
+            skipped.add(&file_info.path, IronCarrierError::ChecksumMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the file content from the stream and writes it to disk
+    ///
+    /// Errors writing the file are recorded in `skipped`, the content is still consumed from the stream,
+    /// so the next files can be received  
+    /// Only errors reading the stream are returned
+    async fn read_file(
+        &mut self,
+        file_info: FileInfo,
+        events_buffer: &FileEventsBuffer,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        let size = file_info.content_size();
+        let file_info = match self.destination(&file_info) {
+            Ok(file_info) => file_info,
+            Err(err) => {
+                skipped.add(&file_info.path, err);
+                return self.discard_content(size).await;
+            }
+        };
+
+        let checksum = ChecksumMode::for_size(size, self.sampled_checksum_min_size);
+        if !self
+            .read_to_temp_file(&file_info, size, checksum, skipped)
+            .await?
+        {
+            return Ok(());
+        }
+
+        if let Err(err) = self.replace_local_file(&file_info, events_buffer).await {
+            skipped.add(&file_info.path, err);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the local file with the temp file of `file_info`  
+    /// The temp file is discarded when a [crate::events::SyncObserver] refuses the received file, or when it doesn't fit
+    /// the alias quota
+    async fn replace_local_file(
+        &self,
+        file_info: &FileInfo,
+        events_buffer: &FileEventsBuffer,
+    ) -> crate::Result<()> {
+        if let Err(err) = self
+            .accept_temp_files(std::slice::from_ref(file_info))
+            .await
+        {
+            fs::remove_temp_file(file_info, self.config).await.ok();
+            return Err(err);
+        }
+
+        self.apply_temp_file(file_info, events_buffer).await
+    }
+
+    /// Fails when a [crate::events::SyncObserver] refuses any of the received `files`, or when they don't fit the
+    /// alias quota
+    async fn accept_temp_files(&self, files: &[FileInfo]) -> crate::Result<()> {
+        for file_info in files {
+            if self
+                .events
+                .decide(|observer| observer.on_file_received(file_info))
+                == Decision::Veto
+            {
+                log::info!(
+                    "received file {:?} was refused by an observer",
+                    file_info.path
+                );
+                return Err(IronCarrierError::Vetoed.into());
+            }
         }
 
-        buf_write.flush().await?;
+        self.check_quota(files).await
+    }
+
+    /// Replaces the local file with the temp file of `file_info`, once accepted by [Receiver::accept_temp_files]
+    async fn apply_temp_file(
+        &self,
+        file_info: &FileInfo,
+        events_buffer: &FileEventsBuffer,
+    ) -> crate::Result<()> {
+        let replaced = local_size(file_info, self.config);
+        events_buffer.add_event(file_info, &self.peer_address);
+        let versions = self.events.versions();
+        let merged = match fs::merge_temp_file(file_info, self.config, versions).await {
+            Ok(merged) => merged,
+            Err(err) => {
+                log::warn!("cannot merge {:?}: {}", file_info.path, err);
+                None
+            }
+        };
 
-        events_buffer.add_event(&file_info, &self.peer_address);
-        fs::flush_temp_file(&file_info, &self.config).await?;
+        match &merged {
+            Some(merged) => fs::flush_temp_file(merged, self.config, versions).await?,
+            None => {
+                fs::flush_temp_file(file_info, self.config, versions).await?;
+                fs::keep_merge_base(file_info, self.config, versions).await;
+            }
+        }
 
+        let written = merged.as_ref().unwrap_or(file_info);
+        self.record_usage(&written.alias, replaced, local_size(written, self.config));
+        self.events
+            .notify(|observer| observer.on_file_written(written));
         Ok(())
     }
 
-    pub async fn wait_files<'b>(
+    /// Replaces the local files with the temp files of `received`, one right after the other, once all of them are
+    /// accepted by [Receiver::accept_temp_files]  
+    /// When `missing` tells why some files were not received, or any file is refused, all the temp files are discarded
+    /// and the local files are kept
+    async fn apply_together(
+        &self,
+        description: &str,
+        received: Vec<FileInfo>,
+        missing: Option<&str>,
+        events_buffer: &FileEventsBuffer,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        let refused = match missing {
+            None => self.accept_temp_files(&received).await.err(),
+            Some(missing) => Some(missing.into()),
+        };
+        if let Some(err) = refused {
+            log::error!(
+                "discarding {} of {} files from {}: {}",
+                description,
+                received.len(),
+                self.peer_address,
+                err
+            );
+            for file_info in received {
+                fs::remove_temp_file(&file_info, self.config).await.ok();
+                skipped.add(&file_info.path, &err);
+            }
+            return Ok(());
+        }
+
+        for file_info in received {
+            if let Err(err) = self.apply_temp_file(&file_info, events_buffer).await {
+                skipped.add(&file_info.path, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a batch of files, each one prefixed by its length
+    ///
+    /// All the files are written to temp files first, they only replace the local files once the whole batch was
+    /// received and accepted. Otherwise the batch is discarded and the local files are kept
+    async fn read_batch(
         &mut self,
-        events_buffer: &'b FileEventsBuffer,
+        files: Vec<FileInfo>,
+        events_buffer: &FileEventsBuffer,
+        skipped: &mut SkippedFiles,
     ) -> crate::Result<()> {
-        while self.files.len() > 0 {
+        let mut received = Vec::with_capacity(files.len());
+        let mut complete = true;
+
+        for mut file_info in files {
+            let mut size_buf = [0u8; 8];
+            self.read_chunk(&mut size_buf[..]).await?;
+            let size: u64 = bincode::deserialize(&size_buf)?;
+            file_info.size = Some(size);
+
+            match self.destination(&file_info) {
+                Ok(file_info) => {
+                    if self
+                        .read_to_temp_file(&file_info, size, ChecksumMode::Full, skipped)
+                        .await?
+                    {
+                        received.push(file_info);
+                    } else {
+                        complete = false;
+                    }
+                }
+                Err(err) => {
+                    skipped.add(&file_info.path, err);
+                    self.discard_content(size).await?;
+                    complete = false;
+                }
+            }
+        }
+
+        let missing = match complete {
+            true => None,
+            false => Some("other files of its batch were not received"),
+        };
+        self.apply_together("batch", received, missing, events_buffer, skipped)
+            .await
+    }
+
+    /// Reads the files of an atomic group, sent like a pack
+    ///
+    /// All the files are written to temp files first, they only replace the local files, one right after the other,
+    /// once the whole group was received and accepted. Otherwise the group is discarded and the local files are kept
+    async fn read_group(
+        &mut self,
+        files: Vec<FileInfo>,
+        events_buffer: &FileEventsBuffer,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        let mut received = Vec::with_capacity(files.len());
+        let mut complete = true;
+
+        for mut file_info in files {
+            let mut size_buf = [0u8; 8];
+            self.read_chunk(&mut size_buf[..]).await?;
+            let size: u64 = bincode::deserialize(&size_buf)?;
+            if size == PACK_SKIPPED {
+                skipped.add(&file_info.path, "the peer couldn't send the file");
+                complete = false;
+                continue;
+            }
+
+            file_info.size = Some(size);
+            match self.destination(&file_info) {
+                Ok(file_info) => {
+                    if self
+                        .read_to_temp_file(&file_info, size, ChecksumMode::Full, skipped)
+                        .await?
+                    {
+                        received.push(file_info);
+                    } else {
+                        complete = false;
+                    }
+                }
+                Err(err) => {
+                    skipped.add(&file_info.path, err);
+                    self.discard_content(size).await?;
+                    complete = false;
+                }
+            }
+        }
+
+        let missing = match complete {
+            true => None,
+            false => Some("other files of its atomic group were not received"),
+        };
+        self.apply_together("atomic group", received, missing, events_buffer, skipped)
+            .await
+    }
+
+    /// Reads the files of a pack, each one prefixed by its length  
+    /// Unlike a batch, each file replaces the local one as soon as it is received
+    async fn read_pack(
+        &mut self,
+        files: Vec<FileInfo>,
+        events_buffer: &FileEventsBuffer,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        for mut file_info in files {
+            let mut size_buf = [0u8; 8];
+            self.read_chunk(&mut size_buf[..]).await?;
+            let size: u64 = bincode::deserialize(&size_buf)?;
+            if size == PACK_SKIPPED {
+                log::debug!("peer couldn't send file {:?}", file_info.path);
+                continue;
+            }
+
+            file_info.size = Some(size);
+            self.read_file(file_info, events_buffer, skipped).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn wait_files(&mut self, events_buffer: &FileEventsBuffer) -> crate::Result<()> {
+        let mut skipped = SkippedFiles::new();
+        self.mismatched.clear();
+
+        while !self.files.is_empty()
+            || !self.batches.is_empty()
+            || !self.packs.is_empty()
+            || !self.groups.is_empty()
+            || !self.ranges.is_empty()
+        {
             let mut handle_buf = [0u8; 8];
-            self.stream.read_exact(&mut handle_buf[..]).await?;
+            self.read_chunk(&mut handle_buf[..]).await?;
 
             let file_handle: u64 = bincode::deserialize(&handle_buf)?;
             // TODO: handle error
             match self.files.remove(&file_handle) {
                 Some(file_info) => {
-                    self.read_file(file_info, events_buffer).await?;
+                    self.read_file(file_info, events_buffer, &mut skipped)
+                        .await?;
+                }
+                None if self.batches.contains_key(&file_handle) => {
+                    let files = self.batches.remove(&file_handle).unwrap_or_default();
+                    self.read_batch(files, events_buffer, &mut skipped).await?;
+                }
+                None if self.packs.contains_key(&file_handle) => {
+                    let files = self.packs.remove(&file_handle).unwrap_or_default();
+                    self.read_pack(files, events_buffer, &mut skipped).await?;
+                }
+                None if self.groups.contains_key(&file_handle) => {
+                    let files = self.groups.remove(&file_handle).unwrap_or_default();
+                    self.read_group(files, events_buffer, &mut skipped).await?;
+                }
+                None if self.ranges.contains_key(&file_handle) => {
+                    let (file_info, offset, length) = self.ranges.remove(&file_handle).unwrap();
+                    self.read_range(file_info, offset, length, &mut skipped)
+                        .await?;
                 }
                 None => {
                     log::error!("file handle {} don't exist", &file_handle)
@@ -101,27 +1184,159 @@ impl<'a, T: AsyncRead + Unpin> Receiver<'a, T> {
             }
         }
 
+        skipped.log_summary(&format!("receiving files from {}", self.peer_address));
+
         Ok(())
     }
 
+    /// Returns true if `path` was discarded by the last [Receiver::wait_files] because it didn't match its checksum
+    pub fn checksum_mismatched(&self, path: &Path) -> bool {
+        self.mismatched.contains(path)
+    }
+
     pub fn prepare_file_transfer(&mut self, file: FileInfo) -> u64 {
         self.ident += 1;
         self.files.insert(self.ident, file);
 
         self.ident
     }
+
+    /// Prepares the transfer of `files` in a single batch, returns the handle of the batch
+    pub fn prepare_batch_transfer(&mut self, files: Vec<FileInfo>) -> u64 {
+        self.ident += 1;
+        self.batches.insert(self.ident, files);
+
+        self.ident
+    }
+
+    /// Prepares the transfer of `files` in a single pack, returns the handle of the pack
+    pub fn prepare_pack_transfer(&mut self, files: Vec<FileInfo>) -> u64 {
+        self.ident += 1;
+        self.packs.insert(self.ident, files);
+
+        self.ident
+    }
+
+    /// Prepares the transfer of `files` as an atomic group, returns the handle of the group
+    pub fn prepare_group_transfer(&mut self, files: Vec<FileInfo>) -> u64 {
+        self.ident += 1;
+        self.groups.insert(self.ident, files);
+
+        self.ident
+    }
+
+    /// Creates the temp file of `file_info` and reserves its size on disk, so its parts can be received separately
+    pub async fn prepare_temp_file(&self, file_info: &FileInfo) -> crate::Result<()> {
+        if let Some(existing) = fs::find_case_collision(&file_info.get_absolute_path(self.config)?)
+        {
+            return Err(
+                IronCarrierError::CaseCollision(existing.to_string_lossy().into_owned()).into(),
+            );
+        }
+
+        let temp_file = fs::get_temp_file(file_info, self.config).await?;
+        self.reserve_space(
+            temp_file.as_ref(),
+            file_info.content_size(),
+            &file_info.alias,
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the bytes of `file_info` already received from this peer before the transfer was interrupted,
+    /// if its temp file has them and the peer still has the same version of the file
+    pub async fn resume_offset(&self, file_info: &FileInfo) -> Option<u64> {
+        let path = self.config.paths.get(&file_info.alias)?;
+        let (partial, received) = TransferJournal::new(path)
+            .pending(&self.peer_address)
+            .await
+            .partial
+            .remove(&file_info.path)?;
+        if partial.size != file_info.size
+            || partial.modified_at != file_info.modified_at
+            || received == 0
+            || received >= file_info.content_size()
+        {
+            return None;
+        }
+
+        let temp_path = fs::get_temp_path(file_info, self.config).ok()?;
+        let temp_file = self
+            .config
+            .storage(&file_info.alias)
+            .metadata(&temp_path)
+            .await
+            .ok()?;
+        if temp_file.len < received {
+            return None;
+        }
+
+        Some(received)
+    }
+
+    /// Prepares the transfer of `length` bytes of `file`, starting at `offset`, returns the handle of the transfer
+    pub fn prepare_range_transfer(&mut self, file: FileInfo, offset: u64, length: u64) -> u64 {
+        self.ident += 1;
+        self.ranges.insert(self.ident, (file, offset, length));
+
+        self.ident
+    }
+
+    /// Replaces the local file with the temp file assembled from its parts, if its content hash is `sha256`  
+    /// The content is hashed with the algorithm of `sha256`, the peer may use another one for the alias  
+    /// Returns false, discarding the temp file, if the content doesn't match
+    pub async fn complete_temp_file(
+        &self,
+        file_info: &FileInfo,
+        sha256: &str,
+        events_buffer: &FileEventsBuffer,
+    ) -> crate::Result<bool> {
+        let algorithm = HashAlgorithm::of_hash(sha256);
+        if fs::hash_temp_file(file_info, self.config, algorithm).await? != sha256 {
+            log::error!("assembled file {:?} doesn't match its hash", file_info.path);
+            fs::remove_temp_file(file_info, self.config).await.ok();
+            return Ok(false);
+        }
+
+        self.replace_local_file(file_info, events_buffer).await?;
+
+        Ok(true)
+    }
+
+    /// Removes a prepared transfer, used when the sender can't provide the file
+    pub fn cancel_file_transfer(&mut self, file_handle: u64) {
+        self.files.remove(&file_handle);
+        self.packs.remove(&file_handle);
+        self.groups.remove(&file_handle);
+        self.ranges.remove(&file_handle);
+    }
+}
+
+/// Returns the size of the local copy of `file_info`, 0 if it doesn't exist
+fn local_size(file_info: &FileInfo, config: &Config) -> u64 {
+    file_info
+        .get_absolute_path(config)
+        .ok()
+        .and_then(|path| path.metadata().ok())
+        .map(|metadata| metadata.len())
+        .unwrap_or_default()
 }
 
 pub(crate) fn file_streamers<'a, T>(
     stream: T,
     config: &'a Config,
+    events: &'a EventBus,
     peer_address: String,
 ) -> (Receiver<'a, ReadHalf<T>>, Sender<WriteHalf<T>>)
 where
     T: AsyncRead + AsyncWrite,
 {
     let (rx, tx) = tokio::io::split(stream);
-    (Receiver::new(rx, config, peer_address), Sender::new(tx))
+    (
+        Receiver::new(rx, config, events, peer_address),
+        Sender::new(tx, config),
+    )
 }
 
 #[cfg(test)]
@@ -132,6 +1347,7 @@ mod tests {
     };
 
     use super::*;
+    use crate::fs::FileKind;
 
     fn sample_config(test_folder: &str) -> Config {
         Config::parse_content(format!(
@@ -154,17 +1370,29 @@ mod tests {
 
     #[tokio::test]
     async fn file_streamer() -> Result<(), Box<dyn std::error::Error>> {
-        let (rx_stream, tx_stream) = tokio::io::duplex(BUFFER_SIZE);
+        let (rx_stream, tx_stream) = tokio::io::duplex(8 * 1024);
 
         let config = Arc::new(sample_config("file_streamer"));
+        let events = EventBus::new();
 
-        let mut tx = Sender { stream: tx_stream };
+        let mut tx = Sender::new(tx_stream, &config);
         let mut rx = Receiver {
             ident: 0,
             files: HashMap::new(),
+            batches: HashMap::new(),
+            packs: HashMap::new(),
+            groups: HashMap::new(),
+            ranges: HashMap::new(),
             stream: rx_stream,
             config: &config,
+            events: &events,
             peer_address: "".into(),
+            cancel: CancellationToken::new(),
+            usage: Default::default(),
+            over_quota: Default::default(),
+            mismatched: HashSet::new(),
+            sampled_checksum_min_size: None,
+            progress: None,
         };
 
         create_tmp_file("./tmp/file_streamer/file_1".into(), "some content");
@@ -179,7 +1407,9 @@ mod tests {
 
         let file_handle = rx.prepare_file_transfer(file);
         tokio::spawn(async move {
-            tx.send_file(file_handle, &mut buffer).await.unwrap();
+            tx.send_file(file_handle, &mut buffer, ChecksumMode::Full)
+                .await
+                .unwrap();
         });
 
         let events_buffer = FileEventsBuffer::new(config.clone());
@@ -194,4 +1424,383 @@ mod tests {
 
         return Ok(());
     }
+
+    #[tokio::test]
+    async fn can_receive_batches() -> crate::Result<()> {
+        let (rx_stream, tx_stream) = tokio::io::duplex(8 * 1024);
+
+        let config = Arc::new(sample_config("receive_batches"));
+        let events = EventBus::new();
+
+        let mut tx = Sender::new(tx_stream, &config);
+        let mut rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        // files differing only by extension are received at the same time
+        let files: Vec<FileInfo> = ["file.txt", "file.md"]
+            .iter()
+            .map(|path| FileInfo {
+                alias: "a".into(),
+                path: PathBuf::from(path),
+                modified_at: Some(0),
+                created_at: None,
+                deleted_at: None,
+                size: None,
+                kind: FileKind::Regular,
+                extra: Default::default(),
+            })
+            .collect();
+
+        let batch_handle = rx.prepare_batch_transfer(files);
+        tokio::spawn(async move {
+            tx.send_batch(batch_handle, &[b"text".to_vec(), b"markdown".to_vec()])
+                .await
+                .unwrap();
+        });
+
+        let events_buffer = FileEventsBuffer::new(config.clone());
+        rx.wait_files(&events_buffer).await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/receive_batches/file.txt").await?,
+            "text"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/receive_batches/file.md").await?,
+            "markdown"
+        );
+
+        tokio::fs::remove_dir_all("./tmp/receive_batches").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refused_batches_are_discarded() -> crate::Result<()> {
+        let (rx_stream, tx_stream) = tokio::io::duplex(8 * 1024);
+
+        let config = Arc::new(sample_config("refused_batches"));
+        let events = EventBus::new();
+
+        /// Refuses to receive `lock`
+        struct RefuseLock;
+        impl crate::events::SyncObserver for RefuseLock {
+            fn on_file_received(&self, file: &FileInfo) -> Decision {
+                match file.path == Path::new("lock") {
+                    true => Decision::Veto,
+                    false => Decision::Proceed,
+                }
+            }
+        }
+        events.add_observer(Arc::new(RefuseLock));
+
+        let mut tx = Sender::new(tx_stream, &config);
+        let mut rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        let files: Vec<FileInfo> = ["index", "lock"]
+            .iter()
+            .map(|path| FileInfo {
+                alias: "a".into(),
+                path: PathBuf::from(path),
+                modified_at: Some(0),
+                created_at: None,
+                deleted_at: None,
+                size: None,
+                kind: FileKind::Regular,
+                extra: Default::default(),
+            })
+            .collect();
+
+        let batch_handle = rx.prepare_batch_transfer(files);
+        tokio::spawn(async move {
+            tx.send_batch(batch_handle, &[b"idx1".to_vec(), b"lck1".to_vec()])
+                .await
+                .unwrap();
+        });
+
+        let events_buffer = FileEventsBuffer::new(config.clone());
+        rx.wait_files(&events_buffer).await?;
+
+        // a file of the batch was refused, so none of them replaced the local files
+        assert!(!Path::new("./tmp/refused_batches/index").exists());
+        assert!(!Path::new("./tmp/refused_batches/index.ironcarrier").exists());
+        assert!(!Path::new("./tmp/refused_batches/lock").exists());
+
+        tokio::fs::remove_dir_all("./tmp/refused_batches")
+            .await
+            .ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_receive_packs() -> crate::Result<()> {
+        let (rx_stream, tx_stream) = tokio::io::duplex(8 * 1024);
+
+        let config = Arc::new(sample_config("receive_packs"));
+        let events = EventBus::new();
+
+        let mut tx = Sender::new(tx_stream, &config);
+        let mut rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        let files: Vec<FileInfo> = ["complete", "unreadable", "truncated", "last"]
+            .iter()
+            .map(|path| FileInfo {
+                alias: "a".into(),
+                path: PathBuf::from(path),
+                modified_at: Some(0),
+                created_at: None,
+                deleted_at: None,
+                size: Some(8),
+                kind: FileKind::Regular,
+                extra: Default::default(),
+            })
+            .collect();
+
+        let pack_handle = rx.prepare_pack_transfer(files);
+        tokio::spawn(async move {
+            tx.start_pack(pack_handle).await.unwrap();
+            tx.send_pack_entry(Some((&mut &b"complete"[..], 8)))
+                .await
+                .unwrap();
+            tx.send_pack_entry::<&[u8]>(None).await.unwrap();
+            // the file got shorter after it was listed
+            tx.send_pack_entry(Some((&mut &b"short"[..], 8)))
+                .await
+                .unwrap();
+            tx.send_pack_entry(Some((&mut &b"last"[..], 4)))
+                .await
+                .unwrap();
+        });
+
+        let events_buffer = FileEventsBuffer::new(config.clone());
+        rx.wait_files(&events_buffer).await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/receive_packs/complete").await?,
+            "complete"
+        );
+        assert!(!Path::new("./tmp/receive_packs/unreadable").exists());
+        assert!(rx.checksum_mismatched(Path::new("truncated")));
+        assert!(!Path::new("./tmp/receive_packs/truncated").exists());
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/receive_packs/last").await?,
+            "last"
+        );
+
+        tokio::fs::remove_dir_all("./tmp/receive_packs").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn atomic_groups_are_applied_together() -> crate::Result<()> {
+        let (rx_stream, tx_stream) = tokio::io::duplex(8 * 1024);
+
+        let config = Arc::new(sample_config("receive_groups"));
+        let events = EventBus::new();
+
+        let mut tx = Sender::new(tx_stream, &config);
+        let mut rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        let file = |path: &str| FileInfo {
+            alias: "a".into(),
+            path: PathBuf::from(path),
+            modified_at: Some(0),
+            created_at: None,
+            deleted_at: None,
+            size: Some(4),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        let complete = rx.prepare_group_transfer(vec![file("db"), file("wal")]);
+        let incomplete = rx.prepare_group_transfer(vec![file("index"), file("lock")]);
+        tokio::spawn(async move {
+            tx.start_pack(complete).await.unwrap();
+            tx.send_pack_entry(Some((&mut &b"db 1"[..], 4)))
+                .await
+                .unwrap();
+            tx.send_pack_entry(Some((&mut &b"wal1"[..], 4)))
+                .await
+                .unwrap();
+            tx.start_pack(incomplete).await.unwrap();
+            tx.send_pack_entry(Some((&mut &b"idx1"[..], 4)))
+                .await
+                .unwrap();
+            tx.send_pack_entry::<&[u8]>(None).await.unwrap();
+        });
+
+        let events_buffer = FileEventsBuffer::new(config.clone());
+        rx.wait_files(&events_buffer).await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/receive_groups/db").await?,
+            "db 1"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/receive_groups/wal").await?,
+            "wal1"
+        );
+        // a file of the group couldn't be sent, so none of them replaced the local files
+        assert!(!Path::new("./tmp/receive_groups/index").exists());
+        assert!(!Path::new("./tmp/receive_groups/lock").exists());
+
+        tokio::fs::remove_dir_all("./tmp/receive_groups").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupted_files_are_discarded() -> crate::Result<()> {
+        let (rx_stream, mut tx_stream) = tokio::io::duplex(8 * 1024);
+
+        let config = Arc::new(sample_config("corrupted_files"));
+        let events = EventBus::new();
+        let mut rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        let file = FileInfo {
+            size: Some(7),
+            deleted_at: None,
+            ..FileInfo::new_deleted("a".into(), "file".into(), None)
+        };
+        let file_handle = rx.prepare_file_transfer(file);
+        // the content changed after the checksum was computed
+        tx_stream
+            .write_all(&bincode::serialize(&file_handle)?)
+            .await?;
+        tx_stream.write_all(b"changed").await?;
+        tx_stream.write_all(&Sha256::digest(b"content")).await?;
+
+        let events_buffer = FileEventsBuffer::new(config.clone());
+        rx.wait_files(&events_buffer).await?;
+
+        assert!(rx.checksum_mismatched(Path::new("file")));
+        assert!(!Path::new("./tmp/corrupted_files/file").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_over_quota_are_refused() -> crate::Result<()> {
+        create_tmp_file(PathBuf::from("./tmp/receive_quota/existing"), "12345");
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/receive_quota\"
+            [limits.a]
+            max_total_size = 10"
+                .to_string(),
+        )?;
+        let events = EventBus::new();
+        let mut subscriber = events.subscribe();
+        let (rx_stream, _) = tokio::io::duplex(10);
+        let rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        let file = |path: &str, size| FileInfo {
+            size: Some(size),
+            deleted_at: None,
+            ..FileInfo::new_deleted("a".into(), path.into(), None)
+        };
+        rx.check_quota(&[file("new", 5)]).await?;
+        // replacing a file only uses the difference
+        rx.check_quota(&[file("existing", 10)]).await?;
+        assert!(rx.check_quota(&[file("new", 6)]).await.is_err());
+        assert_eq!(
+            subscriber.recv().await?,
+            Event::QuotaExceeded {
+                alias: "a".into(),
+                used: 5,
+                quota: 10
+            }
+        );
+
+        tokio::fs::remove_dir_all("./tmp/receive_quota").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_larger_than_the_disk_are_refused() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/receive_disk_full").await?;
+        let config = sample_config("receive_disk_full");
+        let events = EventBus::new();
+        let (rx_stream, _) = tokio::io::duplex(10);
+        let rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        let file = |size| FileInfo {
+            size: Some(size),
+            deleted_at: None,
+            ..FileInfo::new_deleted("a".into(), "new".into(), None)
+        };
+        rx.check_available_space(&[file(5)])?;
+        let err = rx.check_available_space(&[file(u64::MAX / 2)]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<IronCarrierError>(),
+            Some(IronCarrierError::StorageFull(alias)) if alias == "a"
+        ));
+
+        tokio::fs::remove_dir_all("./tmp/receive_disk_full").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_assemble_file_from_ranges() -> crate::Result<()> {
+        let (rx_stream, tx_stream) = tokio::io::duplex(8 * 1024);
+
+        let config = Arc::new(sample_config("assemble_ranges"));
+        let events = EventBus::new();
+
+        let mut tx = Sender::new(tx_stream, &config);
+        let mut rx = Receiver::new(rx_stream, &config, &events, "".into());
+
+        create_tmp_file(
+            "./tmp/assemble_ranges/source".into(),
+            "first half|second half",
+        );
+        let sha256 = crate::manifest::hash_file(
+            Path::new("./tmp/assemble_ranges/source"),
+            HashAlgorithm::Sha256,
+        )
+        .await?;
+
+        let file = FileInfo {
+            alias: "a".into(),
+            path: PathBuf::from("file"),
+            modified_at: Some(0),
+            created_at: None,
+            deleted_at: None,
+            size: Some(22),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        rx.prepare_temp_file(&file).await?;
+        // the second half arrives first, like it would from a faster peer
+        let second = rx.prepare_range_transfer(file.clone(), 11, 11);
+        let first = rx.prepare_range_transfer(file.clone(), 0, 11);
+        tokio::spawn(async move {
+            tx.send_file(second, &mut &b"second half"[..], ChecksumMode::Full)
+                .await
+                .unwrap();
+            tx.send_file(first, &mut &b"first half|"[..], ChecksumMode::Full)
+                .await
+                .unwrap();
+        });
+
+        let events_buffer = FileEventsBuffer::new(config.clone());
+        rx.wait_files(&events_buffer).await?;
+        assert!(
+            rx.complete_temp_file(&file, &sha256, &events_buffer)
+                .await?
+        );
+
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/assemble_ranges/file").await?,
+            "first half|second half"
+        );
+
+        tokio::fs::remove_dir_all("./tmp/assemble_ranges").await?;
+
+        Ok(())
+    }
 }
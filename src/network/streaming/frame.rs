@@ -1,5 +1,4 @@
 use bytes::{Buf, BytesMut};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{
     io::AsyncRead,
     io::AsyncReadExt,
@@ -7,88 +6,10 @@ use tokio::{
     io::{AsyncWriteExt, ReadHalf, WriteHalf},
 };
 
+use super::codec::{decode_frame, encode_frame, FrameMessage};
 use crate::IronCarrierError;
 
 const BUFFER_SIZE: usize = 8 * 1024;
-const COMMAND_SIZE: usize = 8;
-
-/// A Message to be serialized or deserialized for the rpc call
-///
-/// It uses [bincode] to serialize arguments
-/// #Examples
-///
-/// ``` ignore
-/// let mut message = FrameMessage::new("message_name".to_string());
-/// message.append_arg(1);
-/// message.append_arg("a");
-/// ```
-#[derive(Serialize, Deserialize, Debug)]
-pub struct FrameMessage {
-    ident: String,
-    data: Vec<u8>,
-}
-
-impl FrameMessage {
-    /// Creates a new [FrameMessage] without any args
-    pub fn new(ident: &str) -> Self {
-        Self {
-            ident: ident.to_owned(),
-            data: Vec::new(),
-        }
-    }
-
-    /// Returns the name of this frame
-    pub fn frame_ident(&self) -> &str {
-        &self.ident
-    }
-
-    /// Add an argument of type `T` to this frame
-    ///
-    /// Returns [Ok] if successful
-    pub fn with_arg<A: Serialize>(mut self, arg: &A) -> crate::Result<Self> {
-        let ser_value = bincode::serialize(arg)?;
-        let ser_size = bincode::serialize(&ser_value.len())?;
-
-        self.data.extend(ser_size);
-        self.data.extend(ser_value);
-
-        Ok(self)
-    }
-
-    /// Return the next argument in this frame
-    ///
-    /// This function may fail if the argument can't be deserialized to [`T`]
-    /// or if there isn't an argument to be retrieved
-    ///
-    /// [`OK`]`(`[T]`)` if the argument is correct
-    pub fn next_arg<A: DeserializeOwned>(&mut self) -> crate::Result<A> {
-        let size = std::mem::size_of::<usize>();
-
-        if self.data.len() < size {
-            return Err(IronCarrierError::ParseCommandError.into());
-        }
-
-        let bytes: Vec<u8> = self.data.drain(0..size).collect();
-
-        let size: usize = bincode::deserialize(&bytes)?;
-
-        if self.data.len() < size {
-            return Err(IronCarrierError::ParseCommandError.into());
-        }
-
-        let bytes: Vec<u8> = self.data.drain(0..size).collect();
-        let result = bincode::deserialize::<A>(&bytes)?;
-
-        Ok(result)
-    }
-}
-
-impl From<&str> for FrameMessage {
-    /// Creates a new [`FrameMessage`] from a primitive [`str`]
-    fn from(name: &str) -> Self {
-        FrameMessage::new(name)
-    }
-}
 
 /// Creates and return a pair of [FrameReader]  and [FrameWriter] using stream
 /// It consumes stream in the process
@@ -128,23 +49,13 @@ impl<T: AsyncRead + Unpin> FrameReader<T> {
     /// Returns [Ok]`(`[Some]`(`[FrameMessage]`)` `)` if success  
     /// Returns [Ok]`(`[None]`)` if there is not enough information to parse a frame
     fn parse_frame(&mut self) -> crate::Result<Option<FrameMessage>> {
-        if self.buffer.remaining() < COMMAND_SIZE {
-            return Ok(None);
-        }
-
-        let bytes = &self.buffer.as_ref()[..COMMAND_SIZE];
-        let to_read: usize = bincode::deserialize(bytes)?;
-
-        if self.buffer.remaining() < to_read as usize + COMMAND_SIZE {
-            return Ok(None);
+        match decode_frame(self.buffer.as_ref())? {
+            Some((frame, used)) => {
+                self.buffer.advance(used);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
         }
-
-        let bytes = &self.buffer.as_ref()[COMMAND_SIZE..COMMAND_SIZE + to_read];
-        let result: FrameMessage = bincode::deserialize(bytes)?;
-
-        self.buffer.advance(to_read + COMMAND_SIZE);
-
-        return Ok(Some(result));
     }
 
     /// Read and parse the next [FrameMessage]  
@@ -158,10 +69,7 @@ impl<T: AsyncRead + Unpin> FrameReader<T> {
     /// Returns [Err] if there isn't enought information for a full [FrameMessage] to be parsed  
     pub async fn next_frame(&mut self) -> crate::Result<Option<FrameMessage>> {
         loop {
-            if let Some(frame) = self
-                .parse_frame()
-                .map_err(|_| IronCarrierError::ParseCommandError)?
-            {
+            if let Some(frame) = self.parse_frame()? {
                 return Ok(Some(frame));
             }
 
@@ -194,15 +102,10 @@ impl<T: AsyncWrite + Unpin> FrameWriter<T> {
     ///
     /// It may fail if stream can't be written
     pub async fn write_frame(&mut self, frame: FrameMessage) -> crate::Result<()> {
-        let ser_value = bincode::serialize(&frame)?;
-        let ser_size = bincode::serialize(&ser_value.len())?;
+        let encoded = encode_frame(&frame)?;
 
         self.socket_stream
-            .write_all(&ser_size[..])
-            .await
-            .map_err(|_| IronCarrierError::NetworkIOWritingError)?;
-        self.socket_stream
-            .write_all(&ser_value[..])
+            .write_all(&encoded[..])
             .await
             .map_err(|_| IronCarrierError::NetworkIOWritingError)?;
 
@@ -240,15 +143,15 @@ mod tests {
         });
 
         assert_eq!(
-            server_reader.next_frame().await?.unwrap().ident,
+            server_reader.next_frame().await?.unwrap().frame_ident(),
             "some message".to_string()
         );
         assert_eq!(
-            server_reader.next_frame().await?.unwrap().ident,
+            server_reader.next_frame().await?.unwrap().frame_ident(),
             "message_data".to_string()
         );
         assert_eq!(
-            server_reader.next_frame().await?.unwrap().ident,
+            server_reader.next_frame().await?.unwrap().frame_ident(),
             "other message".to_string()
         );
 
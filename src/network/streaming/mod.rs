@@ -1,5 +1,14 @@
+mod checksum;
+mod chunk_size;
+pub mod codec;
 mod file_streamer;
 mod frame;
+mod rate_limit;
+mod zero_copy;
 
+pub(crate) use checksum::{negotiate as negotiate_sampled_checksum, ChecksumMode};
+pub(crate) use chunk_size::MAX_CHUNK_SIZE;
+pub(crate) use codec::FrameMessage;
 pub(crate) use file_streamer::{file_streamers, Receiver as FileReceiver, Sender as FileSender};
-pub(crate) use frame::{frame_stream, FrameMessage, FrameReader, FrameWriter};
+pub(crate) use frame::{frame_stream, FrameReader, FrameWriter};
+pub(crate) use zero_copy::ZeroCopySocket;
@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{BandwidthWindow, Config};
+
+/// Time the stream can fall behind the limit and still catch up, longer idle periods are not saved for a burst
+const MAX_CATCH_UP: Duration = Duration::from_secs(1);
+/// Time between the evaluations of [Config::bandwidth_schedule] while a stream is sending
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps the bytes sent in a stream under a number of bytes per second
+///
+/// The rate is averaged since the first chunk, so a slow chunk lets the next ones go faster
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimit {
+    bytes_per_second: u64,
+    started_at: Option<Instant>,
+    sent: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            started_at: None,
+            sent: 0,
+        }
+    }
+
+    /// Records that `bytes` were sent at `now`, returns how long to wait before sending more
+    pub fn record(&mut self, bytes: usize, now: Instant) -> Duration {
+        let started_at = *self.started_at.get_or_insert(now);
+        let elapsed = now.duration_since(started_at);
+        if elapsed > self.expected_time(self.sent) + MAX_CATCH_UP {
+            self.started_at = Some(now);
+            self.sent = bytes as u64;
+            return self.expected_time(self.sent);
+        }
+
+        self.sent += bytes as u64;
+        self.expected_time(self.sent).saturating_sub(elapsed)
+    }
+
+    fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Time `bytes` take to be sent at the limit
+    fn expected_time(&self, bytes: u64) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64)
+    }
+
+    /// Records that `bytes` were sent, waiting until the stream is back within the limit
+    pub async fn throttle(&mut self, bytes: usize) {
+        let wait = self.record(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Bandwidth limit of a stream, the limit of the network of the peer, replaced by [Config::bandwidth_schedule] during
+/// its windows
+///
+/// The schedule is evaluated again while the stream is sending, so long transfers follow it
+pub(crate) struct BandwidthLimit {
+    schedule: Vec<BandwidthWindow>,
+    network_limit: Option<u64>,
+    rate_limit: Option<RateLimit>,
+    checked_at: Option<Instant>,
+}
+
+impl BandwidthLimit {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            schedule: config.bandwidth_schedule.clone(),
+            network_limit: None,
+            rate_limit: None,
+            checked_at: None,
+        }
+    }
+
+    /// Sets the limit of the network of the peer, used outside the windows of the schedule, [None] if unlimited
+    pub fn set_network_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.network_limit = bytes_per_second;
+        self.rate_limit = bytes_per_second.map(RateLimit::new);
+        self.checked_at = None;
+    }
+
+    /// Returns the bytes per second at `minute`, counted from midnight, [None] if unlimited
+    fn limit_at(&self, minute: u32) -> Option<u64> {
+        match self.schedule.iter().find(|window| window.contains(minute)) {
+            Some(window) => window.limit(),
+            None => self.network_limit,
+        }
+    }
+
+    /// Evaluates the schedule at `minute`, the rate is only reset when the limit changes
+    fn update(&mut self, minute: u32, now: Instant) {
+        let limit = self.limit_at(minute);
+        if self
+            .rate_limit
+            .map(|rate_limit| rate_limit.bytes_per_second())
+            != limit
+        {
+            log::debug!("bandwidth limit changed to {:?} bytes per second", limit);
+            self.rate_limit = limit.map(RateLimit::new);
+        }
+        self.checked_at = Some(now);
+    }
+
+    /// Records that `bytes` were sent, waiting until the stream is back within the current limit
+    pub async fn throttle(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let due = self
+            .checked_at
+            .is_none_or(|checked_at| now.duration_since(checked_at) >= SCHEDULE_CHECK_INTERVAL);
+        if !self.schedule.is_empty() && due {
+            self.update(minute_of_day(), now);
+        }
+
+        if let Some(rate_limit) = self.rate_limit.as_mut() {
+            rate_limit.throttle(bytes).await;
+        }
+    }
+}
+
+/// Returns the minutes since midnight, in local time
+#[cfg(unix)]
+fn minute_of_day() -> u32 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut local) }.is_null() {
+        return (now / 60 % (24 * 60)) as u32;
+    }
+
+    (local.tm_hour * 60 + local.tm_min) as u32
+}
+
+/// Returns the minutes since midnight, the time zone is not available, so UTC is used
+#[cfg(not(unix))]
+fn minute_of_day() -> u32 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() / 60 % (24 * 60)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_is_kept_under_the_limit() {
+        let mut rate_limit = RateLimit::new(1000);
+        let start = Instant::now();
+
+        assert_eq!(rate_limit.record(500, start), Duration::from_millis(500));
+        assert_eq!(
+            rate_limit.record(500, start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+        // a slow chunk lets the next ones catch up
+        assert_eq!(
+            rate_limit.record(500, start + Duration::from_millis(1500)),
+            Duration::ZERO
+        );
+        // but idle time is not saved for a burst
+        assert_eq!(
+            rate_limit.record(1000, start + Duration::from_secs(10)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn schedule_replaces_the_network_limit() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp\"
+
+            [[bandwidth_schedule]]
+            from = \"08:00\"
+            to = \"18:00\"
+            limit = 1000
+
+            [[bandwidth_schedule]]
+            from = \"22:00\"
+            to = \"06:00\""
+                .to_string(),
+        )?;
+
+        let mut bandwidth = BandwidthLimit::new(&config);
+        bandwidth.set_network_limit(Some(500));
+        let now = Instant::now();
+
+        bandwidth.update(9 * 60, now);
+        assert_eq!(bandwidth.rate_limit.unwrap().bytes_per_second(), 1000);
+        bandwidth.update(23 * 60, now);
+        assert!(bandwidth.rate_limit.is_none());
+        bandwidth.update(3 * 60, now);
+        assert!(bandwidth.rate_limit.is_none());
+        bandwidth.update(20 * 60, now);
+        assert_eq!(bandwidth.rate_limit.unwrap().bytes_per_second(), 500);
+
+        assert!(Config::parse_content(
+            "[paths]
+            a = \"./tmp\"
+            [[bandwidth_schedule]]
+            from = \"8am\"
+            to = \"18:00\""
+                .to_string()
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
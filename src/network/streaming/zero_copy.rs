@@ -0,0 +1,145 @@
+//! File contents written from the disk to the socket by the kernel, see [Config::zero_copy_send]
+//!
+//! Only the streams of [crate::network::transport::TcpTransport] are written this way, other transports, like tunnels,
+//! get the content through their own writer. The content is still read once to compute its checksum, but it isn't
+//! copied back to the kernel to be sent
+
+use crate::{config::Config, network::transport::BoxedStream};
+
+/// Socket of a file stream, the file contents are sent to it with `sendfile`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) struct ZeroCopySocket {
+    fd: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl ZeroCopySocket {
+    /// Returns the socket of `stream`, [None] if it isn't a TCP stream or [Config::zero_copy_send] is disabled
+    pub fn of(stream: &BoxedStream, config: &Config) -> Option<Self> {
+        use std::os::fd::AsFd;
+
+        if !config.zero_copy_send {
+            return None;
+        }
+
+        let tcp_stream = stream
+            .as_ref()
+            .as_any()
+            .downcast_ref::<tokio::net::TcpStream>()?;
+        let fd = tcp_stream.as_fd().try_clone_to_owned().ok()?;
+        match tokio::io::unix::AsyncFd::with_interest(fd, tokio::io::Interest::WRITABLE) {
+            Ok(fd) => Some(Self { fd }),
+            Err(err) => {
+                log::debug!("cannot send files without copying them: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Sends `length` bytes of `file` to the socket, starting at `offset`
+    pub async fn send(
+        &self,
+        file: &impl std::os::fd::AsRawFd,
+        offset: u64,
+        length: usize,
+    ) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mut offset = offset as libc::off_t;
+        let mut remaining = length;
+        while remaining > 0 {
+            let mut guard = self.fd.writable().await?;
+            let sent = guard.try_io(|fd| {
+                let sent = unsafe {
+                    libc::sendfile(fd.as_raw_fd(), file.as_raw_fd(), &mut offset, remaining)
+                };
+                if sent < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(sent as usize)
+                }
+            });
+
+            match sent {
+                // the file is shorter than it was when it was read
+                Ok(Ok(0)) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(Ok(sent)) => remaining -= sent,
+                Ok(Err(err)) => return Err(err),
+                Err(_would_block) => continue,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Files are always sent through the writer of the stream on this platform
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) struct ZeroCopySocket;
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl ZeroCopySocket {
+    /// Zero copy is not supported on this platform, always returns [None]
+    pub fn of(_stream: &BoxedStream, _config: &Config) -> Option<Self> {
+        None
+    }
+
+    /// Zero copy is not supported on this platform, always fails
+    pub async fn send(
+        &self,
+        _file: &tokio::fs::File,
+        _offset: u64,
+        _length: usize,
+    ) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+    use crate::{
+        events::EventBus,
+        network::streaming::{file_streamers, ChecksumMode},
+    };
+    use sha2::{Digest, Sha256};
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn local_files_are_sent_to_tcp_sockets() -> crate::Result<()> {
+        std::fs::create_dir_all("./tmp/zero_copy")?;
+        std::fs::write("./tmp/zero_copy/file", b"file content")?;
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/zero_copy\""
+                .to_string(),
+        )?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let stream: BoxedStream =
+            Box::new(tokio::net::TcpStream::connect(listener.local_addr()?).await?);
+        let (mut remote, _) = listener.accept().await?;
+
+        let socket = ZeroCopySocket::of(&stream, &config);
+        assert!(socket.is_some());
+
+        let events = EventBus::new();
+        let (_rx, tx) = file_streamers(stream, &config, &events, "".into());
+        let mut tx = tx.with_zero_copy(socket);
+        tx.send_local_file(
+            7,
+            std::path::Path::new("./tmp/zero_copy/file"),
+            ChecksumMode::Full,
+        )
+        .await?;
+
+        let mut received = vec![0u8; 8 + 12 + 32];
+        remote.read_exact(&mut received).await?;
+        assert_eq!(bincode::deserialize::<u64>(&received[..8])?, 7);
+        assert_eq!(&received[8..20], b"file content");
+        assert_eq!(&received[20..], &Sha256::digest(b"file content")[..]);
+
+        std::fs::remove_dir_all("./tmp/zero_copy")?;
+        Ok(())
+    }
+}
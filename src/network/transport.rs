@@ -0,0 +1,266 @@
+//! Transports used to connect to the peers
+//!
+//! Each peer session uses two streams, one for the rpc frames and one for the file contents, both are established by
+//! a [Transport]. [TcpTransport] is used by default, other transports, like unix sockets, in-memory streams for tests
+//! or custom tunnels, can be provided with [crate::IronCarrierBuilder::transport]
+
+use std::{any::Any, collections::HashMap, time::Duration};
+
+use futures::future::BoxFuture;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+use super::locality::host_of;
+use crate::config::{Config, SocketOptions};
+
+/// Byte stream between two peers, the frames and file contents are written to it by iron-carrier
+pub trait TransportStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {
+    /// Returns the stream as [Any], so the TCP streams can be written without copying the file contents
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> TransportStream for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Stream returned by a [Transport]
+pub type BoxedStream = Box<dyn TransportStream>;
+
+/// Establishes the streams with the peers
+pub trait Transport: Send + Sync {
+    /// Connects to the peer at `address`, as it is written in the peers list of the configuration
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, crate::Result<BoxedStream>>;
+
+    /// Starts listening for connections from the peers on `port`
+    fn listen(&self, port: u32) -> BoxFuture<'_, crate::Result<Box<dyn TransportListener>>>;
+}
+
+/// Accepts the connections from the peers
+pub trait TransportListener: Send {
+    /// Waits for the next connection, returning the stream and the address of the peer
+    ///
+    /// Both streams of a peer session must report the same address, since they are paired by it
+    fn accept(&mut self) -> BoxFuture<'_, crate::Result<(BoxedStream, String)>>;
+}
+
+/// Default [Transport], connects to the peers with TCP
+///
+/// The sockets follow [Config::socket] and [Config::peer_sockets] when the transport is created with [TcpTransport::new],
+/// the default transport keeps the operating system defaults
+#[derive(Debug, Default, Clone)]
+pub struct TcpTransport {
+    socket: SocketOptions,
+    peer_sockets: HashMap<String, SocketOptions>,
+}
+
+impl TcpTransport {
+    /// Creates a transport with the socket options of `config`
+    pub fn new(config: &Config) -> Self {
+        Self {
+            socket: config.socket.clone(),
+            peer_sockets: config.peer_sockets.clone(),
+        }
+    }
+
+    /// Returns the socket options for the peer at `address`, the peers are matched by host
+    fn socket_options(&self, address: &str) -> SocketOptions {
+        let host = host_of(address);
+        match self
+            .peer_sockets
+            .iter()
+            .find(|(peer, _)| peer.as_str() == address || host_of(peer) == host)
+        {
+            Some((_, options)) => options.or(&self.socket),
+            None => self.socket.clone(),
+        }
+    }
+}
+
+/// Applies `options` to `stream`, options that can't be set are only logged, the connection is still used
+fn apply_socket_options(stream: &TcpStream, options: &SocketOptions) {
+    let socket = SockRef::from(stream);
+    let mut result = Ok(());
+    if let Some(nodelay) = options.nodelay {
+        result = result.and_then(|_| socket.set_nodelay(nodelay));
+    }
+    if let Some(size) = options.send_buffer_size {
+        result = result.and_then(|_| socket.set_send_buffer_size(size));
+    }
+    if let Some(size) = options.recv_buffer_size {
+        result = result.and_then(|_| socket.set_recv_buffer_size(size));
+    }
+    match options.keepalive_seconds {
+        Some(0) => result = result.and_then(|_| socket.set_keepalive(false)),
+        Some(seconds) => {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(seconds));
+            result = result.and_then(|_| socket.set_tcp_keepalive(&keepalive));
+        }
+        None => {}
+    }
+
+    if let Err(err) = result {
+        log::warn!("cannot set socket options {:?}: {}", options, err);
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, crate::Result<BoxedStream>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(address).await?;
+            apply_socket_options(&stream, &self.socket_options(address));
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+
+    fn listen(&self, port: u32) -> BoxFuture<'_, crate::Result<Box<dyn TransportListener>>> {
+        Box::pin(async move {
+            let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+            Ok(Box::new(TcpTransportListener {
+                listener,
+                transport: self.clone(),
+            }) as Box<dyn TransportListener>)
+        })
+    }
+}
+
+impl TransportListener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, crate::Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, socket) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as BoxedStream, socket.ip().to_string()))
+        })
+    }
+}
+
+/// Listener of [TcpTransport], applies the socket options of the peers to the accepted connections
+struct TcpTransportListener {
+    listener: TcpListener,
+    transport: TcpTransport,
+}
+
+impl TransportListener for TcpTransportListener {
+    fn accept(&mut self) -> BoxFuture<'_, crate::Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, socket) = self.listener.accept().await?;
+            let address = socket.ip().to_string();
+            apply_socket_options(&stream, &self.transport.socket_options(&address));
+            Ok((Box::new(stream) as BoxedStream, address))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        config::Config,
+        events::{Event, EventBus, PeerActivity},
+        network::{authorization::PeerAuthorizations, peer::Peer, server::Server},
+        simulation::MemoryNetwork,
+        sync::{
+            alias_locks::AliasLocks, file_events_buffer::FileEventsBuffer,
+            pause_switch::PauseSwitch,
+        },
+    };
+
+    #[tokio::test]
+    async fn peers_can_use_custom_transport() -> crate::Result<()> {
+        std::fs::create_dir_all("./tmp/transport")?;
+        let config = Arc::new(Config::parse_content(
+            "port = 9000
+            [paths]
+            a = \"./tmp/transport\""
+                .to_string(),
+        )?);
+        let transport: Arc<dyn Transport> = Arc::new(MemoryNetwork::new().transport("memory"));
+        let events_buffer = Arc::new(FileEventsBuffer::new(config.clone()));
+        let events = Arc::new(EventBus::new());
+
+        let mut server = Server::new(
+            config.clone(),
+            events_buffer.clone(),
+            events.clone(),
+            Arc::new(AliasLocks::new(&config)),
+            Arc::new(PauseSwitch::new(events.clone(), CancellationToken::new())),
+            Arc::new(PeerAuthorizations::new(&config, Default::default())),
+            transport.clone(),
+            CancellationToken::new(),
+        );
+        let (sync_events, _sync_events_receiver) = mpsc::channel(1);
+        server.start(sync_events).await?;
+
+        // the sessions opened by this node are recorded apart from the ones the server accepts
+        let peer_events = EventBus::new();
+        let mut sessions = peer_events.subscribe();
+        let mut peer = Peer::new(
+            "memory:9000",
+            transport.as_ref(),
+            &config,
+            &events_buffer,
+            &peer_events,
+        )
+        .await?;
+        peer.fetch_peer_status().await?;
+        assert!(peer.alias_hash("a").is_some());
+        drop(peer);
+
+        for expected in [
+            PeerActivity::Connected,
+            PeerActivity::Accepted,
+            PeerActivity::Disconnected { reason: None },
+        ] {
+            match sessions.recv().await? {
+                Event::PeerSession { activity, .. } => assert_eq!(activity, expected),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+
+        std::fs::remove_dir_all("./tmp/transport")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn socket_options_are_applied_to_tcp_connections() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "port = 9001
+            [paths]
+            a = \"./tmp/socket_options\"
+            [socket]
+            nodelay = true
+            [peer_sockets]
+            \"127.0.0.1:9001\" = { keepalive_seconds = 30 }
+            \"localhost:9001\" = { nodelay = false }"
+                .to_string(),
+        )?;
+        let transport = TcpTransport::new(&config);
+        assert_eq!(
+            transport.socket_options("127.0.0.1"),
+            SocketOptions {
+                nodelay: Some(true),
+                keepalive_seconds: Some(30),
+                ..Default::default()
+            }
+        );
+        assert_eq!(transport.socket_options("10.0.0.1:9001"), config.socket);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let stream = TcpStream::connect(address).await?;
+        apply_socket_options(&stream, &transport.socket_options("127.0.0.1"));
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay()?);
+        assert!(socket.keepalive()?);
+
+        std::fs::remove_dir_all("./tmp/socket_options")?;
+        Ok(())
+    }
+}
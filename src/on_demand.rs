@@ -0,0 +1,243 @@
+//! Aliases where only the metadata is synchronized, see [Config::on_demand]
+//!
+//! Files received from the peers are kept as placeholders, the metadata of the file is kept in the alias root and an
+//! empty `<name>.placeholder.ironcarrier` file is left where the file would be. Placeholders are listed to the peers as
+//! the files they stand for, so the aliases still agree, and the content is only downloaded when the file is fetched
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    config::Config,
+    events::EventBus,
+    fs::FileInfo,
+    network::{peer::Peer, transport::TcpTransport},
+    sync::{file_events_buffer::FileEventsBuffer, FileAction},
+    IronCarrierError,
+};
+
+/// Placeholders are read, changed and written back, the placeholders of every alias are changed one at a time
+static PLACEHOLDERS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+type Entries = BTreeMap<PathBuf, FileInfo>;
+
+/// Files of an alias that are not downloaded yet
+pub(crate) struct Placeholders {
+    root_path: PathBuf,
+    state_path: PathBuf,
+}
+
+impl Placeholders {
+    pub fn new(alias_root_path: &Path) -> Self {
+        Placeholders {
+            root_path: alias_root_path.to_owned(),
+            state_path: alias_root_path.join(".placeholders.ironcarrier"),
+        }
+    }
+
+    /// Returns the placeholders, by path
+    pub async fn get(&self) -> Entries {
+        if !self.state_path.exists() {
+            return Entries::new();
+        }
+
+        match tokio::fs::read(&self.state_path).await {
+            Ok(contents) => bincode::deserialize(&contents).unwrap_or_else(|err| {
+                log::error!("placeholders are invalid, ignoring them: {}", err);
+                Entries::new()
+            }),
+            Err(err) => {
+                log::error!("cannot read placeholders: {}", err);
+                Entries::new()
+            }
+        }
+    }
+
+    /// Writes `entries`, removing the state file when there are none
+    async fn write(&self, entries: &Entries) -> crate::Result<()> {
+        if entries.is_empty() {
+            if self.state_path.exists() {
+                tokio::fs::remove_file(&self.state_path).await?;
+            }
+            return Ok(());
+        }
+
+        tokio::fs::write(&self.state_path, bincode::serialize(entries)?).await?;
+        Ok(())
+    }
+
+    /// Returns the empty file left where the file of `path` would be
+    fn stub_path(&self, path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(".placeholder.ironcarrier");
+        self.root_path.join(path).with_file_name(name)
+    }
+
+    /// Keeps `files` as placeholders, replacing the previous metadata of the same paths
+    pub async fn add(&self, files: &[FileInfo]) -> crate::Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let _lock = PLACEHOLDERS_LOCK.lock().await;
+        let mut entries = self.get().await;
+        for file in files {
+            let stub_path = self.stub_path(&file.path);
+            if let Some(parent) = stub_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&stub_path, b"").await?;
+            entries.insert(file.path.clone(), file.clone());
+        }
+
+        log::debug!("{} files kept as placeholders", files.len());
+        self.write(&entries).await
+    }
+
+    /// Removes the placeholder of `path`, returning its metadata, [None] if the file is not a placeholder
+    pub async fn remove(&self, path: &Path) -> crate::Result<Option<FileInfo>> {
+        let _lock = PLACEHOLDERS_LOCK.lock().await;
+        let mut entries = self.get().await;
+        let removed = entries.remove(path);
+        if removed.is_some() {
+            tokio::fs::remove_file(self.stub_path(path)).await.ok();
+            self.write(&entries).await?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Moves the placeholder of `src` to `dest`, returns false if `src` is not a placeholder
+    pub async fn rename(&self, src: &FileInfo, dest: &FileInfo) -> crate::Result<bool> {
+        match self.remove(&src.path).await? {
+            Some(_) => {
+                self.add(std::slice::from_ref(dest)).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Returns the placeholders of `alias` with a path inside `path`
+async fn placeholders_in(
+    config: &Config,
+    alias: &str,
+    path: &Path,
+) -> crate::Result<Vec<FileInfo>> {
+    let root_path = config
+        .paths
+        .get(alias)
+        .ok_or_else(|| IronCarrierError::AliasNotAvailable(alias.to_owned()))?;
+
+    Ok(Placeholders::new(root_path)
+        .get()
+        .await
+        .into_values()
+        .filter(|file| file.path.starts_with(path))
+        .collect())
+}
+
+/// Downloads the placeholders of `alias` inside `path`, a file or a folder relative to the alias root
+/// Each file is requested from the configured peers, in order, until one of them provides it
+/// Returns the paths of the downloaded files
+pub async fn fetch(config: &Config, alias: &str, path: &Path) -> crate::Result<Vec<PathBuf>> {
+    let alias = config.alias_id(alias);
+    let files = placeholders_in(config, &alias, path).await?;
+    if files.is_empty() {
+        return Err(IronCarrierError::PlaceholderNotFound(path.display().to_string()).into());
+    }
+
+    let events_buffer = FileEventsBuffer::new(Arc::new(config.clone()));
+    let events = EventBus::new();
+    let transport = TcpTransport::new(config);
+    let placeholders = Placeholders::new(&config.paths[&alias]);
+    let mut fetched = Vec::new();
+    for file in files {
+        let mut result: crate::Result<()> =
+            Err(IronCarrierError::PeerDisconectedError(file.path.display().to_string()).into());
+        for peer_address in config.peers.iter().flatten() {
+            result = async {
+                let mut peer =
+                    Peer::new(peer_address, &transport, config, &events_buffer, &events).await?;
+                peer.sync_action(&FileAction::Request(file.clone())).await
+            }
+            .await;
+
+            match &result {
+                Ok(_) => break,
+                Err(err) => log::debug!(
+                    "cannot fetch {:?} from peer {}: {}",
+                    file.path,
+                    peer_address,
+                    err
+                ),
+            }
+        }
+
+        result?;
+        placeholders.remove(&file.path).await?;
+        log::info!("fetched {:?} of alias {}", file.path, alias);
+        fetched.push(file.path);
+    }
+
+    Ok(fetched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version_store::VersionStores;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn placeholders_are_listed_as_their_files() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/on_demand").await?;
+        tokio::fs::write("./tmp/on_demand/local", b"local").await?;
+        let config = Config::parse_content(
+            "on_demand = [ \"a\" ]
+
+            [paths]
+            a = \"./tmp/on_demand\""
+                .to_string(),
+        )?;
+        let root_path = Path::new("./tmp/on_demand");
+        let placeholders = Placeholders::new(root_path);
+
+        let mut remote =
+            FileInfo::new_deleted("a".to_owned(), PathBuf::from("photos/remote"), None);
+        remote.deleted_at = None;
+        remote.modified_at = Some(10);
+        remote.size = Some(2048);
+        placeholders.add(std::slice::from_ref(&remote)).await?;
+        assert!(Path::new("./tmp/on_demand/photos/remote.placeholder.ironcarrier").exists());
+
+        let files = crate::fs::scan_path(root_path, "a", &config, &CancellationToken::new())
+            .await?
+            .to_vec()?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].path, Path::new("photos/remote"));
+        assert_eq!(files[1].size, Some(2048));
+
+        // files without content are left out of the lists used to read them
+        let files = crate::fs::walk_path(root_path, "a", &config).await?;
+        assert_eq!(files.len(), 1);
+
+        assert_eq!(
+            placeholders_in(&config, "a", Path::new("photos"))
+                .await?
+                .len(),
+            1
+        );
+        crate::fs::delete_file(&remote, &config, &VersionStores::new()).await?;
+        assert!(placeholders.get().await.is_empty());
+        assert!(!Path::new("./tmp/on_demand/photos/remote.placeholder.ironcarrier").exists());
+        assert!(!Path::new("./tmp/on_demand/.placeholders.ironcarrier").exists());
+
+        tokio::fs::remove_dir_all("./tmp/on_demand").await?;
+        Ok(())
+    }
+}
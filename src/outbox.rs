@@ -0,0 +1,242 @@
+//! Changes that couldn't be sent to offline peers, kept on disk until the peer is reachable again
+//!
+//! The outbox is kept in the alias root, with the changes of each peer. Only the change is kept, not the content, the
+//! files are read again when the outbox is sent. Each path keeps its latest change, and the outbox of a peer is cleared
+//! for an alias once the alias is fully synchronized with it, since the comparison finds the same changes
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, fs::FileInfo, sync::FileAction};
+
+/// Changes are read, changed and written back, the outboxes of every alias are changed one at a time
+static OUTBOX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Change of a file waiting in the outbox of a peer, see [crate::IronCarrier::outbox]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingChange {
+    /// Address of the peer missing the change
+    pub peer: String,
+    /// Alias of the file
+    pub alias: String,
+    /// Path of the file, relative to the alias root
+    pub path: PathBuf,
+    /// True when the file was deleted, otherwise it was created or changed
+    pub deleted: bool,
+}
+
+/// Change kept in the outbox, moves are kept as the deletion of the source and the creation of the destination
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Change {
+    Changed(FileInfo),
+    Deleted(FileInfo),
+}
+
+type Changes = HashMap<String, BTreeMap<PathBuf, Change>>;
+
+pub(crate) struct Outbox {
+    outbox_path: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(alias_root_path: &Path) -> Self {
+        Outbox {
+            outbox_path: alias_root_path.join(".outbox.ironcarrier"),
+        }
+    }
+
+    async fn read(&self) -> Changes {
+        if !self.outbox_path.exists() {
+            return Changes::new();
+        }
+
+        match tokio::fs::read(&self.outbox_path).await {
+            Ok(contents) => bincode::deserialize(&contents).unwrap_or_else(|err| {
+                log::error!("outbox is invalid, ignoring it: {}", err);
+                Changes::new()
+            }),
+            Err(err) => {
+                log::error!("cannot read outbox: {}", err);
+                Changes::new()
+            }
+        }
+    }
+
+    /// Writes `changes`, removing the outbox when there are none
+    async fn write(&self, mut changes: Changes) -> crate::Result<()> {
+        changes.retain(|_, changes| !changes.is_empty());
+        if changes.is_empty() {
+            if self.outbox_path.exists() {
+                tokio::fs::remove_file(&self.outbox_path).await?;
+            }
+            return Ok(());
+        }
+
+        tokio::fs::write(&self.outbox_path, bincode::serialize(&changes)?).await?;
+        Ok(())
+    }
+
+    /// Keeps `action` for `peer_address`, replacing the previous changes of the same paths
+    pub async fn push(&self, peer_address: &str, action: &FileAction) -> crate::Result<()> {
+        let _lock = OUTBOX_LOCK.lock().await;
+        let mut changes = self.read().await;
+        let peer = changes.entry(peer_address.to_owned()).or_default();
+        match action {
+            FileAction::Create(file) | FileAction::Update(file) => {
+                peer.insert(file.path.clone(), Change::Changed(file.clone()));
+            }
+            FileAction::Remove(file) => {
+                peer.insert(file.path.clone(), Change::Deleted(file.clone()));
+            }
+            FileAction::Move(src, dest) => {
+                peer.insert(src.path.clone(), Change::Deleted(src.clone()));
+                peer.insert(dest.path.clone(), Change::Changed(dest.clone()));
+            }
+            FileAction::Request(_) => return Ok(()),
+        }
+
+        self.write(changes).await
+    }
+
+    /// Returns the changes waiting for `peer_address`, in path order, as the actions that send them
+    pub async fn pending(&self, peer_address: &str) -> Vec<FileAction> {
+        self.read()
+            .await
+            .remove(peer_address)
+            .unwrap_or_default()
+            .into_values()
+            .map(|change| match change {
+                Change::Changed(file) => FileAction::Update(file),
+                Change::Deleted(file) => FileAction::Remove(file),
+            })
+            .collect()
+    }
+
+    /// Removes the changes of `paths` from the outbox of `peer_address`, once they were sent
+    pub async fn sent(&self, peer_address: &str, paths: &[PathBuf]) -> crate::Result<()> {
+        let _lock = OUTBOX_LOCK.lock().await;
+        let mut changes = self.read().await;
+        if let Some(peer) = changes.get_mut(peer_address) {
+            for path in paths {
+                peer.remove(path);
+            }
+        }
+
+        self.write(changes).await
+    }
+
+    /// Removes every change waiting for `peer_address`
+    pub async fn clear(&self, peer_address: &str) -> crate::Result<()> {
+        let _lock = OUTBOX_LOCK.lock().await;
+        let mut changes = self.read().await;
+        if changes.remove(peer_address).is_none() {
+            return Ok(());
+        }
+
+        self.write(changes).await
+    }
+
+    /// Returns the peers with changes waiting in this outbox
+    async fn peers(&self) -> Vec<String> {
+        self.read().await.into_keys().collect()
+    }
+}
+
+/// Returns the changes waiting in the outboxes of every alias, sorted by peer, alias and path
+pub(crate) async fn pending_changes(config: &Config) -> Vec<PendingChange> {
+    let mut pending = Vec::new();
+    for (alias, path) in config.paths.iter() {
+        for (peer, changes) in Outbox::new(path).read().await {
+            pending.extend(changes.into_iter().map(|(path, change)| PendingChange {
+                peer: peer.clone(),
+                alias: alias.clone(),
+                path,
+                deleted: matches!(change, Change::Deleted(_)),
+            }));
+        }
+    }
+
+    pending.sort_by(|a, b| (&a.peer, &a.alias, &a.path).cmp(&(&b.peer, &b.alias, &b.path)));
+    pending
+}
+
+/// Returns the peers with changes waiting in the outbox of any alias
+pub(crate) async fn peers_with_changes(config: &Config) -> Vec<String> {
+    let mut peers = Vec::new();
+    for path in config.paths.values() {
+        peers.extend(Outbox::new(path).peers().await);
+    }
+
+    peers.sort();
+    peers.dedup();
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn outbox_keeps_the_latest_change_of_each_path() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/outbox").await?;
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/outbox\""
+                .to_string(),
+        )?;
+        let outbox = Outbox::new(Path::new("./tmp/outbox"));
+        let file = |path: &str| FileInfo::new_deleted("a".to_owned(), PathBuf::from(path), None);
+
+        outbox
+            .push("10.0.0.2:8090", &FileAction::Create(file("file_1")))
+            .await?;
+        outbox
+            .push(
+                "10.0.0.2:8090",
+                &FileAction::Move(file("file_1"), file("file_2")),
+            )
+            .await?;
+        outbox
+            .push("10.0.0.3:8090", &FileAction::Remove(file("file_3")))
+            .await?;
+
+        let pending = outbox.pending("10.0.0.2:8090").await;
+        assert_eq!(pending.len(), 2);
+        assert!(
+            matches!(&pending[0], FileAction::Remove(file) if file.path == Path::new("file_1"))
+        );
+        assert!(
+            matches!(&pending[1], FileAction::Update(file) if file.path == Path::new("file_2"))
+        );
+        assert_eq!(
+            peers_with_changes(&config).await,
+            vec!["10.0.0.2:8090", "10.0.0.3:8090"]
+        );
+
+        outbox
+            .sent("10.0.0.2:8090", &[PathBuf::from("file_1")])
+            .await?;
+        outbox.clear("10.0.0.3:8090").await?;
+        assert_eq!(
+            pending_changes(&config).await,
+            vec![PendingChange {
+                peer: "10.0.0.2:8090".to_owned(),
+                alias: "a".to_owned(),
+                path: PathBuf::from("file_2"),
+                deleted: false,
+            }]
+        );
+
+        outbox
+            .sent("10.0.0.2:8090", &[PathBuf::from("file_2")])
+            .await?;
+        assert!(!Path::new("./tmp/outbox/.outbox.ironcarrier").exists());
+
+        tokio::fs::remove_dir_all("./tmp/outbox").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+//! File name patterns used in the configuration
+
+use std::path::Path;
+
+use crate::manifest::portable_path;
+
+/// Glob pattern matched against paths relative to the alias root
+///
+/// `*` matches anything except `/`, `**` matches anything, including `/`, and `?` matches a single character
+/// Patterns without a `/` are matched against the file name only, so `*.md` matches markdown files in any folder
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    pattern: Vec<char>,
+    file_name_only: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.trim_start_matches('/').chars().collect(),
+            file_name_only: !pattern.contains('/'),
+        }
+    }
+
+    /// Returns true if the relative `path` matches this pattern
+    pub fn matches(&self, path: &Path) -> bool {
+        let target = if self.file_name_only {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.to_owned())
+        } else {
+            portable_path(path)
+        };
+
+        match target {
+            Some(target) => matches(&self.pattern, &target.chars().collect::<Vec<char>>()),
+            None => false,
+        }
+    }
+}
+
+fn matches(pattern: &[char], target: &[char]) -> bool {
+    match pattern {
+        [] => target.is_empty(),
+        // `**/` matches whole folders, including none
+        ['*', '*', '/', rest @ ..] => {
+            matches(rest, target)
+                || (0..target.len())
+                    .any(|index| target[index] == '/' && matches(rest, &target[index + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=target.len()).any(|start| matches(rest, &target[start..])),
+        ['*', rest @ ..] => {
+            let max = target
+                .iter()
+                .position(|c| *c == '/')
+                .unwrap_or(target.len());
+            (0..=max).any(|start| matches(rest, &target[start..]))
+        }
+        ['?', rest @ ..] => match target {
+            [c, target @ ..] if *c != '/' => matches(rest, target),
+            _ => false,
+        },
+        [p, rest @ ..] => match target {
+            [c, target @ ..] if c == p => matches(rest, target),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_match_paths() {
+        let markdown = Pattern::new("*.md");
+        assert!(markdown.matches(Path::new("readme.md")));
+        assert!(markdown.matches(Path::new("docs/notes/todo.md")));
+        assert!(!markdown.matches(Path::new("readme.md.iso")));
+
+        let docs = Pattern::new("docs/*.txt");
+        assert!(docs.matches(Path::new("docs/a.txt")));
+        assert!(!docs.matches(Path::new("docs/nested/a.txt")));
+        assert!(!docs.matches(Path::new("other/docs/a.txt")));
+
+        let nested = Pattern::new("docs/**/?.txt");
+        assert!(nested.matches(Path::new("docs/a.txt")));
+        assert!(nested.matches(Path::new("docs/nested/deep/a.txt")));
+        assert!(!nested.matches(Path::new("docs/ab.txt")));
+    }
+}
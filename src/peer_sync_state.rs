@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::fs::FileInfo;
+
+/// File list and hash of an alias, as agreed with a peer in the last synchronization
+#[derive(Serialize, Deserialize)]
+struct AgreedState {
+    hash: u64,
+    files: Vec<FileInfo>,
+}
+
+/// Persists, for each peer, the last file list both sides agreed on
+///
+/// When the peer reports the same hash as the stored one, nothing changed on the peer since the last synchronization,
+/// so the stored list can be used instead of asking the peer for the complete list again
+pub(crate) struct PeerSyncState {
+    state_path: PathBuf,
+}
+
+impl PeerSyncState {
+    pub fn new(alias_root_path: &Path) -> Self {
+        PeerSyncState {
+            state_path: alias_root_path.join(".peers.ironcarrier"),
+        }
+    }
+
+    async fn read_state(&self) -> HashMap<String, AgreedState> {
+        if !self.state_path.exists() {
+            return HashMap::new();
+        }
+
+        match tokio::fs::read(&self.state_path).await {
+            Ok(contents) => bincode::deserialize(&contents).unwrap_or_else(|err| {
+                log::error!("peer sync state is invalid, ignoring it: {}", err);
+                HashMap::new()
+            }),
+            Err(err) => {
+                log::error!("cannot read peer sync state: {}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    async fn write_state(&self, state: &HashMap<String, AgreedState>) -> crate::Result<()> {
+        let contents = bincode::serialize(state)?;
+        tokio::fs::write(&self.state_path, contents).await?;
+
+        Ok(())
+    }
+
+    /// Returns the hash and the sorted file list last agreed with `peer_address`
+    pub async fn get(&self, peer_address: &str) -> Option<(u64, Vec<FileInfo>)> {
+        self.read_state()
+            .await
+            .remove(peer_address)
+            .map(|agreed| (agreed.hash, agreed.files))
+    }
+
+    /// Stores `files` as the file list agreed with `peer_address`
+    pub async fn set(
+        &self,
+        peer_address: &str,
+        hash: u64,
+        files: Vec<FileInfo>,
+    ) -> crate::Result<()> {
+        log::debug!("storing agreed file list with peer {}", peer_address);
+
+        let mut state = self.read_state().await;
+        state.insert(peer_address.to_owned(), AgreedState { hash, files });
+
+        self.write_state(&state).await
+    }
+
+    /// Forgets the file list agreed with `peer_address`
+    pub async fn remove(&self, peer_address: &str) -> crate::Result<()> {
+        let mut state = self.read_state().await;
+        if state.remove(peer_address).is_some() {
+            log::debug!("removing agreed file list with peer {}", peer_address);
+            self.write_state(&state).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_store_agreed_state() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/peer_sync_state").await?;
+        let state = PeerSyncState::new(Path::new("./tmp/peer_sync_state"));
+
+        assert!(state.get("peer_a").await.is_none());
+
+        let files = vec![FileInfo::new_deleted("a".into(), "file".into(), None)];
+        state.set("peer_a", 10, files).await?;
+
+        let (hash, files) = state.get("peer_a").await.unwrap();
+        assert_eq!(hash, 10);
+        assert_eq!(files[0].path, Path::new("file"));
+        assert!(state.get("peer_b").await.is_none());
+
+        state.remove("peer_a").await?;
+        assert!(state.get("peer_a").await.is_none());
+
+        tokio::fs::remove_dir_all("./tmp/peer_sync_state").await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! Files that keep failing to synchronize
+//!
+//! A file skipped by [Config::quarantine_after] synchronizations in a row, like a file always locked, unreadable or
+//! changing while it is sent, is quarantined: it is left out of the synchronizations for [Config::quarantine_cooldown]
+//! seconds, instead of being retried and logged on every synchronization. The quarantine of each alias is kept in its
+//! root, with the last errors of each file, and listed in the node status
+//!
+//! A file failing again after its cooldown goes back to the quarantine right away, files that don't fail for a cooldown
+//! are forgotten
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Errors kept for each file, the oldest are dropped
+const MAX_ERRORS: usize = 10;
+
+/// A failed attempt to synchronize a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedAttempt {
+    /// When the attempt failed, in seconds since the unix epoch
+    pub at: u64,
+    /// Why the attempt failed
+    pub reason: String,
+}
+
+/// A file left out of the synchronizations, see [crate::IronCarrier::quarantined_files]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedFile {
+    /// Alias of the file
+    pub alias: String,
+    /// Path of the file, relative to the alias root
+    pub path: PathBuf,
+    /// When the file is synchronized again, in seconds since the unix epoch
+    pub until: u64,
+    /// Last failed attempts, the oldest first
+    pub errors: Vec<FailedAttempt>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Entry {
+    errors: Vec<FailedAttempt>,
+    /// Failures in a row, each one less than a cooldown after the previous
+    failures: u32,
+    /// End of the quarantine, in seconds since the unix epoch
+    until: u64,
+}
+
+impl Entry {
+    /// Last failure or end of the quarantine, failures are counted in a row up to a cooldown after it
+    fn last_seen(&self) -> u64 {
+        let last_failure = self.errors.last().map(|error| error.at).unwrap_or_default();
+        last_failure.max(self.until)
+    }
+}
+
+fn now_as_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Quarantine of an alias, kept in its root
+pub(crate) struct Quarantine {
+    state_path: PathBuf,
+}
+
+impl Quarantine {
+    pub fn new(alias_root_path: &Path) -> Self {
+        Quarantine {
+            state_path: alias_root_path.join(".quarantine.ironcarrier"),
+        }
+    }
+
+    async fn read_state(&self) -> HashMap<PathBuf, Entry> {
+        if !self.state_path.exists() {
+            return HashMap::new();
+        }
+
+        match tokio::fs::read(&self.state_path).await {
+            Ok(contents) => bincode::deserialize(&contents).unwrap_or_else(|err| {
+                log::error!("quarantine is invalid, ignoring it: {}", err);
+                HashMap::new()
+            }),
+            Err(err) => {
+                log::error!("cannot read quarantine: {}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    async fn write_state(&self, state: &HashMap<PathBuf, Entry>) -> crate::Result<()> {
+        if state.is_empty() {
+            if self.state_path.exists() {
+                tokio::fs::remove_file(&self.state_path).await?;
+            }
+            return Ok(());
+        }
+
+        let contents = bincode::serialize(state)?;
+        tokio::fs::write(&self.state_path, contents).await?;
+
+        Ok(())
+    }
+
+    /// Returns the files in quarantine now
+    pub async fn active(&self) -> HashSet<PathBuf> {
+        let now = now_as_secs();
+        self.read_state()
+            .await
+            .into_iter()
+            .filter(|(_, entry)| entry.until > now)
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// Records the files that failed in a synchronization of `alias`, with the reason of each failure
+    /// Returns the files that went to the quarantine
+    pub async fn record_failures<'a>(
+        &self,
+        config: &Config,
+        alias: &str,
+        failures: impl Iterator<Item = (&'a Path, &'a str)>,
+    ) -> crate::Result<Vec<PathBuf>> {
+        if config.quarantine_after == 0 {
+            return Ok(Vec::new());
+        }
+
+        let now = now_as_secs();
+        let cooldown = config.quarantine_cooldown;
+        let mut state = self.read_state().await;
+        let mut quarantined = Vec::new();
+        for (path, reason) in failures {
+            let entry = state.entry(path.to_owned()).or_default();
+            if entry.last_seen() + cooldown < now {
+                entry.failures = 0;
+            }
+            entry.failures += 1;
+            entry.errors.push(FailedAttempt {
+                at: now,
+                reason: reason.to_owned(),
+            });
+            if entry.errors.len() > MAX_ERRORS {
+                entry.errors.remove(0);
+            }
+
+            if entry.failures >= config.quarantine_after && entry.until <= now {
+                log::warn!(
+                    "{:?} of alias {} failed {} times in a row, leaving it out for {} seconds: {}",
+                    path,
+                    alias,
+                    entry.failures,
+                    cooldown,
+                    reason
+                );
+                entry.until = now + cooldown;
+                quarantined.push(path.to_owned());
+            }
+        }
+
+        // files that didn't fail for a cooldown are working again
+        state.retain(|_, entry| entry.last_seen() + cooldown >= now);
+        self.write_state(&state).await?;
+
+        Ok(quarantined)
+    }
+
+    /// Returns the files in quarantine now, with their errors
+    async fn quarantined_files(&self, alias: &str) -> Vec<QuarantinedFile> {
+        let now = now_as_secs();
+        self.read_state()
+            .await
+            .into_iter()
+            .filter(|(_, entry)| entry.until > now)
+            .map(|(path, entry)| QuarantinedFile {
+                alias: alias.to_owned(),
+                path,
+                until: entry.until,
+                errors: entry.errors,
+            })
+            .collect()
+    }
+}
+
+/// Returns the files in quarantine in every alias, sorted by alias and path
+pub(crate) async fn quarantined_files(config: &Config) -> Vec<QuarantinedFile> {
+    let mut quarantined = Vec::new();
+    for (alias, path) in config.paths.iter() {
+        quarantined.extend(Quarantine::new(path).quarantined_files(alias).await);
+    }
+
+    quarantined.sort_by(|a, b| (&a.alias, &a.path).cmp(&(&b.alias, &b.path)));
+    quarantined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn files_failing_in_a_row_are_quarantined() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/quarantine").await?;
+        let config = Config::parse_content(
+            "quarantine_after = 2
+            quarantine_cooldown = 600
+            [paths]
+            a = \"./tmp/quarantine\""
+                .to_string(),
+        )?;
+
+        let quarantine = Quarantine::new(Path::new("./tmp/quarantine"));
+        let failures = [
+            (Path::new("locked"), "the file is locked"),
+            (Path::new("unreadable"), "permission denied"),
+        ];
+        assert!(quarantine
+            .record_failures(&config, "a", failures.iter().copied())
+            .await?
+            .is_empty());
+        assert!(quarantine.active().await.is_empty());
+
+        let quarantined = quarantine
+            .record_failures(&config, "a", failures[..1].iter().copied())
+            .await?;
+        assert_eq!(quarantined, vec![PathBuf::from("locked")]);
+        assert_eq!(quarantine.active().await, [PathBuf::from("locked")].into());
+
+        let listed = quarantined_files(&config).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].alias, "a");
+        assert_eq!(listed[0].errors.len(), 2);
+        assert_eq!(listed[0].errors[1].reason, "the file is locked");
+        assert!(listed[0].until >= listed[0].errors[1].at + 600);
+
+        tokio::fs::remove_dir_all("./tmp/quarantine").await?;
+        Ok(())
+    }
+}
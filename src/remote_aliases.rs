@@ -0,0 +1,85 @@
+//! Aliases shared with a peer and their stats
+//!
+//! A peer is asked for the aliases both sides have, with the number of files, their size and the hash of the file list.
+//! The hash is the same one the peers compare before a synchronization, so an alias with the same hash in both sides is
+//! in sync. Useful to check the peers after changing the configuration, without starting a synchronization
+
+use std::{fmt::Display, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config, events::EventBus, network::peer::Peer, network::transport::TcpTransport,
+    sync::file_events_buffer::FileEventsBuffer,
+};
+
+/// Stats of an alias, see [alias_stats]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AliasStats {
+    /// Alias, as known by the peers, see [Config::alias_ids]
+    pub alias: String,
+    /// Number of files, deleted files are not counted
+    pub files: u64,
+    /// Total size of the files, in bytes
+    pub bytes: u64,
+    /// Hash of the file list, equal in both peers when the alias is in sync
+    pub hash: u64,
+}
+
+impl Display for AliasStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{} files\t{} bytes\thash {:016x}",
+            self.alias, self.files, self.bytes, self.hash
+        )
+    }
+}
+
+/// Returns the stats of the local aliases listed in `aliases`, sorted by alias, aliases not configured are left out
+pub async fn alias_stats(config: &Config, aliases: &[String]) -> crate::Result<Vec<AliasStats>> {
+    let mut stats = Vec::new();
+    for alias in aliases {
+        let root_path = match config.paths.get(alias) {
+            Some(root_path) => root_path,
+            None => continue,
+        };
+
+        let (hash, files) =
+            crate::fs::get_file_list_with_hash(root_path, alias, config, &CancellationToken::new())
+                .await?;
+        let mut alias_stats = AliasStats {
+            alias: alias.clone(),
+            files: 0,
+            bytes: 0,
+            hash,
+        };
+        let mut reader = files.reader()?;
+        while let Some(file) = reader.next_entry()? {
+            if file.deleted_at.is_none() {
+                alias_stats.files += 1;
+                alias_stats.bytes += file.size.unwrap_or_default();
+            }
+        }
+
+        stats.push(alias_stats);
+    }
+
+    stats.sort_by(|a, b| a.alias.cmp(&b.alias));
+    Ok(stats)
+}
+
+/// Asks `peer_address` for the stats of the aliases it shares with this node
+/// Aliases paused in the peer are left out, see [Config::paused_aliases]
+pub async fn query_peer_aliases(
+    config: &Config,
+    peer_address: &str,
+) -> crate::Result<Vec<AliasStats>> {
+    let events_buffer = FileEventsBuffer::new(Arc::new(config.clone()));
+    let events = EventBus::new();
+    let transport = TcpTransport::new(config);
+    let mut peer = Peer::new(peer_address, &transport, config, &events_buffer, &events).await?;
+
+    peer.query_aliases().await
+}
@@ -0,0 +1,293 @@
+//! Repair of local files corrupted on disk
+//!
+//! Bit rot and partial writes don't change the size or the modification time of a file, so the synchronization doesn't notice them.
+//! The repair hashes every local file agreed with the peers, same size and modification time, and compares it with the hash reported
+//! by each peer. The peers are trusted over the local content, files that don't match any peer are fetched again, as long as the
+//! peers agree on their content
+
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf, sync::Arc};
+
+use crate::{
+    config::Config,
+    events::EventBus,
+    fs::{self, FileInfo, FileKind},
+    network::peer::{Peer, PeerFileList},
+    network::transport::TcpTransport,
+    skipped_files::SkippedFiles,
+    sync::file_events_buffer::FileEventsBuffer,
+    sync::FileAction,
+};
+
+/// Outcome of [repair] for a file that didn't match the peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairOutcome {
+    /// The file was fetched again from the peer
+    Repaired {
+        /// Address of the peer that provided the content
+        peer: String,
+    },
+    /// The file was left untouched, because the peers don't agree on its content, or it couldn't be fetched
+    Unresolved {
+        /// Why the file wasn't repaired
+        reason: String,
+    },
+}
+
+/// File that didn't match the peers, see [repair]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairedFile {
+    /// Alias of the file
+    pub alias: String,
+    /// Path of the file, relative to the alias root
+    pub path: PathBuf,
+    /// What was done with the file
+    pub outcome: RepairOutcome,
+}
+
+impl Display for RepairedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            RepairOutcome::Repaired { peer } => write!(
+                f,
+                "repaired {}/{} from {}",
+                self.alias,
+                self.path.display(),
+                peer
+            ),
+            RepairOutcome::Unresolved { reason } => write!(
+                f,
+                "not repaired {}/{}: {}",
+                self.alias,
+                self.path.display(),
+                reason
+            ),
+        }
+    }
+}
+
+/// Result of [repair]
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Number of local files compared with at least one peer
+    pub verified: u64,
+    /// Files that didn't match the peers
+    pub files: Vec<RepairedFile>,
+}
+
+/// What to do with a local file, given the hashes reported by the peers
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict<'a> {
+    Intact,
+    Repair(&'a str),
+    Unresolved,
+}
+
+/// Compares `local_hash` with the `(peer, hash)` pairs reported by the peers
+/// A file matching any peer is intact, otherwise it is repaired from the first peer when all the peers report the same hash
+fn verdict<'a>(local_hash: &str, reported: &'a [(String, String)]) -> Verdict<'a> {
+    if reported.iter().any(|(_, hash)| hash == local_hash) {
+        return Verdict::Intact;
+    }
+
+    match reported.split_first() {
+        None => Verdict::Intact,
+        Some(((peer, hash), others)) if others.iter().all(|(_, other)| other == hash) => {
+            Verdict::Repair(peer)
+        }
+        Some(_) => Verdict::Unresolved,
+    }
+}
+
+/// A local file and the hashes reported for it by the peers with the same size and modification time
+struct Candidate {
+    file: FileInfo,
+    local_hash: Option<String>,
+    reported: Vec<(String, String)>,
+}
+
+/// Compares the hash of the local files of the aliases synchronized with `peer_address` with the peer, recording what it
+/// reports in `candidates`
+async fn query_peer(
+    config: &Config,
+    peer_address: &str,
+    events_buffer: &FileEventsBuffer,
+    events: &EventBus,
+    candidates: &mut BTreeMap<(String, PathBuf), Candidate>,
+    skipped: &mut SkippedFiles,
+) -> crate::Result<()> {
+    let transport = TcpTransport::new(config);
+    let mut peer = Peer::new(peer_address, &transport, config, events_buffer, events).await?;
+
+    let aliases = config
+        .paths
+        .keys()
+        .filter(|alias| config.syncs_alias_with(alias, peer_address));
+    for alias in aliases {
+        let mut peer_files = PeerFileList::remote(alias);
+        while let Some(peer_file) = peer.next_file(&mut peer_files).await? {
+            let candidate = match candidates.get_mut(&(alias.clone(), peer_file.path.clone())) {
+                Some(candidate)
+                    if peer_file.deleted_at.is_none()
+                        && peer_file.modified_at == candidate.file.modified_at
+                        && peer_file.size == candidate.file.size =>
+                {
+                    candidate
+                }
+                _ => continue,
+            };
+
+            if candidate.local_hash.is_none() {
+                let hash = match candidate.file.get_absolute_path(config) {
+                    Ok(path) => {
+                        crate::manifest::hash_file(&path, config.hashing(alias).algorithm).await
+                    }
+                    Err(err) => Err(err),
+                };
+                match hash {
+                    Ok(hash) => candidate.local_hash = Some(hash),
+                    Err(err) => {
+                        skipped.add(&candidate.file.path, err);
+                        continue;
+                    }
+                }
+            }
+
+            match peer.query_file_hash(&peer_file).await {
+                Ok(hash) => candidate.reported.push((peer_address.to_owned(), hash)),
+                Err(err) => log::warn!(
+                    "peer {} cannot hash {:?}: {}",
+                    peer_address,
+                    peer_file.path,
+                    err
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetches `file` again from `peer_address`
+async fn fetch_file(
+    config: &Config,
+    peer_address: &str,
+    events_buffer: &FileEventsBuffer,
+    events: &EventBus,
+    file: &FileInfo,
+) -> crate::Result<()> {
+    let transport = TcpTransport::new(config);
+    let mut peer = Peer::new(peer_address, &transport, config, events_buffer, events).await?;
+    peer.sync_action(&FileAction::Request(file.clone())).await
+}
+
+/// Verifies the content of the local files with the configured peers, fetching again the files that don't match them
+///
+/// Only the files with the same size and modification time in both sides are compared, the other differences are left
+/// to the synchronization
+pub async fn repair(config: &Config) -> crate::Result<RepairReport> {
+    let events_buffer = FileEventsBuffer::new(Arc::new(config.clone()));
+    let events = EventBus::new();
+    let mut skipped = SkippedFiles::new();
+    let mut candidates = BTreeMap::new();
+    for (alias, root_path) in &config.paths {
+        let (_, files) = fs::get_files_with_hash(root_path, alias, config).await?;
+        candidates.extend(
+            files
+                .into_iter()
+                .filter(|file| file.deleted_at.is_none() && file.kind == FileKind::Regular)
+                .map(|file| {
+                    (
+                        (alias.clone(), file.path.clone()),
+                        Candidate {
+                            file,
+                            local_hash: None,
+                            reported: Vec::new(),
+                        },
+                    )
+                }),
+        );
+    }
+
+    for peer_address in config.peers.iter().flatten() {
+        if let Err(err) = query_peer(
+            config,
+            peer_address,
+            &events_buffer,
+            &events,
+            &mut candidates,
+            &mut skipped,
+        )
+        .await
+        {
+            log::error!("cannot verify files with peer {}: {}", peer_address, err);
+        }
+    }
+
+    let mut report = RepairReport::default();
+    for ((alias, path), candidate) in candidates {
+        let local_hash = match &candidate.local_hash {
+            Some(local_hash) if !candidate.reported.is_empty() => local_hash,
+            _ => continue,
+        };
+        report.verified += 1;
+
+        let outcome = match verdict(local_hash, &candidate.reported) {
+            Verdict::Intact => continue,
+            Verdict::Unresolved => RepairOutcome::Unresolved {
+                reason: "the peers don't agree on its content".to_string(),
+            },
+            // each repair connects to the peer again, there are usually few of them
+            Verdict::Repair(peer) => {
+                match fetch_file(config, peer, &events_buffer, &events, &candidate.file).await {
+                    Ok(_) => RepairOutcome::Repaired {
+                        peer: peer.to_owned(),
+                    },
+                    Err(err) => RepairOutcome::Unresolved {
+                        reason: err.to_string(),
+                    },
+                }
+            }
+        };
+
+        let file = RepairedFile {
+            alias,
+            path,
+            outcome,
+        };
+        log::warn!("{}", file);
+        report.files.push(file);
+    }
+
+    skipped.log_summary("verifying files with the peers");
+    log::info!(
+        "{} files verified, {} didn't match the peers",
+        report.verified,
+        report.files.len()
+    );
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_repaired_when_peers_agree() {
+        let reported = |hashes: &[&str]| -> Vec<(String, String)> {
+            hashes
+                .iter()
+                .enumerate()
+                .map(|(index, hash)| (format!("peer_{}", index), hash.to_string()))
+                .collect()
+        };
+
+        assert_eq!(verdict("a", &reported(&[])), Verdict::Intact);
+        assert_eq!(verdict("a", &reported(&["b", "a"])), Verdict::Intact);
+        assert_eq!(
+            verdict("a", &reported(&["b", "b"])),
+            Verdict::Repair("peer_0")
+        );
+        assert_eq!(verdict("a", &reported(&["b", "c"])), Verdict::Unresolved);
+    }
+}
@@ -0,0 +1,266 @@
+//! Files found by the last scan of each alias, kept up to date with the folders changed since
+//!
+//! While the file watcher runs, it records the folders where files changed as hints. The next scan of the alias only
+//! reads those folders again and merges them with the index kept in the alias root, instead of reading the whole alias.
+//! The index is only trusted after a complete scan ran with the watcher running, changes made while the watcher is
+//! stopped, or events the watcher lost, make the next scan read the whole alias
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OwnedMutexGuard;
+
+use crate::fs::FileInfo;
+
+/// Changes recorded by the watcher for a single alias
+#[derive(Default)]
+struct AliasHints {
+    /// The watcher is running for the alias
+    watching: bool,
+    /// The index was written by a complete scan while watching
+    indexed: bool,
+    /// Times the index was invalidated, a complete scan only makes the index trusted if it wasn't invalidated meanwhile
+    invalidations: u64,
+    /// Folders where files were created, changed or removed, relative to the alias root
+    folders: HashSet<PathBuf>,
+    /// Folders created, removed or renamed, they are read again with everything inside
+    trees: HashSet<PathBuf>,
+}
+
+/// How the next scan of an alias reads it, see [ScanHints::begin]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ScanPlan {
+    /// The watcher is not running, the alias is read entirely and no index is kept
+    Untracked,
+    /// The alias is read entirely and the index is written, `invalidations` is the count when the scan started
+    Complete { invalidations: u64 },
+    /// Only the `folders` and `trees` changed since the last scan are read, the rest comes from the index
+    Incremental {
+        folders: HashSet<PathBuf>,
+        trees: HashSet<PathBuf>,
+    },
+}
+
+impl ScanPlan {
+    /// Returns true if `path`, relative to the alias root, is read again by the plan
+    pub fn affects(&self, path: &Path) -> bool {
+        match self {
+            ScanPlan::Incremental { folders, trees } => {
+                trees.iter().any(|tree| path.starts_with(tree))
+                    || path.parent().is_some_and(|parent| folders.contains(parent))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Folders changed in each alias since its last scan, recorded by the file watcher, see [crate::config::Config::incremental_scan]
+#[derive(Default)]
+pub(crate) struct ScanHints {
+    aliases: Mutex<HashMap<String, AliasHints>>,
+    scans: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ScanHints {
+    /// Records that the watcher started watching `alias`, the next scan reads it entirely
+    pub fn watch(&self, alias: &str) {
+        let mut aliases = self.aliases.lock().unwrap();
+        let hints = aliases.entry(alias.to_owned()).or_default();
+        hints.watching = true;
+        hints.indexed = false;
+        hints.invalidations += 1;
+    }
+
+    /// Records that files inside `folder`, relative to the root of `alias`, were created, changed or removed
+    pub fn folder_changed(&self, alias: &str, folder: &Path) {
+        if let Some(hints) = self.aliases.lock().unwrap().get_mut(alias) {
+            hints.folders.insert(folder.to_owned());
+        }
+    }
+
+    /// Records that `folder`, relative to the root of `alias`, was created, removed or renamed
+    pub fn tree_changed(&self, alias: &str, folder: &Path) {
+        if let Some(hints) = self.aliases.lock().unwrap().get_mut(alias) {
+            hints.trees.insert(folder.to_owned());
+        }
+    }
+
+    /// Stops trusting the index of `alias`, the next scan reads it entirely
+    pub fn invalidate(&self, alias: &str) {
+        if let Some(hints) = self.aliases.lock().unwrap().get_mut(alias) {
+            log::debug!("index of alias {} is not trusted anymore", alias);
+            hints.indexed = false;
+            hints.invalidations += 1;
+            hints.folders.clear();
+            hints.trees.clear();
+        }
+    }
+
+    /// Stops trusting the index of every alias, used when the watcher loses events
+    pub fn invalidate_all(&self) {
+        let aliases: Vec<String> = self.aliases.lock().unwrap().keys().cloned().collect();
+        for alias in aliases {
+            self.invalidate(&alias);
+        }
+    }
+
+    /// Waits until no other scan of `alias` is running, scans of the same alias read and write the same index
+    pub async fn lock(&self, alias: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .scans
+            .lock()
+            .unwrap()
+            .entry(alias.to_owned())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Returns how the next scan reads `alias`, taking the changes recorded so far
+    /// Changes recorded while the scan runs are kept for the next one
+    pub fn begin(&self, alias: &str) -> ScanPlan {
+        let mut aliases = self.aliases.lock().unwrap();
+        let hints = match aliases.get_mut(alias) {
+            Some(hints) if hints.watching => hints,
+            _ => return ScanPlan::Untracked,
+        };
+
+        let folders = std::mem::take(&mut hints.folders);
+        let trees = std::mem::take(&mut hints.trees);
+        if !hints.indexed {
+            return ScanPlan::Complete {
+                invalidations: hints.invalidations,
+            };
+        }
+
+        // folders inside a tree are read along with it
+        let outer_trees: HashSet<PathBuf> = trees
+            .iter()
+            .filter(|tree| {
+                !trees
+                    .iter()
+                    .any(|other| other != *tree && tree.starts_with(other))
+            })
+            .cloned()
+            .collect();
+        let folders = folders
+            .into_iter()
+            .filter(|folder| !outer_trees.iter().any(|tree| folder.starts_with(tree)))
+            .collect();
+
+        ScanPlan::Incremental {
+            folders,
+            trees: outer_trees,
+        }
+    }
+
+    /// Records that a complete scan of `alias` wrote its index, it is trusted unless it was invalidated since
+    /// `invalidations`
+    pub fn indexed(&self, alias: &str, invalidations: u64) {
+        if let Some(hints) = self.aliases.lock().unwrap().get_mut(alias) {
+            if hints.invalidations == invalidations {
+                hints.indexed = true;
+            }
+        }
+    }
+}
+
+/// Index of an alias, the files found by its last scan, kept in the alias root
+pub(crate) struct ScanIndex {
+    index_path: PathBuf,
+}
+
+impl ScanIndex {
+    pub fn new(alias_root_path: &Path) -> Self {
+        ScanIndex {
+            index_path: alias_root_path.join(".index.ironcarrier"),
+        }
+    }
+
+    /// Returns the files in the index, [None] when it doesn't exist or can't be read
+    pub async fn read(&self) -> Option<Vec<FileInfo>> {
+        let contents = match tokio::fs::read(&self.index_path).await {
+            Ok(contents) => contents,
+            Err(err) => {
+                log::error!("cannot read scan index: {}", err);
+                return None;
+            }
+        };
+
+        bincode::deserialize(&contents)
+            .map_err(|err| log::error!("scan index is invalid, ignoring it: {}", err))
+            .ok()
+    }
+
+    pub async fn write(&self, files: &[FileInfo]) -> crate::Result<()> {
+        let contents = bincode::serialize(files)?;
+        tokio::fs::write(&self.index_path, contents).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn only_changed_folders_are_read_again() -> crate::Result<()> {
+        for folder in ["a", "b", "c/d"] {
+            tokio::fs::create_dir_all(format!("./tmp/scan_index/{}", folder)).await?;
+        }
+        tokio::fs::write("./tmp/scan_index/a/file", b"a").await?;
+        tokio::fs::write("./tmp/scan_index/b/file", b"b").await?;
+
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/scan_index\""
+                .to_string(),
+        )?;
+        let root_path = Path::new("./tmp/scan_index");
+        let cancel = CancellationToken::new();
+        let scan = || async {
+            let files = crate::fs::scan_path(root_path, "a", &config, &cancel)
+                .await?
+                .to_vec()?;
+            crate::Result::Ok(
+                files
+                    .into_iter()
+                    .map(|file| file.path)
+                    .collect::<Vec<PathBuf>>(),
+            )
+        };
+
+        config.scan_hints().watch("a");
+        assert_eq!(scan().await?.len(), 2);
+        assert!(Path::new("./tmp/scan_index/.index.ironcarrier").exists());
+
+        // changes without hints are not seen, the index is used
+        tokio::fs::write("./tmp/scan_index/b/unseen", b"b").await?;
+        tokio::fs::remove_file("./tmp/scan_index/a/file").await?;
+        tokio::fs::write("./tmp/scan_index/a/new", b"a").await?;
+        tokio::fs::write("./tmp/scan_index/c/d/file", b"d").await?;
+        config.scan_hints().folder_changed("a", Path::new("a"));
+        config.scan_hints().tree_changed("a", Path::new("c"));
+        assert_eq!(
+            scan().await?,
+            vec![
+                PathBuf::from("a/new"),
+                PathBuf::from("b/file"),
+                PathBuf::from("c/d/file")
+            ]
+        );
+
+        // until the index is invalidated
+        config.scan_hints().invalidate("a");
+        assert_eq!(scan().await?.len(), 4);
+
+        tokio::fs::remove_dir_all("./tmp/scan_index").await?;
+        Ok(())
+    }
+}
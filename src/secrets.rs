@@ -0,0 +1,172 @@
+//! Secrets of the configuration kept out of the file
+//!
+//! Options holding secrets, like passwords and access keys, accept the secret itself or where to find it:
+//! `{ secret_file = "/run/secrets/s3_key" }` reads it from a file, like the secrets mounted in containers, and
+//! `{ keychain = "iron-carrier", account = "s3" }` reads it from the keychain of the system. The secrets are read when
+//! the configuration is parsed
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Deserializer};
+
+/// Value of an option holding a secret
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    /// The secret itself
+    Inline(String),
+    /// File with the secret, a trailing line break is not part of the secret
+    File { secret_file: PathBuf },
+    /// Entry of the system keychain, the secret service in Linux, the login keychain in macOS, or the generic
+    /// credential `keychain:account` of the Windows credential manager
+    Keychain { keychain: String, account: String },
+}
+
+impl SecretSource {
+    fn read(self) -> std::io::Result<String> {
+        let secret = match self {
+            SecretSource::Inline(secret) => return Ok(secret),
+            SecretSource::File { secret_file } => std::fs::read_to_string(secret_file)?,
+            SecretSource::Keychain { keychain, account } => read_keychain(&keychain, &account)?,
+        };
+
+        Ok(secret.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
+/// Deserializes a secret, see [SecretSource]
+pub(crate) fn deserialize_secret<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    SecretSource::deserialize(deserializer)?
+        .read()
+        .map_err(|err| serde::de::Error::custom(format!("cannot read secret: {}", err)))
+}
+
+/// Deserializes an optional secret, see [SecretSource]
+pub(crate) fn deserialize_optional_secret<'de, D>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_secret(deserializer).map(Some)
+}
+
+/// Returns the output of `command`, failing if it doesn't succeed
+#[cfg(not(windows))]
+fn read_command(command: &mut std::process::Command) -> std::io::Result<String> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Reads the secret of `account` in the login keychain, with the `security` command
+#[cfg(target_os = "macos")]
+fn read_keychain(keychain: &str, account: &str) -> std::io::Result<String> {
+    read_command(std::process::Command::new("security").args([
+        "find-generic-password",
+        "-s",
+        keychain,
+        "-a",
+        account,
+        "-w",
+    ]))
+}
+
+/// Reads the secret of `account` from the secret service, like GNOME Keyring or KWallet, with the `secret-tool` command
+#[cfg(all(unix, not(target_os = "macos")))]
+fn read_keychain(keychain: &str, account: &str) -> std::io::Result<String> {
+    read_command(
+        std::process::Command::new("secret-tool")
+            .args(["lookup", "service", keychain, "account", account]),
+    )
+}
+
+/// Reads the generic credential `keychain:account` of the credential manager
+#[cfg(windows)]
+fn read_keychain(keychain: &str, account: &str) -> std::io::Result<String> {
+    use windows_sys::Win32::Security::Credentials::{
+        CredFree, CredReadW, CREDENTIALW, CRED_TYPE_GENERIC,
+    };
+
+    let target: Vec<u16> = format!("{}:{}", keychain, account)
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut credential: *mut CREDENTIALW = std::ptr::null_mut();
+    if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // the credential manager keeps the passwords of generic credentials in UTF-16
+    let blob = unsafe {
+        std::slice::from_raw_parts(
+            (*credential).CredentialBlob,
+            (*credential).CredentialBlobSize as usize,
+        )
+    };
+    let utf16: Vec<u16> = blob
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    let secret = String::from_utf16(&utf16)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+    unsafe { CredFree(credential as *const _) };
+
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, MirrorConfig};
+
+    #[test]
+    fn secrets_are_read_from_files() -> crate::Result<()> {
+        std::fs::create_dir_all("./tmp/secrets")?;
+        std::fs::write("./tmp/secrets/s3_key", "secret\n")?;
+
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/secrets\"
+
+            [mirrors.a]
+            type = \"s3\"
+            endpoint = \"http://localhost:9000\"
+            bucket = \"backup\"
+            access_key = \"key\"
+            secret_key = { secret_file = \"./tmp/secrets/s3_key\" }"
+                .to_string(),
+        )?;
+        match &config.mirrors["a"] {
+            MirrorConfig::S3(s3) => {
+                assert_eq!(s3.access_key, "key");
+                assert_eq!(s3.secret_key, "secret");
+            }
+            _ => panic!("expected a s3 mirror"),
+        }
+
+        assert!(Config::parse_content(
+            "[paths]
+            a = \"./tmp/secrets\"
+
+            [mirrors.a]
+            type = \"webdav\"
+            url = \"https://cloud.example.com\"
+            password = { secret_file = \"./tmp/secrets/missing\" }"
+                .to_string(),
+        )
+        .is_err());
+
+        std::fs::remove_dir_all("./tmp/secrets")?;
+        Ok(())
+    }
+}
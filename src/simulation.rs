@@ -0,0 +1,819 @@
+//! In-memory simulation of several nodes, for tests
+//!
+//! Available with the `simulation` feature. The nodes are connected by a [MemoryNetwork] and keep the content of their
+//! aliases in a [MemoryStorage], so scenarios with conflicts and disconnections run without sockets and always in the same
+//! order: the nodes only synchronize when [Simulation::sync] is called, one synchronization at a time, and the files carry
+//! the modification time given by the test.
+//!
+//! Failures are injected in the streams of a node with [Simulation::set_faults], see [FaultyTransport]
+//!
+//! Only the state files of the synchronization are written to disk, under the root folder of the [Simulation].
+//! The features that depend on the local file system, like deletion tracking, are not available, see [crate::storage]
+
+use futures::future::BoxFuture;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, DuplexStream, ReadBuf},
+    sync::mpsc,
+    time::Sleep,
+};
+
+use crate::{
+    config::Config,
+    events::SyncObserver,
+    network::transport::{BoxedStream, Transport, TransportListener},
+    storage::{Storage, StorageFile, StorageMetadata},
+    IronCarrier,
+};
+
+/// Port of the simulated nodes, they are told apart by their names
+const SIMULATION_PORT: u32 = 8090;
+/// Capacity of the in-memory streams, in bytes
+const STREAM_CAPACITY: usize = 64 * 1024;
+
+type Files = Arc<Mutex<BTreeMap<PathBuf, (Vec<u8>, u64)>>>;
+
+/// [Storage] that keeps the files in memory, folders exist while they have files
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    files: Files,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the file at `path` with `content` and `modified_at`, in seconds since the unix epoch
+    pub fn insert(&self, path: impl Into<PathBuf>, content: &[u8], modified_at: u64) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.into(), (content.to_vec(), modified_at));
+    }
+
+    /// Returns the content and modification time of the file at `path`
+    pub fn get(&self, path: &Path) -> Option<(Vec<u8>, u64)> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    /// Removes the file at `path`, returns false if it doesn't exist
+    pub fn remove(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().remove(path).is_some()
+    }
+
+    /// Returns every file, with its content and modification time, sorted by path
+    pub fn files(&self) -> Vec<(PathBuf, Vec<u8>, u64)> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, (content, modified_at))| (path.clone(), content.clone(), *modified_at))
+            .collect()
+    }
+
+    fn file(&self, path: &Path) -> Box<dyn StorageFile> {
+        Box::new(MemoryFile {
+            files: self.files.clone(),
+            path: path.to_owned(),
+            position: 0,
+        })
+    }
+}
+
+/// File of a [MemoryStorage] being written
+struct MemoryFile {
+    files: Files,
+    path: PathBuf,
+    position: u64,
+}
+
+impl AsyncWrite for MemoryFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let position = self.position as usize;
+        let mut files = self.files.lock().unwrap();
+        let (content, _) = files.entry(self.path.clone()).or_default();
+        if content.len() < position + buf.len() {
+            content.resize(position + buf.len(), 0);
+        }
+        content[position..position + buf.len()].copy_from_slice(buf);
+        drop(files);
+
+        self.position += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for MemoryFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        match position {
+            SeekFrom::Start(position) => self.position = position,
+            _ => return Err(io::ErrorKind::Unsupported.into()),
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<StorageMetadata>> {
+        Box::pin(async move {
+            let files = self.files.lock().unwrap();
+            match files.get(path) {
+                Some((content, modified_at)) => Ok(StorageMetadata {
+                    len: content.len() as u64,
+                    modified_at: Some(*modified_at),
+                    ..Default::default()
+                }),
+                None if files.keys().any(|file| file.starts_with(path)) => Ok(StorageMetadata {
+                    is_dir: true,
+                    ..Default::default()
+                }),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<(PathBuf, StorageMetadata)>>> {
+        Box::pin(async move {
+            let children: Vec<PathBuf> = self
+                .files
+                .lock()
+                .unwrap()
+                .keys()
+                .filter_map(|file| file.strip_prefix(path).ok())
+                .filter_map(|relative| relative.components().next())
+                .map(|child| path.join(child))
+                .collect();
+
+            let mut entries: Vec<(PathBuf, StorageMetadata)> = Vec::new();
+            for child in children {
+                if entries.last().map(|(last, _)| last) != Some(&child) {
+                    let metadata = self.metadata(&child).await?;
+                    entries.push((child, metadata));
+                }
+            }
+            Ok(entries)
+        })
+    }
+
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncRead + Unpin + Send>>> {
+        Box::pin(async move {
+            match self.files.lock().unwrap().get(path) {
+                Some((content, _)) => Ok(Box::new(std::io::Cursor::new(content.clone()))
+                    as Box<dyn AsyncRead + Unpin + Send>),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+    }
+
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.to_owned(), Default::default());
+            Ok(self.file(path))
+        })
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            match self.files.lock().unwrap().contains_key(path) {
+                true => Ok(self.file(path)),
+                false => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut files = self.files.lock().unwrap();
+            let file = files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+            files.insert(to.to_owned(), file);
+            Ok(())
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match self.files.lock().unwrap().remove(path) {
+                Some(_) => Ok(()),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+    }
+
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.files
+                .lock()
+                .unwrap()
+                .retain(|file, _| !file.starts_with(path));
+            Ok(())
+        })
+    }
+
+    fn set_modified<'a>(
+        &'a self,
+        path: &'a Path,
+        modified_at: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match self.files.lock().unwrap().get_mut(path) {
+                Some(file) => {
+                    file.1 = modified_at;
+                    Ok(())
+                }
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct NetworkState {
+    /// Listeners of each address, in the form `name:port`
+    listeners: HashMap<String, mpsc::UnboundedSender<(DuplexStream, String)>>,
+    /// Pairs of nodes that can't reach each other, the first name is always the smaller one
+    partitions: HashSet<(String, String)>,
+}
+
+/// Network of in-memory streams between named nodes, each node gets its [Transport] from [MemoryNetwork::transport]
+///
+/// The nodes are addressed as `name:port`, the name is reported as the address of the connecting node
+#[derive(Debug, Default, Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
+
+impl MemoryNetwork {
+    /// Creates a network without nodes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the [Transport] of the node `name`
+    pub fn transport(&self, name: &str) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            name: name.to_owned(),
+        }
+    }
+
+    /// Stops the connections between `a` and `b`, the streams already established are kept
+    pub fn disconnect(&self, a: &str, b: &str) {
+        self.state.lock().unwrap().partitions.insert(pair(a, b));
+    }
+
+    /// Allows the connections between `a` and `b` again
+    pub fn reconnect(&self, a: &str, b: &str) {
+        self.state.lock().unwrap().partitions.remove(&pair(a, b));
+    }
+}
+
+/// [Transport] of a node of a [MemoryNetwork]
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    name: String,
+}
+
+struct MemoryListener(mpsc::UnboundedReceiver<(DuplexStream, String)>);
+
+impl Transport for MemoryTransport {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, crate::Result<BoxedStream>> {
+        Box::pin(async move {
+            let name = address.split(':').next().unwrap_or_default();
+            let state = self.network.state.lock().unwrap();
+            if state.partitions.contains(&pair(&self.name, name)) {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+            }
+
+            let (local, remote) = tokio::io::duplex(STREAM_CAPACITY);
+            match state.listeners.get(address) {
+                Some(listener) => listener
+                    .send((remote, self.name.clone()))
+                    .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?,
+                None => return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into()),
+            }
+            Ok(Box::new(local) as BoxedStream)
+        })
+    }
+
+    fn listen(&self, port: u32) -> BoxFuture<'_, crate::Result<Box<dyn TransportListener>>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.network
+            .state
+            .lock()
+            .unwrap()
+            .listeners
+            .insert(format!("{}:{}", self.name, port), sender);
+        Box::pin(
+            async move { Ok(Box::new(MemoryListener(receiver)) as Box<dyn TransportListener>) },
+        )
+    }
+}
+
+impl TransportListener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, crate::Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, address) = self.0.recv().await.ok_or("network closed")?;
+            Ok((Box::new(stream) as BoxedStream, address))
+        })
+    }
+}
+
+/// Failures injected by a [FaultyTransport]
+///
+/// The byte positions count the bytes written by the node to each stream, every stream has the frames or the file contents
+/// of a peer session. The faults only affect the streams established after they are set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// Delay before each write
+    pub latency: Duration,
+    /// Number of the next connection attempts that are refused
+    pub refused_connections: u32,
+    /// Bytes written before the stream is closed, the peer reads the end of the stream
+    pub truncate_at: Option<u64>,
+    /// Bytes written before the stream fails, reads and writes fail with [io::ErrorKind::ConnectionReset] from then on
+    pub disconnect_at: Option<u64>,
+}
+
+/// [Transport] that injects [Faults] in the streams of another transport
+#[derive(Clone)]
+pub struct FaultyTransport<T> {
+    inner: T,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    /// Wraps `inner`, without faults until [FaultyTransport::set_faults] is called
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            faults: Default::default(),
+        }
+    }
+
+    /// Injects `faults` in the streams established from now on
+    pub fn set_faults(&self, faults: Faults) {
+        *self.faults.lock().unwrap() = faults;
+    }
+}
+
+fn inject_faults(stream: BoxedStream, faults: &Mutex<Faults>) -> BoxedStream {
+    Box::new(FaultyStream {
+        inner: stream,
+        faults: faults.lock().unwrap().clone(),
+        written: 0,
+        delay: None,
+        disconnected: false,
+    })
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn connect<'a>(&'a self, address: &'a str) -> BoxFuture<'a, crate::Result<BoxedStream>> {
+        Box::pin(async move {
+            {
+                let mut faults = self.faults.lock().unwrap();
+                if faults.refused_connections > 0 {
+                    faults.refused_connections -= 1;
+                    return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+                }
+            }
+
+            let stream = self.inner.connect(address).await?;
+            Ok(inject_faults(stream, &self.faults))
+        })
+    }
+
+    fn listen(&self, port: u32) -> BoxFuture<'_, crate::Result<Box<dyn TransportListener>>> {
+        Box::pin(async move {
+            let listener = self.inner.listen(port).await?;
+            Ok(Box::new(FaultyListener {
+                inner: listener,
+                faults: self.faults.clone(),
+            }) as Box<dyn TransportListener>)
+        })
+    }
+}
+
+struct FaultyListener {
+    inner: Box<dyn TransportListener>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl TransportListener for FaultyListener {
+    fn accept(&mut self) -> BoxFuture<'_, crate::Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, address) = self.inner.accept().await?;
+            Ok((inject_faults(stream, &self.faults), address))
+        })
+    }
+}
+
+struct FaultyStream {
+    inner: BoxedStream,
+    faults: Faults,
+    written: u64,
+    /// Latency of the write in progress
+    delay: Option<Pin<Box<Sleep>>>,
+    disconnected: bool,
+}
+
+impl AsyncRead for FaultyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.disconnected {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FaultyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.faults.latency.is_zero() {
+            let latency = self.faults.latency;
+            let delay = self
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(latency)));
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+
+        if self
+            .faults
+            .disconnect_at
+            .is_some_and(|position| self.written >= position)
+        {
+            self.disconnected = true;
+        }
+        if self.disconnected {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self
+            .faults
+            .truncate_at
+            .is_some_and(|position| self.written >= position)
+        {
+            return match Pin::new(&mut self.inner).poll_shutdown(cx) {
+                Poll::Ready(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Poll::Pending => Poll::Pending,
+            };
+        }
+
+        // writes stop at the next fault, so it happens at the exact position
+        let limit = [self.faults.disconnect_at, self.faults.truncate_at]
+            .iter()
+            .flatten()
+            .map(|position| (position - self.written) as usize)
+            .fold(buf.len(), std::cmp::min);
+        let written = match Pin::new(&mut self.inner).poll_write(cx, &buf[..limit]) {
+            Poll::Ready(Ok(written)) => written,
+            poll => return poll,
+        };
+        self.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Result of a synchronization cycle with a peer, as reported to the [SyncObserver]
+type CycleResult = (String, Result<(), String>);
+
+/// Forwards the end of the synchronization cycles to [Simulation::sync]
+struct CycleObserver(mpsc::UnboundedSender<CycleResult>);
+
+impl SyncObserver for CycleObserver {
+    fn on_error(&self, peer_address: &str, error: &(dyn std::error::Error + Send + Sync)) {
+        self.0
+            .send((peer_address.to_owned(), Err(error.to_string())))
+            .ok();
+    }
+
+    fn on_cycle_complete(&self, peer_address: &str) {
+        self.0.send((peer_address.to_owned(), Ok(()))).ok();
+    }
+}
+
+struct SimulatedNode {
+    carrier: IronCarrier,
+    transport: FaultyTransport<MemoryTransport>,
+    storages: HashMap<String, MemoryStorage>,
+    cycles: tokio::sync::Mutex<mpsc::UnboundedReceiver<CycleResult>>,
+}
+
+/// Nodes connected by a [MemoryNetwork], see the [module documentation](self)
+pub struct Simulation {
+    root: PathBuf,
+    network: MemoryNetwork,
+    nodes: BTreeMap<String, SimulatedNode>,
+}
+
+impl Simulation {
+    /// Creates a simulation without nodes, the state files of the nodes are written under `root`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            network: MemoryNetwork::new(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the network connecting the nodes, to disconnect them
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+
+    /// Returns the address of the node `name`, as used by the other nodes
+    pub fn address(name: &str) -> String {
+        format!("{}:{}", name, SIMULATION_PORT)
+    }
+
+    /// Adds and starts the node `name`, with empty `aliases`
+    pub async fn add_node(&mut self, name: &str, aliases: &[&str]) -> crate::Result<()> {
+        let node_root = self.root.join(name);
+        let mut content = format!(
+            "port = {}\nenable_file_watcher = false\n[paths]\n",
+            SIMULATION_PORT
+        );
+        for alias in aliases {
+            content.push_str(&format!(
+                "{} = {:?}\n",
+                alias,
+                node_root.join(alias).to_string_lossy()
+            ));
+        }
+
+        let (sender, cycles) = mpsc::unbounded_channel();
+        let transport = FaultyTransport::new(self.network.transport(name));
+        let mut builder = IronCarrier::builder()
+            .config(Config::parse_content(content)?)
+            .transport(transport.clone())
+            .observer(CycleObserver(sender));
+        let mut storages = HashMap::new();
+        for alias in aliases {
+            let storage = MemoryStorage::new();
+            builder = builder.storage(alias, storage.clone());
+            storages.insert(alias.to_string(), storage);
+        }
+
+        let mut carrier = builder.build()?;
+        carrier.start().await?;
+        self.nodes.insert(
+            name.to_owned(),
+            SimulatedNode {
+                carrier,
+                transport,
+                storages,
+                cycles: tokio::sync::Mutex::new(cycles),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn node(&self, name: &str) -> &SimulatedNode {
+        self.nodes
+            .get(name)
+            .unwrap_or_else(|| panic!("node {} is not in the simulation", name))
+    }
+
+    /// Injects `faults` in the streams of the node `name` established from now on, in both directions
+    pub fn set_faults(&self, name: &str, faults: Faults) {
+        self.node(name).transport.set_faults(faults);
+    }
+
+    /// Returns the storage of `alias` in the node `name`
+    pub fn storage(&self, name: &str, alias: &str) -> &MemoryStorage {
+        self.node(name)
+            .storages
+            .get(alias)
+            .unwrap_or_else(|| panic!("node {} doesn't have alias {}", name, alias))
+    }
+
+    fn absolute_path(&self, name: &str, alias: &str, path: &str) -> PathBuf {
+        let mut absolute_path = self.node(name).carrier.config().paths[alias].clone();
+        absolute_path.extend(Path::new(path).components());
+        absolute_path
+    }
+
+    /// Writes the file at `path`, relative to the root of `alias`, in the node `name`
+    pub fn write_file(
+        &self,
+        name: &str,
+        alias: &str,
+        path: &str,
+        content: &[u8],
+        modified_at: u64,
+    ) {
+        let absolute_path = self.absolute_path(name, alias, path);
+        self.storage(name, alias)
+            .insert(absolute_path, content, modified_at);
+    }
+
+    /// Returns the content of the file at `path`, relative to the root of `alias`, in the node `name`
+    pub fn read_file(&self, name: &str, alias: &str, path: &str) -> Option<Vec<u8>> {
+        let absolute_path = self.absolute_path(name, alias, path);
+        self.storage(name, alias)
+            .get(&absolute_path)
+            .map(|(content, _)| content)
+    }
+
+    /// Synchronizes the node `name` with the node `peer`, returns once the synchronization ended
+    pub async fn sync(&self, name: &str, peer: &str) -> crate::Result<()> {
+        let node = self.node(name);
+        let address = Simulation::address(peer);
+        let mut cycles = node.cycles.lock().await;
+        node.carrier.sync_peer(&address).await?;
+
+        loop {
+            match cycles.recv().await {
+                Some((cycle_peer, result)) if cycle_peer == address => {
+                    return result.map_err(Into::into)
+                }
+                Some(_) => continue,
+                None => return Err(crate::IronCarrierError::NotStarted.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn newer_changes_win_after_reconnecting() -> crate::Result<()> {
+        let mut simulation = Simulation::new("./tmp/simulation");
+        simulation.add_node("a", &["docs"]).await?;
+        simulation.add_node("b", &["docs"]).await?;
+
+        simulation.write_file("a", "docs", "dir/notes.txt", b"first", 10);
+        simulation.sync("a", "b").await?;
+        assert_eq!(
+            simulation.read_file("b", "docs", "dir/notes.txt"),
+            Some(b"first".to_vec())
+        );
+
+        simulation.network().disconnect("a", "b");
+        simulation.write_file("a", "docs", "dir/notes.txt", b"from a", 20);
+        simulation.write_file("b", "docs", "dir/notes.txt", b"from b", 30);
+        assert!(simulation.sync("a", "b").await.is_err());
+
+        simulation.network().reconnect("a", "b");
+        simulation.sync("a", "b").await?;
+        for node in ["a", "b"] {
+            assert_eq!(
+                simulation.read_file(node, "docs", "dir/notes.txt"),
+                Some(b"from b".to_vec())
+            );
+        }
+
+        std::fs::remove_dir_all("./tmp/simulation")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_transfers_are_not_applied() -> crate::Result<()> {
+        let mut simulation = Simulation::new("./tmp/simulation_faults");
+        simulation.add_node("a", &["docs"]).await?;
+        simulation.add_node("b", &["docs"]).await?;
+
+        let content: Vec<u8> = (0..200_000u32).map(|byte| byte as u8).collect();
+        simulation.write_file("a", "docs", "large", &content, 10);
+
+        let failures = [
+            Faults {
+                refused_connections: 1,
+                ..Default::default()
+            },
+            Faults {
+                disconnect_at: Some(100_000),
+                ..Default::default()
+            },
+            Faults {
+                truncate_at: Some(100_000),
+                latency: Duration::from_millis(1),
+                ..Default::default()
+            },
+        ];
+        for faults in failures {
+            simulation.set_faults("a", faults);
+            assert!(simulation.sync("a", "b").await.is_err());
+            assert_eq!(simulation.read_file("b", "docs", "large"), None);
+        }
+
+        simulation.set_faults("a", Faults::default());
+        simulation.sync("a", "b").await?;
+        assert_eq!(simulation.read_file("b", "docs", "large"), Some(content));
+
+        std::fs::remove_dir_all("./tmp/simulation_faults")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_downloads_are_resumed() -> crate::Result<()> {
+        let mut simulation = Simulation::new("./tmp/simulation_resume");
+        simulation.add_node("a", &["docs"]).await?;
+        simulation.add_node("b", &["docs"]).await?;
+
+        let content: Vec<u8> = (0..200_000u32).map(|byte| byte as u8).collect();
+        simulation.write_file("a", "docs", "large", &content, 10);
+        // an empty alias would be seeded with a pack instead
+        simulation.write_file("b", "docs", "small", b"small", 10);
+
+        simulation.set_faults(
+            "a",
+            Faults {
+                disconnect_at: Some(100_000),
+                ..Default::default()
+            },
+        );
+        assert!(simulation.sync("b", "a").await.is_err());
+        assert_eq!(simulation.read_file("b", "docs", "large"), None);
+
+        let journal = crate::transfer_journal::TransferJournal::new(Path::new(
+            "./tmp/simulation_resume/b/docs",
+        ));
+        let pending = journal.pending(&Simulation::address("a")).await;
+        assert!(pending.queued.contains(Path::new("large")));
+        assert!(pending.partial[Path::new("large")].1 > 0);
+
+        simulation.set_faults("a", Faults::default());
+        simulation.sync("b", "a").await?;
+        assert_eq!(simulation.read_file("b", "docs", "large"), Some(content));
+        assert!(journal
+            .pending(&Simulation::address("a"))
+            .await
+            .queued
+            .is_empty());
+
+        std::fs::remove_dir_all("./tmp/simulation_resume")?;
+        Ok(())
+    }
+}
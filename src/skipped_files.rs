@@ -0,0 +1,189 @@
+//! Keeps track of files that couldn't be processed
+//!
+//! A single unreadable file should not abort a whole scan or synchronization,
+//! the file is recorded here instead and a summary is reported at the end of the operation.
+//! The summary counts the files skipped for the same reason, so a folder with thousands of unreadable files is
+//! reported in a single line
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+/// Max number of reasons written to the log when reporting skipped files
+const MAX_REPORTED_REASONS: usize = 10;
+/// Paths kept as examples of each reason
+const MAX_EXAMPLES: usize = 3;
+
+/// Files skipped for the same reason, see [SkippedFiles::groups]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SkippedGroup {
+    pub reason: String,
+    /// Files skipped, each path is counted once
+    pub files: u64,
+    /// First paths skipped
+    pub examples: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SkippedFiles {
+    /// Skipped paths, with the reason and whether it was a failure
+    entries: Vec<(PathBuf, String, bool)>,
+}
+
+impl SkippedFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `path` as skipped because of `reason`
+    pub fn add<R: Display>(&mut self, path: &Path, reason: R) {
+        log::debug!("skipping {:?}: {}", path, reason);
+        self.entries
+            .push((path.to_owned(), reason.to_string(), true));
+    }
+
+    /// Records `path` as skipped on purpose, like a change refused by an observer, it isn't counted as a failure
+    pub fn add_refused<R: Display>(&mut self, path: &Path, reason: R) {
+        log::debug!("skipping {:?}: {}", path, reason);
+        self.entries
+            .push((path.to_owned(), reason.to_string(), false));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Paths recorded after the first `start` entries
+    pub fn paths_since(&self, start: usize) -> impl Iterator<Item = &Path> {
+        self.entries
+            .iter()
+            .skip(start)
+            .map(|(path, _, _)| path.as_path())
+    }
+
+    /// Paths that failed after the first `start` entries, with the reason of each failure
+    pub fn failures_since(&self, start: usize) -> impl Iterator<Item = (&Path, &str)> {
+        self.entries
+            .iter()
+            .skip(start)
+            .filter(|(_, _, failed)| *failed)
+            .map(|(path, reason, _)| (path.as_path(), reason.as_str()))
+    }
+
+    /// Returns the skipped files grouped by reason, the reasons with more files first
+    pub fn groups(&self) -> Vec<SkippedGroup> {
+        let mut positions = HashMap::new();
+        let mut groups: Vec<(SkippedGroup, HashSet<&Path>)> = Vec::new();
+        for (path, reason, _) in &self.entries {
+            let position = *positions.entry(reason.as_str()).or_insert_with(|| {
+                groups.push((
+                    SkippedGroup {
+                        reason: reason.clone(),
+                        files: 0,
+                        examples: Vec::new(),
+                    },
+                    HashSet::new(),
+                ));
+                groups.len() - 1
+            });
+
+            let (group, paths) = &mut groups[position];
+            if paths.insert(path.as_path()) {
+                group.files += 1;
+                if group.examples.len() < MAX_EXAMPLES {
+                    group.examples.push(path.clone());
+                }
+            }
+        }
+
+        let mut groups: Vec<SkippedGroup> = groups.into_iter().map(|(group, _)| group).collect();
+        groups.sort_by_key(|group| std::cmp::Reverse(group.files));
+        groups
+    }
+
+    /// Logs a summary of the skipped files, `operation` describes what was being done when the files were skipped
+    pub fn log_summary(&self, operation: &str) {
+        if self.is_empty() {
+            return;
+        }
+
+        log::warn!("{} file(s) skipped while {}", self.len(), operation);
+        let groups = self.groups();
+        for group in groups.iter().take(MAX_REPORTED_REASONS) {
+            match group.files {
+                1 => log::warn!("  {:?}: {}", group.examples[0], group.reason),
+                files => log::warn!(
+                    "  {} files, like {:?}: {}",
+                    files,
+                    group.examples[0],
+                    group.reason
+                ),
+            }
+        }
+
+        if groups.len() > MAX_REPORTED_REASONS {
+            log::warn!(
+                "  ... and {} more reasons",
+                groups.len() - MAX_REPORTED_REASONS
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_record_skipped_files() {
+        let mut skipped = SkippedFiles::new();
+        assert!(skipped.is_empty());
+
+        skipped.add(Path::new("a"), "permission denied");
+        skipped.add(Path::new("b"), "permission denied");
+        skipped.add_refused(Path::new("c"), "refused");
+
+        assert_eq!(skipped.len(), 3);
+        assert_eq!(skipped.entries[0].0, Path::new("a"));
+        assert_eq!(skipped.entries[1].0, Path::new("b"));
+        assert_eq!(
+            skipped.failures_since(1).collect::<Vec<_>>(),
+            vec![(Path::new("b"), "permission denied")]
+        );
+    }
+
+    #[test]
+    fn skipped_files_are_grouped_by_reason() {
+        let mut skipped = SkippedFiles::new();
+        for file in 0..1000 {
+            skipped.add(
+                &Path::new("folder").join(file.to_string()),
+                "permission denied",
+            );
+        }
+        skipped.add(Path::new("folder/0"), "permission denied");
+        skipped.add(Path::new("locked"), "the file is locked");
+
+        assert_eq!(
+            skipped.groups(),
+            vec![
+                SkippedGroup {
+                    reason: "permission denied".into(),
+                    files: 1000,
+                    examples: vec!["folder/0".into(), "folder/1".into(), "folder/2".into()],
+                },
+                SkippedGroup {
+                    reason: "the file is locked".into(),
+                    files: 1,
+                    examples: vec!["locked".into()],
+                },
+            ]
+        );
+    }
+}
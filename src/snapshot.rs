@@ -0,0 +1,310 @@
+//! Named snapshots of an alias
+//!
+//! A snapshot records the file list of an alias along with the blocks of every file.
+//! The blocks are kept in the block store and referenced by the snapshot, so restoring never depends on the versions still kept for each file
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    block_store::{BlockHash, BlockStore},
+    config::Config,
+    deletion_tracker::DeletionTracker,
+    fs::{self, FileInfo, FileKind},
+    version_store::VersionStores,
+    IronCarrierError,
+};
+
+/// A file recorded in a snapshot
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry {
+    path: PathBuf,
+    modified_at: Option<u64>,
+    size: u64,
+    blocks: Vec<BlockHash>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    created_at: u64,
+    files: Vec<SnapshotEntry>,
+}
+
+/// Summary of a stored snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    /// Name given to the snapshot
+    pub name: String,
+    /// Creation time, in seconds since the unix epoch
+    pub created_at: u64,
+    /// Number of files in the snapshot
+    pub files: usize,
+}
+
+fn block_store_path(config: &Config) -> crate::Result<&Path> {
+    match &config.block_store_path {
+        Some(path) => Ok(path),
+        None => Err(IronCarrierError::BlockStoreNotConfigured.into()),
+    }
+}
+
+fn alias_path<'a>(config: &'a Config, alias: &str) -> crate::Result<&'a PathBuf> {
+    config
+        .paths
+        .get(alias)
+        .ok_or_else(|| IronCarrierError::AliasNotAvailable(alias.to_owned()).into())
+}
+
+/// Returns the path for the snapshot `name` of `alias`, names can't contain path separators
+fn snapshot_path(config: &Config, alias: &str, name: &str) -> crate::Result<PathBuf> {
+    let is_valid =
+        !name.is_empty() && name != "." && name != ".." && !name.contains(std::path::is_separator);
+    if !is_valid {
+        return Err(IronCarrierError::SnapshotNotFound(name.to_owned()).into());
+    }
+
+    Ok(block_store_path(config)?
+        .join("snapshots")
+        .join(alias)
+        .join(name))
+}
+
+async fn read_snapshot(config: &Config, alias: &str, name: &str) -> crate::Result<Snapshot> {
+    let path = snapshot_path(config, alias, name)?;
+    if !path.exists() {
+        return Err(IronCarrierError::SnapshotNotFound(name.to_owned()).into());
+    }
+
+    Ok(bincode::deserialize(&tokio::fs::read(path).await?)?)
+}
+
+fn now_as_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Records the current content of `alias` as the snapshot `name`, replacing any snapshot with the same name
+pub async fn create_snapshot(config: &Config, alias: &str, name: &str) -> crate::Result<()> {
+    let root_path = alias_path(config, alias)?;
+    let path = snapshot_path(config, alias, name)?;
+    let previous = if path.exists() {
+        Some(read_snapshot(config, alias, name).await?)
+    } else {
+        None
+    };
+
+    log::info!("creating snapshot {} of alias {}", name, alias);
+    let mut blocks = BlockStore::open(block_store_path(config)?).await?;
+    let mut files = Vec::new();
+    for file in fs::walk_path(root_path, alias, config).await? {
+        if file.deleted_at.is_some() {
+            continue;
+        }
+
+        files.push(SnapshotEntry {
+            blocks: blocks
+                .store_file(&root_path.join(&file.path), config.block_size(alias))
+                .await?,
+            path: file.path,
+            modified_at: file.modified_at,
+            size: file.size.unwrap_or_default(),
+        });
+    }
+
+    if let Some(previous) = previous {
+        for hash in previous.files.iter().flat_map(|file| file.blocks.iter()) {
+            blocks.release(hash);
+        }
+        blocks.collect_garbage().await?;
+    }
+
+    let snapshot = Snapshot {
+        created_at: now_as_secs(),
+        files,
+    };
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&path, bincode::serialize(&snapshot)?).await?;
+    blocks.save().await?;
+
+    log::info!(
+        "snapshot {} of alias {} created with {} files",
+        name,
+        alias,
+        snapshot.files.len()
+    );
+
+    Ok(())
+}
+
+/// Returns the snapshots stored for `alias`, sorted by creation time
+pub async fn list_snapshots(config: &Config, alias: &str) -> crate::Result<Vec<SnapshotInfo>> {
+    alias_path(config, alias)?;
+    let snapshots_path = block_store_path(config)?.join("snapshots").join(alias);
+    if !snapshots_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    let mut entries = tokio::fs::read_dir(snapshots_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let snapshot = read_snapshot(config, alias, &name).await?;
+        snapshots.push(SnapshotInfo {
+            name,
+            created_at: snapshot.created_at,
+            files: snapshot.files.len(),
+        });
+    }
+
+    snapshots.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.name.cmp(&b.name)));
+    Ok(snapshots)
+}
+
+/// Removes the snapshot `name` of `alias`, along with the blocks only used by it
+pub async fn remove_snapshot(config: &Config, alias: &str, name: &str) -> crate::Result<()> {
+    let snapshot = read_snapshot(config, alias, name).await?;
+
+    log::info!("removing snapshot {} of alias {}", name, alias);
+    let mut blocks = BlockStore::open(block_store_path(config)?).await?;
+    for hash in snapshot.files.iter().flat_map(|file| file.blocks.iter()) {
+        blocks.release(hash);
+    }
+
+    tokio::fs::remove_file(snapshot_path(config, alias, name)?).await?;
+    blocks.collect_garbage().await?;
+    blocks.save().await?;
+
+    Ok(())
+}
+
+/// Restores `alias` to the content recorded in the snapshot `name`
+///
+/// Files that changed since the snapshot are rewritten and files created after it are deleted, the replaced content is kept as a previous version.
+/// Restored files get the current time as modification time, so the restored content is the one propagated to the peers in the next synchronization
+pub async fn restore_snapshot(config: &Config, alias: &str, name: &str) -> crate::Result<()> {
+    let root_path = alias_path(config, alias)?;
+    let snapshot = read_snapshot(config, alias, name).await?;
+    let deletion_tracker = DeletionTracker::new(root_path);
+    let versions = VersionStores::new();
+
+    log::info!("restoring alias {} to snapshot {}", alias, name);
+
+    let snapshot_paths: HashSet<&Path> = snapshot
+        .files
+        .iter()
+        .map(|file| file.path.as_path())
+        .collect();
+    for file in fs::walk_path(root_path, alias, config).await? {
+        if file.deleted_at.is_some() || snapshot_paths.contains(file.path.as_path()) {
+            continue;
+        }
+
+        fs::delete_file(&file, config, &versions).await?;
+        deletion_tracker.add_entry(&file.path).await?;
+    }
+
+    let mut restored = 0;
+    for entry in snapshot.files.iter() {
+        let is_unchanged = root_path
+            .join(&entry.path)
+            .metadata()
+            .map(|metadata| FileInfo::new(alias.to_owned(), entry.path.clone(), metadata))
+            .map(|current| {
+                current.modified_at == entry.modified_at && current.size == Some(entry.size)
+            })
+            .unwrap_or_default();
+        if is_unchanged {
+            continue;
+        }
+
+        log::debug!("restoring {:?}", entry.path);
+        let file_info = FileInfo {
+            alias: alias.to_owned(),
+            path: entry.path.clone(),
+            modified_at: Some(now_as_secs()),
+            created_at: None,
+            deleted_at: None,
+            size: Some(entry.size),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        {
+            let mut temp_file = fs::get_temp_file(&file_info, config).await?;
+            let blocks = BlockStore::open(block_store_path(config)?).await?;
+            blocks.write_blocks(&entry.blocks, &mut temp_file).await?;
+        }
+
+        fs::flush_temp_file(&file_info, config, &versions).await?;
+        deletion_tracker.remove_entry(&entry.path).await?;
+        restored += 1;
+    }
+
+    log::info!(
+        "alias {} restored to snapshot {}, {} files rewritten",
+        alias,
+        name,
+        restored
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_restore_snapshot() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "
+            block_store_path = \"./tmp/snapshot/store\"
+
+            [paths]
+            a = \"./tmp/snapshot/a\""
+                .to_string(),
+        )?;
+
+        tokio::fs::write("./tmp/snapshot/a/changed", "original").await?;
+        tokio::fs::write("./tmp/snapshot/a/deleted", "deleted").await?;
+        create_snapshot(&config, "a", "before").await?;
+
+        tokio::fs::write("./tmp/snapshot/a/changed", "new content").await?;
+        tokio::fs::remove_file("./tmp/snapshot/a/deleted").await?;
+        tokio::fs::write("./tmp/snapshot/a/created", "created").await?;
+
+        let snapshots = list_snapshots(&config, "a").await?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].name, "before");
+        assert_eq!(snapshots[0].files, 2);
+
+        restore_snapshot(&config, "a", "before").await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/snapshot/a/changed").await?,
+            "original"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string("./tmp/snapshot/a/deleted").await?,
+            "deleted"
+        );
+        assert!(!Path::new("./tmp/snapshot/a/created").exists());
+
+        remove_snapshot(&config, "a", "before").await?;
+        assert!(list_snapshots(&config, "a").await?.is_empty());
+        assert!(restore_snapshot(&config, "a", "before").await.is_err());
+
+        tokio::fs::remove_dir_all("./tmp/snapshot").await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+//! Sorted lists kept on disk when they don't fit the memory budget
+//!
+//! Without [Config::memory_budget_mb], lists are kept in memory, like a [Vec].
+//! With it, entries are buffered until the budget is reached, then the buffer is sorted and written to a temp file,
+//! the list is read back by merging those files, so only one entry of each file is in memory at a time
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::hash_map::DefaultHasher,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+};
+
+use crate::config::Config;
+
+/// Used to give each spill file an unique name
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+type Compare<T> = Arc<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
+
+/// Sorted entries written to a temp file, removed when dropped
+struct Run {
+    path: PathBuf,
+    len: usize,
+}
+
+impl Run {
+    fn write<T: Serialize>(entries: &[T]) -> crate::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "iron-carrier-{}-{}.spool",
+            std::process::id(),
+            NEXT_RUN.fetch_add(1, AtomicOrdering::SeqCst)
+        ));
+
+        log::debug!("spilling {} entries to {:?}", entries.len(), path);
+        let run = Run {
+            path,
+            len: entries.len(),
+        };
+
+        let mut writer = BufWriter::new(File::create(&run.path)?);
+        for entry in entries {
+            bincode::serialize_into(&mut writer, entry)?;
+        }
+        std::io::Write::flush(&mut writer)?;
+
+        Ok(run)
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Collects entries, spilling them to disk when the memory budget is reached
+pub(crate) struct Spool<T> {
+    buffer: Vec<T>,
+    buffer_size: usize,
+    budget: Option<usize>,
+    runs: Vec<Run>,
+    compare: Compare<T>,
+}
+
+impl<T> Spool<T>
+where
+    T: Serialize + DeserializeOwned + Clone,
+{
+    /// Creates a spool that sorts its entries with `compare`
+    pub fn new<F>(config: &Config, compare: F) -> Self
+    where
+        F: Fn(&T, &T) -> Ordering + Send + Sync + 'static,
+    {
+        Self {
+            buffer: Vec::new(),
+            buffer_size: 0,
+            budget: config.memory_budget_mb.map(|mb| mb * 1024 * 1024),
+            runs: Vec::new(),
+            compare: Arc::new(compare),
+        }
+    }
+
+    pub fn push(&mut self, entry: T) -> crate::Result<()> {
+        if let Some(budget) = self.budget {
+            self.buffer_size +=
+                std::mem::size_of::<T>() + bincode::serialized_size(&entry)? as usize;
+            self.buffer.push(entry);
+
+            if self.buffer_size >= budget {
+                self.spill()?;
+            }
+        } else {
+            self.buffer.push(entry);
+        }
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> crate::Result<()> {
+        let compare = self.compare.clone();
+        self.buffer.sort_by(|a, b| compare(a, b));
+        self.runs.push(Run::write(&self.buffer)?);
+        self.buffer.clear();
+        self.buffer_size = 0;
+
+        Ok(())
+    }
+
+    /// Sorts the entries, returning the final list
+    pub fn finish(mut self) -> crate::Result<SortedList<T>> {
+        if !self.runs.is_empty() && !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        let compare = self.compare.clone();
+        self.buffer.sort_by(|a, b| compare(a, b));
+        let len = self.buffer.len() + self.runs.iter().map(|run| run.len).sum::<usize>();
+
+        Ok(SortedList {
+            entries: Arc::new(self.buffer),
+            runs: Arc::new(self.runs),
+            len,
+            compare: self.compare,
+        })
+    }
+}
+
+/// List produced by a [Spool], it can be read as many times as needed
+pub(crate) struct SortedList<T> {
+    entries: Arc<Vec<T>>,
+    runs: Arc<Vec<Run>>,
+    len: usize,
+    compare: Compare<T>,
+}
+
+impl<T> SortedList<T>
+where
+    T: Serialize + DeserializeOwned + Clone,
+{
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the list didn't fit the memory budget and is kept on disk
+    pub fn is_spilled(&self) -> bool {
+        !self.runs.is_empty()
+    }
+
+    /// Returns a reader for the entries, in order, the reader keeps the list available even if it is dropped
+    pub fn reader(&self) -> crate::Result<SortedReader<T>> {
+        let mut sources = Vec::with_capacity(self.runs.len());
+        for run in self.runs.iter() {
+            let mut source = RunReader {
+                reader: BufReader::new(File::open(&run.path)?),
+                remaining: run.len,
+                head: None,
+            };
+            source.advance()?;
+            sources.push(source);
+        }
+
+        Ok(SortedReader {
+            entries: self.entries.clone(),
+            next: 0,
+            _runs: self.runs.clone(),
+            sources,
+            compare: self.compare.clone(),
+        })
+    }
+
+    /// Reads the whole list into memory
+    pub fn to_vec(&self) -> crate::Result<Vec<T>> {
+        let mut reader = self.reader()?;
+        let mut entries = Vec::with_capacity(self.len);
+        while let Some(entry) = reader.next_entry()? {
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+impl<T> SortedList<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Hash,
+{
+    /// Returns the same hash as [crate::crypto::calculate_hash] for a [Vec] with the entries of this list
+    pub fn hash(&self) -> crate::Result<u64> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_usize(self.len);
+
+        let mut reader = self.reader()?;
+        while let Some(entry) = reader.next_entry()? {
+            entry.hash(&mut hasher);
+        }
+
+        Ok(hasher.finish())
+    }
+}
+
+struct RunReader<T> {
+    reader: BufReader<File>,
+    remaining: usize,
+    head: Option<T>,
+}
+
+impl<T: DeserializeOwned> RunReader<T> {
+    fn advance(&mut self) -> crate::Result<()> {
+        self.head = if self.remaining > 0 {
+            self.remaining -= 1;
+            Some(bincode::deserialize_from(&mut self.reader)?)
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+}
+
+/// Reads a [SortedList] in order, merging the entries kept on disk
+pub(crate) struct SortedReader<T> {
+    entries: Arc<Vec<T>>,
+    next: usize,
+    _runs: Arc<Vec<Run>>,
+    sources: Vec<RunReader<T>>,
+    compare: Compare<T>,
+}
+
+impl<T> SortedReader<T>
+where
+    T: DeserializeOwned + Clone,
+{
+    pub fn next_entry(&mut self) -> crate::Result<Option<T>> {
+        if self.sources.is_empty() {
+            let entry = self.entries.get(self.next).cloned();
+            self.next += 1;
+            return Ok(entry);
+        }
+
+        let mut smallest: Option<usize> = None;
+        for (index, source) in self.sources.iter().enumerate() {
+            let head = match &source.head {
+                Some(head) => head,
+                None => continue,
+            };
+
+            smallest = match smallest {
+                Some(current)
+                    if (self.compare)(head, self.sources[current].head.as_ref().unwrap())
+                        != Ordering::Less =>
+                {
+                    Some(current)
+                }
+                _ => Some(index),
+            };
+        }
+
+        match smallest {
+            Some(index) => {
+                let entry = self.sources[index].head.take();
+                self.sources[index].advance()?;
+                Ok(entry)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(budget: &str) -> crate::Result<Config> {
+        Config::parse_content(format!(
+            "{}
+            [paths]
+            a = \"./tmp/spool\"",
+            budget
+        ))
+    }
+
+    #[test]
+    fn spilled_list_is_read_in_order() -> crate::Result<()> {
+        let numbers: Vec<u64> = (0..100_000).map(|n| (n * 7919) % 100_000).collect();
+
+        let mut in_memory = Spool::new(&config("")?, |a: &u64, b: &u64| a.cmp(b));
+        let mut spilled = Spool::new(&config("memory_budget_mb = 1")?, |a: &u64, b: &u64| {
+            a.cmp(b)
+        });
+        for number in &numbers {
+            in_memory.push(*number)?;
+            spilled.push(*number)?;
+        }
+
+        let in_memory = in_memory.finish()?;
+        let spilled = spilled.finish()?;
+        assert!(!in_memory.is_spilled());
+        assert!(spilled.is_spilled());
+        assert_eq!(spilled.len(), numbers.len());
+
+        let mut sorted = numbers.clone();
+        sorted.sort();
+        assert_eq!(in_memory.to_vec()?, sorted);
+        assert_eq!(spilled.to_vec()?, sorted);
+        assert_eq!(spilled.hash()?, crate::crypto::calculate_hash(&sorted));
+
+        std::fs::remove_dir_all("./tmp/spool")?;
+        Ok(())
+    }
+}
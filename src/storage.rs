@@ -0,0 +1,249 @@
+//! Storage backends for the alias roots
+//!
+//! The files of an alias are read and written through a [Storage], [LocalStorage] is used unless another backend is
+//! set for the alias with [crate::config::Config::set_storage] or [crate::IronCarrierBuilder::storage]
+//! The root path of the alias, in [crate::config::Config::paths], is passed to the backend as it is
+//!
+//! Features that depend on the local file system are only available for aliases in [LocalStorage]: special files,
+//! hard links, creation times, fsync, previous versions, deletion tracking and the file watcher
+
+use futures::future::BoxFuture;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeek, AsyncWrite},
+};
+
+/// Metadata of a file or folder in a [Storage]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageMetadata {
+    /// True for folders
+    pub is_dir: bool,
+    /// Size of the file content, in bytes
+    pub len: u64,
+    /// Modification time, in seconds since the unix epoch
+    pub modified_at: Option<u64>,
+    /// Creation time, in seconds since the unix epoch
+    pub created_at: Option<u64>,
+}
+
+impl From<&std::fs::Metadata> for StorageMetadata {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        let secs = |time: io::Result<SystemTime>| {
+            time.ok()
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs())
+        };
+
+        StorageMetadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified_at: secs(metadata.modified()),
+            created_at: secs(metadata.created()),
+        }
+    }
+}
+
+/// File being written in a [Storage], received files are written to a temp file before replacing the final one
+pub trait StorageFile: AsyncWrite + AsyncSeek + Unpin + Send + Sync {
+    /// Reserves `size` bytes for the file, so a full storage is noticed before any content is written
+    /// Backends without preallocation can ignore it
+    fn preallocate(&self, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the content written so far durable
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+impl StorageFile for File {
+    fn preallocate(&self, size: u64) -> io::Result<()> {
+        crate::fs::preallocate(self, size)
+    }
+
+    fn sync_data(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(File::sync_data(self))
+    }
+}
+
+/// Backend holding the files of an alias, all paths are absolute, starting at the alias root path
+pub trait Storage: Send + Sync {
+    /// Returns the metadata of the file or folder at `path`
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<StorageMetadata>>;
+
+    /// Returns the files and folders directly inside the folder at `path`, with their full path
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<(PathBuf, StorageMetadata)>>>;
+
+    /// Opens the file at `path` for reading
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncRead + Unpin + Send>>>;
+
+    /// Creates, or truncates, the file at `path` for writing, the missing parent folders are created
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>>;
+
+    /// Opens the existing file at `path` for writing, keeping its content
+    fn open_write<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>>;
+
+    /// Moves the file at `from` to `to`, replacing it
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Removes the file at `path`
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Removes the folder at `path` with all its content
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Sets the modification time of the file at `path`, in seconds since the unix epoch
+    fn set_modified<'a>(
+        &'a self,
+        path: &'a Path,
+        modified_at: u64,
+    ) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Default [Storage], the files are kept in the local file system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalStorage;
+
+impl Storage for LocalStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<StorageMetadata>> {
+        Box::pin(async move { Ok(StorageMetadata::from(&tokio::fs::metadata(path).await?)) })
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Vec<(PathBuf, StorageMetadata)>>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            let mut dir = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let metadata = tokio::fs::metadata(entry.path()).await?;
+                entries.push((entry.path(), StorageMetadata::from(&metadata)));
+            }
+
+            Ok(entries)
+        })
+    }
+
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncRead + Unpin + Send>>> {
+        Box::pin(async move {
+            let file = File::open(path).await?;
+            Ok(Box::new(file) as Box<dyn AsyncRead + Unpin + Send>)
+        })
+    }
+
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            if let Some(parent) = path.parent() {
+                if !parent.exists() {
+                    log::debug!("creating folders {:?}", parent);
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+            }
+
+            let file = File::create(path).await?;
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Box<dyn StorageFile>>> {
+        Box::pin(async move {
+            let file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+            Ok(Box::new(file) as Box<dyn StorageFile>)
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::rename(from, to))
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::remove_file(path))
+    }
+
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::remove_dir_all(path))
+    }
+
+    fn set_modified<'a>(
+        &'a self,
+        path: &'a Path,
+        modified_at: u64,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mod_time = SystemTime::UNIX_EPOCH + Duration::from_secs(modified_at);
+            filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mod_time))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{
+        config::Config,
+        fs::{self, FileInfo, FileKind},
+        simulation::MemoryStorage,
+        version_store::VersionStores,
+    };
+
+    #[tokio::test]
+    async fn aliases_can_use_custom_storage() -> crate::Result<()> {
+        let mut config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/storage\""
+                .to_string(),
+        )?;
+        let storage = MemoryStorage::default();
+        storage.insert("./tmp/storage/dir/file", b"content", 10);
+        config.set_storage("a", Arc::new(storage.clone()))?;
+        assert!(config.set_storage("b", Arc::new(LocalStorage)).is_err());
+
+        let files = fs::walk_path(Path::new("./tmp/storage"), "a", &config).await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("dir/file"));
+        assert_eq!(files[0].modified_at, Some(10));
+        assert_eq!(fs::read_content(&files[0], &config).await?, b"content");
+
+        let received = FileInfo {
+            alias: "a".to_string(),
+            path: PathBuf::from("received"),
+            modified_at: Some(20),
+            created_at: None,
+            deleted_at: None,
+            size: Some(3),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+        let mut temp_file = fs::get_temp_file(&received, &config).await?;
+        temp_file.write_all(b"new").await?;
+        let versions = VersionStores::new();
+        fs::flush_temp_file(&received, &config, &versions).await?;
+        fs::delete_file(&files[0], &config, &versions).await?;
+
+        assert_eq!(
+            storage.files(),
+            vec![(PathBuf::from("./tmp/storage/received"), b"new".to_vec(), 20)]
+        );
+        assert!(!Path::new("./tmp/storage/received").exists());
+
+        std::fs::remove_dir_all("./tmp/storage")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::config::Config;
+
+/// Serializes the changes written to each alias by the peer sessions running at the same time
+///
+/// Locks are only held while the local files are written, never while waiting for a peer to read its own files,
+/// so two peers synchronizing with each other can't wait on one another
+pub(crate) struct AliasLocks {
+    locks: HashMap<String, Arc<Mutex<()>>>,
+}
+
+impl AliasLocks {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            locks: config
+                .paths
+                .keys()
+                .map(|alias| (alias.clone(), Arc::new(Mutex::new(()))))
+                .collect(),
+        }
+    }
+
+    /// Waits until no other session is writing to `alias`, the alias is released when the guard is dropped
+    /// Returns [None] for aliases that don't exist in this node, there is nothing to write to
+    pub async fn lock(&self, alias: &str) -> Option<OwnedMutexGuard<()>> {
+        match self.locks.get(alias) {
+            Some(lock) => Some(lock.clone().lock_owned().await),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn aliases_are_locked_separately() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "
+            [paths]
+            a = \"./tmp/alias_locks/a\"
+            b = \"./tmp/alias_locks/b\""
+                .to_string(),
+        )?;
+        let locks = AliasLocks::new(&config);
+
+        let guard = locks.lock("a").await;
+        assert!(guard.is_some());
+        assert!(locks.lock("b").await.is_some());
+        assert!(locks.lock("c").await.is_none());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), locks.lock("a"))
+                .await
+                .is_err()
+        );
+
+        drop(guard);
+        assert!(locks.lock("a").await.is_some());
+
+        std::fs::remove_dir_all("./tmp/alias_locks")?;
+        Ok(())
+    }
+}
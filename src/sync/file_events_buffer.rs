@@ -2,14 +2,30 @@ use std::{
     collections::HashMap,
     path::PathBuf,
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
+use super::introduced_peers::IntroducedPeers;
 use crate::{config::Config, fs::FileInfo};
 
+/// Change applied to a local file by the synchronization, on behalf of a peer
+struct AppliedChange {
+    peer_address: String,
+    applied_at: Instant,
+    /// Modification time the file is expected to have, [None] when the file was removed
+    modified_at: Option<u64>,
+}
+
 /// Keeps track of received events to avoid sending the same events back
+///
+/// Writes, renames and deletions made by the synchronization are picked up by the file watcher like any other change,
+/// an event is only suppressed if the file is still in the state the synchronization left it, so local changes made
+/// right after a synchronization are still sent to every peer
 pub(crate) struct FileEventsBuffer {
     config: Arc<Config>,
-    events: Arc<RwLock<HashMap<PathBuf, (String, std::time::Instant)>>>,
+    events: Arc<RwLock<HashMap<PathBuf, AppliedChange>>>,
+    /// Approved peers learned from the introducers also receive the events
+    introduced_peers: Arc<IntroducedPeers>,
 }
 
 impl FileEventsBuffer {
@@ -17,12 +33,26 @@ impl FileEventsBuffer {
         FileEventsBuffer {
             events: Arc::new(RwLock::new(HashMap::new())),
             config,
+            introduced_peers: Default::default(),
         }
     }
 
-    /// Returns a [Vec]<`[String]`> containing peer address that can receive events for this [FileInfo]
+    /// Sends the events to the approved peers of `introduced_peers` as well
+    pub fn with_introduced_peers(mut self, introduced_peers: Arc<IntroducedPeers>) -> Self {
+        self.introduced_peers = introduced_peers;
+        self
+    }
+
+    /// Returns a [Vec]<`[String]`> containing peer address that can receive events for this [FileInfo]  
+    /// Archives don't send their local changes, see [Config::archive_mode]
     pub fn allowed_peers_for_event(&self, file: &FileInfo) -> Option<Vec<String>> {
+        if self.config.archive_mode {
+            return None;
+        }
+
         let mut peers = self.config.peers.clone()?;
+        peers.extend(self.introduced_peers.approved());
+        peers.retain(|peer| self.config.syncs_alias_with(&file.alias, peer));
 
         let absolute_path = file.get_absolute_path(&self.config);
         let absolute_path = match absolute_path {
@@ -30,42 +60,55 @@ impl FileEventsBuffer {
             Err(_) => return Some(peers),
         };
 
-        let limit = std::time::Instant::now()
-            - std::time::Duration::from_secs(self.config.delay_watcher_events * 2);
+        let limit = Instant::now() - Duration::from_secs(self.config.delay_watcher_events * 2);
         let received_file_events = self.events.read().unwrap();
 
-        if let Some((event_peer_address, event_time)) = received_file_events.get(&absolute_path) {
-            if event_time > &limit {
-                peers.retain(|p| !p.starts_with(event_peer_address));
+        if let Some(change) = received_file_events.get(&absolute_path) {
+            if change.applied_at > limit && change.modified_at == file.modified_at {
+                peers.retain(|p| !p.starts_with(&change.peer_address));
             }
         }
         Some(peers)
     }
 
+    /// Records that `file_info` is about to be written, or removed, on behalf of `peer_address`
     pub fn add_event(&self, file_info: &FileInfo, peer_address: &str) {
         let absolute_path = file_info.get_absolute_path(&self.config).unwrap();
+        let applied_at = Instant::now();
 
         let mut received_events_guard = self.events.write().unwrap();
         received_events_guard.insert(
             absolute_path.clone(),
-            (peer_address.to_owned(), std::time::Instant::now()),
+            AppliedChange {
+                peer_address: peer_address.to_owned(),
+                applied_at,
+                modified_at: file_info
+                    .deleted_at
+                    .is_none()
+                    .then_some(file_info.modified_at)
+                    .flatten(),
+            },
         );
 
         let received_events = self.events.clone();
         let debounce_time = self.config.delay_watcher_events + 1;
         tokio::spawn(async move {
-            tokio::time::sleep(tokio::time::Duration::from_secs(debounce_time)).await;
+            tokio::time::sleep(Duration::from_secs(debounce_time)).await;
 
             let mut received_events_guard = received_events.write().unwrap();
-            received_events_guard.remove(&absolute_path);
+            // a newer change to the same file has its own timer
+            if received_events_guard
+                .get(&absolute_path)
+                .is_some_and(|change| change.applied_at == applied_at)
+            {
+                received_events_guard.remove(&absolute_path);
+            }
         });
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
-
     use super::*;
 
     #[tokio::test]
@@ -94,4 +137,43 @@ mod tests {
         tokio::time::sleep(Duration::from_secs(1)).await;
         assert_eq!(2, buffer.allowed_peers_for_event(&file_info).unwrap().len());
     }
+
+    #[tokio::test]
+    async fn local_changes_after_sync_are_sent_back() {
+        let config = Config::parse_content(
+            "peers = [\"a\", \"b\"]
+        [paths]
+        a = \"./tmp\"
+        "
+            .into(),
+        )
+        .unwrap();
+
+        let buffer = FileEventsBuffer::new(Arc::new(config));
+        let file = |modified_at| FileInfo {
+            modified_at,
+            deleted_at: None,
+            ..FileInfo::new_deleted("a".into(), "file".into(), None)
+        };
+
+        buffer.add_event(&file(Some(10)), "a");
+        assert_eq!(
+            vec!["b".to_string()],
+            buffer.allowed_peers_for_event(&file(Some(10))).unwrap()
+        );
+        assert_eq!(
+            2,
+            buffer
+                .allowed_peers_for_event(&file(Some(11)))
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            2,
+            buffer
+                .allowed_peers_for_event(&FileInfo::new_deleted("a".into(), "file".into(), None))
+                .unwrap()
+                .len()
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, path::Path, path::PathBuf, sync::Arc, time::Duration};
+use std::{path::Path, path::PathBuf, sync::Arc, time::Duration};
 
 use notify::{watcher, DebouncedEvent, Error, RecommendedWatcher, RecursiveMode, Watcher};
 use tokio::sync::mpsc::Sender;
@@ -9,7 +9,7 @@ use crate::{config::Config, deletion_tracker::DeletionTracker, fs::FileInfo};
 pub(crate) struct FileWatcher {
     event_sender: Sender<SyncEvent>,
     config: Arc<Config>,
-    notify_watcher: RecommendedWatcher,
+    _notify_watcher: RecommendedWatcher,
     events_buffer: Arc<FileEventsBuffer>,
 }
 
@@ -22,17 +22,24 @@ impl FileWatcher {
         let (tx, rx) = std::sync::mpsc::channel();
 
         let mut notify_watcher = watcher(tx, Duration::from_secs(config.delay_watcher_events))?;
-        for (_, path) in config.paths.iter() {
-            let path = path.canonicalize().unwrap();
-            if let Err(_) = notify_watcher.watch(path, RecursiveMode::Recursive) {
-                eprintln!("Cannot watch path");
+        for alias in config.paths.keys() {
+            for path in config.alias_roots(alias) {
+                let path = path.canonicalize().unwrap();
+                if notify_watcher
+                    .watch(path, RecursiveMode::Recursive)
+                    .is_err()
+                {
+                    eprintln!("Cannot watch path");
+                } else {
+                    config.scan_hints().watch(alias);
+                }
             }
         }
 
         let file_watcher = FileWatcher {
             event_sender,
             config,
-            notify_watcher,
+            _notify_watcher: notify_watcher,
             events_buffer,
         };
 
@@ -46,59 +53,103 @@ impl FileWatcher {
         let events_buffer = self.events_buffer.clone();
         let sync_event_sender = self.event_sender.clone();
 
-        tokio::task::spawn_blocking(move || loop {
-            match notify_events_receiver.recv() {
-                Ok(event) => {
-                    let config = config.clone();
-                    let sync_event_sender = sync_event_sender.clone();
-                    let events_buffer = events_buffer.clone();
-
-                    tokio::spawn(async move {
-                        if let Some(event) =
-                            map_to_sync_event(event, &config.paths, &events_buffer).await
-                        {
-                            sync_event_sender.send(event).await.ok();
-                        }
-                    });
-                }
-                Err(_) => break,
+        tokio::task::spawn_blocking(move || {
+            while let Ok(event) = notify_events_receiver.recv() {
+                record_scan_hints(&event, &config);
+
+                let config = config.clone();
+                let sync_event_sender = sync_event_sender.clone();
+                let events_buffer = events_buffer.clone();
+
+                tokio::spawn(async move {
+                    if let Some(event) = map_to_sync_event(event, &config, &events_buffer).await {
+                        sync_event_sender.send(event).await.ok();
+                    }
+                });
             }
         });
     }
 }
 
-fn get_alias_for_path(
-    file_path: &Path,
-    paths: &HashMap<String, PathBuf>,
-) -> Option<(String, PathBuf)> {
+/// Returns the alias of `file_path`, along with the folder of the alias it is in
+fn get_alias_for_path(file_path: &Path, config: &Config) -> Option<(String, PathBuf)> {
     let file_path = if file_path.is_relative() {
         file_path.canonicalize().ok()?
     } else {
         file_path.to_owned()
     };
 
-    for (alias, config_path) in paths.iter() {
-        let config_path = match config_path.canonicalize() {
-            Ok(config_path) => config_path,
-            Err(_) => return None,
+    for alias in config.paths.keys() {
+        for config_path in config.alias_roots(alias) {
+            let config_path = match config_path.canonicalize() {
+                Ok(config_path) => config_path,
+                Err(_) => return None,
+            };
+
+            if file_path.starts_with(&config_path) {
+                return Some((alias.clone(), config_path.clone()));
+            }
+        }
+    }
+
+    None
+}
+
+/// Records the folders changed by `event` for the next scan, see [Config::incremental_scan]
+fn record_scan_hints(event: &DebouncedEvent, config: &Config) {
+    let changed_paths = match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path) => vec![path],
+        DebouncedEvent::Rename(src_path, dest_path) => vec![src_path, dest_path],
+        DebouncedEvent::Rescan | DebouncedEvent::Error(..) => {
+            config.scan_hints().invalidate_all();
+            return;
+        }
+        _ => return,
+    };
+
+    for path in changed_paths {
+        if crate::fs::is_special_file(path) {
+            continue;
+        }
+        let (alias, root) = match get_alias_for_path(path, config) {
+            Some(alias) => alias,
+            None => continue,
+        };
+        let relative_path = match path.strip_prefix(&root) {
+            Ok(relative_path) if relative_path != Path::new("") => relative_path,
+            _ => continue,
         };
 
-        if file_path.starts_with(&config_path) {
-            return Some((alias.clone(), config_path.clone()));
+        // removed paths can't be told apart from folders anymore
+        if path.is_dir() || !path.exists() {
+            config.scan_hints().tree_changed(&alias, relative_path);
+        }
+        if let Some(parent) = relative_path.parent() {
+            config.scan_hints().folder_changed(&alias, parent);
         }
     }
+}
 
-    return None;
+/// Returns true if the file at `file_path` is left out by [Config::ignore_hidden], [Config::include_only] or
+/// [crate::config::AliasLimits::max_depth]
+fn is_left_out(config: &Config, alias: &str, file_path: &Path, relative_path: &Path) -> bool {
+    config.exceeds_max_depth(alias, relative_path)
+        || !config.is_included(alias, relative_path)
+        || (config.ignore_hidden.contains(alias)
+            && (crate::fs::is_hidden(relative_path) || crate::fs::has_hidden_attribute(file_path)))
 }
 
 /// Map a [DebouncedEvent] to a [SyncEvent]`(` alias, file_path)
 ///
 /// Returns [Some]`(`[SyncEvent]`)` if success  
 /// Returns [None] for ignored events
-async fn map_to_sync_event<'b>(
+async fn map_to_sync_event(
     event: DebouncedEvent,
-    paths: &HashMap<String, PathBuf>,
-    events_buffer: &'b FileEventsBuffer,
+    config: &Config,
+    events_buffer: &FileEventsBuffer,
 ) -> Option<SyncEvent> {
     match event {
         notify::DebouncedEvent::Create(file_path) => {
@@ -106,19 +157,21 @@ async fn map_to_sync_event<'b>(
                 return None;
             }
 
-            let (alias, root) = get_alias_for_path(&file_path, paths)?;
+            let (alias, root) = get_alias_for_path(&file_path, config)?;
             let metadata = file_path.metadata().ok()?;
+            if let Err(err) = config.check_file_size(&alias, metadata.len()) {
+                log::debug!("ignoring change to {:?}: {}", file_path, err);
+                return None;
+            }
             let relative_path = file_path.strip_prefix(&root).ok()?;
+            if is_left_out(config, &alias, &file_path, relative_path) {
+                return None;
+            }
 
             let file = FileInfo::new(alias, relative_path.to_owned(), metadata);
-            events_buffer
-                .allowed_peers_for_event(&file)
-                .and_then(|peers| {
-                    Some(SyncEvent::BroadcastToAllPeers(
-                        FileAction::Create(file),
-                        peers.to_vec(),
-                    ))
-                })
+            events_buffer.allowed_peers_for_event(&file).map(|peers| {
+                SyncEvent::BroadcastToAllPeers(FileAction::Create(file), peers.to_vec())
+            })
         }
 
         notify::DebouncedEvent::Write(file_path) => {
@@ -126,64 +179,84 @@ async fn map_to_sync_event<'b>(
                 return None;
             }
 
-            let (alias, root) = get_alias_for_path(&file_path, paths)?;
+            let (alias, root) = get_alias_for_path(&file_path, config)?;
             let metadata = file_path.metadata().ok()?;
+            if let Err(err) = config.check_file_size(&alias, metadata.len()) {
+                log::debug!("ignoring change to {:?}: {}", file_path, err);
+                return None;
+            }
             let relative_path = file_path.strip_prefix(&root).ok()?;
+            if is_left_out(config, &alias, &file_path, relative_path) {
+                return None;
+            }
 
             let file = FileInfo::new(alias, relative_path.to_owned(), metadata);
-            DeletionTracker::new(&root).remove_entry(&file.path).await;
+            DeletionTracker::new(&root)
+                .remove_entry(&file.path)
+                .await
+                .ok();
 
-            events_buffer
-                .allowed_peers_for_event(&file)
-                .and_then(|peers| {
-                    Some(SyncEvent::BroadcastToAllPeers(
-                        FileAction::Update(file),
-                        peers.to_vec(),
-                    ))
-                })
+            events_buffer.allowed_peers_for_event(&file).map(|peers| {
+                SyncEvent::BroadcastToAllPeers(FileAction::Update(file), peers.to_vec())
+            })
         }
         notify::DebouncedEvent::Remove(file_path) => {
             if crate::fs::is_special_file(&file_path) {
                 return None;
             }
 
-            let (alias, root) = get_alias_for_path(&file_path, paths)?;
+            let (alias, root) = get_alias_for_path(&file_path, config)?;
             let relative_path = file_path.strip_prefix(&root).ok()?;
+            if config.ignores_hidden(&alias, relative_path)
+                || config.exceeds_max_depth(&alias, relative_path)
+                || !config.is_included(&alias, relative_path)
+            {
+                return None;
+            }
 
             let file = FileInfo::new_deleted(alias, relative_path.to_owned(), None);
-            DeletionTracker::new(&root).add_entry(&file.path).await;
+            DeletionTracker::new(&root).add_entry(&file.path).await.ok();
+            // the deletion is still tracked, so the file is not received again
+            if !config.delete_propagation(&file.alias).outgoing() {
+                log::debug!("not sending deletion of {:?}", file.path);
+                return None;
+            }
 
-            events_buffer
-                .allowed_peers_for_event(&file)
-                .and_then(|peers| {
-                    Some(SyncEvent::BroadcastToAllPeers(
-                        FileAction::Remove(file),
-                        peers.to_vec(),
-                    ))
-                })
+            events_buffer.allowed_peers_for_event(&file).map(|peers| {
+                SyncEvent::BroadcastToAllPeers(FileAction::Remove(file), peers.to_vec())
+            })
         }
         notify::DebouncedEvent::Rename(src_path, dest_path) => {
             if crate::fs::is_special_file(&src_path) || crate::fs::is_special_file(&dest_path) {
                 return None;
             }
 
-            let (alias, root) = get_alias_for_path(&src_path, paths)?;
+            let (alias, root) = get_alias_for_path(&src_path, config)?;
             let relative_path = src_path.strip_prefix(&root).ok()?;
             let src_file = FileInfo::new_deleted(alias.clone(), relative_path.to_owned(), None);
-            DeletionTracker::new(&root).add_entry(&src_file.path).await;
+            DeletionTracker::new(&root)
+                .add_entry(&src_file.path)
+                .await
+                .ok();
 
             let metadata = dest_path.metadata().ok();
             let relative_path = dest_path.strip_prefix(&root).ok()?;
             let dest_file = FileInfo::new(alias, relative_path.to_owned(), metadata?);
 
+            // files renamed in or out of the included files are sent as created or deleted
+            let action = match (
+                config.is_included(&src_file.alias, &src_file.path),
+                config.is_included(&dest_file.alias, &dest_file.path),
+            ) {
+                (true, true) => FileAction::Move(src_file, dest_file),
+                (true, false) => FileAction::Remove(src_file),
+                (false, true) => FileAction::Create(dest_file),
+                (false, false) => return None,
+            };
+
             events_buffer
-                .allowed_peers_for_event(&src_file)
-                .and_then(|peers| {
-                    Some(SyncEvent::BroadcastToAllPeers(
-                        FileAction::Move(src_file, dest_file),
-                        peers.to_vec(),
-                    ))
-                })
+                .allowed_peers_for_event(action.file())
+                .map(|peers| SyncEvent::BroadcastToAllPeers(action, peers.to_vec()))
         }
         _ => None,
     }
@@ -0,0 +1,329 @@
+//! Commands executed when aliases and files are synchronized
+//!
+//! The commands run with `sh -c` (`cmd /C` on Windows) in the alias folder. For the alias hooks, the changes made by the
+//! synchronization are written to the command stdin as JSON, the alias, peer and number of changes are also available as
+//! environment variables. File hooks run in the background, after the file is received, limited by
+//! [crate::config::Config::max_concurrent_file_hooks]
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command, sync::Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use super::FileAction;
+use crate::{
+    config::{Config, SyncHooks},
+    events::SyncObserver,
+    fs::FileInfo,
+    pattern::Pattern,
+    IronCarrierError,
+};
+
+/// Changes made while synchronizing an alias with a peer, sent to the hook commands
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct SyncSummary {
+    alias: String,
+    peer: String,
+    /// Files sent to the peer
+    sent: Vec<PathBuf>,
+    /// Files received from the peer
+    received: Vec<PathBuf>,
+    /// Local files deleted because they were deleted in the peer
+    deleted: Vec<PathBuf>,
+    /// Files deleted in the peer because they were deleted locally
+    removed: Vec<PathBuf>,
+}
+
+impl SyncSummary {
+    pub fn new(alias: &str, peer: &str) -> Self {
+        Self {
+            alias: alias.to_string(),
+            peer: peer.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, action: &FileAction) {
+        match action {
+            FileAction::Create(file) | FileAction::Update(file) => {
+                self.sent.push(file.path.clone())
+            }
+            FileAction::Request(file) => self.received.push(file.path.clone()),
+            FileAction::Remove(file) => self.removed.push(file.path.clone()),
+            FileAction::Move(..) => {}
+        }
+    }
+
+    pub fn record_deleted(&mut self, path: &Path) {
+        self.deleted.push(path.to_owned());
+    }
+
+    /// Removes the files in `skipped` from the summary, they weren't changed after all
+    pub fn forget<'a>(&mut self, skipped: impl Iterator<Item = &'a Path>) {
+        let skipped: HashSet<&Path> = skipped.collect();
+        if skipped.is_empty() {
+            return;
+        }
+
+        for paths in [
+            &mut self.sent,
+            &mut self.received,
+            &mut self.deleted,
+            &mut self.removed,
+        ] {
+            paths.retain(|path| !skipped.contains(path.as_path()));
+        }
+    }
+}
+
+/// Stage of the synchronization a hook runs at
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum HookStage {
+    PreSync,
+    PostSync,
+}
+
+impl HookStage {
+    fn name(self) -> &'static str {
+        match self {
+            HookStage::PreSync => "pre_sync",
+            HookStage::PostSync => "post_sync",
+        }
+    }
+
+    fn command(self, hooks: &SyncHooks) -> Option<&str> {
+        match self {
+            HookStage::PreSync => hooks.pre_sync.as_deref(),
+            HookStage::PostSync => hooks.post_sync.as_deref(),
+        }
+    }
+}
+
+/// Runs the `stage` command of `hooks`, if there is one, inside `root`
+///
+/// Fails when the command can't be started or exits with an error
+pub(crate) async fn run_hook(
+    hooks: Option<&SyncHooks>,
+    stage: HookStage,
+    root: &Path,
+    summary: &SyncSummary,
+) -> crate::Result<()> {
+    let command = match hooks.and_then(|hooks| stage.command(hooks)) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    log::debug!("running {} hook for alias {}", stage.name(), summary.alias);
+
+    let mut child = shell(command)
+        .current_dir(root)
+        .env("IRON_CARRIER_HOOK", stage.name())
+        .env("IRON_CARRIER_ALIAS", &summary.alias)
+        .env("IRON_CARRIER_PEER", &summary.peer)
+        .env("IRON_CARRIER_FILES_SENT", summary.sent.len().to_string())
+        .env(
+            "IRON_CARRIER_FILES_RECEIVED",
+            summary.received.len().to_string(),
+        )
+        .env(
+            "IRON_CARRIER_FILES_DELETED",
+            summary.deleted.len().to_string(),
+        )
+        .env(
+            "IRON_CARRIER_FILES_REMOVED",
+            summary.removed.len().to_string(),
+        )
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // commands that don't read the summary close stdin early, that is not an error
+        let _ = stdin.write_all(&serde_json::to_vec(summary)?).await;
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(IronCarrierError::HookFailed(format!(
+            "{} hook for alias {} exited with {}",
+            stage.name(),
+            summary.alias,
+            status
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Runs the [crate::config::Config::file_hooks] for the files received from the peers
+pub(crate) struct FileHooks {
+    config: Arc<Config>,
+    patterns: Vec<Pattern>,
+    /// Limits the commands running at the same time
+    slots: Arc<Semaphore>,
+    cancel: CancellationToken,
+}
+
+impl FileHooks {
+    pub fn new(config: Arc<Config>, cancel: CancellationToken) -> Self {
+        Self {
+            patterns: config
+                .file_hooks
+                .iter()
+                .map(|hook| Pattern::new(&hook.pattern))
+                .collect(),
+            slots: Arc::new(Semaphore::new(config.max_concurrent_file_hooks)),
+            config,
+            cancel,
+        }
+    }
+
+    /// Runs `command` for `file` when a slot is available, failures are only logged
+    fn spawn(&self, command: String, file: &FileInfo) {
+        let root = self.config.paths[&file.alias].clone();
+        let absolute_path = match file.get_absolute_path(&self.config) {
+            Ok(path) => path,
+            Err(err) => {
+                log::error!("can't run file hook for {:?}: {}", file.path, err);
+                return;
+            }
+        };
+        let mut child = shell(&command);
+        child
+            .current_dir(root)
+            .env("IRON_CARRIER_ALIAS", &file.alias)
+            .env("IRON_CARRIER_FILE", &file.path)
+            .env("IRON_CARRIER_FILE_PATH", absolute_path)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        let slots = self.slots.clone();
+        let cancel = self.cancel.clone();
+        let path = file.path.clone();
+        tokio::spawn(async move {
+            let _slot = tokio::select! {
+                slot = slots.acquire_owned() => slot,
+                _ = cancel.cancelled() => return,
+            };
+
+            let status = tokio::select! {
+                status = async { child.spawn()?.wait().await } => status,
+                _ = cancel.cancelled() => return,
+            };
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => log::error!("file hook for {:?} exited with {}", path, status),
+                Err(err) => log::error!("can't run file hook for {:?}: {}", path, err),
+            }
+        });
+    }
+}
+
+impl SyncObserver for FileHooks {
+    fn on_file_written(&self, file: &FileInfo) {
+        for (hook, pattern) in self.config.file_hooks.iter().zip(&self.patterns) {
+            if hook.alias.as_ref().is_none_or(|alias| *alias == file.alias)
+                && pattern.matches(&file.path)
+            {
+                self.spawn(hook.command.clone(), file);
+            }
+        }
+    }
+}
+
+/// Returns the command that runs `command` in the shell
+#[cfg(unix)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Returns the command that runs `command` in the shell
+#[cfg(windows)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::fs::FileInfo;
+
+    #[tokio::test]
+    async fn hooks_receive_the_summary() -> crate::Result<()> {
+        let root = Path::new("./tmp/hooks");
+        std::fs::create_dir_all(root)?;
+
+        let hooks = SyncHooks {
+            pre_sync: Some("exit 3".to_string()),
+            post_sync: Some("cat > summary.json; echo $IRON_CARRIER_FILES_SENT > sent".to_string()),
+        };
+
+        let mut summary = SyncSummary::new("a", "peer");
+        let file = |path: &str| FileInfo::new_deleted("a".to_string(), path.into(), None);
+        summary.record(&FileAction::Create(file("created")));
+        summary.record(&FileAction::Update(file("failed")));
+        summary.record(&FileAction::Request(file("requested")));
+        summary.forget(std::iter::once(Path::new("failed")));
+
+        assert!(run_hook(Some(&hooks), HookStage::PreSync, root, &summary)
+            .await
+            .is_err());
+        run_hook(Some(&hooks), HookStage::PostSync, root, &summary).await?;
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(root.join("summary.json"))?)?;
+        assert_eq!(written["sent"], serde_json::json!(["created"]));
+        assert_eq!(written["received"], serde_json::json!(["requested"]));
+        assert_eq!(std::fs::read_to_string(root.join("sent"))?.trim(), "1");
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_hooks_run_for_matching_files() -> crate::Result<()> {
+        let root = Path::new("./tmp/file_hooks");
+        std::fs::create_dir_all(root)?;
+        let config = Arc::new(Config::parse_content(
+            "max_concurrent_file_hooks = 1
+            [paths]
+            a = \"./tmp/file_hooks\"
+
+            [[file_hooks]]
+            pattern = \"*.txt\"
+            command = \"echo $IRON_CARRIER_FILE >> received\""
+                .to_string(),
+        )?);
+
+        let hooks = FileHooks::new(config, CancellationToken::new());
+        let file = |path: &str| FileInfo::new_deleted("a".to_string(), path.into(), None);
+        hooks.on_file_written(&file("notes.txt"));
+        hooks.on_file_written(&file("image.png"));
+
+        // hooks run in the background, wait for the command to start
+        let received = root.join("received");
+        for _ in 0..50 {
+            if received.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        // the slot is released once the command finished writing
+        let _slot = hooks.slots.acquire().await?;
+        assert_eq!(std::fs::read_to_string(received)?, "notes.txt\n");
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! Transfers running with the peers
+//!
+//! Each file sent or received on its own is listed while it runs, with the bytes transferred so far, so front-ends can
+//! show what is going on and cancel a single transfer. Small files sent in batches, packs and atomic groups are not listed
+//!
+//! Cancelling a transfer interrupts the synchronization with its peer, the connection can't be used once a file is left
+//! half sent. The file is then either requeued, it goes after the other transfers in the next synchronization with the
+//! peer, or skipped, it is left out of the synchronizations with the peer until the node restarts
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use tokio_util::sync::CancellationToken;
+
+use super::FileAction;
+
+/// Direction of an [InFlightTransfer]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    /// The file is sent to the peer
+    Send,
+    /// The file is received from the peer
+    Receive,
+}
+
+/// A file being sent to or received from a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlightTransfer {
+    /// Identifies the transfer while it runs, see [crate::IronCarrier::cancel_transfer]
+    pub id: u64,
+    /// Alias of the file
+    pub alias: String,
+    /// Path of the file, relative to the alias root
+    pub path: PathBuf,
+    /// Name of the peer, see [crate::config::Config::peer_name]
+    pub peer: String,
+    /// Whether the file is sent or received
+    pub direction: TransferDirection,
+    /// Bytes of content transferred so far
+    pub bytes_done: u64,
+    /// Size of the file, in bytes
+    pub size: u64,
+    /// Average rate since the transfer started, in bytes per second
+    pub bytes_per_second: u64,
+}
+
+/// What happens to a file after its transfer is cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AfterCancel {
+    /// The file goes after the other transfers in the next synchronization with the peer
+    Requeue,
+    /// The file is left out of the synchronizations with the peer until the node restarts
+    Skip,
+}
+
+struct Running {
+    transfer: InFlightTransfer,
+    peer_address: String,
+    started_at: Instant,
+    bytes_done: Arc<AtomicU64>,
+    cancel: CancellationToken,
+}
+
+/// Files cancelled with a peer, by peer address, alias and path
+type Cancelled = HashMap<(String, String, PathBuf), AfterCancel>;
+
+/// Keeps the transfers running with every peer
+#[derive(Default)]
+pub(crate) struct InFlightTransfers {
+    next_id: AtomicU64,
+    running: Mutex<HashMap<u64, Running>>,
+    cancelled: Mutex<Cancelled>,
+}
+
+impl InFlightTransfers {
+    /// Lists the transfer of `action` with `peer_address` until the returned guard is dropped
+    /// Returns [None] for actions that don't transfer content, like moves and deletions
+    pub fn start(
+        &self,
+        peer_address: &str,
+        peer_name: &str,
+        action: &FileAction,
+    ) -> Option<TransferGuard<'_>> {
+        let (file, direction) = match action {
+            FileAction::Create(file) | FileAction::Update(file) => (file, TransferDirection::Send),
+            FileAction::Request(file) => (file, TransferDirection::Receive),
+            FileAction::Move(..) | FileAction::Remove(_) => return None,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_done = Arc::new(AtomicU64::new(0));
+        let cancel = CancellationToken::new();
+        self.running.lock().unwrap().insert(
+            id,
+            Running {
+                transfer: InFlightTransfer {
+                    id,
+                    alias: file.alias.clone(),
+                    path: file.path.clone(),
+                    peer: peer_name.to_owned(),
+                    direction,
+                    bytes_done: 0,
+                    size: file.content_size(),
+                    bytes_per_second: 0,
+                },
+                peer_address: peer_address.to_owned(),
+                started_at: Instant::now(),
+                bytes_done: bytes_done.clone(),
+                cancel: cancel.clone(),
+            },
+        );
+
+        Some(TransferGuard {
+            transfers: self,
+            id,
+            bytes_done,
+            cancel,
+        })
+    }
+
+    /// Returns the transfers running now, sorted by id
+    pub fn list(&self) -> Vec<InFlightTransfer> {
+        let mut transfers: Vec<InFlightTransfer> = self
+            .running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|running| {
+                let bytes_done = running.bytes_done.load(Ordering::Relaxed);
+                let elapsed = running.started_at.elapsed().as_secs_f64();
+                InFlightTransfer {
+                    bytes_done,
+                    bytes_per_second: if elapsed > 0.0 {
+                        (bytes_done as f64 / elapsed) as u64
+                    } else {
+                        0
+                    },
+                    ..running.transfer.clone()
+                }
+            })
+            .collect();
+        transfers.sort_by_key(|transfer| transfer.id);
+        transfers
+    }
+
+    /// Cancels the transfer `id`, the file is handled according to `after`
+    /// Returns false if there is no such transfer, like when it is already done
+    pub fn cancel(&self, id: u64, after: AfterCancel) -> bool {
+        let running = self.running.lock().unwrap();
+        let running = match running.get(&id) {
+            Some(running) => running,
+            None => return false,
+        };
+
+        log::info!(
+            "transfer of {:?} with {} cancelled, {:?}",
+            running.transfer.path,
+            running.transfer.peer,
+            after
+        );
+        self.cancelled.lock().unwrap().insert(
+            (
+                running.peer_address.clone(),
+                running.transfer.alias.clone(),
+                running.transfer.path.clone(),
+            ),
+            after,
+        );
+        running.cancel.cancel();
+
+        true
+    }
+
+    /// Returns what to do with `path`, when its transfer with `peer_address` was cancelled
+    /// A requeued file is only returned once, it is transferred normally after that
+    pub fn after_cancel(
+        &self,
+        peer_address: &str,
+        alias: &str,
+        path: &Path,
+    ) -> Option<AfterCancel> {
+        let mut cancelled = self.cancelled.lock().unwrap();
+        let key = (peer_address.to_owned(), alias.to_owned(), path.to_owned());
+        let after = *cancelled.get(&key)?;
+        if after == AfterCancel::Requeue {
+            cancelled.remove(&key);
+        }
+
+        Some(after)
+    }
+}
+
+/// A transfer listed in [InFlightTransfers], it is removed from the list when the guard is dropped
+pub(crate) struct TransferGuard<'a> {
+    transfers: &'a InFlightTransfers,
+    id: u64,
+    bytes_done: Arc<AtomicU64>,
+    cancel: CancellationToken,
+}
+
+impl TransferGuard<'_> {
+    /// Counter of the bytes transferred, updated by the file streamers
+    pub fn progress(&self) -> Arc<AtomicU64> {
+        self.bytes_done.clone()
+    }
+
+    /// Completes when the transfer is cancelled
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+}
+
+impl Drop for TransferGuard<'_> {
+    fn drop(&mut self) {
+        self.transfers.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FileInfo;
+
+    fn file(path: &str) -> FileInfo {
+        let mut file = FileInfo::new(
+            "a".into(),
+            path.into(),
+            std::path::Path::new("./Cargo.toml").metadata().unwrap(),
+        );
+        file.size = Some(100);
+        file
+    }
+
+    #[tokio::test]
+    async fn transfers_are_listed_and_cancelled() {
+        let transfers = InFlightTransfers::default();
+        assert!(transfers
+            .start("peer:8090", "peer", &FileAction::Remove(file("removed")))
+            .is_none());
+
+        let sent = transfers
+            .start("peer:8090", "peer", &FileAction::Update(file("sent")))
+            .unwrap();
+        let received = transfers
+            .start("peer:8090", "peer", &FileAction::Request(file("received")))
+            .unwrap();
+        received.progress().fetch_add(40, Ordering::Relaxed);
+
+        let listed = transfers.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].direction, TransferDirection::Send);
+        assert_eq!(listed[1].path, PathBuf::from("received"));
+        assert_eq!((listed[1].bytes_done, listed[1].size), (40, 100));
+
+        assert!(transfers.cancel(listed[0].id, AfterCancel::Requeue));
+        assert!(transfers.cancel(listed[1].id, AfterCancel::Skip));
+        sent.cancelled().await;
+        received.cancelled().await;
+        drop((sent, received));
+        assert!(transfers.list().is_empty());
+        assert!(!transfers.cancel(listed[0].id, AfterCancel::Skip));
+
+        let sent = PathBuf::from("sent");
+        assert_eq!(
+            transfers.after_cancel("peer:8090", "a", &sent),
+            Some(AfterCancel::Requeue)
+        );
+        assert_eq!(transfers.after_cancel("peer:8090", "a", &sent), None);
+        let received = PathBuf::from("received");
+        for _ in 0..2 {
+            assert_eq!(
+                transfers.after_cancel("peer:8090", "a", &received),
+                Some(AfterCancel::Skip)
+            );
+        }
+        assert_eq!(transfers.after_cancel("other:8090", "a", &received), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! Peers learned from the introducers, see [crate::config::Config::introducers]
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Addresses shared by the introducers, they are synchronized like the configured peers once approved
+#[derive(Debug, Default)]
+pub(crate) struct IntroducedPeers {
+    /// Address of each learned peer, with true when it was approved
+    peers: Mutex<BTreeMap<String, bool>>,
+}
+
+impl IntroducedPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `address`, returns true if it wasn't known before
+    pub fn learn(&self, address: &str, approved: bool) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.contains_key(address) {
+            return false;
+        }
+
+        peers.insert(address.to_owned(), approved);
+        true
+    }
+
+    /// Approves `address`, returns false if it was never learned
+    pub fn approve(&self, address: &str) -> bool {
+        match self.peers.lock().unwrap().get_mut(address) {
+            Some(approved) => {
+                *approved = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the addresses of the approved peers
+    pub fn approved(&self) -> Vec<String> {
+        self.filter(true)
+    }
+
+    /// Returns the addresses of the peers waiting for approval
+    pub fn pending(&self) -> Vec<String> {
+        self.filter(false)
+    }
+
+    fn filter(&self, approved: bool) -> Vec<String> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, is_approved)| **is_approved == approved)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_wait_for_approval() {
+        let peers = IntroducedPeers::new();
+        assert!(peers.learn("10.0.0.2:8090", false));
+        assert!(peers.learn("10.0.0.3:8090", true));
+        assert!(!peers.learn("10.0.0.2:8090", true));
+
+        assert_eq!(peers.pending(), vec!["10.0.0.2:8090".to_string()]);
+        assert_eq!(peers.approved(), vec!["10.0.0.3:8090".to_string()]);
+
+        assert!(peers.approve("10.0.0.2:8090"));
+        assert!(!peers.approve("10.0.0.4:8090"));
+        assert!(peers.pending().is_empty());
+    }
+}
@@ -0,0 +1,461 @@
+//! One way mirroring of aliases to remote storage
+//!
+//! The mirror keeps a manifest with the files pushed to it, including tombstones for deleted files.
+//! On each push, the local file list is compared with the manifest and only the differences are sent
+
+mod s3;
+mod sftp;
+mod webdav;
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{Config, MirrorConfig},
+    fs::{self, FileInfo},
+    manifest::portable_path,
+    skipped_files::SkippedFiles,
+    sync::pause_switch::PauseSwitch,
+    IronCarrierError,
+};
+
+/// Key of the manifest object, it can't collide with an alias file since files ending with `ironcarrier` are never synchronized
+const MANIFEST_KEY: &str = ".manifest.ironcarrier";
+
+/// Remote storage addressed by keys, keys are relative paths using `/` as separator
+pub(crate) trait MirrorBackend {
+    /// Returns the content of the object at `key`, [None] if it doesn't exist
+    async fn get(&self, key: &str) -> crate::Result<Option<Vec<u8>>>;
+    /// Writes `content` to the object at `key`
+    async fn put(&self, key: &str, content: Vec<u8>) -> crate::Result<()>;
+    /// Writes the content of the local file at `path` to the object at `key`, returning the object ETag if the backend provides one
+    async fn upload_file(&self, key: &str, path: &Path) -> crate::Result<Option<String>>;
+    /// Removes the object at `key`
+    async fn delete(&self, key: &str) -> crate::Result<()>;
+
+    /// Moves the object at `from` to `to`, returns false if the backend can't move objects
+    async fn rename(&self, _from: &str, _to: &str) -> crate::Result<bool> {
+        Ok(false)
+    }
+
+    /// Returns the current ETag of every object, [None] if the backend can't list them
+    async fn etags(&self) -> crate::Result<Option<HashMap<String, String>>> {
+        Ok(None)
+    }
+}
+
+/// A file as it was pushed to the mirror
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MirroredFile {
+    file: FileInfo,
+    etag: Option<String>,
+}
+
+impl MirroredFile {
+    fn is_live(&self) -> bool {
+        self.file.deleted_at.is_none()
+    }
+}
+
+/// Percent encodes a path segment, only unreserved characters are kept
+fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Turns an unsuccessful http response into an error
+async fn check_response(response: reqwest::Response) -> crate::Result<reqwest::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(IronCarrierError::MirrorError(format!("{} {}", status, body.trim())).into())
+}
+
+/// Returns the ETag header of an http response
+fn etag(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.to_owned())
+}
+
+/// Pushes every configured mirror and sftp peer, errors are logged and don't stop the other mirrors  
+/// Paused aliases are skipped, see [Config::paused_aliases]
+pub(crate) async fn push_mirrors(config: &Config, pause_switch: &PauseSwitch) {
+    for (alias, mirror) in &config.mirrors {
+        if pause_switch.is_alias_paused(alias) {
+            continue;
+        }
+
+        let root_path = &config.paths[alias];
+        let result = match mirror {
+            MirrorConfig::S3(s3_config) => {
+                push_alias(&s3::S3Mirror::new(s3_config), alias, root_path, config).await
+            }
+            MirrorConfig::WebDav(webdav_config) => {
+                push_alias(
+                    &webdav::WebDavMirror::new(webdav_config),
+                    alias,
+                    root_path,
+                    config,
+                )
+                .await
+            }
+        };
+
+        if let Err(err) = result {
+            log::error!("failed to push alias {} to mirror: {}", alias, err);
+        }
+    }
+
+    for sftp_peer in &config.sftp_peers {
+        for (alias, root_path) in &config.paths {
+            if pause_switch.is_alias_paused(alias) {
+                continue;
+            }
+
+            let mirror = sftp::SftpMirror::new(sftp_peer, alias);
+            if let Err(err) = push_alias(&mirror, alias, root_path, config).await {
+                log::error!(
+                    "failed to push alias {} to sftp peer {}: {}",
+                    alias,
+                    sftp_peer.destination,
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Pushes the local changes of `alias` to `backend`
+///
+/// The manifest is written even when the push fails halfway, so the files already sent are not sent again
+pub(crate) async fn push_alias<B: MirrorBackend>(
+    backend: &B,
+    alias: &str,
+    root_path: &Path,
+    config: &Config,
+) -> crate::Result<()> {
+    let mut manifest: HashMap<PathBuf, MirroredFile> = match backend.get(MANIFEST_KEY).await? {
+        Some(content) => bincode::deserialize::<Vec<MirroredFile>>(&content)?
+            .into_iter()
+            .map(|mirrored| (mirrored.file.path.clone(), mirrored))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    if let Some(etags) = backend.etags().await? {
+        forget_changed_files(&mut manifest, &etags);
+    }
+
+    log::debug!("pushing alias {} to mirror", alias);
+    let local_files = fs::walk_path(root_path, alias, config).await?;
+    let mut skipped = SkippedFiles::new();
+    let result = push_files(backend, root_path, local_files, &mut manifest, &mut skipped).await;
+
+    let manifest: Vec<MirroredFile> = manifest.into_values().collect();
+    backend
+        .put(MANIFEST_KEY, bincode::serialize(&manifest)?)
+        .await?;
+
+    skipped.log_summary(&format!("pushing alias {} to mirror", alias));
+    result
+}
+
+/// Removes from the manifest the files changed or removed directly in the mirror, so they are sent again
+fn forget_changed_files(
+    manifest: &mut HashMap<PathBuf, MirroredFile>,
+    etags: &HashMap<String, String>,
+) {
+    manifest.retain(|path, mirrored| {
+        if !mirrored.is_live() {
+            return true;
+        }
+
+        let key = match portable_path(path) {
+            Some(key) => key,
+            None => return true,
+        };
+
+        match (etags.get(&key), &mirrored.etag) {
+            (None, _) => {
+                log::debug!("{} was removed from the mirror", key);
+                false
+            }
+            (Some(current), Some(pushed)) if current != pushed => {
+                log::debug!("{} was changed in the mirror", key);
+                false
+            }
+            _ => true,
+        }
+    });
+}
+
+async fn push_files<B: MirrorBackend>(
+    backend: &B,
+    root_path: &Path,
+    local_files: Vec<FileInfo>,
+    manifest: &mut HashMap<PathBuf, MirroredFile>,
+    skipped: &mut SkippedFiles,
+) -> crate::Result<()> {
+    let mut pushed = 0;
+    let mut local_paths = HashSet::with_capacity(local_files.len());
+    let mut to_upload = Vec::new();
+    let mut to_delete = HashMap::new();
+
+    for local_file in local_files {
+        local_paths.insert(local_file.path.clone());
+        if portable_path(&local_file.path).is_none() {
+            skipped.add(&local_file.path, "path is not valid unicode");
+            continue;
+        }
+
+        let mirrored = manifest
+            .get(&local_file.path)
+            .filter(|mirrored| mirrored.is_live());
+
+        if local_file.deleted_at.is_some() {
+            if mirrored.is_some() {
+                to_delete.insert(local_file.path.clone(), local_file);
+            }
+            continue;
+        }
+
+        let is_unchanged = mirrored
+            .map(|mirrored| {
+                mirrored.file.modified_at == local_file.modified_at
+                    && mirrored.file.size == local_file.size
+            })
+            .unwrap_or_default();
+        if !is_unchanged {
+            to_upload.push(local_file);
+        }
+    }
+
+    // files removed while the deletion wasn't tracked, like when the file watcher is disabled
+    for mirrored in manifest.values() {
+        if mirrored.is_live() && !local_paths.contains(&mirrored.file.path) {
+            to_delete.insert(
+                mirrored.file.path.clone(),
+                FileInfo::new_deleted(
+                    mirrored.file.alias.clone(),
+                    mirrored.file.path.clone(),
+                    None,
+                ),
+            );
+        }
+    }
+
+    for local_file in to_upload {
+        let key = portable_path(&local_file.path).unwrap_or_default();
+        let absolute_path = root_path.join(&local_file.path);
+        if let Err(err) = tokio::fs::File::open(&absolute_path).await {
+            skipped.add(&local_file.path, err);
+            continue;
+        }
+
+        // a new file with the same size and modification time of a removed file was moved
+        let moved_from = to_delete
+            .keys()
+            .find(|path| {
+                manifest.get(*path).is_some_and(|mirrored| {
+                    mirrored.is_live()
+                        && mirrored.file.size == local_file.size
+                        && mirrored.file.modified_at == local_file.modified_at
+                })
+            })
+            .cloned();
+
+        if let Some(from) = moved_from {
+            let from_key = portable_path(&from).unwrap_or_default();
+            if backend.rename(&from_key, &key).await? {
+                log::debug!("moved {} to {} in mirror", from_key, key);
+                let tombstone = to_delete.remove(&from).unwrap();
+                let etag = manifest
+                    .get(&from)
+                    .and_then(|mirrored| mirrored.etag.clone());
+                manifest.insert(
+                    from,
+                    MirroredFile {
+                        file: tombstone,
+                        etag: None,
+                    },
+                );
+                manifest.insert(
+                    local_file.path.clone(),
+                    MirroredFile {
+                        file: local_file,
+                        etag,
+                    },
+                );
+                pushed += 1;
+                continue;
+            }
+        }
+
+        log::debug!("sending {} to mirror", key);
+        let etag = backend.upload_file(&key, &absolute_path).await?;
+        manifest.insert(
+            local_file.path.clone(),
+            MirroredFile {
+                file: local_file,
+                etag,
+            },
+        );
+        pushed += 1;
+    }
+
+    for (path, tombstone) in to_delete {
+        let key = portable_path(&path).unwrap_or_default();
+        log::debug!("removing {} from mirror", key);
+        backend.delete(&key).await?;
+        manifest.insert(
+            path,
+            MirroredFile {
+                file: tombstone,
+                etag: None,
+            },
+        );
+        pushed += 1;
+    }
+
+    log::info!("{} changes pushed to mirror", pushed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn config_for(root_path: &Path) -> Config {
+        Config::parse_content(format!("[paths]\na = {:?}", root_path)).unwrap()
+    }
+
+    /// Keeps the objects in memory, the ETag is the content length
+    #[derive(Default)]
+    struct MemoryMirror {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MirrorBackend for MemoryMirror {
+        async fn get(&self, key: &str) -> crate::Result<Option<Vec<u8>>> {
+            Ok(self.objects.lock().unwrap().get(key).cloned())
+        }
+
+        async fn put(&self, key: &str, content: Vec<u8>) -> crate::Result<()> {
+            self.objects.lock().unwrap().insert(key.to_owned(), content);
+            Ok(())
+        }
+
+        async fn upload_file(&self, key: &str, path: &Path) -> crate::Result<Option<String>> {
+            let content = tokio::fs::read(path).await?;
+            let etag = content.len().to_string();
+            self.put(key, content).await?;
+            Ok(Some(etag))
+        }
+
+        async fn delete(&self, key: &str) -> crate::Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> crate::Result<bool> {
+            let mut objects = self.objects.lock().unwrap();
+            let content = objects.remove(from).unwrap();
+            objects.insert(to.to_owned(), content);
+            Ok(true)
+        }
+
+        async fn etags(&self) -> crate::Result<Option<HashMap<String, String>>> {
+            Ok(Some(
+                self.objects
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, content)| (key.clone(), content.len().to_string()))
+                    .collect(),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn can_push_alias() -> crate::Result<()> {
+        let root_path = Path::new("./tmp/mirror/push_alias");
+        tokio::fs::create_dir_all(root_path.join("folder")).await?;
+        tokio::fs::write(root_path.join("file_1"), "content").await?;
+        tokio::fs::write(root_path.join("folder/file_2"), "content 2").await?;
+
+        let config = config_for(root_path);
+        let mirror = MemoryMirror::default();
+        push_alias(&mirror, "a", root_path, &config).await?;
+        assert_eq!(
+            mirror.objects.lock().unwrap()["folder/file_2"],
+            b"content 2".to_vec()
+        );
+
+        tokio::fs::remove_file(root_path.join("file_1")).await?;
+        push_alias(&mirror, "a", root_path, &config).await?;
+
+        let manifest: Vec<MirroredFile> =
+            bincode::deserialize(&mirror.objects.lock().unwrap()[MANIFEST_KEY])?;
+        let tombstone = manifest
+            .iter()
+            .find(|mirrored| mirrored.file.path == Path::new("file_1"))
+            .unwrap();
+        assert!(!tombstone.is_live());
+        assert!(!mirror.objects.lock().unwrap().contains_key("file_1"));
+
+        tokio::fs::remove_dir_all(root_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_move_and_restore_changed_files() -> crate::Result<()> {
+        let root_path = Path::new("./tmp/mirror/move_files");
+        tokio::fs::create_dir_all(root_path).await?;
+        tokio::fs::write(root_path.join("file_1"), "content").await?;
+
+        let config = config_for(root_path);
+        let mirror = MemoryMirror::default();
+        push_alias(&mirror, "a", root_path, &config).await?;
+
+        tokio::fs::rename(root_path.join("file_1"), root_path.join("file_2")).await?;
+        push_alias(&mirror, "a", root_path, &config).await?;
+        assert!(!mirror.objects.lock().unwrap().contains_key("file_1"));
+        assert_eq!(
+            mirror.objects.lock().unwrap()["file_2"],
+            b"content".to_vec()
+        );
+
+        mirror
+            .objects
+            .lock()
+            .unwrap()
+            .insert("file_2".into(), b"changed in the mirror".to_vec());
+        push_alias(&mirror, "a", root_path, &config).await?;
+        assert_eq!(
+            mirror.objects.lock().unwrap()["file_2"],
+            b"content".to_vec()
+        );
+
+        tokio::fs::remove_dir_all(root_path).await?;
+
+        Ok(())
+    }
+}
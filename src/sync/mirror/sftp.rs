@@ -0,0 +1,167 @@
+//! SFTP mirror, using the system `sftp` command in batch mode
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use super::MirrorBackend;
+use crate::{config::SftpPeer, IronCarrierError};
+
+/// Used to give unique names to the local temp files
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) struct SftpMirror {
+    peer: SftpPeer,
+    root_path: String,
+}
+
+/// Quotes `arg` for the sftp batch file
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns true if the sftp error output reports a missing file
+fn is_not_found(stderr: &str) -> bool {
+    stderr.contains("not found") || stderr.contains("No such file")
+}
+
+fn temp_file_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "{}.{}.ironcarrier",
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+impl SftpMirror {
+    pub fn new(peer: &SftpPeer, alias: &str) -> Self {
+        SftpMirror {
+            peer: peer.clone(),
+            root_path: format!("{}/{}", peer.path.trim_end_matches('/'), alias),
+        }
+    }
+
+    fn remote_path(&self, key: &str) -> String {
+        format!("{}/{}", self.root_path, key)
+    }
+
+    /// Runs `commands` in a single sftp session, the session stops at the first failed command  
+    /// Returns the error output when a command fails
+    async fn run_batch(&self, commands: &[String]) -> crate::Result<Result<(), String>> {
+        let mut command = Command::new("sftp");
+        command.arg("-q").arg("-b").arg("-");
+        if let Some(port) = self.peer.port {
+            command.arg("-P").arg(port.to_string());
+        }
+
+        let mut child = command
+            .arg(&self.peer.destination)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(commands.join("\n").as_bytes()).await?;
+            stdin.write_all(b"\n").await?;
+        }
+
+        let output = child.wait_with_output().await?;
+        if output.status.success() {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(String::from_utf8_lossy(&output.stderr)
+                .trim()
+                .to_owned()))
+        }
+    }
+}
+
+impl MirrorBackend for SftpMirror {
+    async fn get(&self, key: &str) -> crate::Result<Option<Vec<u8>>> {
+        let temp_path = temp_file_path();
+        let result = self
+            .run_batch(&[format!(
+                "get {} {}",
+                quote(&self.remote_path(key)),
+                quote(&temp_path.to_string_lossy())
+            )])
+            .await?;
+
+        match result {
+            Ok(_) => {
+                let content = tokio::fs::read(&temp_path).await;
+                tokio::fs::remove_file(&temp_path).await.ok();
+                Ok(Some(content?))
+            }
+            Err(stderr) if is_not_found(&stderr) => Ok(None),
+            Err(stderr) => Err(IronCarrierError::MirrorError(stderr).into()),
+        }
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> crate::Result<()> {
+        let temp_path = temp_file_path();
+        tokio::fs::write(&temp_path, content).await?;
+
+        let result = self.upload_file(key, &temp_path).await;
+        tokio::fs::remove_file(&temp_path).await.ok();
+
+        result.map(|_| ())
+    }
+
+    async fn upload_file(&self, key: &str, path: &Path) -> crate::Result<Option<String>> {
+        // folders that already exist make mkdir fail, "-" tells sftp to ignore the failure
+        let mut folder = self.root_path.clone();
+        let mut commands = vec![format!("-mkdir {}", quote(&folder))];
+        if let Some((parents, _)) = key.rsplit_once('/') {
+            for segment in parents.split('/') {
+                folder = format!("{}/{}", folder, segment);
+                commands.push(format!("-mkdir {}", quote(&folder)));
+            }
+        }
+
+        commands.push(format!(
+            "put {} {}",
+            quote(&path.to_string_lossy()),
+            quote(&self.remote_path(key))
+        ));
+
+        match self.run_batch(&commands).await? {
+            Ok(_) => Ok(None),
+            Err(stderr) => Err(IronCarrierError::MirrorError(stderr).into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> crate::Result<()> {
+        match self
+            .run_batch(&[format!("rm {}", quote(&self.remote_path(key)))])
+            .await?
+        {
+            Err(stderr) if !is_not_found(&stderr) => {
+                Err(IronCarrierError::MirrorError(stderr).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_build_remote_paths() {
+        let peer = SftpPeer::parse("sftp://user@host/srv/files/").unwrap();
+        let mirror = SftpMirror::new(&peer, "a");
+
+        assert_eq!(
+            mirror.remote_path("folder/file"),
+            "/srv/files/a/folder/file"
+        );
+        assert_eq!(quote("my \"file\""), "\"my \\\"file\\\"\"");
+    }
+}
@@ -0,0 +1,302 @@
+//! WebDAV mirror (Nextcloud, ownCloud, Apache mod_dav, ...)
+//!
+//! Files changed or removed directly in the server are detected by their ETag and sent again
+
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use std::{collections::HashMap, path::Path};
+
+use super::{check_response, encode_segment, etag, MirrorBackend};
+use crate::{config::WebDavConfig, IronCarrierError};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:getetag/><d:resourcetype/></d:prop></d:propfind>"#;
+
+pub(crate) struct WebDavMirror {
+    client: Client,
+    config: WebDavConfig,
+}
+
+/// An entry of a PROPFIND response
+#[derive(Debug, PartialEq)]
+struct DavEntry {
+    href: String,
+    etag: Option<String>,
+    is_collection: bool,
+}
+
+/// Decodes the percent encoded characters of `value`
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Returns the first descendant of `node` with the DAV `name` tag
+fn dav_element<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.descendants()
+        .find(|child| child.has_tag_name(("DAV:", name)))
+}
+
+/// Parses a multistatus response, returning the entries with a successful status
+fn parse_multistatus(content: &str) -> crate::Result<Vec<DavEntry>> {
+    let document = roxmltree::Document::parse(content)
+        .map_err(|err| IronCarrierError::MirrorError(err.to_string()))?;
+    let entries = document
+        .descendants()
+        .filter(|node| node.has_tag_name(("DAV:", "response")))
+        .filter_map(|response| {
+            let href = dav_element(response, "href")?.text()?.trim().to_owned();
+            let propstat = response
+                .children()
+                .filter(|child| child.has_tag_name(("DAV:", "propstat")))
+                .find(|propstat| {
+                    dav_element(*propstat, "status")
+                        .and_then(|status| status.text())
+                        .is_some_and(|status| status.contains(" 200 "))
+                })?;
+
+            Some(DavEntry {
+                href,
+                etag: dav_element(propstat, "getetag")
+                    .and_then(|etag| etag.text())
+                    .map(|etag| etag.trim().to_owned()),
+                is_collection: dav_element(propstat, "collection").is_some(),
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+impl WebDavMirror {
+    pub fn new(config: &WebDavConfig) -> Self {
+        WebDavMirror {
+            client: Client::new(),
+            config: config.clone(),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        let segments: Vec<String> = key.split('/').map(encode_segment).collect();
+        format!(
+            "{}/{}",
+            self.config.url.trim_end_matches('/'),
+            segments.join("/")
+        )
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.config.username {
+            Some(username) => request.basic_auth(username, self.config.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Creates the collections needed to store `key`, including the mirror root
+    async fn create_parents(&self, key: &str) -> crate::Result<()> {
+        let mkcol = Method::from_bytes(b"MKCOL")?;
+        let mut collections = vec![format!("{}/", self.config.url.trim_end_matches('/'))];
+        if let Some((parents, _)) = key.rsplit_once('/') {
+            let mut parent = String::new();
+            for segment in parents.split('/') {
+                parent = if parent.is_empty() {
+                    segment.to_owned()
+                } else {
+                    format!("{}/{}", parent, segment)
+                };
+                collections.push(format!("{}/", self.url(&parent)));
+            }
+        }
+
+        for collection in collections {
+            let response = self.request(mkcol.clone(), &collection).send().await?;
+            // 405 means the collection already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check_response(response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the entries directly inside the collection at `url`
+    async fn propfind(&self, url: &str) -> crate::Result<Option<Vec<DavEntry>>> {
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND")?, url)
+            .header("depth", "1")
+            .header("content-type", "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let content = check_response(response).await?.text().await?;
+        Ok(Some(parse_multistatus(&content)?))
+    }
+}
+
+impl MirrorBackend for WebDavMirror {
+    async fn get(&self, key: &str) -> crate::Result<Option<Vec<u8>>> {
+        let response = self.request(Method::GET, &self.url(key)).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let content = check_response(response).await?.bytes().await?;
+        Ok(Some(content.to_vec()))
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> crate::Result<()> {
+        self.create_parents(key).await?;
+        let response = self
+            .request(Method::PUT, &self.url(key))
+            .body(content)
+            .send()
+            .await?;
+
+        check_response(response).await?;
+        Ok(())
+    }
+
+    async fn upload_file(&self, key: &str, path: &Path) -> crate::Result<Option<String>> {
+        self.create_parents(key).await?;
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        let response = self
+            .request(Method::PUT, &self.url(key))
+            .header("content-length", size)
+            .body(file)
+            .send()
+            .await?;
+
+        let response = check_response(response).await?;
+        Ok(etag(&response))
+    }
+
+    async fn delete(&self, key: &str) -> crate::Result<()> {
+        let response = self.request(Method::DELETE, &self.url(key)).send().await?;
+        if response.status() != StatusCode::NOT_FOUND {
+            check_response(response).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> crate::Result<bool> {
+        self.create_parents(to).await?;
+        let response = self
+            .request(Method::from_bytes(b"MOVE")?, &self.url(from))
+            .header("destination", self.url(to))
+            .header("overwrite", "T")
+            .send()
+            .await?;
+
+        check_response(response).await?;
+        Ok(true)
+    }
+
+    async fn etags(&self) -> crate::Result<Option<HashMap<String, String>>> {
+        let root_url = Url::parse(&format!("{}/", self.config.url.trim_end_matches('/')))?;
+        let root_path = percent_decode(root_url.path());
+
+        let mut etags = HashMap::new();
+        let mut collections = vec![root_url.to_string()];
+        while let Some(collection) = collections.pop() {
+            let entries = match self.propfind(&collection).await? {
+                Some(entries) => entries,
+                None => continue,
+            };
+
+            for entry in entries {
+                let url = root_url.join(&entry.href)?;
+                let path = percent_decode(url.path());
+                let key = match path.strip_prefix(&root_path) {
+                    Some(key) => key.trim_matches('/').to_owned(),
+                    None => continue,
+                };
+
+                if key.is_empty() || url.as_str() == collection {
+                    continue;
+                }
+
+                if entry.is_collection {
+                    collections.push(url.to_string());
+                } else if let Some(etag) = entry.etag {
+                    etags.insert(key, etag);
+                }
+            }
+        }
+
+        Ok(Some(etags))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_propfind_responses() -> crate::Result<()> {
+        let content = r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:">
+                <d:response>
+                    <d:href>/dav/backup/</d:href>
+                    <d:propstat>
+                        <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+                        <d:status>HTTP/1.1 200 OK</d:status>
+                    </d:propstat>
+                </d:response>
+                <d:response>
+                    <d:href>/dav/backup/my%20file</d:href>
+                    <d:propstat>
+                        <d:prop><d:getetag>"abc"</d:getetag><d:resourcetype/></d:prop>
+                        <d:status>HTTP/1.1 200 OK</d:status>
+                    </d:propstat>
+                </d:response>
+            </d:multistatus>"#;
+
+        let entries = parse_multistatus(content)?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_collection);
+        assert_eq!(
+            entries[1],
+            DavEntry {
+                href: "/dav/backup/my%20file".into(),
+                etag: Some("\"abc\"".into()),
+                is_collection: false
+            }
+        );
+        assert_eq!(percent_decode(&entries[1].href), "/dav/backup/my file");
+
+        Ok(())
+    }
+}
@@ -1,19 +1,32 @@
 //! Handle synchronization
 
+pub(crate) mod alias_locks;
 pub(crate) mod file_events_buffer;
 mod file_watcher;
+pub(crate) mod hooks;
+pub(crate) mod in_flight;
+pub(crate) mod introduced_peers;
+mod mirror;
+pub(crate) mod pause_switch;
+pub(crate) mod sync_state;
+/// Synchronization orchestration
 pub mod synchronizer;
+pub(crate) mod transfer_queue;
 
 use crate::fs::FileInfo;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::Notify;
 
+pub use in_flight::{InFlightTransfer, TransferDirection};
+pub use sync_state::{AliasSyncState, SyncPhase, SyncState, TransferProgress};
 pub use synchronizer::Synchronizer;
 
 type PeerAddress = String;
 
 /// Synchronization Event Types
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum SyncEvent {
     /// Add peer to synchronization list
     EnqueueSyncToPeer(PeerAddress, bool),
@@ -23,9 +36,15 @@ pub(crate) enum SyncEvent {
 
     /// Broadcast event to all configurated peers
     BroadcastToAllPeers(FileAction, Vec<String>),
+
+    /// Synchronize a single alias with all configured peers
+    SyncAlias(String),
+
+    /// Send the changes kept in the outbox of an offline peer
+    SendOutbox(PeerAddress),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum FileAction {
     Create(FileInfo),
     Update(FileInfo),
@@ -33,3 +52,16 @@ pub(crate) enum FileAction {
     Remove(FileInfo),
     Request(FileInfo),
 }
+
+impl FileAction {
+    /// Returns the file affected by this action, the source file for moves
+    pub fn file(&self) -> &FileInfo {
+        match self {
+            FileAction::Create(file)
+            | FileAction::Update(file)
+            | FileAction::Move(file, _)
+            | FileAction::Remove(file)
+            | FileAction::Request(file) => file,
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config,
+    events::{Event, EventBus},
+    manifest::Manifest,
+};
+
+/// Holds the synchronization while paused
+///
+/// While paused, scheduled synchronizations and file changes are not sent to the peers, and peers requesting a synchronization wait until it is resumed  
+/// When halted, the sessions in progress are also interrupted and the connections from the peers are refused, so nothing is
+/// written or deleted until the synchronization is resumed  
+/// Aliases are also paused on their own, see [Config::paused_aliases]
+pub(crate) struct PauseSwitch {
+    paused: AtomicBool,
+    paused_aliases: Mutex<HashSet<String>>,
+    halted: AtomicBool,
+    resumed: Notify,
+    events: Arc<EventBus>,
+    /// Parent of the sessions token, cancelled when the synchronizer is stopped
+    cancel: CancellationToken,
+    /// Cancelled when halted, the sessions with the peers use a child of it
+    sessions: Mutex<CancellationToken>,
+}
+
+impl PauseSwitch {
+    pub fn new(events: Arc<EventBus>, cancel: CancellationToken) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            paused_aliases: Default::default(),
+            halted: AtomicBool::new(false),
+            resumed: Notify::new(),
+            events,
+            sessions: Mutex::new(cancel.child_token()),
+            cancel,
+        }
+    }
+
+    /// Starts with the aliases of [Config::paused_aliases] paused
+    pub fn with_paused_aliases(self, aliases: &HashSet<String>) -> Self {
+        *self.paused_aliases.lock().unwrap() = aliases.clone();
+        self
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    /// Returns the token for a new session with a peer, it is cancelled when the synchronization is halted
+    pub fn session_token(&self) -> CancellationToken {
+        self.sessions.lock().unwrap().child_token()
+    }
+
+    /// Pauses the synchronization, returns false if it was already paused
+    #[cfg_attr(not(any(feature = "grpc", test)), allow(dead_code))]
+    pub fn pause(&self) -> bool {
+        let changed = !self.paused.swap(true, Ordering::SeqCst);
+        if changed {
+            log::info!("synchronization paused");
+            self.events.emit(Event::SynchronizationPaused);
+        }
+
+        changed
+    }
+
+    /// Pauses the synchronization and interrupts the sessions in progress, returns false if it was already halted  
+    /// Files partially received are kept in their temp files and the transfer queues are kept in the transfer journals
+    #[cfg_attr(not(any(feature = "grpc", test)), allow(dead_code))]
+    pub fn halt(&self) -> bool {
+        self.paused.store(true, Ordering::SeqCst);
+        let changed = !self.halted.swap(true, Ordering::SeqCst);
+        if changed {
+            let sessions = std::mem::replace(
+                &mut *self.sessions.lock().unwrap(),
+                self.cancel.child_token(),
+            );
+            sessions.cancel();
+
+            log::warn!(
+                "synchronization halted, writes from the peers are refused until it is resumed"
+            );
+            self.events.emit(Event::SynchronizationHalted);
+        }
+
+        changed
+    }
+
+    /// Resumes the synchronization, returns false if it wasn't paused
+    #[cfg_attr(not(any(feature = "grpc", test)), allow(dead_code))]
+    pub fn resume(&self) -> bool {
+        self.halted.store(false, Ordering::SeqCst);
+        let changed = self.paused.swap(false, Ordering::SeqCst);
+        if changed {
+            log::info!("synchronization resumed");
+            self.resumed.notify_waiters();
+            self.events.emit(Event::SynchronizationResumed);
+        }
+
+        changed
+    }
+
+    pub fn is_alias_paused(&self, alias: &str) -> bool {
+        self.paused_aliases.lock().unwrap().contains(alias)
+    }
+
+    /// Pauses the synchronization of `alias`, returns false if it was already paused
+    pub fn pause_alias(&self, alias: &str) -> bool {
+        let changed = self.paused_aliases.lock().unwrap().insert(alias.to_owned());
+        if changed {
+            log::info!("synchronization of alias {} paused", alias);
+            self.events.emit(Event::AliasPaused {
+                alias: alias.to_owned(),
+            });
+        }
+
+        changed
+    }
+
+    /// Resumes the synchronization of `alias`, returns false if it wasn't paused
+    pub fn resume_alias(&self, alias: &str) -> bool {
+        let changed = self.paused_aliases.lock().unwrap().remove(alias);
+        if changed {
+            log::info!("synchronization of alias {} resumed", alias);
+            self.events.emit(Event::AliasResumed {
+                alias: alias.to_owned(),
+            });
+        }
+
+        changed
+    }
+
+    /// Waits until the synchronization is resumed, returns right away if it isn't paused
+    pub async fn wait_resumed(&self) {
+        loop {
+            let resumed = self.resumed.notified();
+            tokio::pin!(resumed);
+            resumed.as_mut().enable();
+
+            if !self.is_paused() {
+                return;
+            }
+
+            resumed.await;
+        }
+    }
+}
+
+/// Writes the [Manifest] of every local alias to its root, as `.manifest-<seconds since the unix epoch>.ironcarrier`,
+/// so the state of the aliases when the synchronization was halted can be compared later  
+/// Returns the paths written
+#[cfg_attr(not(any(feature = "grpc", test)), allow(dead_code))]
+pub(crate) async fn record_manifests(config: &Config) -> crate::Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for (alias, path) in config.paths.iter() {
+        if !config.is_local_storage(alias) {
+            continue;
+        }
+
+        let manifest = Manifest::generate(config, alias).await?;
+        let manifest_path = path.join(format!(".manifest-{}.ironcarrier", manifest.generated_at));
+        tokio::fs::write(&manifest_path, manifest.to_json()?).await?;
+
+        log::info!("manifest of alias {} written to {:?}", alias, manifest_path);
+        written.push(manifest_path);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn waits_until_resumed() {
+        let events = Arc::new(EventBus::new());
+        let mut subscriber = events.subscribe();
+        let switch = Arc::new(PauseSwitch::new(events, CancellationToken::new()));
+
+        assert!(switch.pause());
+        assert!(!switch.pause());
+
+        let waiting = tokio::spawn({
+            let switch = switch.clone();
+            async move { switch.wait_resumed().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        assert!(switch.resume());
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::SynchronizationPaused
+        );
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::SynchronizationResumed
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_are_paused_on_their_own() {
+        let events = Arc::new(EventBus::new());
+        let mut subscriber = events.subscribe();
+        let switch = PauseSwitch::new(events, CancellationToken::new())
+            .with_paused_aliases(&HashSet::from(["a".to_owned()]));
+
+        assert!(switch.is_alias_paused("a"));
+        assert!(!switch.is_alias_paused("b"));
+        assert!(!switch.is_paused());
+
+        assert!(switch.pause_alias("b"));
+        assert!(!switch.pause_alias("b"));
+        assert!(switch.resume_alias("a"));
+        assert!(!switch.resume_alias("a"));
+        assert!(!switch.is_alias_paused("a"));
+
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::AliasPaused {
+                alias: "b".to_owned()
+            }
+        );
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::AliasResumed {
+                alias: "a".to_owned()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn halting_interrupts_sessions() -> crate::Result<()> {
+        let events = Arc::new(EventBus::new());
+        let mut subscriber = events.subscribe();
+        let switch = PauseSwitch::new(events, CancellationToken::new());
+
+        let session = switch.session_token();
+        assert!(switch.halt());
+        assert!(!switch.halt());
+        assert!(session.is_cancelled());
+        assert!(switch.is_paused());
+        assert!(!switch.session_token().is_cancelled());
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::SynchronizationHalted
+        );
+
+        assert!(switch.resume());
+        assert!(!switch.is_halted());
+
+        std::fs::create_dir_all("./tmp/halt_manifests/a")?;
+        std::fs::write("./tmp/halt_manifests/a/file", "content")?;
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/halt_manifests/a\""
+                .to_string(),
+        )?;
+        let manifests = record_manifests(&config).await?;
+        let manifest = Manifest::from_json(&std::fs::read_to_string(&manifests[0])?)?;
+        assert_eq!(manifest.files.len(), 1);
+
+        std::fs::remove_dir_all("./tmp/halt_manifests")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,453 @@
+//! Synchronization state of each alias with each peer
+//!
+//! The state is derived from the hashes of the file lists acknowledged by both peers, an alias is only up to date when
+//! the peer reports the same hash as the local one. States are updated by the synchronizations started by this peer
+//!
+//! While an alias is synchronized with a peer, it goes through the [SyncPhase]s, each transition is emitted as
+//! [Event::SyncPhaseChanged], so front-ends can follow the synchronization without parsing the logs
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use super::in_flight::InFlightTransfers;
+use crate::{
+    config::Config,
+    events::{Event, EventBus, TransferPreview},
+};
+
+/// Weight of the last transfer in the throughput of a peer
+const THROUGHPUT_WEIGHT: f64 = 0.5;
+
+/// Synchronization state of an alias with a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncState {
+    /// Both peers have the same files for the alias
+    UpToDate,
+    /// The alias is being synchronized with the peer
+    Syncing,
+    /// The peers have different files for the alias
+    OutOfSync {
+        /// When the difference was noticed, in seconds since the unix epoch
+        since: u64,
+    },
+    /// The last synchronization with the peer failed
+    Error {
+        /// Why the synchronization failed
+        reason: String,
+    },
+}
+
+/// Step of the synchronization of an alias with a peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SyncPhase {
+    /// Nothing is happening with the alias
+    #[default]
+    Idle,
+    /// The local files of the alias are being listed
+    Scanning,
+    /// The local files are being compared with the files of the peer
+    Comparing,
+    /// Files are being deleted, sent and received
+    Transferring {
+        /// Transfers finished so far
+        progress: TransferProgress,
+    },
+    /// The transfers are done, but some files were skipped and the alias is still different in the peers
+    Conflicted {
+        /// Number of files skipped
+        files: u64,
+    },
+    /// The synchronization failed
+    Error {
+        /// Why the synchronization failed
+        reason: String,
+    },
+}
+
+impl SyncPhase {
+    /// Returns true if the alias can go from this phase to `next`
+    ///
+    /// A synchronization always starts with [SyncPhase::Scanning], and ends with [SyncPhase::Idle],
+    /// [SyncPhase::Conflicted] or [SyncPhase::Error]
+    pub fn can_move_to(&self, next: &SyncPhase) -> bool {
+        use SyncPhase::*;
+
+        matches!(
+            (self, next),
+            (Idle | Conflicted { .. } | Error { .. }, Scanning)
+                | (Scanning, Comparing | Idle)
+                | (Comparing, Transferring { .. } | Idle)
+                | (
+                    Transferring { .. },
+                    Transferring { .. } | Conflicted { .. } | Idle
+                )
+                | (Scanning | Comparing | Transferring { .. }, Error { .. })
+        )
+    }
+
+    /// Returns true while the alias is being synchronized
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            SyncPhase::Scanning | SyncPhase::Comparing | SyncPhase::Transferring { .. }
+        )
+    }
+}
+
+/// Progress of the transfers of an alias, see [SyncPhase::Transferring]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Files sent or received so far
+    pub done: u64,
+    /// Files to send and to receive
+    pub total: u64,
+}
+
+/// [SyncState] of an alias with a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasSyncState {
+    /// Alias, as configured in [crate::config::Config::paths]
+    pub alias: String,
+    /// Name of the peer, see [Config::peer_name]
+    pub peer: String,
+    /// Current state
+    pub state: SyncState,
+    /// When the state was last updated, in seconds since the unix epoch
+    pub updated_at: u64,
+    /// Changes being applied, while [SyncState::Syncing]
+    pub preview: Option<TransferPreview>,
+    /// Current step of the synchronization
+    pub phase: SyncPhase,
+}
+
+struct Entry {
+    state: SyncState,
+    updated_at: u64,
+    /// When the peers stopped being up to date, kept while the alias is synchronized
+    out_of_sync_since: Option<u64>,
+    preview: Option<TransferPreview>,
+}
+
+fn now_as_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Keeps the [SyncState] and the [SyncPhase] of each alias and peer
+pub(crate) struct SyncStates {
+    entries: Mutex<BTreeMap<(String, String), Entry>>,
+    /// Phase of each alias and peer, aliases not listed are [SyncPhase::Idle]
+    phases: Mutex<HashMap<(String, String), SyncPhase>>,
+    /// Recent throughput with each peer, in bytes per second
+    throughput: Mutex<HashMap<String, f64>>,
+    events: Arc<EventBus>,
+    /// Names of the peers shown in the events and the states, the addresses are shown without it
+    config: Option<Arc<Config>>,
+    /// Files being sent and received with the peers
+    in_flight: InFlightTransfers,
+}
+
+impl SyncStates {
+    pub fn new(events: Arc<EventBus>) -> Self {
+        Self {
+            entries: Default::default(),
+            phases: Default::default(),
+            throughput: Default::default(),
+            events,
+            config: None,
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Shows the peers by the names of [Config::peer_names]
+    pub fn with_peer_names(mut self, config: Arc<Config>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Returns the files being sent and received with the peers
+    pub fn in_flight(&self) -> &InFlightTransfers {
+        &self.in_flight
+    }
+
+    fn peer_name(&self, peer: &str) -> String {
+        match &self.config {
+            Some(config) => config.peer_name(peer),
+            None => peer.to_owned(),
+        }
+    }
+
+    /// Moves `alias` with `peer` to `phase`, emitting [Event::SyncPhaseChanged]
+    /// Transitions not allowed by [SyncPhase::can_move_to] are ignored
+    pub fn enter(&self, alias: &str, peer: &str, phase: SyncPhase) {
+        let mut phases = self.phases.lock().unwrap();
+        let current = phases
+            .entry((alias.to_owned(), peer.to_owned()))
+            .or_default();
+        if !current.can_move_to(&phase) {
+            log::debug!(
+                "alias {} with peer {} cannot go from {:?} to {:?}",
+                alias,
+                peer,
+                current,
+                phase
+            );
+            return;
+        }
+
+        let from = std::mem::replace(current, phase.clone());
+        self.events.emit(Event::SyncPhaseChanged {
+            alias: alias.to_owned(),
+            peer: self.peer_name(peer),
+            from,
+            to: phase,
+        });
+    }
+
+    /// Records that `done` of the `total` transfers of `alias` with `peer` are finished
+    pub fn transferring(&self, alias: &str, peer: &str, done: u64, total: u64) {
+        self.enter(
+            alias,
+            peer,
+            SyncPhase::Transferring {
+                progress: TransferProgress { done, total },
+            },
+        );
+    }
+
+    fn phase(&self, alias: &str, peer: &str) -> SyncPhase {
+        self.phases
+            .lock()
+            .unwrap()
+            .get(&(alias.to_owned(), peer.to_owned()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn update(&self, alias: &str, peer: &str, state: impl FnOnce(u64) -> SyncState) {
+        let now = now_as_secs();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry((alias.to_owned(), peer.to_owned()))
+            .or_insert(Entry {
+                state: SyncState::UpToDate,
+                updated_at: now,
+                out_of_sync_since: None,
+                preview: None,
+            });
+
+        let since = *entry.out_of_sync_since.get_or_insert(now);
+        entry.state = state(since);
+        entry.updated_at = now;
+        entry.preview = None;
+        if entry.state == SyncState::UpToDate {
+            entry.out_of_sync_since = None;
+        }
+    }
+
+    /// Compares the hash of `alias` with the hash reported by `peer`, the alias is ignored if the peer doesn't have it
+    pub fn compare(&self, alias: &str, peer: &str, hash: u64, peer_hash: Option<u64>) {
+        match peer_hash {
+            Some(peer_hash) if peer_hash == hash => {
+                self.update(alias, peer, |_| SyncState::UpToDate)
+            }
+            Some(_) => self.out_of_sync(alias, peer),
+            None => {}
+        }
+    }
+
+    pub fn syncing(&self, alias: &str, peer: &str) {
+        self.update(alias, peer, |_| SyncState::Syncing);
+    }
+
+    /// Keeps the changes about to be applied to `alias`, until the synchronization ends
+    pub fn planned(&self, alias: &str, peer: &str, preview: TransferPreview) {
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&(alias.to_owned(), peer.to_owned()))
+        {
+            entry.preview = Some(preview);
+        }
+    }
+
+    /// Records that `bytes` were transferred with `peer` in `elapsed`
+    pub fn transferred(&self, peer: &str, bytes: u64, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if bytes == 0 || seconds == 0.0 {
+            return;
+        }
+
+        let rate = bytes as f64 / seconds;
+        self.throughput
+            .lock()
+            .unwrap()
+            .entry(peer.to_owned())
+            .and_modify(|throughput| {
+                *throughput = *throughput * (1.0 - THROUGHPUT_WEIGHT) + rate * THROUGHPUT_WEIGHT
+            })
+            .or_insert(rate);
+    }
+
+    /// Returns the seconds needed to transfer `bytes` with `peer`, [None] if nothing was transferred with the peer yet
+    pub fn eta(&self, peer: &str, bytes: u64) -> Option<u64> {
+        self.throughput
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|throughput| (bytes as f64 / throughput).ceil() as u64)
+    }
+
+    pub fn out_of_sync(&self, alias: &str, peer: &str) {
+        self.update(alias, peer, |since| SyncState::OutOfSync { since });
+    }
+
+    /// Marks the aliases of `peer` that are not up to date as failed, the aliases being synchronized go to
+    /// [SyncPhase::Error]
+    pub fn failed(&self, peer: &str, reason: &str) {
+        let now = now_as_secs();
+        let mut entries = self.entries.lock().unwrap();
+        for ((_, entry_peer), entry) in entries.iter_mut() {
+            if entry_peer == peer && entry.state != SyncState::UpToDate {
+                entry.state = SyncState::Error {
+                    reason: reason.to_owned(),
+                };
+                entry.updated_at = now;
+            }
+        }
+        drop(entries);
+
+        let active: Vec<String> = self
+            .phases
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((_, phase_peer), phase)| phase_peer == peer && phase.is_active())
+            .map(|((alias, _), _)| alias.clone())
+            .collect();
+        for alias in active {
+            self.enter(
+                &alias,
+                peer,
+                SyncPhase::Error {
+                    reason: reason.to_owned(),
+                },
+            );
+        }
+    }
+
+    /// Returns the state of every alias and peer, sorted by alias and peer
+    pub fn states(&self) -> Vec<AliasSyncState> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((alias, peer), entry)| AliasSyncState {
+                alias: alias.clone(),
+                peer: self.peer_name(peer),
+                state: entry.state.clone(),
+                updated_at: entry.updated_at,
+                preview: entry.preview.clone(),
+                phase: self.phase(alias, peer),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_sync_keeps_first_time() {
+        let states = SyncStates::new(Arc::new(EventBus::new()));
+        states.compare("a", "peer", 1, Some(1));
+        states.compare("b", "peer", 1, None);
+        assert_eq!(states.states().len(), 1);
+        assert_eq!(states.states()[0].state, SyncState::UpToDate);
+
+        states.compare("a", "peer", 1, Some(2));
+        let first = states.states()[0].state.clone();
+        states.syncing("a", "peer");
+        assert_eq!(states.states()[0].state, SyncState::Syncing);
+        states.out_of_sync("a", "peer");
+        assert_eq!(states.states()[0].state, first);
+
+        states.failed("peer", "connection lost");
+        assert_eq!(
+            states.states()[0].state,
+            SyncState::Error {
+                reason: "connection lost".to_string()
+            }
+        );
+        states.compare("a", "peer", 2, Some(2));
+        assert_eq!(states.states()[0].state, SyncState::UpToDate);
+    }
+
+    #[test]
+    fn eta_follows_recent_throughput() {
+        let states = SyncStates::new(Arc::new(EventBus::new()));
+        assert_eq!(states.eta("peer", 100), None);
+
+        states.transferred("peer", 1000, Duration::from_secs(1));
+        assert_eq!(states.eta("peer", 5000), Some(5));
+        states.transferred("peer", 3000, Duration::from_secs(1));
+        assert_eq!(states.eta("peer", 4000), Some(2));
+
+        states.syncing("a", "peer");
+        states.planned("a", "peer", TransferPreview::default());
+        assert!(states.states()[0].preview.is_some());
+        states.compare("a", "peer", 1, Some(1));
+        assert!(states.states()[0].preview.is_none());
+    }
+
+    #[test]
+    fn phases_follow_the_synchronization() {
+        let events = Arc::new(EventBus::new());
+        let mut subscriber = events.subscribe();
+        let states = SyncStates::new(events);
+
+        // transfers can't start before the files are compared
+        states.transferring("a", "peer", 0, 2);
+        states.enter("a", "peer", SyncPhase::Scanning);
+        states.enter("a", "peer", SyncPhase::Comparing);
+        states.transferring("a", "peer", 0, 2);
+        states.transferring("a", "peer", 1, 2);
+        states.enter("a", "peer", SyncPhase::Conflicted { files: 1 });
+        states.enter("a", "peer", SyncPhase::Scanning);
+        states.failed("peer", "connection lost");
+
+        let mut transitions = Vec::new();
+        while let Ok(Event::SyncPhaseChanged { from, to, .. }) = subscriber.try_recv() {
+            transitions.push((from, to));
+        }
+        let transferring = |done| SyncPhase::Transferring {
+            progress: TransferProgress { done, total: 2 },
+        };
+        let error = SyncPhase::Error {
+            reason: "connection lost".to_string(),
+        };
+        assert_eq!(
+            transitions,
+            vec![
+                (SyncPhase::Idle, SyncPhase::Scanning),
+                (SyncPhase::Scanning, SyncPhase::Comparing),
+                (SyncPhase::Comparing, transferring(0)),
+                (transferring(0), transferring(1)),
+                (transferring(1), SyncPhase::Conflicted { files: 1 }),
+                (SyncPhase::Conflicted { files: 1 }, SyncPhase::Scanning),
+                (SyncPhase::Scanning, error.clone()),
+            ]
+        );
+
+        // phases are reported with the states
+        states.out_of_sync("a", "peer");
+        assert_eq!(states.states()[0].phase, error);
+    }
+}
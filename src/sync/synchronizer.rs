@@ -1,46 +1,685 @@
-use std::sync::Arc;
-use tokio::sync::{mpsc, mpsc::Receiver, mpsc::Sender};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    sync::{broadcast, mpsc, mpsc::Receiver, mpsc::Sender, Semaphore},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 
 use super::{
-    file_events_buffer::FileEventsBuffer, file_watcher::FileWatcher, FileAction, SyncEvent,
+    alias_locks::AliasLocks,
+    file_events_buffer::FileEventsBuffer,
+    file_watcher::FileWatcher,
+    hooks::{run_hook, FileHooks, HookStage, SyncSummary},
+    in_flight::{AfterCancel, InFlightTransfers},
+    introduced_peers::IntroducedPeers,
+    mirror,
+    pause_switch::PauseSwitch,
+    sync_state::{SyncPhase, SyncStates},
+    transfer_queue::TransferQueue,
+    FileAction, SyncEvent,
+};
+use crate::{
+    audit_log::AuditLog,
+    config::Config,
+    events::{Decision, Event, EventBus, TransferPreview},
+    fs,
+    fs::{FileInfo, FileKind},
+    locked_files,
+    network::authorization::PeerAuthorizations,
+    network::peer::{Peer, PeerFileList},
+    network::server::Server,
+    network::transport::{BoxedStream, TcpTransport, Transport},
+    on_demand::Placeholders,
+    outbox::{self, Outbox},
+    peer_sync_state::PeerSyncState,
+    quarantine::Quarantine,
+    skipped_files::SkippedFiles,
+    spool::{SortedList, SortedReader},
+    transfer_journal::TransferJournal,
+    IronCarrierError,
 };
-use crate::{config::Config, fs, fs::FileInfo, network::peer::Peer, network::server::Server};
 
+/// Files up to this size are sent to the peer in batches, saving a round trip for each file
+const SMALL_FILE_SIZE: u64 = 64 * 1024;
+/// Max number of files sent in a single batch
+const BATCH_MAX_FILES: usize = 1000;
+/// Max total size of the files sent in a single batch
+const BATCH_MAX_SIZE: u64 = 8 * 1024 * 1024;
+/// Interval between the attempts to send the outboxes of offline peers
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Max number of files in a single pack when bootstrapping an alias, the pack manifest is sent in one frame
+const BOOTSTRAP_PACK_FILES: usize = 10_000;
+
+/// Coordinates the synchronization between this machine and the configured peers
 pub struct Synchronizer {
     config: Arc<Config>,
     server: Server,
     file_watcher: Option<FileWatcher>,
     events_buffer: Arc<FileEventsBuffer>,
+    events: Arc<EventBus>,
+    pause_switch: Arc<PauseSwitch>,
+    alias_locks: Arc<AliasLocks>,
+    /// Establishes the connections with the peers
+    transport: Arc<dyn Transport>,
+    /// Limits the peer sessions running at the same time to [Config::max_concurrent_peers]
+    sync_slots: Arc<Semaphore>,
+    /// Peers with a full synchronization in progress, started by this node
+    syncing_peers: Arc<Mutex<HashSet<String>>>,
+    /// Tasks running in the background, like the mirrors schedule, they are stopped when the synchronizer is dropped
+    background_tasks: Vec<JoinHandle<()>>,
+    /// Cancelled when the synchronizer is stopped, interrupting scans, transfers and peer sessions
+    cancel: CancellationToken,
+    /// Peers learned from the [Config::introducers]
+    introduced_peers: Arc<IntroducedPeers>,
+    /// Hosts allowed to connect, see [Config::authorized_peers_path]
+    authorizations: Arc<PeerAuthorizations>,
+    /// Schedules the synchronizations, available once the services are started
+    sync_events: Option<Sender<SyncEvent>>,
+    /// State of each alias with each peer
+    sync_states: Arc<SyncStates>,
+}
+
+/// Stores the current file list of `alias` as agreed with the peer, if both sides have the same hash  
+/// Otherwise, the previous agreed state is discarded  
+/// Lists that don't fit [Config::memory_budget_mb] are not stored, since the stored list is read back into memory  
+/// With the same hash, the peer has every local change, so the outbox of the peer is cleared
+async fn store_agreed_state(
+    peer_address: &str,
+    peer_hash: Option<u64>,
+    alias: &str,
+    path: &Path,
+    hash: u64,
+    files: &SortedList<FileInfo>,
+) {
+    if peer_hash == Some(hash) {
+        if let Err(err) = Outbox::new(path).clear(peer_address).await {
+            log::error!("cannot clear outbox of alias {}: {}", alias, err);
+        }
+    }
+
+    let sync_state = PeerSyncState::new(path);
+    let result = if peer_hash == Some(hash) && !files.is_spilled() {
+        match files.to_vec() {
+            Ok(files) => sync_state.set(peer_address, hash, files).await,
+            Err(err) => Err(err),
+        }
+    } else {
+        sync_state.remove(peer_address).await
+    };
+
+    if let Err(err) = result {
+        log::error!("cannot store sync state for alias {}: {}", alias, err);
+    }
+}
+
+/// Sends the changes kept in the outbox of `peer` for every alias, see [crate::outbox]  
+/// Changed files are read again, files deleted since are left out, their deletion is in the outbox
+async fn send_outbox(
+    peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+    config: &Config,
+) -> crate::Result<()> {
+    let peer_address = peer.get_address().to_owned();
+    for (alias, path) in config.paths.iter() {
+        let outbox = Outbox::new(path);
+        let pending = outbox.pending(&peer_address).await;
+        if pending.is_empty() {
+            continue;
+        }
+
+        log::info!(
+            "sending {} changes of alias {} kept for peer {}",
+            pending.len(),
+            alias,
+            config.peer_name(&peer_address)
+        );
+        let mut sent = Vec::with_capacity(pending.len());
+        let mut result = Ok(());
+        for action in pending {
+            let action = match action {
+                FileAction::Update(file) => {
+                    match file
+                        .get_absolute_path(config)
+                        .and_then(|file_path| Ok(file_path.metadata()?))
+                    {
+                        Ok(metadata) => {
+                            FileAction::Update(FileInfo::new(alias.clone(), file.path, metadata))
+                        }
+                        Err(_) => {
+                            sent.push(file.path);
+                            continue;
+                        }
+                    }
+                }
+                action => action,
+            };
+
+            match peer.sync_action(&action).await {
+                Err(err) if is_file_error(err.as_ref()) => {
+                    log::warn!("cannot send {:?}: {}", action.file().path, err);
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+                Ok(_) => {}
+            }
+            sent.push(action.file().path.clone());
+        }
+
+        if let Err(err) = outbox.sent(&peer_address, &sent).await {
+            log::error!("cannot update outbox of alias {}: {}", alias, err);
+        }
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Records in `journal` that the transfers of `files` are finished, errors are only logged
+async fn record_done(journal: &TransferJournal, peer_address: &str, files: &[FileInfo]) {
+    if let Err(err) = journal.done(peer_address, files).await {
+        log::error!("cannot record finished transfers: {}", err);
+    }
+}
+
+/// Sends the files in `batch` to `peer`, emptying it
+async fn send_batch(
+    peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+    batch: &mut Vec<FileInfo>,
+    journal: &TransferJournal,
+    skipped: &mut SkippedFiles,
+) -> crate::Result<()> {
+    let batch = std::mem::take(batch);
+    peer.send_files(batch.clone(), skipped).await?;
+    record_done(journal, peer.get_address(), &batch).await;
+    Ok(())
+}
+
+/// Local files of an alias compared with a peer
+enum LocalFiles<'a> {
+    /// Listed by a complete scan
+    Scanned(SortedReader<FileInfo>),
+    /// Read as the comparison goes, see [Config::streaming_scan]
+    Streamed(fs::FileStream<'a>),
 }
 
-/// lookup for the peer file  
-/// if the file is found, return the file and remove it from peer_files
-fn get_peer_file(file: &FileInfo, peer_files: &mut Vec<FileInfo>) -> Option<FileInfo> {
-    // let file = RemoteFile::from(file);
-    match peer_files.binary_search_by(|f| f.cmp(&file)) {
-        Ok(index) => Some(peer_files.remove(index)),
-        Err(_) => None,
+impl LocalFiles<'_> {
+    async fn next_entry(&mut self) -> crate::Result<Option<FileInfo>> {
+        match self {
+            LocalFiles::Scanned(reader) => reader.next_entry(),
+            LocalFiles::Streamed(stream) => stream.next_entry().await,
+        }
     }
 }
 
+/// Runs the transfers of an alias with a peer, small files are sent in batches and the files of
+/// [Config::atomic_groups] are kept for [TransferRun::finish]
+struct TransferRun<'a> {
+    alias: &'a str,
+    peer_address: &'a str,
+    config: &'a Config,
+    alias_locks: &'a AliasLocks,
+    sync_states: &'a SyncStates,
+    journal: &'a TransferJournal,
+    cancel: &'a CancellationToken,
+    batch: Vec<FileInfo>,
+    batch_size: u64,
+    groups: BTreeMap<usize, Vec<FileAction>>,
+    /// Files left out of the synchronization, see [crate::quarantine]
+    quarantined: HashSet<PathBuf>,
+    done: u64,
+    total: u64,
+}
+
+impl<'a> TransferRun<'a> {
+    fn new(
+        alias: &'a str,
+        peer_address: &'a str,
+        config: &'a Config,
+        alias_locks: &'a AliasLocks,
+        sync_states: &'a SyncStates,
+        journal: &'a TransferJournal,
+        cancel: &'a CancellationToken,
+    ) -> Self {
+        TransferRun {
+            alias,
+            peer_address,
+            config,
+            alias_locks,
+            sync_states,
+            journal,
+            cancel,
+            batch: Vec::new(),
+            batch_size: 0,
+            groups: BTreeMap::new(),
+            quarantined: HashSet::new(),
+            done: 0,
+            total: 0,
+        }
+    }
+
+    fn report_progress(&self) {
+        self.sync_states
+            .transferring(self.alias, self.peer_address, self.done, self.total);
+    }
+
+    async fn send_batch(
+        &mut self,
+        peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        self.done += self.batch.len() as u64;
+        send_batch(peer, &mut self.batch, self.journal, skipped).await?;
+        self.batch_size = 0;
+        Ok(())
+    }
+
+    /// Runs `transfers` in their order, the files of the atomic groups are kept for [TransferRun::finish]  
+    /// Files requeued after their transfer was cancelled go last, skipped and quarantined files are recorded in `skipped`,
+    /// see [crate::sync::in_flight] and [crate::quarantine]
+    async fn run(
+        &mut self,
+        peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+        transfers: &SortedList<FileAction>,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        let mut transfers = transfers.reader()?;
+        let mut requeued = Vec::new();
+        while let Some(peer_action) = transfers.next_entry()? {
+            let path = &peer_action.file().path;
+            if self.quarantined.contains(path) {
+                skipped.add_refused(path, "the file is quarantined");
+                continue;
+            }
+
+            match self
+                .sync_states
+                .in_flight()
+                .after_cancel(self.peer_address, self.alias, path)
+            {
+                Some(AfterCancel::Requeue) => requeued.push(peer_action),
+                Some(AfterCancel::Skip) => skipped.add_refused(path, "the transfer was cancelled"),
+                None => self.run_action(peer, peer_action, skipped).await?,
+            }
+        }
+
+        for peer_action in requeued {
+            self.run_action(peer, peer_action, skipped).await?;
+        }
+
+        if !self.batch.is_empty() {
+            self.send_batch(peer, skipped).await?;
+            self.report_progress();
+        }
+
+        Ok(())
+    }
+
+    /// Runs `peer_action`, small files are sent in batches and the files of the atomic groups are kept
+    async fn run_action(
+        &mut self,
+        peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+        peer_action: FileAction,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(IronCarrierError::Cancelled.into());
+        }
+
+        if let Some(group) = self
+            .config
+            .atomic_group(self.alias, &peer_action.file().path)
+        {
+            self.groups.entry(group).or_default().push(peer_action);
+            return Ok(());
+        }
+
+        match peer_action {
+            FileAction::Create(file) | FileAction::Update(file)
+                if file.content_size() <= SMALL_FILE_SIZE =>
+            {
+                self.batch_size += file.content_size();
+                self.batch.push(file);
+
+                if self.batch.len() >= BATCH_MAX_FILES || self.batch_size >= BATCH_MAX_SIZE {
+                    self.send_batch(peer, skipped).await?;
+                    self.report_progress();
+                }
+            }
+            peer_action => {
+                // the pending batch goes first, keeping the transfer order
+                if !self.batch.is_empty() {
+                    self.send_batch(peer, skipped).await?;
+                }
+
+                let file = peer_action.file().clone();
+                Synchronizer::sync_peer_action(
+                    peer,
+                    peer_action,
+                    self.alias,
+                    self.alias_locks,
+                    self.sync_states.in_flight(),
+                    skipped,
+                )
+                .await?;
+                record_done(self.journal, self.peer_address, &[file]).await;
+                self.done += 1;
+                self.report_progress();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends and requests the files of each atomic group, see [Config::atomic_groups]
+    async fn finish(
+        &mut self,
+        peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        for actions in std::mem::take(&mut self.groups).into_values() {
+            if self.cancel.is_cancelled() {
+                return Err(IronCarrierError::Cancelled.into());
+            }
+
+            let (requests, sends): (Vec<&FileAction>, Vec<&FileAction>) = actions
+                .iter()
+                .partition(|action| matches!(action, FileAction::Request(_)));
+            let files = |actions: Vec<&FileAction>| -> Vec<FileInfo> {
+                actions
+                    .into_iter()
+                    .map(|action| action.file().clone())
+                    .collect()
+            };
+            let (requests, sends) = (files(requests), files(sends));
+            if !sends.is_empty() {
+                peer.send_group(sends.clone(), skipped).await?;
+            }
+            if !requests.is_empty() {
+                let _lock = self.alias_locks.lock(self.alias).await;
+                peer.request_group(requests.clone()).await?;
+            }
+
+            record_done(self.journal, self.peer_address, &[sends, requests].concat()).await;
+            self.done += actions.len() as u64;
+            self.report_progress();
+        }
+
+        Ok(())
+    }
+}
+
+/// Learns the peers shared by `peer`, when it is one of the [Config::introducers]  
+/// Returns the new peers that can be synchronized right away, the others wait for approval
+async fn learn_introduced_peers(
+    peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+    config: &Config,
+    introduced_peers: &IntroducedPeers,
+    events: &EventBus,
+) -> Vec<String> {
+    let introducer = peer.get_address().to_owned();
+    if !config.introducers.contains(&introducer) {
+        return Vec::new();
+    }
+
+    let addresses = match peer.query_peers().await {
+        Ok(addresses) => addresses,
+        Err(err) => {
+            log::warn!("cannot get the peers of introducer {}: {}", introducer, err);
+            return Vec::new();
+        }
+    };
+
+    let approved = config.auto_add_introduced_peers;
+    let mut new_peers = Vec::new();
+    for address in addresses {
+        if config.peers.iter().flatten().any(|peer| *peer == address)
+            || !introduced_peers.learn(&address, approved)
+        {
+            continue;
+        }
+
+        log::info!(
+            "peer {} introduced by {}",
+            config.peer_name(&address),
+            config.peer_name(&introducer)
+        );
+        events.emit(Event::PeerIntroduced {
+            address: address.clone(),
+            introducer: introducer.clone(),
+            approved,
+        });
+        if approved {
+            new_peers.push(address);
+        }
+    }
+
+    new_peers
+}
+
+/// Seeds `alias` in packs, when it is empty in one of the sides, see [Config::enable_bootstrap]  
+/// Returns true if files were received, the local files must be scanned again in this case
+///
+/// Only the regular files are seeded, deletions, links and the files that fail are left to the normal synchronization
+async fn bootstrap_alias(
+    peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+    alias: &str,
+    local_files: &SortedList<FileInfo>,
+    config: &Config,
+    alias_locks: &AliasLocks,
+) -> crate::Result<bool> {
+    let empty_hash = crate::crypto::calculate_hash(&Vec::<FileInfo>::new());
+    let peer_empty = peer.alias_hash(alias) == Some(empty_hash);
+    let mut skipped = SkippedFiles::new();
+    let mut pack = Vec::new();
+
+    let received = if local_files.len() == 0 && !peer_empty {
+        log::info!(
+            "bootstrapping alias {} from peer {}",
+            alias,
+            peer.get_name()
+        );
+        let _lock = alias_locks.lock(alias).await;
+        let mut peer_files = PeerFileList::remote(alias);
+        while let Some(file) = peer.next_file(&mut peer_files).await? {
+            if file.deleted_at.is_some()
+                || file.kind != FileKind::Regular
+                || !peer.in_scope(alias, &file.path)
+            {
+                continue;
+            }
+            if let Err(err) = fs::check_representable(&file.path)
+                .and_then(|_| config.check_file_size(alias, file.content_size()))
+            {
+                skipped.add(&file.path, err);
+                continue;
+            }
+
+            pack.push(file);
+            if pack.len() >= BOOTSTRAP_PACK_FILES {
+                peer.request_pack(std::mem::take(&mut pack)).await?;
+            }
+        }
+        if !pack.is_empty() {
+            peer.request_pack(pack).await?;
+        }
+
+        true
+    } else if peer_empty && local_files.len() > 0 && !config.archive_mode {
+        log::info!("bootstrapping alias {} to peer {}", alias, peer.get_name());
+        let mut local_files = local_files.reader()?;
+        while let Some(file) = local_files.next_entry()? {
+            if file.deleted_at.is_some()
+                || file.kind != FileKind::Regular
+                || !peer.in_scope(alias, &file.path)
+            {
+                continue;
+            }
+
+            pack.push(file);
+            if pack.len() >= BOOTSTRAP_PACK_FILES {
+                peer.send_pack(std::mem::take(&mut pack), &mut skipped)
+                    .await?;
+            }
+        }
+        if !pack.is_empty() {
+            peer.send_pack(pack, &mut skipped).await?;
+        }
+
+        false
+    } else {
+        return Ok(false);
+    };
+
+    skipped.log_summary(&format!("bootstrapping alias {}", alias));
+    Ok(received)
+}
+
+/// Returns true if `err` only affects a single file, so the synchronization can carry on without it
+fn is_file_error(err: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    matches!(
+        err.downcast_ref::<IronCarrierError>(),
+        Some(IronCarrierError::IOReadingError)
+            | Some(IronCarrierError::IOWritingError)
+            | Some(IronCarrierError::QuotaExceeded(_))
+            | Some(IronCarrierError::StorageFull(_))
+            | Some(IronCarrierError::ChecksumMismatch)
+            | Some(IronCarrierError::PeerLowOnDiskSpace(_))
+            | Some(IronCarrierError::FileLocked(_))
+            | Some(IronCarrierError::PathRejected(_))
+    )
+}
+
 impl Synchronizer {
+    /// Creates a new [Synchronizer] for the given [Config]
     pub fn new(config: Config) -> Self {
+        let transport = Arc::new(TcpTransport::new(&config));
+        Synchronizer::with_transport(config, transport)
+    }
+
+    /// Creates a new [Synchronizer] for the given [Config], connecting to the peers with `transport`
+    pub(crate) fn with_transport(config: Config, transport: Arc<dyn Transport>) -> Self {
         let config = Arc::new(config);
-        let events_buffer = Arc::new(FileEventsBuffer::new(config.clone()));
-        let server = Server::new(config.clone(), events_buffer.clone());
+        let introduced_peers = Arc::new(IntroducedPeers::new());
+        let events_buffer = Arc::new(
+            FileEventsBuffer::new(config.clone()).with_introduced_peers(introduced_peers.clone()),
+        );
+        let mut events = EventBus::new();
+        if let Some(path) = &config.audit_log_path {
+            match AuditLog::open(path) {
+                Ok(audit_log) => events = events.with_audit_log(audit_log),
+                Err(err) => log::error!("cannot open audit log {:?}: {}", path, err),
+            }
+        }
+        let events = Arc::new(events);
+        let alias_locks = Arc::new(AliasLocks::new(&config));
+        let cancel = CancellationToken::new();
+        let pause_switch = Arc::new(
+            PauseSwitch::new(events.clone(), cancel.clone())
+                .with_paused_aliases(&config.paused_aliases),
+        );
+        let authorizations = Arc::new(PeerAuthorizations::new(&config, introduced_peers.clone()));
+        let server = Server::new(
+            config.clone(),
+            events_buffer.clone(),
+            events.clone(),
+            alias_locks.clone(),
+            pause_switch.clone(),
+            authorizations.clone(),
+            transport.clone(),
+            cancel.child_token(),
+        );
+        if !config.file_hooks.is_empty() {
+            events.add_observer(Arc::new(FileHooks::new(
+                config.clone(),
+                cancel.child_token(),
+            )));
+        }
+        let sync_slots = Arc::new(Semaphore::new(config.max_concurrent_peers));
+        let sync_states = Arc::new(SyncStates::new(events.clone()).with_peer_names(config.clone()));
 
         Synchronizer {
-            config: config,
+            config,
             events_buffer,
+            events,
+            pause_switch,
+            alias_locks,
+            transport,
+            sync_slots,
+            syncing_peers: Arc::new(Mutex::new(HashSet::new())),
+            background_tasks: Vec::new(),
+            cancel,
             server,
             file_watcher: None,
+            introduced_peers,
+            authorizations,
+            sync_events: None,
+            sync_states,
         }
     }
 
+    /// Returns a receiver for the [Event]s emitted by this [Synchronizer]
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.config.clone()
+    }
+
+    pub(crate) fn event_bus(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+
+    pub(crate) fn sync_states(&self) -> Arc<SyncStates> {
+        self.sync_states.clone()
+    }
+
+    pub(crate) fn pause_switch(&self) -> Arc<PauseSwitch> {
+        self.pause_switch.clone()
+    }
+
+    pub(crate) fn introduced_peers(&self) -> Arc<IntroducedPeers> {
+        self.introduced_peers.clone()
+    }
+
+    pub(crate) fn authorizations(&self) -> Arc<PeerAuthorizations> {
+        self.authorizations.clone()
+    }
+
+    /// Returns the token cancelled when this synchronizer is stopped
+    pub(crate) fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Starts the server, the file watcher and the synchronization with the configured peers
     pub async fn start(&mut self, _auto_exit: bool) -> crate::Result<()> {
         let (sync_events_sender, sync_events_receiver) = mpsc::channel(50);
 
+        self.start_services(sync_events_sender).await?;
+        self.sync_events(sync_events_receiver).await;
+
+        Ok(())
+    }
+
+    /// Starts the server, the file watcher, the control service and the schedules, the synchronizations are only
+    /// executed when the events sent to `sync_events_sender` are processed by [Synchronizer::sync_events]
+    pub(crate) async fn start_services(
+        &mut self,
+        sync_events_sender: Sender<SyncEvent>,
+    ) -> crate::Result<()> {
         log::debug!("starting syncronizer");
+        self.sync_events = Some(sync_events_sender.clone());
+        if self.config.replace_locked_files_on_start {
+            locked_files::replace_locked_files(&self.config, self.events.versions()).await?;
+        }
         self.server.start(sync_events_sender.clone()).await?;
 
         if self.config.enable_file_watcher {
@@ -59,16 +698,46 @@ impl Synchronizer {
             }
         }
 
-        self.schedule_peers(sync_events_sender).await?;
-        self.sync_events(sync_events_receiver).await;
+        self.start_control_service(sync_events_sender.clone());
+        self.schedule_peers(sync_events_sender.clone()).await?;
+        self.schedule_outbox(sync_events_sender.clone());
+        self.schedule_scans(sync_events_sender);
+        self.schedule_mirrors();
 
         Ok(())
     }
 
+    #[cfg(feature = "grpc")]
+    fn start_control_service(&mut self, sync_events: Sender<SyncEvent>) {
+        if let Some(address) = &self.config.grpc_address {
+            self.background_tasks.extend(crate::control::start(
+                address,
+                self.config.clone(),
+                self.events.clone(),
+                self.pause_switch.clone(),
+                self.sync_states.clone(),
+                self.authorizations.clone(),
+                sync_events,
+            ));
+        }
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    fn start_control_service(&mut self, _sync_events: Sender<SyncEvent>) {
+        if self.config.grpc_address.is_some() {
+            log::warn!(
+                "grpc_address is configured, but iron-carrier was built without the grpc feature"
+            );
+        }
+    }
+
     async fn schedule_peers(&self, sync_events: Sender<SyncEvent>) -> crate::Result<()> {
         if let Some(peers) = &self.config.peers {
             for peer_address in peers.iter() {
-                log::info!("schedulling peer {} for synchonization", peer_address);
+                log::info!(
+                    "schedulling peer {} for synchonization",
+                    self.config.peer_name(peer_address)
+                );
                 sync_events
                     .send(SyncEvent::EnqueueSyncToPeer(peer_address.to_owned(), false))
                     .await?;
@@ -78,95 +747,558 @@ impl Synchronizer {
         Ok(())
     }
 
-    async fn sync_events(&self, mut events_receiver: Receiver<SyncEvent>) {
-        loop {
-            match events_receiver.recv().await {
-                Some(event) => match event {
-                    SyncEvent::EnqueueSyncToPeer(peer_address, two_way_sync) => {
-                        let config = self.config.clone();
-                        let events_buffer = self.events_buffer.clone();
-
-                        tokio::spawn(async move {
-                            match Synchronizer::sync_peer(
-                                peer_address,
-                                two_way_sync,
-                                &config,
-                                &events_buffer,
-                            )
-                            .await
-                            {
-                                Ok(_) => {
-                                    log::info!("Peer synchronization successful")
-                                }
-                                Err(e) => {
-                                    log::error!("Peer synchronization failed: {}", e);
-                                }
-                            }
-                        });
+    /// Synchronizes each alias with the peers every [Config::scan_interval], starting after the first interval
+    fn schedule_scans(&mut self, sync_events: Sender<SyncEvent>) {
+        for alias in self.config.paths.keys() {
+            let interval = match self.config.scan_interval(alias) {
+                Some(interval) => Duration::from_secs(interval),
+                None => continue,
+            };
+
+            let alias = alias.clone();
+            let sync_events = sync_events.clone();
+            let pause_switch = self.pause_switch.clone();
+            self.background_tasks.push(tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    pause_switch.wait_resumed().await;
+                    log::debug!("scheduled synchronization of alias {}", alias);
+                    if sync_events
+                        .send(SyncEvent::SyncAlias(alias.clone()))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }));
+        }
+    }
+
+    /// Pushes the aliases to their mirrors and sftp peers now and then every [Config::mirror_interval_seconds]
+    fn schedule_mirrors(&mut self) {
+        if self.config.mirrors.is_empty() && self.config.sftp_peers.is_empty() {
+            return;
+        }
+
+        let config = self.config.clone();
+        let pause_switch = self.pause_switch.clone();
+        self.background_tasks.push(tokio::spawn(async move {
+            loop {
+                pause_switch.wait_resumed().await;
+                mirror::push_mirrors(&config, &pause_switch).await;
+                tokio::time::sleep(Duration::from_secs(config.mirror_interval_seconds)).await;
+            }
+        }));
+    }
+
+    /// Sends the outboxes of the offline peers every [OUTBOX_RETRY_INTERVAL], once they are reachable again
+    fn schedule_outbox(&mut self, sync_events: Sender<SyncEvent>) {
+        let config = self.config.clone();
+        let pause_switch = self.pause_switch.clone();
+        self.background_tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(OUTBOX_RETRY_INTERVAL).await;
+                pause_switch.wait_resumed().await;
+                for peer_address in outbox::peers_with_changes(&config).await {
+                    if sync_events
+                        .send(SyncEvent::SendOutbox(peer_address))
+                        .await
+                        .is_err()
+                    {
+                        return;
                     }
-                    SyncEvent::PeerRequestedSync(peer_address, sync_starter, sync_ended) => {
-                        log::info!("Peer requested synchronization: {}", peer_address);
+                }
+            }
+        }));
+    }
+
+    /// Starts a full synchronization with `peer_address`, limited to `alias` when provided  
+    /// Peers already being synchronized are skipped
+    fn enqueue_sync(&self, peer_address: String, two_way_sync: bool, alias: Option<String>) {
+        if !self
+            .syncing_peers
+            .lock()
+            .unwrap()
+            .insert(peer_address.clone())
+        {
+            log::info!(
+                "synchronization with peer {} is already in progress",
+                self.config.peer_name(&peer_address)
+            );
+            return;
+        }
+
+        let config = self.config.clone();
+        let events_buffer = self.events_buffer.clone();
+        let events = self.events.clone();
+        let alias_locks = self.alias_locks.clone();
+        let sync_slots = self.sync_slots.clone();
+        let syncing_peers = self.syncing_peers.clone();
+        let transport = self.transport.clone();
+        let cancel = self.pause_switch.session_token();
+        let introduced_peers = self.introduced_peers.clone();
+        let sync_events = self.sync_events.clone();
+        let sync_states = self.sync_states.clone();
+        let pause_switch = self.pause_switch.clone();
+
+        tokio::spawn(async move {
+            let _slot = sync_slots.acquire().await;
+            events.versions().start_session();
+            let result = Synchronizer::sync_peer(
+                peer_address.clone(),
+                two_way_sync,
+                alias.as_deref(),
+                transport.as_ref(),
+                &config,
+                &events_buffer,
+                &events,
+                &alias_locks,
+                &introduced_peers,
+                &sync_states,
+                &pause_switch,
+                &cancel,
+            )
+            .await;
+            if let Err(err) = events.versions().end_session().await {
+                log::error!(
+                    "cannot save the versions kept from {}: {}",
+                    peer_address,
+                    err
+                );
+            }
+
+            match result {
+                Ok(new_peers) => {
+                    log::info!("Peer synchronization successful");
+                    events.notify(|observer| observer.on_cycle_complete(&peer_address));
+
+                    for new_peer in new_peers {
+                        if let Some(sync_events) = &sync_events {
+                            sync_events
+                                .send(SyncEvent::EnqueueSyncToPeer(new_peer, false))
+                                .await
+                                .ok();
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Peer synchronization failed: {}", e);
+                    sync_states.failed(&peer_address, &e.to_string());
+                    events.notify(|observer| observer.on_error(&peer_address, e.as_ref()));
+                }
+            }
+
+            syncing_peers.lock().unwrap().remove(&peer_address);
+        });
+    }
+
+    pub(crate) async fn sync_events(&self, mut events_receiver: Receiver<SyncEvent>) {
+        while let Some(event) = events_receiver.recv().await {
+            match event {
+                SyncEvent::EnqueueSyncToPeer(peer_address, _) if self.pause_switch.is_paused() => {
+                    log::info!(
+                        "synchronization is paused, ignoring sync with peer {}",
+                        self.config.peer_name(&peer_address)
+                    );
+                }
+                SyncEvent::EnqueueSyncToPeer(peer_address, two_way_sync) => {
+                    self.enqueue_sync(peer_address, two_way_sync, None);
+                }
+                SyncEvent::SyncAlias(alias) if self.pause_switch.is_paused() => {
+                    log::info!(
+                        "synchronization is paused, ignoring sync of alias {}",
+                        alias
+                    );
+                }
+                SyncEvent::SyncAlias(alias) if self.pause_switch.is_alias_paused(&alias) => {
+                    log::debug!("alias {} is paused, ignoring its sync", alias);
+                }
+                SyncEvent::SyncAlias(alias) => {
+                    let peers = self.config.peers.iter().flatten().cloned();
+                    for peer_address in peers.chain(self.introduced_peers.approved()) {
+                        self.enqueue_sync(peer_address, false, Some(alias.clone()));
+                    }
+                }
+                SyncEvent::PeerRequestedSync(peer_address, sync_starter, sync_ended) => {
+                    let peer_name = self.config.peer_name(&peer_address);
+                    log::info!("Peer requested synchronization: {}", peer_name);
+                    let pause_switch = self.pause_switch.clone();
+                    let sync_slots = self.sync_slots.clone();
+
+                    tokio::spawn(async move {
+                        let _slot = sync_slots.acquire().await;
+                        pause_switch.wait_resumed().await;
                         sync_starter.notify_one();
                         sync_ended.notified().await;
 
-                        log::info!("Peer synchronization ended");
-                    }
-                    SyncEvent::BroadcastToAllPeers(action, peers) => {
-                        log::debug!("file changed on disk: {:?}", action);
+                        log::info!("Peer synchronization ended: {}", peer_name);
+                    });
+                }
+                SyncEvent::BroadcastToAllPeers(action, _) if self.pause_switch.is_paused() => {
+                    log::debug!(
+                        "synchronization is paused, change will be sent when resumed: {:?}",
+                        action
+                    );
+                }
+                SyncEvent::BroadcastToAllPeers(action, _)
+                    if self.pause_switch.is_alias_paused(&action.file().alias) =>
+                {
+                    log::debug!(
+                        "alias {} is paused, change will be sent when resumed: {:?}",
+                        action.file().alias,
+                        action
+                    );
+                }
+                SyncEvent::BroadcastToAllPeers(action, peers) => {
+                    log::debug!("file changed on disk: {:?}", action);
+
+                    let results = futures::future::join_all(
+                        peers
+                            .iter()
+                            .map(|peer| self.sync_peer_single_action(peer, &action)),
+                    )
+                    .await;
 
-                        for peer in peers.iter() {
-                            self.sync_peer_single_action(peer, &action).await;
+                    for (peer, result) in peers.iter().zip(results) {
+                        match result {
+                            Err(e) if is_file_error(e.as_ref()) => {
+                                log::error!(
+                                    "Failed to sync action with peer {}: {}",
+                                    self.config.peer_name(peer),
+                                    e
+                                );
+                            }
+                            Err(e) => {
+                                log::warn!(
+                                    "peer {} is unreachable, the change is kept in its outbox: {}",
+                                    self.config.peer_name(peer),
+                                    e
+                                );
+                                self.keep_in_outbox(peer, &action).await;
+                            }
+                            Ok(_) => {}
                         }
                     }
-                },
-                None => {
-                    break;
+                }
+                SyncEvent::SendOutbox(_) if self.pause_switch.is_paused() => {}
+                SyncEvent::SendOutbox(peer_address) => {
+                    let result = match self.connect_peer(&peer_address).await {
+                        Ok(mut peer) => send_outbox(&mut peer, &self.config).await,
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        log::debug!(
+                            "cannot send outbox to peer {}: {}",
+                            self.config.peer_name(&peer_address),
+                            err
+                        );
+                    }
                 }
             }
         }
     }
 
-    async fn sync_peer_single_action<'b>(
+    async fn connect_peer<'a>(
+        &'a self,
+        peer_address: &'a str,
+    ) -> crate::Result<Peer<'a, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>> {
+        let peer = Peer::new(
+            peer_address,
+            self.transport.as_ref(),
+            &self.config,
+            &self.events_buffer,
+            &self.events,
+        )
+        .await?
+        .with_cancellation(self.pause_switch.session_token());
+        Ok(peer)
+    }
+
+    /// Sends `action` to the peer, after the changes kept in its outbox
+    async fn sync_peer_single_action(
         &self,
         peer_address: &str,
-        action: &'b FileAction,
+        action: &FileAction,
     ) -> crate::Result<()> {
-        let mut peer = Peer::new(peer_address, &self.config, &self.events_buffer).await?;
+        let mut peer = self.connect_peer(peer_address).await?;
+        send_outbox(&mut peer, &self.config).await?;
         peer.sync_action(action).await
     }
 
-    async fn sync_peer<'b>(
+    /// Keeps `action` in the outbox of `peer_address`, it is sent once the peer is reachable again
+    async fn keep_in_outbox(&self, peer_address: &str, action: &FileAction) {
+        let alias = &action.file().alias;
+        let path = match self.config.paths.get(alias) {
+            Some(path) => path,
+            None => return,
+        };
+
+        if let Err(err) = Outbox::new(path).push(peer_address, action).await {
+            log::error!("cannot keep change of alias {} in outbox: {}", alias, err);
+        }
+    }
+
+    /// Executes `peer_action` with `peer`, errors affecting a single file are recorded in `skipped`  
+    /// Files sent and received are listed in `in_flight` while they are transferred, cancelling one of them interrupts the
+    /// synchronization with the peer
+    async fn sync_peer_action(
+        peer: &mut Peer<'_, ReadHalf<BoxedStream>, WriteHalf<BoxedStream>>,
+        peer_action: FileAction,
+        alias: &str,
+        alias_locks: &AliasLocks,
+        in_flight: &InFlightTransfers,
+        skipped: &mut SkippedFiles,
+    ) -> crate::Result<()> {
+        // requested files are written locally, other actions only write on the peer
+        let _lock = match peer_action {
+            FileAction::Request(_) => alias_locks.lock(alias).await,
+            _ => None,
+        };
+
+        let peer_name = peer.get_name().to_owned();
+        let result = match in_flight.start(peer.get_address(), &peer_name, &peer_action) {
+            Some(transfer) => {
+                peer.track_progress(Some(transfer.progress()));
+                // the connection is left in the middle of the file, it can't be used for anything else after this
+                let result = tokio::select! {
+                    result = peer.sync_action(&peer_action) => result,
+                    _ = transfer.cancelled() => Err(IronCarrierError::Cancelled.into()),
+                };
+                peer.track_progress(None);
+                result
+            }
+            None => peer.sync_action(&peer_action).await,
+        };
+
+        match result {
+            Err(err) if is_file_error(err.as_ref()) => {
+                skipped.add(&peer_action.file().path, err);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Synchronizes the aliases with `peer_address`, returns the new peers learned from it that can be synchronized
+    #[allow(clippy::too_many_arguments)]
+    async fn sync_peer(
         peer_address: String,
         two_way_sync: bool,
+        only_alias: Option<&str>,
+        transport: &dyn Transport,
         config: &Config,
         events_buffer: &FileEventsBuffer,
-    ) -> crate::Result<()> {
-        let mut peer = Peer::new(&peer_address, config, events_buffer).await?;
-        log::info!("Peer full synchronization started: {}", peer.get_address());
+        events: &EventBus,
+        alias_locks: &AliasLocks,
+        introduced_peers: &IntroducedPeers,
+        sync_states: &SyncStates,
+        pause_switch: &PauseSwitch,
+        cancel: &CancellationToken,
+    ) -> crate::Result<Vec<String>> {
+        let mut peer = Peer::new(&peer_address, transport, config, events_buffer, events)
+            .await?
+            .with_cancellation(cancel.clone());
+        log::info!("Peer full synchronization started: {}", peer.get_name());
 
         peer.start_sync().await?;
+        let new_peers = learn_introduced_peers(&mut peer, config, introduced_peers, events).await;
+
+        let mut skipped = SkippedFiles::new();
+        let mut synced_aliases = Vec::new();
+        let aliases = config
+            .paths
+            .iter()
+            .filter(|(alias, _)| match only_alias {
+                Some(only_alias) => only_alias == alias.as_str(),
+                None => !config.is_manual_scan(alias),
+            })
+            .filter(|(alias, _)| config.syncs_alias_with(alias, &peer_address))
+            .filter(|(alias, _)| !pause_switch.is_alias_paused(alias));
+        for (alias, path) in aliases {
+            if cancel.is_cancelled() {
+                return Err(IronCarrierError::Cancelled.into());
+            }
 
-        for (alias, path) in &config.paths {
-            let (hash, mut local_files) = fs::get_files_with_hash(path, alias).await?;
-            if !peer.need_to_sync(&alias, hash) {
+            let hooks = config.hooks.get(alias);
+            let mut summary = SyncSummary::new(alias, &peer_address);
+            if let Err(err) = run_hook(hooks, HookStage::PreSync, path, &summary).await {
+                log::error!("alias {} not synchronized: {}", alias, err);
                 continue;
             }
 
-            let mut peer_files = peer.fetch_files_for_alias(alias).await?;
-            while let Some(local_file) = local_files.pop() {
-                let peer_action = match get_peer_file(&local_file, &mut peer_files) {
-                    Some(peer_file) => {
+            let agreed_state = PeerSyncState::new(path).get(&peer_address).await;
+            // the hashes are only known once the scan is over, a peer without the alias is checked with them
+            let streaming = config.streaming_scan.contains(alias)
+                && config.is_local_storage(alias)
+                && config.extra_roots(alias).is_empty()
+                && !config.on_demand.contains(alias)
+                && agreed_state.is_none()
+                && peer.alias_hash(alias).is_some();
+
+            sync_states.enter(alias, &peer_address, SyncPhase::Scanning);
+            events.notify(|observer| observer.on_scan_start(alias));
+            let mut local_files = if streaming {
+                log::info!(
+                    "alias {} is compared with peer {} while it is scanned",
+                    alias,
+                    config.peer_name(&peer_address)
+                );
+                sync_states.enter(alias, &peer_address, SyncPhase::Comparing);
+                LocalFiles::Streamed(fs::FileStream::new(path, alias, config, cancel).await?)
+            } else {
+                let (hash, local_files) =
+                    fs::get_file_list_with_hash(path, alias, config, cancel).await?;
+                sync_states.compare(alias, &peer_address, hash, peer.alias_hash(alias));
+                if !peer.need_to_sync(alias, hash) {
+                    sync_states.enter(alias, &peer_address, SyncPhase::Idle);
+                    store_agreed_state(
+                        &peer_address,
+                        peer.alias_hash(alias),
+                        alias,
+                        path,
+                        hash,
+                        &local_files,
+                    )
+                    .await;
+                    if let Err(err) = run_hook(hooks, HookStage::PostSync, path, &summary).await {
+                        log::error!("{}", err);
+                    }
+                    continue;
+                }
+
+                sync_states.enter(alias, &peer_address, SyncPhase::Comparing);
+                // without an agreed state, an empty side is seeded before the files are compared
+                let local_files = if config.enable_bootstrap
+                    && agreed_state.is_none()
+                    && bootstrap_alias(&mut peer, alias, &local_files, config, alias_locks).await?
+                {
+                    fs::get_file_list_with_hash(path, alias, config, cancel)
+                        .await?
+                        .1
+                } else {
+                    local_files
+                };
+                LocalFiles::Scanned(local_files.reader()?)
+            };
+
+            let mut peer_files = match agreed_state {
+                Some((agreed_hash, agreed_files))
+                    if peer.alias_hash(alias) == Some(agreed_hash) =>
+                {
+                    log::debug!(
+                        "alias {} didn't change on peer since last sync, using stored file list",
+                        alias
+                    );
+                    PeerFileList::stored(alias, agreed_files)
+                }
+                _ => PeerFileList::remote(alias),
+            };
+
+            sync_states.syncing(alias, &peer_address);
+            let mut preview = TransferPreview::default();
+            // deletions are applied after the comparison, once the preview is sent
+            let deletes = config.delete_propagation(alias);
+            let mut local_deletions = Vec::new();
+            // files of on demand aliases not downloaded yet only have their metadata updated
+            let on_demand = config.on_demand.contains(alias);
+            let placeholders = if on_demand {
+                Placeholders::new(path).get().await
+            } else {
+                Default::default()
+            };
+            let mut new_placeholders = Vec::new();
+            let mut removals = Vec::new();
+            let skipped_before = skipped.len();
+            let mut next_local_file = local_files.next_entry().await?;
+            let mut next_peer_file = peer.next_file(&mut peer_files).await?;
+            let journal = TransferJournal::new(path);
+            let resumed = journal.pending(&peer_address).await.queued;
+            if !resumed.is_empty() {
+                log::info!(
+                    "alias {} with peer {}: {} transfers left from the last session go first",
+                    alias,
+                    config.peer_name(&peer_address),
+                    resumed.len()
+                );
+            }
+            let mut transfers = TransferQueue::new(config, resumed);
+            let mut run = TransferRun::new(
+                alias,
+                &peer_address,
+                config,
+                alias_locks,
+                sync_states,
+                &journal,
+                cancel,
+            );
+            let quarantine = Quarantine::new(path);
+            run.quarantined = quarantine.active().await;
+            let mut transfers_started = None;
+            // transfers found by a streaming scan are sent as they add up, while the scan goes on
+            let (mut streamed_files, mut streamed_bytes) = (0, 0);
+            loop {
+                // both lists are sorted by path, so files with the same path are compared as they show up
+                let order = match (&next_local_file, &next_peer_file) {
+                    (Some(local_file), Some(peer_file)) => local_file.cmp(peer_file),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => break,
+                };
+
+                let (local_file, peer_file) = match order {
+                    Ordering::Less => (next_local_file.take(), None),
+                    Ordering::Equal => (next_local_file.take(), next_peer_file.take()),
+                    Ordering::Greater => (None, next_peer_file.take()),
+                };
+
+                if local_file.is_some() {
+                    next_local_file = local_files.next_entry().await?;
+                }
+                if peer_file.is_some() {
+                    next_peer_file = peer.next_file(&mut peer_files).await?;
+                }
+                // files of the peer out of the local scope are never listed locally, they are left out
+                let peer_file = peer_file.filter(|file| {
+                    !config.ignores_hidden(alias, &file.path)
+                        && !config.exceeds_max_depth(alias, &file.path)
+                        && config.is_included(alias, &file.path)
+                });
+                // files outside the subscriptions of either side are not synchronized
+                let local_file = local_file.filter(|file| peer.in_scope(alias, &file.path));
+                let peer_file = peer_file.filter(|file| peer.in_scope(alias, &file.path));
+
+                let exists_locally = local_file
+                    .as_ref()
+                    .is_some_and(|file| file.deleted_at.is_none());
+                let peer_size = peer_file.as_ref().map(FileInfo::content_size);
+                let placeholder = if on_demand { peer_file.clone() } else { None };
+
+                let peer_action = match (local_file, peer_file) {
+                    (Some(local_file), Some(peer_file)) => {
                         if local_file.deleted_at.is_some() && peer_file.deleted_at.is_some() {
                             //both files deleted, ignore
                             continue;
                         } else if local_file.deleted_at.is_some() && peer_file.deleted_at.is_none()
                         {
+                            if !deletes.outgoing() {
+                                continue;
+                            }
                             //remove remote file
                             FileAction::Remove(local_file)
                         } else if local_file.deleted_at.is_none() && peer_file.deleted_at.is_some()
                         {
-                            events_buffer.add_event(&local_file, &peer_address);
-                            fs::delete_file(&local_file, &config).await?;
+                            if !deletes.incoming() {
+                                continue;
+                            }
+                            if events.decide(|observer| observer.on_delete(&local_file))
+                                == Decision::Veto
+                            {
+                                skipped.add_refused(&local_file.path, IronCarrierError::Vetoed);
+                                continue;
+                            }
+
+                            local_deletions.push(local_file);
                             continue;
                         } else {
                             match local_file
@@ -174,18 +1306,15 @@ impl Synchronizer {
                                 .unwrap()
                                 .cmp(&peer_file.modified_at.unwrap())
                             {
-                                std::cmp::Ordering::Less => {
-                                    FileAction::Request(local_file);
+                                Ordering::Less => FileAction::Request(local_file),
+                                Ordering::Equal => {
                                     continue;
                                 }
-                                std::cmp::Ordering::Equal => {
-                                    continue;
-                                }
-                                std::cmp::Ordering::Greater => FileAction::Update(local_file),
+                                Ordering::Greater => FileAction::Update(local_file),
                             }
                         }
                     }
-                    None => {
+                    (Some(local_file), None) => {
                         if local_file.deleted_at.is_some() {
                             // deleted local file doesn't exist on remote
                             continue;
@@ -195,21 +1324,230 @@ impl Synchronizer {
                             FileAction::Create(local_file)
                         }
                     }
+                    (None, Some(peer_file)) => {
+                        if peer_file.deleted_at.is_some() {
+                            if !deletes.incoming() {
+                                continue;
+                            }
+                            if events.decide(|observer| observer.on_delete(&peer_file))
+                                == Decision::Veto
+                            {
+                                skipped.add_refused(&peer_file.path, IronCarrierError::Vetoed);
+                                continue;
+                            }
+
+                            local_deletions.push(peer_file);
+                            continue;
+                        } else {
+                            FileAction::Request(peer_file)
+                        }
+                    }
+                    (None, None) => continue,
                 };
 
-                peer.sync_action(&peer_action).await?
+                // archives only receive changes
+                if config.archive_mode && !matches!(peer_action, FileAction::Request(_)) {
+                    continue;
+                }
+
+                match &peer_action {
+                    FileAction::Request(file)
+                        if on_demand
+                            && (!exists_locally || placeholders.contains_key(&file.path)) =>
+                    {
+                        new_placeholders.extend(placeholder);
+                        continue;
+                    }
+                    FileAction::Create(file) | FileAction::Update(file)
+                        if placeholders.contains_key(&file.path) =>
+                    {
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                summary.record(&peer_action);
+                match peer_action {
+                    FileAction::Request(ref file) => match fs::check_representable(&file.path)
+                        .and_then(|_| config.check_file_size(alias, peer_size.unwrap_or_default()))
+                    {
+                        Ok(_) => {
+                            preview.record_transfer(exists_locally, peer_size.unwrap_or_default());
+                            streamed_bytes += peer_size.unwrap_or_default();
+                            transfers.push(peer_action)?
+                        }
+                        Err(err) => {
+                            skipped.add(&file.path, err);
+                            continue;
+                        }
+                    },
+                    FileAction::Create(ref file) | FileAction::Update(ref file) => {
+                        let existing = matches!(peer_action, FileAction::Update(_));
+                        preview.record_transfer(existing, file.content_size());
+                        streamed_bytes += file.content_size();
+                        transfers.push(peer_action)?
+                    }
+                    FileAction::Remove(_) => {
+                        removals.push(peer_action);
+                        continue;
+                    }
+                    peer_action => {
+                        Synchronizer::sync_peer_action(
+                            &mut peer,
+                            peer_action,
+                            alias,
+                            alias_locks,
+                            sync_states.in_flight(),
+                            &mut skipped,
+                        )
+                        .await?;
+                        continue;
+                    }
+                }
+
+                streamed_files += 1;
+                if streaming
+                    && (streamed_files >= BATCH_MAX_FILES || streamed_bytes >= BATCH_MAX_SIZE)
+                {
+                    let queued = std::mem::replace(
+                        &mut transfers,
+                        TransferQueue::new(config, HashSet::new()),
+                    );
+                    run.total = preview.files_added + preview.files_updated;
+                    transfers_started.get_or_insert_with(Instant::now);
+                    run.run(&mut peer, &queued.into_sorted()?, &mut skipped)
+                        .await?;
+                    streamed_files = 0;
+                    streamed_bytes = 0;
+                }
             }
 
-            while let Some(peer_file) = peer_files.pop() {
-                if peer_file.deleted_at.is_some() {
-                    events_buffer.add_event(&peer_file, &peer_address);
-                    fs::delete_file(&peer_file, &config).await?;
-                } else {
-                    peer.sync_action(&FileAction::Request(peer_file)).await?;
+            if let Err(err) = Placeholders::new(path).add(&new_placeholders).await {
+                log::error!("cannot keep placeholders of alias {}: {}", alias, err);
+            }
+            preview.files_deleted = (local_deletions.len() + removals.len()) as u64;
+            preview.eta_seconds = sync_states.eta(&peer_address, preview.bytes);
+            log::info!(
+                "alias {} with peer {}: {} files to add, {} to update, {} to delete, {} bytes",
+                alias,
+                config.peer_name(&peer_address),
+                preview.files_added,
+                preview.files_updated,
+                preview.files_deleted,
+                preview.bytes
+            );
+            sync_states.planned(alias, &peer_address, preview.clone());
+            events.emit(Event::TransferPlanned {
+                alias: alias.clone(),
+                peer: config.peer_name(&peer_address),
+                preview: preview.clone(),
+            });
+            run.total = preview.files_added + preview.files_updated;
+            run.report_progress();
+
+            for file in local_deletions {
+                let _lock = alias_locks.lock(alias).await;
+                events_buffer.add_event(&file, &peer_address);
+                match fs::delete_file(&file, config, events.versions()).await {
+                    Ok(_) => summary.record_deleted(&file.path),
+                    Err(err) => skipped.add(&file.path, err),
                 }
             }
+            for removal in removals {
+                Synchronizer::sync_peer_action(
+                    &mut peer,
+                    removal,
+                    alias,
+                    alias_locks,
+                    sync_states.in_flight(),
+                    &mut skipped,
+                )
+                .await?
+            }
+
+            let transfers_started = *transfers_started.get_or_insert_with(Instant::now);
+            let transfers = transfers.into_sorted()?;
+            if !streaming {
+                if let Err(err) = journal.plan(&peer_address, &transfers).await {
+                    log::error!("cannot store transfers of alias {}: {}", alias, err);
+                }
+            }
+            run.run(&mut peer, &transfers, &mut skipped).await?;
+            run.finish(&mut peer, &mut skipped).await?;
+            if let Err(err) = journal.finish(&peer_address).await {
+                log::error!("cannot clear transfers of alias {}: {}", alias, err);
+            }
+            if let Err(err) = quarantine
+                .record_failures(config, alias, skipped.failures_since(skipped_before))
+                .await
+            {
+                log::error!("cannot update the quarantine of alias {}: {}", alias, err);
+            }
+
+            if skipped.len() == skipped_before {
+                sync_states.transferred(&peer_address, preview.bytes, transfers_started.elapsed());
+                sync_states.enter(alias, &peer_address, SyncPhase::Idle);
+                synced_aliases.push((alias, path));
+            } else {
+                sync_states.out_of_sync(alias, &peer_address);
+                sync_states.enter(
+                    alias,
+                    &peer_address,
+                    SyncPhase::Conflicted {
+                        files: (skipped.len() - skipped_before) as u64,
+                    },
+                );
+            }
+
+            summary.forget(skipped.paths_since(skipped_before));
+            if let Err(err) = run_hook(hooks, HookStage::PostSync, path, &summary).await {
+                log::error!("{}", err);
+            }
         }
 
-        peer.finish_sync(two_way_sync).await
+        if !synced_aliases.is_empty() {
+            peer.fetch_peer_status().await?;
+            for (alias, path) in synced_aliases {
+                let (hash, files) =
+                    fs::get_file_list_with_hash(path, alias, config, cancel).await?;
+                sync_states.compare(alias, &peer_address, hash, peer.alias_hash(alias));
+                store_agreed_state(
+                    &peer_address,
+                    peer.alias_hash(alias),
+                    alias,
+                    path,
+                    hash,
+                    &files,
+                )
+                .await;
+            }
+        }
+
+        let operation = format!(
+            "synchronizing with peer {}",
+            config.peer_name(&peer_address)
+        );
+        skipped.log_summary(&operation);
+        events.error_reports().flush();
+        for group in skipped.groups() {
+            events.emit(Event::FilesSkipped {
+                operation: operation.clone(),
+                reason: group.reason,
+                files: group.files,
+                examples: group.examples,
+            });
+        }
+        peer.finish_sync(two_way_sync).await?;
+
+        Ok(new_peers)
+    }
+}
+
+impl Drop for Synchronizer {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        for task in self.background_tasks.drain(..) {
+            task.abort();
+        }
     }
 }
@@ -0,0 +1,164 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashSet,
+    path::PathBuf,
+};
+
+use super::FileAction;
+use crate::{
+    config::{Config, TransferOrder},
+    fs::{FileInfo, FileKind},
+    pattern::Pattern,
+    spool::{SortedList, Spool},
+};
+
+/// Transfers found while comparing an alias with a peer, sent in the configured order
+///
+/// Files matching [Config::transfer_priorities] go first, the remaining ties follow [Config::transfer_order]  
+/// Transfers left unfinished by a previous session go before the new ones, continuing where it stopped  
+/// Hard links always go last, after the files they point to  
+/// The queue is kept on disk when it doesn't fit [Config::memory_budget_mb]
+pub(crate) struct TransferQueue {
+    actions: Spool<FileAction>,
+}
+
+/// Index of the first priority pattern matched by `action`, files without priority go last
+fn priority(priorities: &[Pattern], action: &FileAction) -> usize {
+    priorities
+        .iter()
+        .position(|pattern| pattern.matches(&action.file().path))
+        .unwrap_or(priorities.len())
+}
+
+fn compare(
+    priorities: &[Pattern],
+    order: TransferOrder,
+    resumed: &HashSet<PathBuf>,
+    a: &FileAction,
+    b: &FileAction,
+) -> Ordering {
+    let (a_file, b_file) = (a.file(), b.file());
+    let by_order = match order {
+        TransferOrder::Path => Ordering::Equal,
+        TransferOrder::SmallestFirst => a_file.size.cmp(&b_file.size),
+        TransferOrder::NewestFirst => Reverse(a_file.modified_at).cmp(&Reverse(b_file.modified_at)),
+    };
+
+    // hard links are created after the files they point to
+    let is_link = |file: &FileInfo| matches!(file.kind, FileKind::HardLink { .. });
+
+    is_link(a_file)
+        .cmp(&is_link(b_file))
+        .then_with(|| {
+            resumed
+                .contains(&b_file.path)
+                .cmp(&resumed.contains(&a_file.path))
+        })
+        .then_with(|| priority(priorities, a).cmp(&priority(priorities, b)))
+        .then(by_order)
+        // lists kept on disk are merged, so ties are broken by path instead of relying on a stable sort
+        .then_with(|| a_file.path.cmp(&b_file.path))
+}
+
+impl TransferQueue {
+    /// Creates a queue where the files in `resumed`, queued by a previous session, go first
+    pub fn new(config: &Config, resumed: HashSet<PathBuf>) -> Self {
+        let priorities: Vec<Pattern> = config
+            .transfer_priorities
+            .iter()
+            .map(|pattern| Pattern::new(pattern))
+            .collect();
+        let order = config.transfer_order;
+
+        Self {
+            actions: Spool::new(config, move |a, b| {
+                compare(&priorities, order, &resumed, a, b)
+            }),
+        }
+    }
+
+    pub fn push(&mut self, action: FileAction) -> crate::Result<()> {
+        self.actions.push(action)
+    }
+
+    /// Returns the queued transfers in the order they must be sent
+    pub fn into_sorted(self) -> crate::Result<SortedList<FileAction>> {
+        self.actions.finish()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FileKind;
+    use std::path::PathBuf;
+
+    fn request(path: &str, size: u64, modified_at: u64) -> FileAction {
+        FileAction::Request(FileInfo {
+            alias: "a".into(),
+            path: PathBuf::from(path),
+            modified_at: Some(modified_at),
+            created_at: None,
+            deleted_at: None,
+            size: Some(size),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        })
+    }
+
+    fn sorted_paths(config: &str) -> crate::Result<Vec<String>> {
+        let config = Config::parse_content(format!(
+            "{}
+            [paths]
+            a = \"./tmp/transfer_queue\"",
+            config
+        ))?;
+
+        let mut queue = TransferQueue::new(&config, HashSet::new());
+        queue.push(request("a.iso", 3000, 1))?;
+        queue.push(request("b.md", 20, 2))?;
+        queue.push(request("c.txt", 10, 3))?;
+        queue.push(request("d.md", 10, 4))?;
+
+        Ok(queue
+            .into_sorted()?
+            .to_vec()?
+            .iter()
+            .map(|action| action.file().path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    #[test]
+    fn transfers_follow_configured_order() -> crate::Result<()> {
+        assert_eq!(sorted_paths("")?, ["a.iso", "b.md", "c.txt", "d.md"]);
+        assert_eq!(
+            sorted_paths("transfer_order = \"smallest_first\"")?,
+            ["c.txt", "d.md", "b.md", "a.iso"]
+        );
+        assert_eq!(
+            sorted_paths("transfer_order = \"newest_first\"")?,
+            ["d.md", "c.txt", "b.md", "a.iso"]
+        );
+        assert_eq!(
+            sorted_paths(
+                "transfer_order = \"smallest_first\"
+                transfer_priorities = [\"*.md\", \"*.iso\"]"
+            )?,
+            ["d.md", "b.md", "a.iso", "c.txt"]
+        );
+
+        let config = Config::parse_content(
+            "[paths]
+            a = \"./tmp/transfer_queue\""
+                .to_string(),
+        )?;
+        let mut queue =
+            TransferQueue::new(&config, vec![PathBuf::from("c.txt")].into_iter().collect());
+        queue.push(request("b.md", 20, 2))?;
+        queue.push(request("c.txt", 10, 3))?;
+        let resumed: Vec<FileAction> = queue.into_sorted()?.to_vec()?;
+        assert_eq!(resumed[0].file().path, PathBuf::from("c.txt"));
+
+        std::fs::remove_dir_all("./tmp/transfer_queue")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+//! Transfers planned with each peer, kept on disk so they survive a restart
+//!
+//! The journal is an append only log in the alias root. The queue planned with a peer is written before the transfers
+//! start, then each finished transfer and the progress of the files being received are appended. After a restart, the
+//! files still queued go first and the files partially received continue from the bytes already in their temp file.
+//! The entries of a peer are dropped once all its transfers are done. Peers are identified by their host, the port is
+//! ignored, like the file receivers do
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{fs::FileInfo, network::locality::host_of, spool::SortedList, sync::FileAction};
+
+#[derive(Serialize, Deserialize)]
+enum JournalEntry {
+    Queued {
+        peer: String,
+        path: PathBuf,
+    },
+    Done {
+        peer: String,
+        path: PathBuf,
+    },
+    Progress {
+        peer: String,
+        file: FileInfo,
+        received: u64,
+    },
+}
+
+/// Transfers with a peer that were not finished
+#[derive(Debug, Default)]
+pub(crate) struct PendingTransfers {
+    /// Files queued for transfer
+    pub queued: HashSet<PathBuf>,
+    /// Files partially received, with the version being received and the bytes already flushed to its temp file
+    pub partial: HashMap<PathBuf, (FileInfo, u64)>,
+}
+
+impl PendingTransfers {
+    fn is_empty(&self) -> bool {
+        self.queued.is_empty() && self.partial.is_empty()
+    }
+}
+
+pub(crate) struct TransferJournal {
+    journal_path: PathBuf,
+}
+
+impl TransferJournal {
+    pub fn new(alias_root_path: &Path) -> Self {
+        TransferJournal {
+            journal_path: alias_root_path.join(".transfers.ironcarrier"),
+        }
+    }
+
+    fn encode(entry: &JournalEntry, buf: &mut Vec<u8>) -> crate::Result<()> {
+        let entry = bincode::serialize(entry)?;
+        buf.extend((entry.len() as u64).to_le_bytes().iter());
+        buf.extend(entry);
+        Ok(())
+    }
+
+    /// Reads the pending transfers of every peer, a record left incomplete by a crash ends the journal
+    async fn read_journal(&self) -> HashMap<String, PendingTransfers> {
+        let mut pending: HashMap<String, PendingTransfers> = HashMap::new();
+        if !self.journal_path.exists() {
+            return pending;
+        }
+
+        let contents = match tokio::fs::read(&self.journal_path).await {
+            Ok(contents) => contents,
+            Err(err) => {
+                log::error!("cannot read transfer journal: {}", err);
+                return pending;
+            }
+        };
+
+        let mut contents = &contents[..];
+        while contents.len() >= 8 {
+            let size = u64::from_le_bytes(contents[..8].try_into().unwrap()) as usize;
+            if contents.len() - 8 < size {
+                break;
+            }
+
+            match bincode::deserialize(&contents[8..8 + size]) {
+                Ok(JournalEntry::Queued { peer, path }) => {
+                    pending.entry(peer).or_default().queued.insert(path);
+                }
+                Ok(JournalEntry::Done { peer, path }) => {
+                    if let Some(transfers) = pending.get_mut(&peer) {
+                        transfers.queued.remove(&path);
+                        transfers.partial.remove(&path);
+                    }
+                }
+                Ok(JournalEntry::Progress {
+                    peer,
+                    file,
+                    received,
+                }) => {
+                    pending
+                        .entry(peer)
+                        .or_default()
+                        .partial
+                        .insert(file.path.clone(), (file, received));
+                }
+                Err(err) => {
+                    log::error!("transfer journal is invalid, ignoring the rest: {}", err);
+                    break;
+                }
+            }
+            contents = &contents[8 + size..];
+        }
+
+        pending.retain(|_, transfers| !transfers.is_empty());
+        pending
+    }
+
+    /// Rewrites the journal with only the `pending` transfers, removing it when there are none
+    async fn write_journal(
+        &self,
+        pending: &HashMap<String, PendingTransfers>,
+    ) -> crate::Result<()> {
+        if pending.is_empty() {
+            if self.journal_path.exists() {
+                tokio::fs::remove_file(&self.journal_path).await?;
+            }
+            return Ok(());
+        }
+
+        let mut contents = Vec::new();
+        for (peer, transfers) in pending {
+            for path in &transfers.queued {
+                let entry = JournalEntry::Queued {
+                    peer: peer.clone(),
+                    path: path.clone(),
+                };
+                Self::encode(&entry, &mut contents)?;
+            }
+            for (file, received) in transfers.partial.values() {
+                let entry = JournalEntry::Progress {
+                    peer: peer.clone(),
+                    file: file.clone(),
+                    received: *received,
+                };
+                Self::encode(&entry, &mut contents)?;
+            }
+        }
+
+        tokio::fs::write(&self.journal_path, contents).await?;
+        Ok(())
+    }
+
+    async fn append(&self, entries: &[JournalEntry]) -> crate::Result<()> {
+        let mut contents = Vec::new();
+        for entry in entries {
+            Self::encode(entry, &mut contents)?;
+        }
+
+        let mut journal = tokio::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.journal_path)
+            .await?;
+        journal.write_all(&contents).await?;
+        journal.flush().await?;
+
+        Ok(())
+    }
+
+    /// Returns the transfers with `peer_address` that were not finished
+    pub async fn pending(&self, peer_address: &str) -> PendingTransfers {
+        self.read_journal()
+            .await
+            .remove(host_of(peer_address))
+            .unwrap_or_default()
+    }
+
+    /// Stores `transfers` as the queue planned with `peer_address`, replacing the previous one
+    /// The progress of files partially received is kept
+    pub async fn plan(
+        &self,
+        peer_address: &str,
+        transfers: &SortedList<FileAction>,
+    ) -> crate::Result<()> {
+        let mut pending = self.read_journal().await;
+        let peer = pending.entry(host_of(peer_address).to_owned()).or_default();
+        peer.queued.clear();
+
+        let mut transfers = transfers.reader()?;
+        while let Some(action) = transfers.next_entry()? {
+            peer.queued.insert(action.file().path.clone());
+        }
+
+        pending.retain(|_, transfers| !transfers.is_empty());
+        self.write_journal(&pending).await
+    }
+
+    /// Records that the transfers of `files` with `peer_address` are finished
+    pub async fn done(&self, peer_address: &str, files: &[FileInfo]) -> crate::Result<()> {
+        if files.is_empty() || !self.journal_path.exists() {
+            return Ok(());
+        }
+
+        let entries: Vec<JournalEntry> = files
+            .iter()
+            .map(|file| JournalEntry::Done {
+                peer: host_of(peer_address).to_owned(),
+                path: file.path.clone(),
+            })
+            .collect();
+        self.append(&entries).await
+    }
+
+    /// Records that the first `received` bytes of `file`, received from `peer_address`, are flushed to its temp file
+    pub async fn progress(
+        &self,
+        peer_address: &str,
+        file: &FileInfo,
+        received: u64,
+    ) -> crate::Result<()> {
+        self.append(&[JournalEntry::Progress {
+            peer: host_of(peer_address).to_owned(),
+            file: file.clone(),
+            received,
+        }])
+        .await
+    }
+
+    /// Forgets the transfers with `peer_address`, once all of them are finished
+    pub async fn finish(&self, peer_address: &str) -> crate::Result<()> {
+        if !self.journal_path.exists() {
+            return Ok(());
+        }
+
+        let mut pending = self.read_journal().await;
+        pending.remove(host_of(peer_address));
+        self.write_journal(&pending).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, fs::FileKind, sync::transfer_queue::TransferQueue};
+
+    #[tokio::test]
+    async fn pending_transfers_survive_restarts() -> crate::Result<()> {
+        tokio::fs::create_dir_all("./tmp/transfer_journal").await?;
+        let config = Config::parse_content(
+            "port = 8090
+            [paths]
+            a = \"./tmp/transfer_journal\""
+                .to_string(),
+        )?;
+        let file = |path: &str| FileInfo {
+            alias: "a".into(),
+            path: PathBuf::from(path),
+            modified_at: Some(1),
+            created_at: None,
+            deleted_at: None,
+            size: Some(100),
+            kind: FileKind::Regular,
+            extra: Default::default(),
+        };
+
+        let mut queue = TransferQueue::new(&config, HashSet::new());
+        queue.push(FileAction::Request(file("a")))?;
+        queue.push(FileAction::Create(file("b")))?;
+        queue.push(FileAction::Request(file("c")))?;
+
+        let journal = TransferJournal::new(Path::new("./tmp/transfer_journal"));
+        journal.plan("peer_a", &queue.into_sorted()?).await?;
+        journal.done("peer_a", &[file("a")]).await?;
+        journal.progress("peer_a", &file("c"), 40).await?;
+
+        let journal = TransferJournal::new(Path::new("./tmp/transfer_journal"));
+        let pending = journal.pending("peer_a").await;
+        assert_eq!(
+            pending.queued,
+            vec![PathBuf::from("b"), PathBuf::from("c")]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(pending.partial[Path::new("c")].1, 40);
+        assert!(journal.pending("peer_b").await.is_empty());
+
+        // a new plan keeps the progress of the files being received
+        journal
+            .plan(
+                "peer_a",
+                &TransferQueue::new(&config, HashSet::new()).into_sorted()?,
+            )
+            .await?;
+        let pending = journal.pending("peer_a").await;
+        assert!(pending.queued.is_empty());
+        assert_eq!(pending.partial[Path::new("c")].1, 40);
+
+        journal.finish("peer_a").await?;
+        assert!(journal.pending("peer_a").await.is_empty());
+        assert!(!Path::new("./tmp/transfer_journal/.transfers.ironcarrier").exists());
+
+        tokio::fs::remove_dir_all("./tmp/transfer_journal").await?;
+        Ok(())
+    }
+}
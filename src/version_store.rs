@@ -0,0 +1,456 @@
+//! Keeps previous versions of files changed or deleted by the synchronization
+//!
+//! The content of each version is kept in the [BlockStore], the store only holds the list of blocks for each version  
+//! The store also keeps the merge base of the files in [Config::merge_text_files], the last content both peers agreed on
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    block_store::{BlockHash, BlockStore},
+    config::Config,
+};
+
+/// A previous version of a file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FileVersion {
+    pub modified_at: Option<u64>,
+    pub size: u64,
+    pub stored_at: u64,
+    pub blocks: Vec<BlockHash>,
+}
+
+/// Versions and merge bases of an alias
+struct AliasVersions {
+    index_path: PathBuf,
+    versions: HashMap<PathBuf, Vec<FileVersion>>,
+    bases_path: PathBuf,
+    bases: HashMap<PathBuf, FileVersion>,
+}
+
+impl AliasVersions {
+    async fn open(root_path: &Path, alias: &str) -> crate::Result<Self> {
+        let index_path = root_path.join("versions").join(alias);
+        let versions = if index_path.exists() {
+            bincode::deserialize(&tokio::fs::read(&index_path).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        let bases_path = root_path.join("bases").join(alias);
+        let bases = if bases_path.exists() {
+            bincode::deserialize(&tokio::fs::read(&bases_path).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            index_path,
+            versions,
+            bases_path,
+            bases,
+        })
+    }
+
+    async fn save(&self) -> crate::Result<()> {
+        if let Some(parent) = self.index_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.index_path, bincode::serialize(&self.versions)?).await?;
+
+        if !self.bases.is_empty() || self.bases_path.exists() {
+            if let Some(parent) = self.bases_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&self.bases_path, bincode::serialize(&self.bases)?).await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct VersionStore {
+    blocks: BlockStore,
+    root_path: PathBuf,
+    /// Aliases are only read when one of their files is versioned
+    aliases: HashMap<String, AliasVersions>,
+}
+
+fn now_as_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+impl VersionStore {
+    /// Opens the version store
+    ///
+    /// Returns [None] if [Config::block_store_path] is not configured
+    pub async fn open(config: &Config) -> crate::Result<Option<Self>> {
+        let root_path = match &config.block_store_path {
+            Some(root_path) => root_path,
+            None => return Ok(None),
+        };
+
+        Ok(Some(VersionStore {
+            blocks: BlockStore::open(root_path).await?,
+            root_path: root_path.to_owned(),
+            aliases: HashMap::new(),
+        }))
+    }
+
+    async fn alias(&mut self, alias: &str) -> crate::Result<&mut AliasVersions> {
+        if !self.aliases.contains_key(alias) {
+            let alias_versions = AliasVersions::open(&self.root_path, alias).await?;
+            self.aliases.insert(alias.to_owned(), alias_versions);
+        }
+
+        Ok(self.aliases.get_mut(alias).expect("alias was just opened"))
+    }
+
+    /// Stores the current content of the file at `absolute_path` as a version of `relative_path`
+    ///
+    /// The oldest versions are dropped when there are more than [Config::versions_to_keep] versions
+    pub async fn add_version(
+        &mut self,
+        config: &Config,
+        alias: &str,
+        relative_path: &Path,
+        absolute_path: &Path,
+    ) -> crate::Result<()> {
+        let metadata = absolute_path.metadata()?;
+        let blocks = self
+            .blocks
+            .store_file(absolute_path, config.block_size(alias))
+            .await?;
+        // archives never drop a version
+        let versions_to_keep = match config.archive_mode {
+            true => usize::MAX,
+            false => config.versions_to_keep,
+        };
+
+        let alias_versions = self.alias(alias).await?;
+        let versions = alias_versions
+            .versions
+            .entry(relative_path.to_owned())
+            .or_default();
+        versions.push(FileVersion {
+            modified_at: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+            size: metadata.len(),
+            stored_at: now_as_secs(),
+            blocks,
+        });
+
+        let dropped_count = versions.len().saturating_sub(versions_to_keep);
+        let dropped: Vec<FileVersion> = versions.drain(..dropped_count).collect();
+        if versions.is_empty() {
+            alias_versions.versions.remove(relative_path);
+        }
+
+        for hash in dropped.iter().flat_map(|version| version.blocks.iter()) {
+            self.blocks.release(hash);
+        }
+
+        Ok(())
+    }
+
+    /// Stores the current content of the file at `absolute_path` as the merge base of `relative_path`, replacing the previous one
+    ///
+    /// `modified_at` is the modification time both peers agreed on
+    pub async fn set_base(
+        &mut self,
+        config: &Config,
+        alias: &str,
+        relative_path: &Path,
+        absolute_path: &Path,
+        modified_at: Option<u64>,
+    ) -> crate::Result<()> {
+        let size = absolute_path.metadata()?.len();
+        let blocks = self
+            .blocks
+            .store_file(absolute_path, config.block_size(alias))
+            .await?;
+
+        let base = FileVersion {
+            modified_at,
+            size,
+            stored_at: now_as_secs(),
+            blocks,
+        };
+        let previous = self
+            .alias(alias)
+            .await?
+            .bases
+            .insert(relative_path.to_owned(), base);
+        if let Some(previous) = previous {
+            for hash in previous.blocks.iter() {
+                self.blocks.release(hash);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the merge base of `relative_path`, if there is one
+    pub async fn base(
+        &mut self,
+        alias: &str,
+        relative_path: &Path,
+    ) -> crate::Result<Option<&FileVersion>> {
+        Ok(self.alias(alias).await?.bases.get(relative_path))
+    }
+
+    /// Reads the content of `version`
+    pub async fn read(&self, version: &FileVersion) -> crate::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(version.size as usize);
+        self.blocks
+            .write_blocks(&version.blocks, &mut content)
+            .await?;
+        Ok(content)
+    }
+
+    /// Persists the versions, removing blocks that are not used anymore
+    pub async fn save(&mut self) -> crate::Result<()> {
+        self.blocks.collect_garbage().await?;
+        self.blocks.save().await?;
+
+        for alias_versions in self.aliases.values() {
+            alias_versions.save().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The [VersionStore] shared by the sync sessions of an engine
+///
+/// The store is opened by the first change of a session and stays open while any session is running, the reference counts
+/// are collected and persisted once, when a session ends, instead of after every file.
+/// Changes made outside of a session are persisted right away
+pub(crate) struct VersionStores {
+    store: Mutex<Option<VersionStore>>,
+    sessions: AtomicUsize,
+}
+
+impl VersionStores {
+    pub fn new() -> Self {
+        Self {
+            store: Mutex::new(None),
+            sessions: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts a sync session, it must be finished by [VersionStores::end_session]
+    pub fn start_session(&self) {
+        self.sessions.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Ends a sync session, persisting the changes made while it was running
+    ///
+    /// The store is closed when no other session is running, releasing the block store
+    pub async fn end_session(&self) -> crate::Result<()> {
+        let mut store = self.store.lock().await;
+        let last_session = self.sessions.fetch_sub(1, Ordering::SeqCst) == 1;
+        let saved = match store.as_mut() {
+            Some(version_store) => version_store.save().await,
+            None => Ok(()),
+        };
+        if last_session {
+            *store = None;
+        }
+
+        saved
+    }
+
+    /// Returns the opened store, opening it if necessary, or [None] if [Config::block_store_path] is not configured
+    async fn open<'a>(
+        store: &'a mut Option<VersionStore>,
+        config: &Config,
+    ) -> crate::Result<Option<&'a mut VersionStore>> {
+        if store.is_none() {
+            *store = VersionStore::open(config).await?;
+        }
+
+        Ok(store.as_mut())
+    }
+
+    /// Closes the store when there is no session running, persisting the changes if `result` succeeded
+    async fn finish<T>(
+        &self,
+        store: &mut Option<VersionStore>,
+        result: crate::Result<T>,
+    ) -> crate::Result<T> {
+        if self.sessions.load(Ordering::SeqCst) == 0 {
+            if let (Ok(_), Some(mut version_store)) = (&result, store.take()) {
+                version_store.save().await?;
+            }
+        }
+
+        result
+    }
+
+    /// Stores the current content of the file at `absolute_path` as a version of `relative_path`, see [VersionStore::add_version]
+    pub async fn add_version(
+        &self,
+        config: &Config,
+        alias: &str,
+        relative_path: &Path,
+        absolute_path: &Path,
+    ) -> crate::Result<()> {
+        let mut store = self.store.lock().await;
+        let result = async {
+            if let Some(version_store) = Self::open(&mut store, config).await? {
+                version_store
+                    .add_version(config, alias, relative_path, absolute_path)
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+
+        self.finish(&mut store, result).await
+    }
+
+    /// Stores the current content of the file at `absolute_path` as the merge base of `relative_path`, see [VersionStore::set_base]
+    pub async fn set_base(
+        &self,
+        config: &Config,
+        alias: &str,
+        relative_path: &Path,
+        absolute_path: &Path,
+        modified_at: Option<u64>,
+    ) -> crate::Result<()> {
+        let mut store = self.store.lock().await;
+        let result = async {
+            if let Some(version_store) = Self::open(&mut store, config).await? {
+                version_store
+                    .set_base(config, alias, relative_path, absolute_path, modified_at)
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+
+        self.finish(&mut store, result).await
+    }
+
+    /// Returns the merge base of `relative_path`, if there is one
+    pub async fn base(
+        &self,
+        config: &Config,
+        alias: &str,
+        relative_path: &Path,
+    ) -> crate::Result<Option<FileVersion>> {
+        let mut store = self.store.lock().await;
+        let result = async {
+            match Self::open(&mut store, config).await? {
+                Some(version_store) => Ok(version_store.base(alias, relative_path).await?.cloned()),
+                None => Ok(None),
+            }
+        }
+        .await;
+
+        self.finish(&mut store, result).await
+    }
+
+    /// Reads the content of `version`
+    pub async fn read(&self, config: &Config, version: &FileVersion) -> crate::Result<Vec<u8>> {
+        let mut store = self.store.lock().await;
+        let result = async {
+            match Self::open(&mut store, config).await? {
+                Some(version_store) => version_store.read(version).await,
+                None => Err(crate::IronCarrierError::BlockStoreNotConfigured.into()),
+            }
+        }
+        .await;
+
+        self.finish(&mut store, result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_configured_number_of_versions() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "
+            block_store_path = \"./tmp/version_store/store\"
+            versions_to_keep = 2
+
+            [paths]
+            a = \"./tmp/version_store/a\""
+                .to_string(),
+        )?;
+
+        let file_path = Path::new("./tmp/version_store/a/file");
+        let stores = VersionStores::new();
+        for content in ["first", "second", "third"].iter() {
+            tokio::fs::write(file_path, content).await?;
+            stores
+                .add_version(&config, "a", Path::new("file"), file_path)
+                .await?;
+        }
+
+        let mut store = VersionStore::open(&config).await?.unwrap();
+        let versions = &store.alias("a").await?.versions[Path::new("file")];
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].size, 6);
+        assert_eq!(versions[1].blocks, vec![BlockHash::from_content(b"third")]);
+        drop(store);
+
+        tokio::fs::remove_dir_all("./tmp/version_store").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_are_saved_when_they_end() -> crate::Result<()> {
+        let config = Config::parse_content(
+            "
+            block_store_path = \"./tmp/version_store_session/store\"
+            versions_to_keep = 1
+
+            [paths]
+            a = \"./tmp/version_store_session/a\""
+                .to_string(),
+        )?;
+
+        let file_path = Path::new("./tmp/version_store_session/a/file");
+        let stores = VersionStores::new();
+        stores.start_session();
+        for content in ["first", "second"].iter() {
+            tokio::fs::write(file_path, content).await?;
+            stores
+                .add_version(&config, "a", Path::new("file"), file_path)
+                .await?;
+        }
+        assert!(!Path::new("./tmp/version_store_session/store/references").exists());
+
+        stores.end_session().await?;
+        let mut store = VersionStore::open(&config).await?.unwrap();
+        assert_eq!(store.alias("a").await?.versions[Path::new("file")].len(), 1);
+        assert!(store
+            .blocks
+            .get(&BlockHash::from_content(b"first"))
+            .await
+            .is_err());
+        drop(store);
+
+        tokio::fs::remove_dir_all("./tmp/version_store_session").await?;
+
+        Ok(())
+    }
+}